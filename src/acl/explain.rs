@@ -0,0 +1,281 @@
+//! Explaining why a target is proxied, bypassed or blocked by ACL
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+
+use crate::{context::Context, relay::socks5::Address};
+
+use super::{AccessControl, Mode, Rules};
+
+/// Decision that ACL makes for an address
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Decision {
+    /// Connect to target via proxy servers (for client)
+    Proxy,
+    /// Connect to target directly (for client)
+    Bypass,
+    /// Allowed to connect (for server)
+    Accept,
+    /// Blocked (for server)
+    Reject,
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Decision::Proxy => f.write_str("proxy"),
+            Decision::Bypass => f.write_str("bypass"),
+            Decision::Accept => f.write_str("accept"),
+            Decision::Reject => f.write_str("reject"),
+        }
+    }
+}
+
+/// Rule that makes the decision
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MatchedRule {
+    /// Section of the rule, like `[proxy_list]`
+    pub section: &'static str,
+    /// The rule itself
+    ///
+    /// IP networks are merged while loading, so this may be a larger network than the one written in ACL.
+    pub rule: String,
+}
+
+/// Explanation of a decision
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    /// The final decision
+    pub decision: Decision,
+    /// Rule that makes the decision, `None` means it is the default of the current mode
+    pub matched: Option<MatchedRule>,
+    /// Default mode of ACL, `[proxy_all]` or `[bypass_all]`
+    pub mode: Mode,
+    /// Addresses resolved for matching IP rules, `None` if DNS resolution was not needed
+    pub resolved: Option<Vec<SocketAddr>>,
+    /// Error of DNS resolution
+    pub resolve_error: Option<String>,
+}
+
+impl Explanation {
+    fn new(decision: Decision, mode: Mode) -> Explanation {
+        Explanation {
+            decision,
+            matched: None,
+            mode,
+            resolved: None,
+            resolve_error: None,
+        }
+    }
+
+    fn matched(mut self, section: &'static str, rule: String) -> Explanation {
+        self.matched = Some(MatchedRule { section, rule });
+        self
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.decision)?;
+
+        match self.matched {
+            Some(ref m) => write!(f, ", matched {} in {}", m.rule, m.section)?,
+            None => match self.decision {
+                Decision::Proxy | Decision::Bypass => {
+                    let default = match self.mode {
+                        Mode::BlackList => "[proxy_all]",
+                        Mode::WhiteList => "[bypass_all]",
+                    };
+                    write!(f, ", no rules matched, default {}", default)?;
+                }
+                Decision::Accept | Decision::Reject => f.write_str(", no rules matched")?,
+            },
+        }
+
+        if let Some(ref addrs) = self.resolved {
+            f.write_str(", resolved [")?;
+            for (idx, addr) in addrs.iter().enumerate() {
+                if idx > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{}", addr.ip())?;
+            }
+            f.write_str("]")?;
+        }
+
+        if let Some(ref err) = self.resolve_error {
+            write!(f, ", resolve failed: {}", err)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Rules {
+    /// Find the rule that `host` matches
+    fn find_host_rule(&self, host: &str, port: Option<u16>) -> Option<String> {
+        if self.exceptions.is_match(host) {
+            return None;
+        }
+
+        if let Some(rule) = self.host.find_match(host) {
+            return Some(rule);
+        }
+
+        if let Some(idx) = self.rule.matches(host).iter().next() {
+            return Some(self.rule.patterns()[idx].clone());
+        }
+
+        port.and_then(|port| self.find_port_rule(port, |rules| rules.find_host_rule(host, None)))
+    }
+
+    /// Find the network that `addr` is in
+    fn find_ip_rule(&self, addr: &IpAddr, port: Option<u16>) -> Option<String> {
+        let rule = match *addr {
+            IpAddr::V4(ref v4) => self.ipv4.iter().find(|n| n.contains(v4)).map(|n| n.to_string()),
+            IpAddr::V6(ref v6) => self.ipv6.iter().find(|n| n.contains(v6)).map(|n| n.to_string()),
+        };
+
+        rule.or_else(|| port.and_then(|port| self.find_port_rule(port, |rules| rules.find_ip_rule(addr, None))))
+    }
+
+    /// Find the port rule, or the rule qualified by `port` that matches `f`
+    fn find_port_rule<F>(&self, port: u16, f: F) -> Option<String>
+    where
+        F: Fn(&Rules) -> Option<String>,
+    {
+        if self.ports.contains(port) {
+            return Some(self.ports.to_string());
+        }
+
+        self.port_rules
+            .iter()
+            .filter(|(ports, _)| ports.contains(port))
+            .find_map(|(ports, rules)| f(rules).map(|rule| format!("{} {}", rule, ports)))
+    }
+}
+
+const PROXY_SECTION: &str = "[proxy_list]";
+const BYPASS_SECTION: &str = "[bypass_list]";
+const OUTBOUND_BLOCK_SECTION: &str = "[outbound_block_list]";
+
+impl AccessControl {
+    /// Explain the decision of `check_target_bypassed` (for client)
+    ///
+    /// This function may perform a DNS resolution
+    pub async fn explain_target(&self, context: &Context, addr: &Address) -> Explanation {
+        match *addr {
+            Address::SocketAddress(ref saddr) => self.explain_ip(saddr),
+            Address::DomainNameAddress(ref host, port) => {
+                if let Some(rule) = self.white_list.find_host_rule(host, Some(port)) {
+                    return Explanation::new(Decision::Proxy, self.mode).matched(PROXY_SECTION, rule);
+                }
+                if let Some(rule) = self.black_list.find_host_rule(host, Some(port)) {
+                    return Explanation::new(Decision::Bypass, self.mode).matched(BYPASS_SECTION, rule);
+                }
+                if self.is_ipv4_empty() && self.is_ipv6_empty() {
+                    return self.explain_default();
+                }
+
+                match context.dns_resolve(host, port).await {
+                    Ok(vaddr) => {
+                        // Bypassed if any of the resolved addresses is not in proxy list
+                        let mut exp = Explanation::new(Decision::Proxy, self.mode);
+                        for addr in &vaddr {
+                            let e = self.explain_ip(addr);
+                            if e.decision == Decision::Bypass {
+                                exp = e;
+                                break;
+                            }
+                            if exp.matched.is_none() {
+                                exp = e;
+                            }
+                        }
+                        exp.resolved = Some(vaddr);
+                        exp
+                    }
+                    Err(err) => {
+                        // Proxied if failed to resolve
+                        let mut exp = Explanation::new(Decision::Proxy, self.mode);
+                        exp.resolve_error = Some(err.to_string());
+                        exp
+                    }
+                }
+            }
+        }
+    }
+
+    /// Explain the decision of `check_ip_in_proxy_list` (for client)
+    fn explain_ip(&self, addr: &SocketAddr) -> Explanation {
+        let (ip, port) = (addr.ip(), Some(addr.port()));
+        match self.mode {
+            Mode::BlackList => match self.black_list.find_ip_rule(&ip, port) {
+                Some(rule) => Explanation::new(Decision::Bypass, self.mode).matched(BYPASS_SECTION, rule),
+                None => self.explain_default(),
+            },
+            Mode::WhiteList => match self.white_list.find_ip_rule(&ip, port) {
+                Some(rule) => Explanation::new(Decision::Proxy, self.mode).matched(PROXY_SECTION, rule),
+                None => self.explain_default(),
+            },
+        }
+    }
+
+    fn explain_default(&self) -> Explanation {
+        let decision = if self.is_default_in_proxy_list() {
+            Decision::Proxy
+        } else {
+            Decision::Bypass
+        };
+        Explanation::new(decision, self.mode)
+    }
+
+    /// Explain the decision of `check_outbound_blocked` and `check_resolved_outbound_blocked` (for server)
+    ///
+    /// Domain names will be resolved if there are IP rules in `[outbound_block_list]`
+    pub async fn explain_outbound(&self, context: &Context, addr: &Address) -> Explanation {
+        let rules = &self.outbound_block;
+
+        let reject = |rule| Explanation::new(Decision::Reject, self.mode).matched(OUTBOUND_BLOCK_SECTION, rule);
+
+        match *addr {
+            Address::SocketAddress(ref saddr) => match rules.find_ip_rule(&saddr.ip(), Some(saddr.port())) {
+                Some(rule) => reject(rule),
+                None => Explanation::new(Decision::Accept, self.mode),
+            },
+            Address::DomainNameAddress(ref host, port) => {
+                if let Some(rule) = rules.find_host_rule(host, Some(port)) {
+                    return reject(rule);
+                }
+                if rules.is_ipv4_empty() && rules.is_ipv6_empty() {
+                    return Explanation::new(Decision::Accept, self.mode);
+                }
+
+                match context.dns_resolve(host, port).await {
+                    Ok(vaddr) => {
+                        // Blocked addresses are skipped while connecting, so it is rejected only if all of them are blocked
+                        let mut exp = Explanation::new(Decision::Accept, self.mode);
+                        for (idx, addr) in vaddr.iter().enumerate() {
+                            match rules.find_ip_rule(&addr.ip(), Some(addr.port())) {
+                                Some(rule) if idx == 0 => exp = reject(rule),
+                                Some(..) => {}
+                                None => {
+                                    exp = Explanation::new(Decision::Accept, self.mode);
+                                    break;
+                                }
+                            }
+                        }
+                        exp.resolved = Some(vaddr);
+                        exp
+                    }
+                    Err(err) => {
+                        let mut exp = Explanation::new(Decision::Reject, self.mode);
+                        exp.resolve_error = Some(err.to_string());
+                        exp
+                    }
+                }
+            }
+        }
+    }
+}
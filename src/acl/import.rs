@@ -0,0 +1,488 @@
+//! Importing rules from other proxy tools' formats into `AccessControl`
+//!
+//! - [gfwlist](https://github.com/gfwlist/gfwlist), base64 encoded AdBlock Plus syntax
+//! - [Clash](https://github.com/Dreamacro/clash/wiki/configuration), `rules` in YAML configuration
+//! - [Surge](https://manual.nssurge.com/), `[Rule]` section in configuration
+//!
+//! These formats have more features than ACL, rules that cannot be represented by ACL will be
+//! listed in `ImportReport` instead of failing the whole loading process.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Error, ErrorKind, Read},
+    net::IpAddr,
+    path::Path,
+};
+
+use ipnet::IpNet;
+use regex::Regex;
+use serde::Deserialize;
+
+use super::{sub_domains_tree::HostRuleType, AccessControl, Mode, ParsingRules};
+
+/// A rule that couldn't be converted into ACL rules
+#[derive(Debug, Clone)]
+pub struct UnconvertedRule {
+    /// Line number in the original file, or index in the `rules` list of Clash configuration, starts from 1
+    pub line: usize,
+    /// The original rule
+    pub rule: String,
+    /// Why it cannot be converted
+    pub reason: &'static str,
+}
+
+impl fmt::Display for UnconvertedRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: \"{}\", {}", self.line, self.rule, self.reason)
+    }
+}
+
+/// Report of importing rules from other formats
+#[derive(Debug, Clone, Default)]
+pub struct ImportReport {
+    /// Number of rules that are successfully converted
+    pub converted: usize,
+    /// Rules that are ignored
+    pub unconverted: Vec<UnconvertedRule>,
+}
+
+impl ImportReport {
+    fn unconverted(&mut self, line: usize, rule: &str, reason: &'static str) {
+        self.unconverted.push(UnconvertedRule {
+            line,
+            rule: rule.to_owned(),
+            reason,
+        });
+    }
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} rules converted, {} rules unconverted",
+            self.converted,
+            self.unconverted.len()
+        )?;
+
+        for r in &self.unconverted {
+            write!(f, "\n  {}", r)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Clash configuration, only `rules` is needed
+#[derive(Deserialize)]
+struct ClashConfig {
+    #[serde(default)]
+    rules: Vec<String>,
+}
+
+/// Rule sets that are being imported
+struct Importer {
+    mode: Mode,
+    bypass: ParsingRules,
+    proxy: ParsingRules,
+    report: ImportReport,
+}
+
+impl Importer {
+    fn new(mode: Mode) -> Importer {
+        Importer {
+            mode,
+            bypass: ParsingRules::new("[bypass_list]"),
+            proxy: ParsingRules::new("[proxy_list]"),
+            report: ImportReport::default(),
+        }
+    }
+
+    fn finish(self) -> io::Result<(AccessControl, ImportReport)> {
+        let acl = AccessControl {
+            outbound_block: ParsingRules::new("[outbound_block_list]").into_rules()?,
+            black_list: self.bypass.into_rules()?,
+            white_list: self.proxy.into_rules()?,
+            mode: self.mode,
+        };
+
+        Ok((acl, self.report))
+    }
+
+    fn list_mut(&mut self, proxy: bool) -> &mut ParsingRules {
+        if proxy {
+            &mut self.proxy
+        } else {
+            &mut self.bypass
+        }
+    }
+
+    fn add_host_rule(&mut self, proxy: bool, ty: HostRuleType, host: &str) {
+        self.list_mut(proxy).add_host_rule(ty, host);
+        self.report.converted += 1;
+    }
+
+    fn add_regex(&mut self, proxy: bool, line: usize, rule: &str, regex: String) {
+        // Checks every regex here, one invalid regex will fail the whole `RegexSet`
+        if Regex::new(&regex).is_err() {
+            self.report.unconverted(line, rule, "invalid regular expression");
+            return;
+        }
+
+        self.list_mut(proxy).rules.push(regex);
+        self.report.converted += 1;
+    }
+
+    fn add_ip_net(&mut self, proxy: bool, net: IpNet) {
+        self.list_mut(proxy).add_ip_net(net);
+        self.report.converted += 1;
+    }
+}
+
+fn read_to_string<P: AsRef<Path>>(p: P) -> io::Result<String> {
+    let mut content = String::new();
+    File::open(p)?.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+impl AccessControl {
+    /// Load rules from a [gfwlist](https://github.com/gfwlist/gfwlist) file
+    ///
+    /// Hosts in the list will be proxied, exceptions (started with `@@`) will be bypassed,
+    /// and all the others will be bypassed by default.
+    ///
+    /// The list could be base64 encoded (as it is published) or in plain text.
+    pub fn load_from_gfwlist<P: AsRef<Path>>(p: P) -> io::Result<(AccessControl, ImportReport)> {
+        let content = read_to_string(p)?;
+        AccessControl::load_from_gfwlist_str(&content)
+    }
+
+    /// Load rules from content of a gfwlist file
+    pub fn load_from_gfwlist_str(content: &str) -> io::Result<(AccessControl, ImportReport)> {
+        let content = decode_gfwlist(content)?;
+
+        let mut importer = Importer::new(Mode::WhiteList);
+
+        for (idx, line) in content.lines().enumerate() {
+            let line_no = idx + 1;
+            let line = line.trim();
+
+            // Empty lines, comments and the `[AutoProxy x.x.x]` header
+            if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+                continue;
+            }
+
+            let (exception, rule) = match line.strip_prefix("@@") {
+                Some(rule) => (true, rule),
+                None => (false, line),
+            };
+
+            if rule.starts_with('/') && rule.ends_with('/') && rule.len() > 1 {
+                importer
+                    .report
+                    .unconverted(line_no, line, "regular expressions in gfwlist match URLs, not hosts");
+                continue;
+            }
+
+            let (ty, host) = if let Some(domain) = rule.strip_prefix("||") {
+                // Domain and all its sub-domains
+                (HostRuleType::Domain, domain)
+            } else if let Some(url) = rule.strip_prefix('|') {
+                // Starts with the URL, `|http://example.com/path`
+                let url = match url.find("://") {
+                    Some(pos) => &url[pos + 3..],
+                    None => url,
+                };
+                (HostRuleType::Full, url)
+            } else if let Some(domain) = rule.strip_prefix('.') {
+                (HostRuleType::Domain, domain)
+            } else {
+                // Keyword in URL, treat it as a domain if it looks like a host
+                (HostRuleType::Domain, rule)
+            };
+
+            let host = extract_host(host);
+            if host.is_empty() || !host.contains('.') {
+                importer.report.unconverted(line_no, line, "not a host rule");
+                continue;
+            }
+
+            if host.contains('*') {
+                if exception {
+                    importer
+                        .report
+                        .unconverted(line_no, line, "wildcard exceptions are not supported");
+                    continue;
+                }
+
+                let regex = wildcard_to_regex(ty, host);
+                importer.add_regex(true, line_no, line, regex);
+                continue;
+            }
+
+            if exception {
+                // Exceptions have higher priority than the other rules in gfwlist
+                importer.proxy.add_exception(ty, host);
+                importer.add_host_rule(false, ty, host);
+            } else {
+                importer.add_host_rule(true, ty, host);
+            }
+        }
+
+        importer.finish()
+    }
+
+    /// Load rules from the `rules` section of a [Clash](https://github.com/Dreamacro/clash) YAML configuration
+    ///
+    /// Rules with `DIRECT` policy will be bypassed, rules with other proxy policies will be proxied.
+    /// The default strategy is decided by the `MATCH` rule.
+    ///
+    /// NOTE: Clash matches rules in order, but ACL doesn't. Rules in `[proxy_list]` have higher priority.
+    pub fn load_from_clash<P: AsRef<Path>>(p: P) -> io::Result<(AccessControl, ImportReport)> {
+        let content = read_to_string(p)?;
+        AccessControl::load_from_clash_str(&content)
+    }
+
+    /// Load rules from content of a Clash YAML configuration
+    pub fn load_from_clash_str(content: &str) -> io::Result<(AccessControl, ImportReport)> {
+        let config: ClashConfig = match serde_yaml::from_str(content) {
+            Ok(c) => c,
+            Err(err) => {
+                let err = Error::new(ErrorKind::InvalidData, format!("invalid Clash configuration: {}", err));
+                return Err(err);
+            }
+        };
+
+        let mut importer = Importer::new(Mode::BlackList);
+        for (idx, rule) in config.rules.iter().enumerate() {
+            // Clash's rules are list items, use their indexes instead of line numbers
+            import_rule_line(&mut importer, idx + 1, rule);
+        }
+
+        importer.finish()
+    }
+
+    /// Load rules from the `[Rule]` section of a [Surge](https://manual.nssurge.com/) configuration
+    ///
+    /// Rules with `DIRECT` policy will be bypassed, rules with other proxy policies will be proxied.
+    /// The default strategy is decided by the `FINAL` rule.
+    ///
+    /// NOTE: Surge matches rules in order, but ACL doesn't. Rules in `[proxy_list]` have higher priority.
+    pub fn load_from_surge<P: AsRef<Path>>(p: P) -> io::Result<(AccessControl, ImportReport)> {
+        let content = read_to_string(p)?;
+        AccessControl::load_from_surge_str(&content)
+    }
+
+    /// Load rules from content of a Surge configuration
+    pub fn load_from_surge_str(content: &str) -> io::Result<(AccessControl, ImportReport)> {
+        let mut importer = Importer::new(Mode::BlackList);
+        let mut in_rule_section = false;
+
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+
+            if line.starts_with('[') && line.ends_with(']') {
+                in_rule_section = line.eq_ignore_ascii_case("[Rule]");
+                continue;
+            }
+
+            if !in_rule_section || line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+
+            import_rule_line(&mut importer, idx + 1, line);
+        }
+
+        importer.finish()
+    }
+}
+
+/// Import one rule in Clash or Surge format, `TYPE,VALUE,POLICY[,OPTIONS]`
+fn import_rule_line(importer: &mut Importer, line_no: usize, line: &str) {
+    // Surge allows comments at the end of rules
+    let rule = match line.find(" //") {
+        Some(pos) => &line[..pos],
+        None => line,
+    };
+
+    let parts: Vec<&str> = rule.split(',').map(str::trim).collect();
+
+    // Default strategy, `MATCH,POLICY` (Clash) or `FINAL,POLICY` (Surge)
+    if parts[0].eq_ignore_ascii_case("MATCH") || parts[0].eq_ignore_ascii_case("FINAL") {
+        match parts.get(1) {
+            Some(policy) if is_direct_policy(policy) => importer.mode = Mode::WhiteList,
+            Some(policy) if is_reject_policy(policy) => {
+                importer.report.unconverted(line_no, line, "REJECT policy is not supported");
+            }
+            Some(..) => importer.mode = Mode::BlackList,
+            None => importer.report.unconverted(line_no, line, "missing policy"),
+        }
+        return;
+    }
+
+    let (ty, value, policy) = match (parts.first(), parts.get(1), parts.get(2)) {
+        (Some(ty), Some(value), Some(policy)) => (ty.to_ascii_uppercase(), *value, *policy),
+        _ => {
+            importer.report.unconverted(line_no, line, "malformed rule");
+            return;
+        }
+    };
+
+    if is_reject_policy(policy) {
+        importer.report.unconverted(line_no, line, "REJECT policy is not supported");
+        return;
+    }
+
+    let proxy = !is_direct_policy(policy);
+
+    match ty.as_str() {
+        "DOMAIN" => importer.add_host_rule(proxy, HostRuleType::Full, value),
+        "DOMAIN-SUFFIX" => importer.add_host_rule(proxy, HostRuleType::Domain, value),
+        "DOMAIN-KEYWORD" => importer.add_regex(proxy, line_no, line, regex::escape(value)),
+        "IP-CIDR" | "IP-CIDR6" => match value.parse::<IpNet>() {
+            Ok(net) => importer.add_ip_net(proxy, net),
+            Err(..) => match value.parse::<IpAddr>() {
+                Ok(ip) => importer.add_ip_net(proxy, IpNet::from(ip)),
+                Err(..) => importer.report.unconverted(line_no, line, "invalid IP CIDR"),
+            },
+        },
+        "GEOIP" => importer.report.unconverted(line_no, line, "GeoIP database is not supported"),
+        _ => importer.report.unconverted(line_no, line, "unsupported rule type"),
+    }
+}
+
+fn is_direct_policy(policy: &str) -> bool {
+    policy.eq_ignore_ascii_case("DIRECT")
+}
+
+fn is_reject_policy(policy: &str) -> bool {
+    // REJECT, REJECT-TINYGIF, REJECT-DROP, ...
+    policy.get(..6).map(|p| p.eq_ignore_ascii_case("REJECT")).unwrap_or(false)
+}
+
+/// gfwlist is published in base64, but some mirrors provide the decoded plain text
+fn decode_gfwlist(content: &str) -> io::Result<String> {
+    let trimmed = content.trim_start();
+    if trimmed.starts_with('[') || trimmed.starts_with('!') {
+        return Ok(content.to_owned());
+    }
+
+    let encoded: String = content.split_whitespace().collect();
+    match base64::decode(&encoded) {
+        Ok(decoded) => match String::from_utf8(decoded) {
+            Ok(s) => Ok(s),
+            Err(..) => Err(Error::new(ErrorKind::InvalidData, "gfwlist is not UTF-8 encoded")),
+        },
+        Err(err) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("gfwlist is not base64 encoded, {}", err),
+        )),
+    }
+}
+
+/// Strips scheme, path, port and AdBlock separators from a URL pattern, `example.com:8080/path^` -> `example.com`
+fn extract_host(url: &str) -> &str {
+    let end = url.find(&['/', ':', '^', '?'][..]).unwrap_or(url.len());
+    &url[..end]
+}
+
+fn wildcard_to_regex(ty: HostRuleType, host: &str) -> String {
+    let pattern = host
+        .split('*')
+        .map(regex::escape)
+        .collect::<Vec<String>>()
+        .join("[^.]*");
+
+    match ty {
+        HostRuleType::Domain => format!("(^|\\.){}$", pattern),
+        HostRuleType::Full => format!("^{}$", pattern),
+        HostRuleType::Suffix => format!("{}$", pattern),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn gfwlist() {
+        let list = "[AutoProxy 0.2.9]\n\
+                    ! comment\n\
+                    ||google.com\n\
+                    |http://www.example.org/path\n\
+                    .twitter.com\n\
+                    @@||cn.google.com\n\
+                    /^https?:\\/\\/[^\\/]+blogspot\\.(.*)/\n";
+        let encoded = base64::encode(list);
+
+        let (acl, report) = AccessControl::load_from_gfwlist_str(&encoded).unwrap();
+        assert_eq!(report.converted, 4);
+        assert_eq!(report.unconverted.len(), 1);
+
+        assert_eq!(acl.check_host_in_proxy_list("www.google.com"), Some(true));
+        assert_eq!(acl.check_host_in_proxy_list("www.example.org"), Some(true));
+        assert_eq!(acl.check_host_in_proxy_list("twitter.com"), Some(true));
+        assert_eq!(acl.check_host_in_proxy_list("maps.cn.google.com"), Some(false));
+        assert_eq!(acl.check_host_in_proxy_list("example.org"), None);
+        assert!(!acl.is_default_in_proxy_list());
+    }
+
+    #[test]
+    fn clash() {
+        let config = "port: 7890\n\
+                      rules:\n  \
+                        - DOMAIN-SUFFIX,google.com,Proxy\n  \
+                        - DOMAIN,www.baidu.com,DIRECT\n  \
+                        - DOMAIN-KEYWORD,facebook,Proxy\n  \
+                        - IP-CIDR,192.168.0.0/16,DIRECT,no-resolve\n  \
+                        - DOMAIN-SUFFIX,ad.com,REJECT\n  \
+                        - GEOIP,CN,DIRECT\n  \
+                        - MATCH,Proxy\n";
+
+        let (acl, report) = AccessControl::load_from_clash_str(config).unwrap();
+        assert_eq!(report.converted, 4);
+        assert_eq!(report.unconverted.len(), 2);
+
+        assert_eq!(acl.check_host_in_proxy_list("mail.google.com"), Some(true));
+        assert_eq!(acl.check_host_in_proxy_list("www.baidu.com"), Some(false));
+        assert_eq!(acl.check_host_in_proxy_list("m.facebook.net"), Some(true));
+        assert!(!acl.check_ip_in_proxy_list(&"192.168.1.1".parse().unwrap()));
+        assert!(acl.is_default_in_proxy_list());
+    }
+
+    #[test]
+    fn clash_non_ascii_policy() {
+        let config = "rules:\n  \
+                        - DOMAIN-SUFFIX,youtube.com,🇭🇰 香港\n  \
+                        - DOMAIN-SUFFIX,qq.com,直连\n  \
+                        - DOMAIN-SUFFIX,ad.com,REJECT-TINYGIF\n";
+
+        let (acl, report) = AccessControl::load_from_clash_str(config).unwrap();
+        assert_eq!(report.converted, 2);
+        assert_eq!(report.unconverted.len(), 1);
+
+        assert_eq!(acl.check_host_in_proxy_list("www.youtube.com"), Some(true));
+        assert_eq!(acl.check_host_in_proxy_list("qq.com"), Some(true));
+    }
+
+    #[test]
+    fn surge() {
+        let config = "[General]\n\
+                      loglevel = notify\n\
+                      [Rule]\n\
+                      # comment\n\
+                      DOMAIN-SUFFIX,google.com,Proxy\n\
+                      IP-CIDR,10.0.0.0/8,DIRECT\n\
+                      USER-AGENT,Instagram*,DIRECT\n\
+                      FINAL,DIRECT\n";
+
+        let (acl, report) = AccessControl::load_from_surge_str(config).unwrap();
+        assert_eq!(report.converted, 2);
+        assert_eq!(report.unconverted.len(), 1);
+
+        assert_eq!(acl.check_host_in_proxy_list("google.com"), Some(true));
+        assert!(!acl.check_ip_in_proxy_list(&"10.1.1.1".parse().unwrap()));
+        assert!(!acl.is_default_in_proxy_list());
+    }
+}
@@ -16,6 +16,21 @@ use regex::{RegexSet, RegexSetBuilder};
 
 use crate::{context::Context, relay::socks5::Address};
 
+use self::{
+    ports::{split_port_qualifier, PortSet},
+    sub_domains_tree::{try_convert_regex, HostRuleType, HostRules},
+};
+
+pub use self::{
+    explain::{Decision, Explanation, MatchedRule},
+    import::{ImportReport, UnconvertedRule},
+};
+
+mod explain;
+mod import;
+mod ports;
+mod sub_domains_tree;
+
 /// Strategy mode that ACL is running
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Mode {
@@ -29,12 +44,22 @@ pub enum Mode {
 struct Rules {
     ipv4: IpRange<Ipv4Net>,
     ipv6: IpRange<Ipv6Net>,
+    host: HostRules,
     rule: RegexSet,
+    exceptions: HostRules,
+    ports: PortSet,
+    port_rules: Vec<(PortSet, Rules)>,
 }
 
 impl fmt::Debug for Rules {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Rules {{ ipv4: {:?}, ipv6: {:?}, rule: [", self.ipv4, self.ipv6)?;
+        write!(
+            f,
+            "Rules {{ ipv4: {:?}, ipv6: {:?}, host: {} rules, rule: [",
+            self.ipv4,
+            self.ipv6,
+            self.host.len()
+        )?;
 
         let max_len = 2;
         let has_more = self.rule.len() > max_len;
@@ -50,49 +75,245 @@ impl fmt::Debug for Rules {
             f.write_str(", ...")?;
         }
 
+        write!(f, "], ports: {}, port_rules: [", self.ports)?;
+
+        for (idx, (ports, rules)) in self.port_rules.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{} => {:?}", ports, rules)?;
+        }
+
         f.write_str("] }")
     }
 }
 
 impl Rules {
     /// Create a new rule
-    fn new(mut ipv4: IpRange<Ipv4Net>, mut ipv6: IpRange<Ipv6Net>, rule: RegexSet) -> Rules {
+    fn new(
+        mut ipv4: IpRange<Ipv4Net>,
+        mut ipv6: IpRange<Ipv6Net>,
+        host: HostRules,
+        rule: RegexSet,
+        exceptions: HostRules,
+        ports: PortSet,
+        port_rules: Vec<(PortSet, Rules)>,
+    ) -> Rules {
         // Optimization, merging networks
         ipv4.simplify();
         ipv6.simplify();
 
-        Rules { ipv4, ipv6, rule }
+        Rules {
+            ipv4,
+            ipv6,
+            host,
+            rule,
+            exceptions,
+            ports,
+            port_rules,
+        }
     }
 
     /// Check if the specified address matches these rules
     fn check_address_matched(&self, addr: &Address) -> bool {
         match *addr {
-            Address::SocketAddress(ref saddr) => self.check_ip_matched(&saddr.ip()),
-            Address::DomainNameAddress(ref domain, ..) => self.check_host_matched(domain),
+            Address::SocketAddress(ref saddr) => self.check_ip_matched(&saddr.ip(), Some(saddr.port())),
+            Address::DomainNameAddress(ref domain, port) => self.check_host_matched(domain, Some(port)),
         }
     }
 
     /// Check if the specified address matches any rules
-    fn check_ip_matched(&self, addr: &IpAddr) -> bool {
-        match addr {
+    ///
+    /// Rules with ports are only checked if `port` is provided
+    fn check_ip_matched(&self, addr: &IpAddr, port: Option<u16>) -> bool {
+        let matched = match addr {
             IpAddr::V4(v4) => self.ipv4.contains(v4),
             IpAddr::V6(v6) => self.ipv6.contains(v6),
-        }
+        };
+
+        matched
+            || match port {
+                None => false,
+                Some(port) => self.check_port_matched(port, |rules| rules.check_ip_matched(addr, None)),
+            }
     }
 
     /// Check if the specified host matches any rules
-    fn check_host_matched(&self, host: &str) -> bool {
-        self.rule.is_match(host)
+    ///
+    /// Rules with ports are only checked if `port` is provided
+    fn check_host_matched(&self, host: &str, port: Option<u16>) -> bool {
+        if self.exceptions.is_match(host) {
+            return false;
+        }
+
+        if self.host.is_match(host) || self.rule.is_match(host) {
+            return true;
+        }
+
+        match port {
+            None => false,
+            Some(port) => self.check_port_matched(port, |rules| rules.check_host_matched(host, None)),
+        }
+    }
+
+    /// Check if `port` matches port rules, or rules qualified by `port` matches `f`
+    fn check_port_matched<F>(&self, port: u16, f: F) -> bool
+    where
+        F: Fn(&Rules) -> bool,
+    {
+        if self.ports.contains(port) {
+            return true;
+        }
+
+        self.port_rules
+            .iter()
+            .any(|(ports, rules)| ports.contains(port) && f(rules))
     }
 
     /// Check if there are no rules for IPv4 addresses
     fn is_ipv4_empty(&self) -> bool {
-        self.ipv4.iter().next().is_none()
+        self.ipv4.iter().next().is_none() && self.port_rules.iter().all(|(_, rules)| rules.is_ipv4_empty())
     }
 
     /// Check if there are no rules for IPv6 addresses
     fn is_ipv6_empty(&self) -> bool {
-        self.ipv6.iter().next().is_none()
+        self.ipv6.iter().next().is_none() && self.port_rules.iter().all(|(_, rules)| rules.is_ipv6_empty())
+    }
+}
+
+/// Rules of a section that are being loaded
+struct ParsingRules {
+    name: &'static str,
+    ipv4: IpRange<Ipv4Net>,
+    ipv6: IpRange<Ipv6Net>,
+    host: HostRules,
+    rules: Vec<String>,
+    exceptions: HostRules,
+    ports: PortSet,
+    port_rules: Vec<(PortSet, ParsingRules)>,
+}
+
+impl ParsingRules {
+    fn new(name: &'static str) -> ParsingRules {
+        ParsingRules {
+            name,
+            ipv4: IpRange::new(),
+            ipv6: IpRange::new(),
+            host: HostRules::new(),
+            rules: Vec::new(),
+            exceptions: HostRules::new(),
+            ports: PortSet::default(),
+            port_rules: Vec::new(),
+        }
+    }
+
+    /// Add a rule line into this section, fails if its ports are invalid
+    fn add_rule(&mut self, line: &str) -> io::Result<()> {
+        if let Some(ports) = PortSet::parse_rule(line) {
+            self.ports.extend(&self.check_ports(line, ports)?);
+            return Ok(());
+        }
+
+        if let Some((rule, ports)) = split_port_qualifier(line) {
+            let ports = self.check_ports(line, ports)?;
+            return self.port_rules_mut(ports).add_rule(rule);
+        }
+
+        if let Some((ty, host)) = HostRuleType::parse_rule(line) {
+            self.add_host_rule(ty, host);
+            return Ok(());
+        }
+
+        match line.parse::<IpNet>() {
+            Ok(net) => self.add_ip_net(net),
+            Err(..) => {
+                // Maybe it is a pure IpAddr
+                match line.parse::<IpAddr>() {
+                    Ok(ip) => self.add_ip_net(IpNet::from(ip)),
+                    Err(..) => match try_convert_regex(line) {
+                        // Most rules are simple domain matchers, they don't have to be compiled into regex
+                        Some((ty, host)) => self.add_host_rule(ty, &host),
+                        None => {
+                            // FIXME: If this line is not a valid regex, how can we know without actually compile it?
+                            self.rules.push(line.to_owned());
+                        }
+                    },
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_ports(&self, line: &str, ports: io::Result<PortSet>) -> io::Result<PortSet> {
+        ports.map_err(|err| Error::new(ErrorKind::Other, format!("{} rule \"{}\" error: {}", self.name, line, err)))
+    }
+
+    /// Rules that only matches destinations with `ports`
+    fn port_rules_mut(&mut self, ports: PortSet) -> &mut ParsingRules {
+        let idx = match self.port_rules.iter().position(|(p, _)| *p == ports) {
+            Some(idx) => idx,
+            None => {
+                self.port_rules.push((ports, ParsingRules::new(self.name)));
+                self.port_rules.len() - 1
+            }
+        };
+        &mut self.port_rules[idx].1
+    }
+
+    fn add_ip_net(&mut self, net: IpNet) {
+        match net {
+            IpNet::V4(v4) => {
+                self.ipv4.add(v4);
+            }
+            IpNet::V6(v6) => {
+                self.ipv6.add(v6);
+            }
+        }
+    }
+
+    fn add_host_rule(&mut self, ty: HostRuleType, host: &str) {
+        match ty {
+            HostRuleType::Full => self.host.add_full(host),
+            HostRuleType::Domain => self.host.add_domain(host),
+            HostRuleType::Suffix => self.host.add_suffix(host),
+        }
+    }
+
+    /// Add a host that shouldn't be matched by this section, even if it matches the other rules
+    fn add_exception(&mut self, ty: HostRuleType, host: &str) {
+        match ty {
+            HostRuleType::Full => self.exceptions.add_full(host),
+            HostRuleType::Domain => self.exceptions.add_domain(host),
+            HostRuleType::Suffix => self.exceptions.add_suffix(host),
+        }
+    }
+
+    fn into_rules(self) -> io::Result<Rules> {
+        const REGEX_SIZE_LIMIT: usize = usize::max_value();
+
+        let regex = match RegexSetBuilder::new(self.rules).size_limit(REGEX_SIZE_LIMIT).build() {
+            Ok(r) => r,
+            Err(err) => {
+                let err = Error::new(ErrorKind::Other, format!("{} regex error: {}", self.name, err));
+                return Err(err);
+            }
+        };
+
+        let mut port_rules = Vec::with_capacity(self.port_rules.len());
+        for (ports, rules) in self.port_rules {
+            port_rules.push((ports, rules.into_rules()?));
+        }
+
+        Ok(Rules::new(
+            self.ipv4,
+            self.ipv6,
+            self.host,
+            regex,
+            self.exceptions,
+            self.ports,
+            port_rules,
+        ))
     }
 }
 
@@ -142,7 +363,18 @@ impl Rules {
 ///
 /// - CIDR form network addresses, like `10.9.0.32/16`
 /// - IP addresses, like `127.0.0.1` or `::1`
+/// - Domain names, like `domain:gmail.com`, matches `gmail.com` and all its sub-domains
+/// - Full host names, like `full:mail.google.com`, matches only `mail.google.com`
+/// - Host suffixes, like `suffix:google.com`, matches all hosts ending with `google.com`
 /// - Regular Expression for matching hosts, like `(^|\.)gmail\.com$`
+/// - Destination ports, like `port:25` or `port:6881-6889,6969`
+///
+/// Rules above could be qualified by destination ports, like `10.0.0.0/8 port:22`
+/// or `domain:gmail.com port:25,465`, which only matches destinations with these ports.
+/// Ports are ignored while checking clients' addresses.
+///
+/// Regular expressions in forms of `(^|\.)gmail\.com$` and `^mail\.google\.com$` are
+/// treated as `domain:` and `full:` rules, which are much cheaper than compiling into `RegexSet`.
 #[derive(Debug, Clone)]
 pub struct AccessControl {
     outbound_block: Rules,
@@ -155,23 +387,22 @@ impl AccessControl {
     /// Load ACL rules from a file
     pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<AccessControl> {
         let fp = File::open(p)?;
-        let r = BufReader::new(fp);
+        AccessControl::load_from_reader(BufReader::new(fp))
+    }
 
+    /// Load ACL rules from a `str`, which is in the same format as ACL files
+    pub fn load_from_str(s: &str) -> io::Result<AccessControl> {
+        AccessControl::load_from_reader(s.as_bytes())
+    }
+
+    fn load_from_reader<R: BufRead>(r: R) -> io::Result<AccessControl> {
         let mut mode = Mode::BlackList;
 
-        let mut outbound_block_ipv4 = IpRange::new();
-        let mut outbound_block_ipv6 = IpRange::new();
-        let mut outbound_block_rules = Vec::new();
-        let mut bypass_ipv4 = IpRange::new();
-        let mut bypass_ipv6 = IpRange::new();
-        let mut bypass_rules = Vec::new();
-        let mut proxy_ipv4 = IpRange::new();
-        let mut proxy_ipv6 = IpRange::new();
-        let mut proxy_rules = Vec::new();
+        let mut outbound_block = ParsingRules::new("[outbound_block_list]");
+        let mut bypass = ParsingRules::new("[black_list] or [bypass_list]");
+        let mut proxy = ParsingRules::new("[white_list] or [proxy_list]");
 
-        let mut curr_ipv4 = &mut bypass_ipv4;
-        let mut curr_ipv6 = &mut bypass_ipv6;
-        let mut curr_rules = &mut bypass_rules;
+        let mut curr = &mut bypass;
 
         for line in r.lines() {
             let line = line?;
@@ -192,87 +423,22 @@ impl AccessControl {
                     mode = Mode::BlackList;
                 }
                 "[outbound_block_list]" => {
-                    curr_ipv4 = &mut outbound_block_ipv4;
-                    curr_ipv6 = &mut outbound_block_ipv6;
-                    curr_rules = &mut outbound_block_rules;
+                    curr = &mut outbound_block;
                 }
                 "[black_list]" | "[bypass_list]" => {
-                    curr_ipv4 = &mut bypass_ipv4;
-                    curr_ipv6 = &mut bypass_ipv6;
-                    curr_rules = &mut bypass_rules;
+                    curr = &mut bypass;
                 }
                 "[white_list]" | "[proxy_list]" => {
-                    curr_ipv4 = &mut proxy_ipv4;
-                    curr_ipv6 = &mut proxy_ipv6;
-                    curr_rules = &mut proxy_rules;
-                }
-                _ => {
-                    match line.parse::<IpNet>() {
-                        Ok(IpNet::V4(v4)) => {
-                            curr_ipv4.add(v4);
-                        }
-                        Ok(IpNet::V6(v6)) => {
-                            curr_ipv6.add(v6);
-                        }
-                        Err(..) => {
-                            // Maybe it is a pure IpAddr
-                            match line.parse::<IpAddr>() {
-                                Ok(IpAddr::V4(v4)) => {
-                                    curr_ipv4.add(Ipv4Net::from(v4));
-                                }
-                                Ok(IpAddr::V6(v6)) => {
-                                    curr_ipv6.add(Ipv6Net::from(v6));
-                                }
-                                Err(..) => {
-                                    // FIXME: If this line is not a valid regex, how can we know without actually compile it?
-                                    curr_rules.push(line);
-                                }
-                            }
-                        }
-                    }
+                    curr = &mut proxy;
                 }
+                _ => curr.add_rule(&line)?,
             }
         }
 
-        const REGEX_SIZE_LIMIT: usize = usize::max_value();
-
-        let outbound_block_regex = match RegexSetBuilder::new(outbound_block_rules)
-            .size_limit(REGEX_SIZE_LIMIT)
-            .build()
-        {
-            Ok(r) => r,
-            Err(err) => {
-                let err = Error::new(ErrorKind::Other, format!("[outbound_block_list] regex error: {}", err));
-                return Err(err);
-            }
-        };
-
-        let bypass_regex = match RegexSetBuilder::new(bypass_rules).size_limit(REGEX_SIZE_LIMIT).build() {
-            Ok(r) => r,
-            Err(err) => {
-                let err = Error::new(
-                    ErrorKind::Other,
-                    format!("[black_list] or [bypass_list] regex error: {}", err),
-                );
-                return Err(err);
-            }
-        };
-
-        let proxy_regex = match RegexSetBuilder::new(proxy_rules).size_limit(REGEX_SIZE_LIMIT).build() {
-            Ok(r) => r,
-            Err(err) => {
-                let err = Error::new(
-                    ErrorKind::Other,
-                    format!("[white_list] or [proxy_list] regex error: {}", err),
-                );
-                return Err(err);
-            }
-        };
-
         Ok(AccessControl {
-            outbound_block: Rules::new(outbound_block_ipv4, outbound_block_ipv6, outbound_block_regex),
-            black_list: Rules::new(bypass_ipv4, bypass_ipv6, bypass_regex),
-            white_list: Rules::new(proxy_ipv4, proxy_ipv6, proxy_regex),
+            outbound_block: outbound_block.into_rules()?,
+            black_list: bypass.into_rules()?,
+            white_list: proxy.into_rules()?,
             mode,
         })
     }
@@ -280,12 +446,16 @@ impl AccessControl {
     /// Check if domain name is in proxy_list.
     /// If so, it should be resolved from remote (for Android's DNS relay)
     pub fn check_host_in_proxy_list(&self, host: &str) -> Option<bool> {
+        self.check_host_port_in_proxy_list(host, None)
+    }
+
+    fn check_host_port_in_proxy_list(&self, host: &str, port: Option<u16>) -> Option<bool> {
         // Addresses in proxy_list will be proxied
-        if self.white_list.check_host_matched(host) {
+        if self.white_list.check_host_matched(host, port) {
             return Some(true);
         }
         // Addresses in bypass_list will be bypassed
-        if self.black_list.check_host_matched(host) {
+        if self.black_list.check_host_matched(host, port) {
             return Some(false);
         }
         None
@@ -308,9 +478,13 @@ impl AccessControl {
     }
 
     pub fn check_ip_in_proxy_list(&self, ip: &IpAddr) -> bool {
+        self.check_ip_port_in_proxy_list(ip, None)
+    }
+
+    fn check_ip_port_in_proxy_list(&self, ip: &IpAddr, port: Option<u16>) -> bool {
         match self.mode {
-            Mode::BlackList => !self.black_list.check_ip_matched(ip),
-            Mode::WhiteList => self.white_list.check_ip_matched(ip),
+            Mode::BlackList => !self.black_list.check_ip_matched(ip, port),
+            Mode::WhiteList => self.white_list.check_ip_matched(ip, port),
         }
     }
 
@@ -326,10 +500,10 @@ impl AccessControl {
     /// This function may perform a DNS resolution
     pub async fn check_target_bypassed(&self, context: &Context, addr: &Address) -> bool {
         match *addr {
-            Address::SocketAddress(ref addr) => !self.check_ip_in_proxy_list(&addr.ip()),
+            Address::SocketAddress(ref addr) => !self.check_ip_port_in_proxy_list(&addr.ip(), Some(addr.port())),
             // Resolve hostname and check the list
             Address::DomainNameAddress(ref host, port) => {
-                if let Some(value) = self.check_host_in_proxy_list(host) {
+                if let Some(value) = self.check_host_port_in_proxy_list(host, Some(port)) {
                     return !value;
                 }
                 if self.is_ipv4_empty() && self.is_ipv6_empty() {
@@ -337,13 +511,13 @@ impl AccessControl {
                 }
                 if let Ok(vaddr) = context.dns_resolve(host, port).await {
                     for addr in vaddr {
-                        if !self.check_ip_in_proxy_list(&addr.ip()) {
+                        if !self.check_ip_port_in_proxy_list(&addr.ip(), Some(addr.port())) {
                             return true;
                         }
                     }
                 }
                 false
-            },
+            }
         }
     }
 
@@ -352,18 +526,18 @@ impl AccessControl {
         match self.mode {
             Mode::BlackList => {
                 // Only clients in black_list will be blocked
-                self.black_list.check_ip_matched(&addr.ip())
+                self.black_list.check_ip_matched(&addr.ip(), None)
             }
             Mode::WhiteList => {
                 // Only clients in white_list will be proxied
-                !self.white_list.check_ip_matched(&addr.ip())
+                !self.white_list.check_ip_matched(&addr.ip(), None)
             }
         }
     }
 
     /// Check if outbound address is blocked (for server)
     ///
-    /// NOTE: `Address::DomainName` is only validated by regex rules,
+    /// NOTE: `Address::DomainName` is only validated by host and port rules,
     ///       resolved addresses are checked in the `lookup_outbound_then!` macro
     pub fn check_outbound_blocked(&self, outbound: &Address) -> bool {
         self.outbound_block.check_address_matched(outbound)
@@ -371,6 +545,7 @@ impl AccessControl {
 
     /// Check resolved outbound address is blocked (for server)
     pub fn check_resolved_outbound_blocked(&self, outbound: &SocketAddr) -> bool {
-        self.outbound_block.check_ip_matched(&outbound.ip())
+        self.outbound_block
+            .check_ip_matched(&outbound.ip(), Some(outbound.port()))
     }
 }
@@ -0,0 +1,116 @@
+//! Destination port rules
+
+use std::{
+    fmt,
+    io::{self, Error, ErrorKind},
+};
+
+/// A set of ports, written as `port:25`, `port:6881-6889` or `port:25,465,587`
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PortSet {
+    ranges: Vec<(u16, u16)>,
+}
+
+impl PortSet {
+    /// Parse a rule in form of `port:25,6881-6889`, returns `None` if it is not a port rule
+    ///
+    /// Fails if ports are not numbers in `[0, 65535]`, or a range is reversed.
+    pub fn parse_rule(rule: &str) -> Option<io::Result<PortSet>> {
+        let mut sp = rule.splitn(2, ':');
+        match sp.next() {
+            Some("port") => {}
+            _ => return None,
+        }
+
+        let ports = sp.next().and_then(PortSet::parse_ports);
+        Some(ports.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "ports should be numbers or ranges in [0, 65535]")))
+    }
+
+    fn parse_ports(s: &str) -> Option<PortSet> {
+        let mut ports = PortSet::default();
+        for item in s.split(',') {
+            let item = item.trim();
+
+            let mut sp = item.splitn(2, '-');
+            let start = sp.next()?.trim().parse::<u16>().ok()?;
+            let end = match sp.next() {
+                Some(end) => end.trim().parse::<u16>().ok()?,
+                None => start,
+            };
+
+            if start > end {
+                return None;
+            }
+
+            ports.ranges.push((start, end));
+        }
+
+        Some(ports)
+    }
+
+    /// Add all ports in `other`
+    pub fn extend(&mut self, other: &PortSet) {
+        self.ranges.extend_from_slice(&other.ranges);
+    }
+
+    /// Check if `port` is in this set
+    pub fn contains(&self, port: u16) -> bool {
+        self.ranges.iter().any(|&(start, end)| start <= port && port <= end)
+    }
+}
+
+impl fmt::Display for PortSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("port:")?;
+        for (idx, &(start, end)) in self.ranges.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(",")?;
+            }
+            if start == end {
+                write!(f, "{}", start)?;
+            } else {
+                write!(f, "{}-{}", start, end)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split the port qualifier from a rule, `10.0.0.0/8 port:25` -> (`10.0.0.0/8`, `port:25`)
+pub fn split_port_qualifier(line: &str) -> Option<(&str, io::Result<PortSet>)> {
+    let idx = line.rfind(char::is_whitespace)?;
+    let ports = PortSet::parse_rule(&line[idx + 1..])?;
+    Some((line[..idx].trim_end(), ports))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{super::AccessControl, *};
+
+    #[test]
+    fn parse_port_rules() {
+        let ports = PortSet::parse_rule("port:25,465, 6881-6889").unwrap().unwrap();
+        assert!(ports.contains(25));
+        assert!(ports.contains(465));
+        assert!(ports.contains(6885));
+        assert!(!ports.contains(587));
+        assert_eq!(ports.to_string(), "port:25,465,6881-6889");
+
+        for rule in &["port:6889-6881", "port:smtp", "port:99999", "port:"] {
+            assert!(PortSet::parse_rule(rule).unwrap().is_err());
+        }
+        assert!(PortSet::parse_rule("domain:example.com").is_none());
+
+        let (rule, ports) = split_port_qualifier("domain:example.com port:25").unwrap();
+        assert_eq!(rule, "domain:example.com");
+        assert!(ports.unwrap().contains(25));
+        assert!(split_port_qualifier("domain:example.com port:smtp").unwrap().1.is_err());
+        assert!(split_port_qualifier("(^|\\.)example\\.com$").is_none());
+
+        // Invalid ports fail loading, instead of being taken as regexes
+        for rule in &["port:10-5", "domain:example.com port:smtp"] {
+            let err = AccessControl::load_from_str(rule).err().unwrap();
+            assert!(err.to_string().contains(rule));
+        }
+    }
+}
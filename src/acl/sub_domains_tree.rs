@@ -0,0 +1,248 @@
+//! Domain name matching without regular expressions
+
+use std::collections::{HashMap, HashSet};
+
+/// A reversed-label trie for matching a domain and all its sub-domains
+///
+/// `example.com` is stored as `com` -> `example`, so `www.example.com` and `example.com`
+/// will both be matched by walking labels from right to left.
+#[derive(Debug, Clone, Default)]
+pub struct SubDomainsTree {
+    children: HashMap<String, SubDomainsTree>,
+    is_end: bool,
+    len: usize,
+}
+
+impl SubDomainsTree {
+    /// Insert a domain, matches itself and all its sub-domains
+    pub fn insert(&mut self, domain: &str) {
+        let mut node = &mut *self;
+        for label in domain.rsplit('.') {
+            node = node.children.entry(label.to_owned()).or_default();
+        }
+
+        if !node.is_end {
+            node.is_end = true;
+            self.len += 1;
+        }
+    }
+
+    /// Find the parent domain of `domain` (or itself) that is in the tree
+    pub fn find<'a>(&self, domain: &'a str) -> Option<&'a str> {
+        let mut node = self;
+        let mut start = domain.len();
+        for label in domain.rsplit('.') {
+            node = node.children.get(label)?;
+
+            start -= label.len();
+            if node.is_end {
+                return Some(&domain[start..]);
+            }
+            // Skip the '.'
+            start = start.saturating_sub(1);
+        }
+        None
+    }
+
+    /// Number of domains inserted
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if tree is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Host rules that could be matched without compiling regular expressions
+///
+/// - `full:example.com` matches `example.com` only
+/// - `domain:example.com` matches `example.com` and all its sub-domains, like `www.example.com`
+/// - `suffix:ample.com` matches all hosts ending with `ample.com`, like `example.com` and `sample.com`
+#[derive(Debug, Clone, Default)]
+pub struct HostRules {
+    full: HashSet<String>,
+    domain: SubDomainsTree,
+    suffix: HashSet<String>,
+}
+
+impl HostRules {
+    /// Create an empty rule set
+    pub fn new() -> HostRules {
+        HostRules::default()
+    }
+
+    /// Add a host that should be exactly matched
+    pub fn add_full(&mut self, host: &str) {
+        self.full.insert(normalize_host(host));
+    }
+
+    /// Add a domain that matches itself and all its sub-domains
+    pub fn add_domain(&mut self, domain: &str) {
+        self.domain.insert(&normalize_host(domain));
+    }
+
+    /// Add a plain string suffix
+    pub fn add_suffix(&mut self, suffix: &str) {
+        self.suffix.insert(normalize_host(suffix));
+    }
+
+    /// Check if `host` matches any rules
+    pub fn is_match(&self, host: &str) -> bool {
+        self.find_match(host).is_some()
+    }
+
+    /// Find the rule that `host` matches, in the same form as it is written in ACL, like `domain:example.com`
+    pub fn find_match(&self, host: &str) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let host = normalize_host(host);
+
+        if self.full.contains(&host) {
+            return Some(format!("full:{}", host));
+        }
+
+        if let Some(domain) = self.domain.find(&host) {
+            return Some(format!("domain:{}", domain));
+        }
+
+        if !self.suffix.is_empty() {
+            for (idx, _) in host.char_indices() {
+                if self.suffix.contains(&host[idx..]) {
+                    return Some(format!("suffix:{}", &host[idx..]));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Total number of rules
+    pub fn len(&self) -> usize {
+        self.full.len() + self.domain.len() + self.suffix.len()
+    }
+
+    /// Check if there are no rules
+    pub fn is_empty(&self) -> bool {
+        self.full.is_empty() && self.domain.is_empty() && self.suffix.is_empty()
+    }
+}
+
+/// Domain names are case insensitive, and the trailing `.` of FQDN is meaningless for matching
+fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Try to convert the most common host regex forms into `HostRules`
+///
+/// - `(^|\.)example\.com$` is equivalent to `domain:example.com`
+/// - `^example\.com$` is equivalent to `full:example.com`
+///
+/// Returns `None` if the regex is not one of these forms. Regexes with uppercase letters are not converted either,
+/// because they are case sensitive while `HostRules` are not.
+pub fn try_convert_regex(rule: &str) -> Option<(HostRuleType, String)> {
+    let (ty, escaped) = if rule.starts_with("(^|\\.)") && rule.ends_with('$') {
+        (HostRuleType::Domain, &rule[6..rule.len() - 1])
+    } else if rule.starts_with('^') && rule.ends_with('$') {
+        (HostRuleType::Full, &rule[1..rule.len() - 1])
+    } else {
+        return None;
+    };
+
+    let mut host = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('.') => host.push('.'),
+                Some('-') => host.push('-'),
+                _ => return None,
+            },
+            c if c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_' => host.push(c),
+            // Other characters are regex syntax
+            _ => return None,
+        }
+    }
+
+    if host.is_empty() {
+        return None;
+    }
+
+    Some((ty, host))
+}
+
+/// Type of host rules
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HostRuleType {
+    /// `full:`
+    Full,
+    /// `domain:`
+    Domain,
+    /// `suffix:`
+    Suffix,
+}
+
+impl HostRuleType {
+    /// Split a rule line into its type and value, `domain:example.com` -> (`Domain`, `example.com`)
+    pub fn parse_rule(line: &str) -> Option<(HostRuleType, &str)> {
+        let mut sp = line.splitn(2, ':');
+        let ty = match sp.next() {
+            Some("full") => HostRuleType::Full,
+            Some("domain") => HostRuleType::Domain,
+            Some("suffix") => HostRuleType::Suffix,
+            _ => return None,
+        };
+
+        sp.next().map(|host| (ty, host.trim()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sub_domains_tree() {
+        let mut tree = SubDomainsTree::default();
+        tree.insert("example.com");
+
+        assert_eq!(tree.find("example.com"), Some("example.com"));
+        assert_eq!(tree.find("www.example.com"), Some("example.com"));
+        assert_eq!(tree.find("com"), None);
+        assert_eq!(tree.find("myexample.com"), None);
+        assert_eq!(tree.find("example.com.cn"), None);
+        assert_eq!(tree.find("a.b.example.com"), Some("example.com"));
+    }
+
+    #[test]
+    fn host_rules() {
+        let mut rules = HostRules::new();
+        rules.add_full("a.example.com");
+        rules.add_domain("Example.ORG");
+        rules.add_suffix("ample.net");
+
+        assert!(rules.is_match("a.example.com"));
+        assert!(!rules.is_match("b.a.example.com"));
+        assert!(rules.is_match("www.example.org."));
+        assert!(rules.is_match("sample.net"));
+        assert!(!rules.is_match("example.com"));
+    }
+
+    #[test]
+    fn convert_regex() {
+        assert_eq!(
+            try_convert_regex(r"(^|\.)google\.com$"),
+            Some((HostRuleType::Domain, "google.com".to_owned()))
+        );
+        assert_eq!(
+            try_convert_regex(r"^www\.google\.com$"),
+            Some((HostRuleType::Full, "www.google.com".to_owned()))
+        );
+        assert_eq!(try_convert_regex(r"(^|\.)google\.[a-z]+$"), None);
+        assert_eq!(try_convert_regex(r"google\.com"), None);
+        assert_eq!(try_convert_regex(r"(^|\.)Google\.com$"), None);
+    }
+}
@@ -0,0 +1,123 @@
+//! This is a tool for testing ACL rules
+//!
+//! It loads an ACL file and prints what `sslocal` or `ssserver` would do with each target,
+//! which rule in which section matched, and whether DNS resolution was involved.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    process,
+};
+
+use clap::clap_app;
+use tokio::runtime::Builder;
+use url::Url;
+
+use shadowsocks::{
+    acl::{AccessControl, ImportReport},
+    context::{Context, ServerState},
+    relay::socks5::Address,
+    Config,
+    ConfigType,
+};
+
+mod logging;
+
+fn main() {
+    let matches = clap_app!(ssacl =>
+        (version: shadowsocks::VERSION)
+        (about: "Explains how targets are proxied, bypassed or blocked by ACL (Access Control List) rules.")
+        (@arg VERBOSE: -v ... "Set the level of debug")
+        (@arg ACL: --acl +takes_value +required "Path to ACL (Access Control List)")
+        (@arg ACL_FORMAT: --("acl-format") +takes_value possible_values(&["acl", "gfwlist", "clash", "surge"]) default_value("acl") "Format of ACL file")
+        (@arg SERVER: --server "Check with rules for server, [outbound_block_list], instead of rules for client")
+        (@arg DNS: --dns +takes_value "DNS nameservers for resolving targets, formatted like \"google\", \"1.1.1.1\", \"8.8.8.8:53,8.8.4.4:53\" or \"tls://1.1.1.1#cloudflare-dns.com\"")
+        (@arg IPV6_FIRST: --("ipv6-first") "Resovle hostname to IPv6 address first")
+        (@arg TARGET: +required ... "Targets to be checked, could be \"domain\", \"ip\", \"domain:port\", \"ip:port\" or \"scheme://domain:port/path\"")
+    )
+    .get_matches();
+
+    let debug_level = matches.occurrences_of("VERBOSE");
+    logging::init(debug_level, "ssacl", true);
+
+    let acl_file = matches.value_of("ACL").expect("acl");
+    let result = match matches.value_of("ACL_FORMAT") {
+        Some("gfwlist") => AccessControl::load_from_gfwlist(acl_file).map(print_import_report),
+        Some("clash") => AccessControl::load_from_clash(acl_file).map(print_import_report),
+        Some("surge") => AccessControl::load_from_surge(acl_file).map(print_import_report),
+        _ => AccessControl::load_from_file(acl_file),
+    };
+
+    let acl = match result {
+        Ok(acl) => acl,
+        Err(err) => {
+            eprintln!("loading ACL \"{}\", {}", acl_file, err);
+            process::exit(1);
+        }
+    };
+
+    let mut targets = Vec::new();
+    for target in matches.values_of("TARGET").expect("target") {
+        match parse_target(target) {
+            Some(addr) => targets.push((target, addr)),
+            None => {
+                eprintln!("invalid target \"{}\"", target);
+                process::exit(1);
+            }
+        }
+    }
+
+    let is_server = matches.is_present("SERVER");
+
+    let mut config = Config::new(if is_server {
+        ConfigType::Server
+    } else {
+        ConfigType::Socks5Local
+    });
+    config.dns = matches.value_of("DNS").map(ToOwned::to_owned);
+    config.ipv6_first = matches.is_present("IPV6_FIRST");
+
+    let mut runtime = Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .expect("create tokio Runtime");
+
+    runtime.block_on(async move {
+        let state = ServerState::new_shared(&config).await;
+        let context = Context::new_shared(config, state);
+
+        for (target, addr) in targets {
+            let exp = if is_server {
+                acl.explain_outbound(&context, &addr).await
+            } else {
+                acl.explain_target(&context, &addr).await
+            };
+
+            println!("{} ({}): {}", target, addr, exp);
+        }
+    });
+}
+
+/// Parse targets, port is 80 if not specified
+fn parse_target(target: &str) -> Option<Address> {
+    if target.contains("://") {
+        let url = Url::parse(target).ok()?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        return match url.host()? {
+            url::Host::Domain(d) => Some(Address::DomainNameAddress(d.to_owned(), port)),
+            url::Host::Ipv4(ip) => Some(Address::SocketAddress(SocketAddr::new(IpAddr::V4(ip), port))),
+            url::Host::Ipv6(ip) => Some(Address::SocketAddress(SocketAddr::new(IpAddr::V6(ip), port))),
+        };
+    }
+
+    if let Ok(ip) = target.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        return Some(Address::SocketAddress(SocketAddr::new(ip, 80)));
+    }
+
+    target.parse::<Address>().ok()
+}
+
+fn print_import_report((acl, report): (AccessControl, ImportReport)) -> AccessControl {
+    eprintln!("imported ACL, {}", report);
+    acl
+}
@@ -4,21 +4,35 @@
 //! or you could specify a configuration file. The format of configuration file is defined
 //! in mod `config`.
 
+use std::{sync::Arc, time::Duration};
+
 use clap::{clap_app, Arg};
 use futures::future::{self, Either};
-use log::info;
+use log::{info, warn};
 use tokio::{self, runtime::Builder};
 
 #[cfg(feature = "local-redir")]
 use shadowsocks::config::RedirType;
 use shadowsocks::{
-    acl::AccessControl,
+    acl::{AccessControl, ImportReport},
+    config::{
+        parse_probe_dns_addr,
+        BalancerStrategy,
+        MuxConfig,
+        OnlineConfig,
+        OnlineConfigUrl,
+        TcpProbe,
+        MAX_MUX_STREAMS,
+    },
     crypto::CipherType,
+    hosts::Hosts,
     plugin::PluginConfig,
-    relay::socks5::Address,
+    relay::{probe::ServerProbe, socks5::Address},
     run_local,
+    run_probe,
     Config,
     ConfigType,
+    ManagerAddr,
     Mode,
     ServerAddr,
     ServerConfig,
@@ -35,6 +49,8 @@ const AVAILABLE_PROTOCOLS: &[&str] = &[
     "tunnel",
     #[cfg(feature = "local-redir")]
     "redir",
+    #[cfg(feature = "local-dns-relay")]
+    "dns",
 ];
 
 fn main() {
@@ -70,7 +86,26 @@ fn main() {
         (@arg NO_DELAY: --("no-delay") !takes_value "Set no-delay option for socket")
         (@arg NOFILE: -n --nofile +takes_value "Set RLIMIT_NOFILE with both soft and hard limit (only for *nix systems)")
         (@arg ACL: --acl +takes_value "Path to ACL (Access Control List)")
+        (@arg ACL_FORMAT: --("acl-format") +takes_value requires[ACL] possible_values(&["acl", "gfwlist", "clash", "surge"]) "Format of ACL file, rules in gfwlist, Clash or Surge formats will be converted to ACL [default: acl]")
+        (@arg HOSTS_FILE: --("hosts-file") +takes_value "Path to hosts file (in /etc/hosts format) for overriding DNS resolution")
         (@arg LOG_WITHOUT_TIME: --("log-without-time") "Log without datetime prefix")
+        (@arg CONTROL_ADDRESS: --("control-address") +takes_value {validator::validate_manager_addr} "Control socket for managing servers at runtime, could be ip:port, domain:port or /path/to/unix.sock")
+        (@arg ONLINE_CONFIG_URL: --("online-config-url") +takes_value {validator::validate_online_config_url} "SIP008 online configuration providing servers, could be a HTTP(S) URL or a file path")
+        (@arg ONLINE_CONFIG_UPDATE_INTERVAL: --("online-config-update-interval") +takes_value requires[ONLINE_CONFIG_URL] {validator::validate_u64} "Interval in seconds between updates of --online-config-url [default: 3600]")
+
+        (@arg BALANCER_STRATEGY: --("balancer-strategy") +takes_value possible_values(BalancerStrategy::available_strategies()) +next_line_help "Strategy for choosing a server for each connection [default: best]")
+        (@arg BALANCER_TCP_PROBE: --("balancer-tcp-probe") +takes_value {validator::validate_tcp_probe} "HTTP URL requested through servers for checking their TCP latency, or \"connect\" for connecting to servers only [default: http://dl.google.com/generate_204]")
+        (@arg BALANCER_TCP_PROBE_STATUS: --("balancer-tcp-probe-status") +takes_value {validator::validate_u16} "Expected status code of --balancer-tcp-probe's response, any response is accepted if not specified")
+        (@arg BALANCER_UDP_PROBE_DNS: --("balancer-udp-probe-dns") +takes_value {validator::validate_probe_dns_addr} "DNS server queried through servers for checking their UDP latency [default: 8.8.8.8:53]")
+        (@arg BALANCER_UDP_PROBE_NAME: --("balancer-udp-probe-name") +takes_value {validator::validate_domain_name} "Name queried for checking servers' UDP latency [default: baidu.com]")
+        (@arg BALANCER_CHECK_INTERVAL: --("balancer-check-interval") +takes_value {validator::validate_u64} "Interval in seconds between checks of a server's latency [default: 6]")
+        (@arg BALANCER_CHECK_TIMEOUT: --("balancer-check-timeout") +takes_value {validator::validate_u64} "Timeout in seconds of checking a server's latency [default: 2]")
+
+        (@arg MUX: --mux "Multiplex TCP relays over a few connections to each server, servers not supporting it are connected as usual")
+        (@arg MUX_MAX_CONNECTIONS: --("mux-max-connections") +takes_value requires[MUX] {validator::validate_usize} "Maximum number of multiplexed connections to each server [default: 4]")
+        (@arg MUX_MAX_STREAMS: --("mux-max-streams") +takes_value requires[MUX] {validator::validate_usize} "Number of TCP relays carried by a multiplexed connection before opening another one, at most 256 [default: 128]")
+
+        (@arg PROBE: --probe +takes_value {validator::validate_usize} "Probe all servers for PROBE rounds with the balancer's probes, print their latencies and scores, then exit")
     );
 
     // FIXME: -6 is not a identifier, so we cannot build it with clap_app!
@@ -113,9 +148,21 @@ fn main() {
     #[cfg(feature = "local-dns-relay")]
     {
         app = clap_app!(@app (app)
-            (@arg LOCAL_DNS_ADDR: --("local-dns") +takes_value {validator::validate_socket_addr} "Specify the address of local DNS server (only for Android)")
-            (@arg REMOTE_DNS_ADDR: --("remote-dns") +takes_value {validator::validate_address} "Specify the address of remote DNS server (only for Android)")
-            (@arg DNS_LOCAL_ADDR: --("dns-relay") +takes_value {validator::validate_server_addr} "Specify the address of DNS relay (only for Android)")
+            (@arg LOCAL_DNS_ADDR: --("local-dns") +takes_value {validator::validate_name_servers} "Specify the addresses of local DNS servers, formatted like \"8.8.8.8\", \"tls://1.1.1.1#cloudflare-dns.com\" or \"https://dns.google/dns-query\", system's name servers by default")
+            (@arg REMOTE_DNS_ADDR: --("remote-dns") +takes_value {validator::validate_name_servers} "Specify the addresses of remote DNS servers, formatted like --local-dns, which are queried through proxy")
+            (@arg REMOTE_DNS_MODE: --("remote-dns-mode") +takes_value possible_values(&["tcp_only", "udp_only", "tcp_and_udp"]) "Transport for sending queries to remote DNS servers through proxy, UDP queries will be retried with TCP if responses are truncated")
+            (@arg DNS_BOGUS_IPS: --("dns-bogus-ips") +takes_value {validator::validate_ip_nets} "Comma separated addresses or networks, responses from local DNS servers with them are considered poisoned and resolved remotely")
+            (@arg LOCAL_DNS_MIN_RTT: --("local-dns-min-rtt") +takes_value {validator::validate_u64} "Responses from local DNS servers that arrived faster than this (in milliseconds) are considered poisoned and resolved remotely")
+            (@arg DNS_CHECK_TTL: --("dns-check-ttl") "Responses from local DNS servers with different TTLs in the same RRset are considered poisoned and resolved remotely")
+            (@arg LOCAL_DNS_ECS: --("local-dns-ecs") +takes_value {validator::validate_dns_client_subnet} "EDNS Client Subnet sent to local DNS servers, \"strip\", \"client\" or a subnet like \"203.0.113.0/24\"")
+            (@arg REMOTE_DNS_ECS: --("remote-dns-ecs") +takes_value {validator::validate_dns_client_subnet} "EDNS Client Subnet sent to remote DNS servers, formatted like --local-dns-ecs")
+            (@arg DNS_LOCAL_ADDR: --("dns-relay") +takes_value {validator::validate_server_addr} "Also run a DNS relay on this address, besides the server of --protocol")
+            (@arg DNS_CACHE_SIZE: --("dns-cache-size") +takes_value {validator::validate_usize} "Maximum number of responses cached by DNS relay, 0 to disable caching")
+            (@arg DNS_CACHE_PREFETCH: --("dns-cache-prefetch") "Refresh popular responses in DNS relay's cache before they are expired")
+            (@arg DNS_TLS_LOCAL_ADDR: --("dns-relay-tls") +takes_value {validator::validate_server_addr} requires[DNS_TLS_CERT DNS_TLS_KEY] "Specify the address of DNS relay serving DNS over TLS")
+            (@arg DNS_HTTPS_LOCAL_ADDR: --("dns-relay-https") +takes_value {validator::validate_server_addr} requires[DNS_TLS_CERT DNS_TLS_KEY] "Specify the address of DNS relay serving DNS over HTTPS")
+            (@arg DNS_TLS_CERT: --("dns-relay-cert") +takes_value "PEM certificate chain for DNS relay serving DNS over TLS or HTTPS")
+            (@arg DNS_TLS_KEY: --("dns-relay-key") +takes_value "PEM private key for DNS relay serving DNS over TLS or HTTPS")
         );
     }
 
@@ -132,6 +179,8 @@ fn main() {
         Some("tunnel") => ConfigType::TunnelLocal,
         #[cfg(feature = "local-redir")]
         Some("redir") => ConfigType::RedirLocal,
+        #[cfg(feature = "local-dns-relay")]
+        Some("dns") => ConfigType::DnsLocal,
         Some(p) => panic!("not supported `protocol` \"{}\"", p),
         None => ConfigType::Socks5Local,
     };
@@ -193,22 +242,76 @@ fn main() {
 
     #[cfg(feature = "local-dns-relay")]
     {
-        use std::net::SocketAddr;
+        use shadowsocks::config::{parse_ip_net, DnsClientSubnet, NameServerAddr};
 
         if let Some(local_dns_addr) = matches.value_of("LOCAL_DNS_ADDR") {
-            let addr = local_dns_addr.parse::<SocketAddr>().expect("local dns address");
-            config.local_dns_addr = Some(addr);
+            let addrs = NameServerAddr::parse_list(local_dns_addr).expect("local dns address");
+            config.local_dns_addr = Some(addrs);
         }
 
         if let Some(remote_dns_addr) = matches.value_of("REMOTE_DNS_ADDR") {
-            let addr = remote_dns_addr.parse::<Address>().expect("remote dns address");
-            config.remote_dns_addr = Some(addr);
+            let addrs = NameServerAddr::parse_list(remote_dns_addr).expect("remote dns address");
+            config.remote_dns_addr = Some(addrs);
+        }
+
+        if let Some(remote_dns_mode) = matches.value_of("REMOTE_DNS_MODE") {
+            config.remote_dns_mode = remote_dns_mode.parse::<Mode>().expect("remote dns mode");
+        }
+
+        if let Some(addrs) = matches.value_of("DNS_BOGUS_IPS") {
+            for addr in addrs.split(',') {
+                let net = parse_ip_net(addr.trim()).expect("dns bogus ips");
+                config.dns_bogus_addrs.push(net);
+            }
+        }
+
+        if let Some(rtt) = matches.value_of("LOCAL_DNS_MIN_RTT") {
+            let rtt = rtt.parse::<u64>().expect("local dns min rtt");
+            config.local_dns_min_rtt = Some(Duration::from_millis(rtt));
+        }
+
+        if matches.is_present("DNS_CHECK_TTL") {
+            config.dns_check_ttl = true;
+        }
+
+        if let Some(ecs) = matches.value_of("LOCAL_DNS_ECS") {
+            config.local_dns_ecs = Some(ecs.parse::<DnsClientSubnet>().expect("local dns ecs"));
+        }
+
+        if let Some(ecs) = matches.value_of("REMOTE_DNS_ECS") {
+            config.remote_dns_ecs = Some(ecs.parse::<DnsClientSubnet>().expect("remote dns ecs"));
         }
 
         if let Some(dns_relay_addr) = matches.value_of("DNS_LOCAL_ADDR") {
             let addr = dns_relay_addr.parse::<ServerAddr>().expect("dns relay address");
             config.dns_local_addr = Some(addr);
         }
+
+        if let Some(cache_size) = matches.value_of("DNS_CACHE_SIZE") {
+            config.dns_cache_size = Some(cache_size.parse::<usize>().expect("dns cache size"));
+        }
+
+        if matches.is_present("DNS_CACHE_PREFETCH") {
+            config.dns_cache_prefetch = true;
+        }
+
+        if let Some(tls_addr) = matches.value_of("DNS_TLS_LOCAL_ADDR") {
+            let addr = tls_addr.parse::<ServerAddr>().expect("dns relay tls address");
+            config.dns_tls_local_addr = Some(addr);
+        }
+
+        if let Some(https_addr) = matches.value_of("DNS_HTTPS_LOCAL_ADDR") {
+            let addr = https_addr.parse::<ServerAddr>().expect("dns relay https address");
+            config.dns_https_local_addr = Some(addr);
+        }
+
+        if let Some(cert_path) = matches.value_of("DNS_TLS_CERT") {
+            config.dns_tls_cert_path = Some(From::from(cert_path));
+        }
+
+        if let Some(key_path) = matches.value_of("DNS_TLS_KEY") {
+            config.dns_tls_key_path = Some(From::from(key_path));
+        }
     }
 
     if let Some(local_addr) = matches.value_of("LOCAL_ADDR") {
@@ -234,19 +337,122 @@ fn main() {
     }
 
     if let Some(acl_file) = matches.value_of("ACL") {
-        let acl = match AccessControl::load_from_file(acl_file) {
+        let result = match matches.value_of("ACL_FORMAT") {
+            Some("gfwlist") => AccessControl::load_from_gfwlist(acl_file).map(report_acl_import),
+            Some("clash") => AccessControl::load_from_clash(acl_file).map(report_acl_import),
+            Some("surge") => AccessControl::load_from_surge(acl_file).map(report_acl_import),
+            _ => AccessControl::load_from_file(acl_file),
+        };
+
+        let acl = match result {
             Ok(acl) => acl,
             Err(err) => {
                 panic!("loading ACL \"{}\", {}", acl_file, err);
             }
         };
-        config.acl = Some(acl);
+        config.acl = Some(Arc::new(acl));
+    }
+
+    if let Some(hosts_file) = matches.value_of("HOSTS_FILE") {
+        let mut hosts = match Hosts::load_from_file(hosts_file) {
+            Ok(hosts) => hosts,
+            Err(err) => {
+                panic!("loading hosts \"{}\", {}", hosts_file, err);
+            }
+        };
+        // Records in configuration file take precedence
+        if let Some(ref h) = config.hosts {
+            hosts.extend(Hosts::clone(h));
+        }
+        config.hosts = Some(Arc::new(hosts));
     }
 
     if matches.is_present("IPV6_FIRST") {
         config.ipv6_first = true;
     }
 
+    if let Some(addr) = matches.value_of("CONTROL_ADDRESS") {
+        config.control_addr = Some(addr.parse::<ManagerAddr>().expect("control address"));
+    }
+
+    if let Some(url) = matches.value_of("ONLINE_CONFIG_URL") {
+        let url = url.parse::<OnlineConfigUrl>().expect("online config url");
+        match config.online_config {
+            Some(ref mut online) => online.url = url,
+            None => config.online_config = Some(OnlineConfig::new(url)),
+        }
+    }
+
+    if let Some(interval) = matches.value_of("ONLINE_CONFIG_UPDATE_INTERVAL") {
+        let interval = interval.parse::<u64>().expect("online config update interval");
+        if interval == 0 {
+            panic!("online config update interval must be greater than 0");
+        }
+        if let Some(ref mut online) = config.online_config {
+            online.update_interval = Duration::from_secs(interval);
+        }
+    }
+
+    if let Some(strategy) = matches.value_of("BALANCER_STRATEGY") {
+        config.balancer.strategy = strategy.parse::<BalancerStrategy>().expect("balancer strategy");
+    }
+
+    if let Some(probe) = matches.value_of("BALANCER_TCP_PROBE") {
+        config.balancer.tcp_probe = probe.parse::<TcpProbe>().expect("balancer tcp probe");
+    }
+
+    if let Some(status) = matches.value_of("BALANCER_TCP_PROBE_STATUS") {
+        config.balancer.tcp_probe_status = Some(status.parse::<u16>().expect("balancer tcp probe status"));
+    }
+
+    if let Some(addr) = matches.value_of("BALANCER_UDP_PROBE_DNS") {
+        config.balancer.udp_probe_dns = parse_probe_dns_addr(addr).expect("balancer udp probe dns");
+    }
+
+    if let Some(name) = matches.value_of("BALANCER_UDP_PROBE_NAME") {
+        config.balancer.udp_probe_name = name.to_owned();
+    }
+
+    if let Some(interval) = matches.value_of("BALANCER_CHECK_INTERVAL") {
+        let interval = interval.parse::<u64>().expect("balancer check interval");
+        if interval == 0 {
+            panic!("balancer check interval must be greater than 0");
+        }
+        config.balancer.check_interval = Duration::from_secs(interval);
+    }
+
+    if let Some(timeout) = matches.value_of("BALANCER_CHECK_TIMEOUT") {
+        let timeout = timeout.parse::<u64>().expect("balancer check timeout");
+        if timeout == 0 {
+            panic!("balancer check timeout must be greater than 0");
+        }
+        config.balancer.check_timeout = Duration::from_secs(timeout);
+    }
+
+    if matches.is_present("MUX") && config.mux.is_none() {
+        config.mux = Some(MuxConfig::default());
+    }
+
+    if let Some(n) = matches.value_of("MUX_MAX_CONNECTIONS") {
+        let n = n.parse::<usize>().expect("mux max connections");
+        if n == 0 {
+            panic!("mux max connections must be greater than 0");
+        }
+        if let Some(ref mut mux) = config.mux {
+            mux.max_connections = n;
+        }
+    }
+
+    if let Some(n) = matches.value_of("MUX_MAX_STREAMS") {
+        let n = n.parse::<usize>().expect("mux max streams");
+        if n == 0 || n > MAX_MUX_STREAMS {
+            panic!("mux max streams must be in [1, {}]", MAX_MUX_STREAMS);
+        }
+        if let Some(ref mut mux) = config.mux {
+            mux.max_streams = n;
+        }
+    }
+
     if let Some(faddr) = matches.value_of("FORWARD_ADDR") {
         let addr = faddr.parse::<Address>().expect("forward-addr");
         config.forward = Some(addr);
@@ -265,7 +471,19 @@ fn main() {
 
     // DONE READING options
 
-    if config.local_addr.is_none() {
+    let probe_rounds = match matches.value_of("PROBE") {
+        Some(rounds) => {
+            let rounds = rounds.parse::<usize>().expect("probe rounds");
+            if rounds == 0 {
+                panic!("probe rounds must be greater than 0");
+            }
+            Some(rounds)
+        }
+        None => None,
+    };
+
+    // Probing doesn't listen on anything
+    if config.local_addr.is_none() && probe_rounds.is_none() {
         eprintln!(
             "missing `local_address`, consider specifying it by --local-addr command line option, \
              or \"local_address\" and \"local_port\" in configuration file"
@@ -274,11 +492,11 @@ fn main() {
         return;
     }
 
-    if config.server.is_empty() {
+    if config.server.is_empty() && config.online_config.is_none() {
         eprintln!(
             "missing proxy servers, consider specifying it by \
              --server-addr, --encrypt-method, --password command line option, \
-                or --server-url command line option, \
+                or --server-url, --online-config-url command line option, \
                 or configuration file, check more details in https://shadowsocks.org/en/config/quick-guide.html"
         );
         println!("{}", matches.usage());
@@ -294,6 +512,15 @@ fn main() {
         builder.threaded_scheduler();
     }
     let mut runtime = builder.enable_all().build().expect("create tokio Runtime");
+
+    if let Some(rounds) = probe_rounds {
+        match runtime.block_on(run_probe(config, rounds)) {
+            Ok(probes) => print_probes(&probes),
+            Err(err) => panic!("probing servers failed with {}", err),
+        }
+        return;
+    }
+
     runtime.block_on(async move {
         let abort_signal = monitor::create_signal_monitor();
         let server = run_local(config);
@@ -311,3 +538,58 @@ fn main() {
         }
     });
 }
+
+/// Prints results of probing in a table, TCP and UDP are in separated rows
+fn print_probes(probes: &[ServerProbe]) {
+    let names = probes.iter().map(|p| p.server.to_string()).collect::<Vec<_>>();
+    let width = names.iter().map(String::len).max().unwrap_or(0).max("SERVER".len());
+
+    println!(
+        "{:<width$}  {:<4}  {:<5}  {:>7}  {:>9}  {:>8}  {:>5}",
+        "SERVER",
+        "TYPE",
+        "WORKS",
+        "RTT(ms)",
+        "STDEV(ms)",
+        "FAILURES",
+        "SCORE",
+        width = width
+    );
+
+    for (name, probe) in names.iter().zip(probes) {
+        for (name, server_type, stat) in &[(name.as_str(), "TCP", &probe.tcp), ("", "UDP", &probe.udp)] {
+            // Latencies are meaningless if all probes failed
+            let (works, rtt, stdev) = if stat.is_working() {
+                ("yes", stat.rtt.to_string(), format!("{:.1}", stat.stdev))
+            } else {
+                ("no", "-".to_owned(), "-".to_owned())
+            };
+
+            println!(
+                "{:<width$}  {:<4}  {:<5}  {:>7}  {:>9}  {:>7.1}%  {:>5}",
+                name,
+                server_type,
+                works,
+                rtt,
+                stdev,
+                stat.fail_rate * 100.0,
+                stat.score,
+                width = width
+            );
+        }
+    }
+}
+
+fn report_acl_import((acl, report): (AccessControl, ImportReport)) -> AccessControl {
+    info!(
+        "imported ACL, {} rules converted, {} rules unconverted",
+        report.converted,
+        report.unconverted.len()
+    );
+
+    for rule in &report.unconverted {
+        warn!("ACL rule not converted, {}", rule);
+    }
+
+    acl
+}
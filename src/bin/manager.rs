@@ -7,7 +7,10 @@
 //! *It should be notice that the extented configuration file is not suitable for the server
 //! side.*
 
-use std::net::{IpAddr, SocketAddr};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
 use clap::{clap_app, Arg};
 use futures::future::{self, Either};
@@ -123,7 +126,7 @@ fn main() {
                 panic!("loading ACL \"{}\", {}", acl_file, err);
             }
         };
-        config.acl = Some(acl);
+        config.acl = Some(Arc::new(acl));
     }
 
     if matches.is_present("IPV6_FIRST") {
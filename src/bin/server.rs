@@ -7,7 +7,10 @@
 //! *It should be notice that the extented configuration file is not suitable for the server
 //! side.*
 
-use std::net::{IpAddr, SocketAddr};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
 use clap::{clap_app, Arg};
 use futures::future::{self, Either};
@@ -17,6 +20,7 @@ use tokio::{self, runtime::Builder};
 use shadowsocks::{
     acl::AccessControl,
     crypto::CipherType,
+    hosts::Hosts,
     plugin::PluginConfig,
     run_server,
     Config,
@@ -57,6 +61,7 @@ fn main() {
         (@arg NO_DELAY: --("no-delay") !takes_value "Set no-delay option for socket")
         (@arg NOFILE: -n --nofile +takes_value "Set RLIMIT_NOFILE with both soft and hard limit (only for *nix systems)")
         (@arg ACL: --acl +takes_value "Path to ACL (Access Control List)")
+        (@arg HOSTS_FILE: --("hosts-file") +takes_value "Path to hosts file (in /etc/hosts format) for overriding DNS resolution")
         (@arg LOG_WITHOUT_TIME: --("log-without-time") "Log without datetime prefix")
     );
 
@@ -146,7 +151,21 @@ fn main() {
                 panic!("loading ACL \"{}\", {}", acl_file, err);
             }
         };
-        config.acl = Some(acl);
+        config.acl = Some(Arc::new(acl));
+    }
+
+    if let Some(hosts_file) = matches.value_of("HOSTS_FILE") {
+        let mut hosts = match Hosts::load_from_file(hosts_file) {
+            Ok(hosts) => hosts,
+            Err(err) => {
+                panic!("loading hosts \"{}\", {}", hosts_file, err);
+            }
+        };
+        // Records in configuration file take precedence
+        if let Some(ref h) = config.hosts {
+            hosts.extend(Hosts::clone(h));
+        }
+        config.hosts = Some(Arc::new(hosts));
     }
 
     if matches.is_present("IPV6_FIRST") {
@@ -4,7 +4,14 @@
 
 use std::net::SocketAddr;
 
-use shadowsocks::{relay::socks5::Address, ManagerAddr, ServerAddr, ServerConfig};
+use shadowsocks::{
+    config::{parse_ip_net, parse_probe_dns_addr, DnsClientSubnet, NameServerAddr, OnlineConfigUrl, TcpProbe},
+    relay::socks5::Address,
+    ManagerAddr,
+    ServerAddr,
+    ServerConfig,
+};
+use trust_dns_proto::rr::Name;
 
 macro_rules! validate_type {
     ($name:ident, $ty:ty, $help:expr) => {
@@ -23,12 +30,30 @@ validate_type!(
     "should be either ip:port or domain:port"
 );
 validate_type!(validate_socket_addr, SocketAddr, "should be ip:port");
+validate_type!(validate_usize, usize, "should be unsigned integer");
+validate_type!(validate_u16, u16, "should be unsigned integer less than 65536");
+validate_type!(validate_u64, u64, "should be unsigned integer");
 validate_type!(validate_address, Address, "should be either ip:port or domain:port");
+validate_type!(
+    validate_dns_client_subnet,
+    DnsClientSubnet,
+    "should be either strip, client or ip/prefix"
+);
+validate_type!(
+    validate_tcp_probe,
+    TcpProbe,
+    "should be either a HTTP URL or connect"
+);
 validate_type!(
     validate_manager_addr,
     ManagerAddr,
     "should be either ip:port, domain:port or /path/to/unix.sock"
 );
+validate_type!(
+    validate_online_config_url,
+    OnlineConfigUrl,
+    "should be either a HTTP URL or a file path"
+);
 
 pub fn validate_server_url(v: String) -> Result<(), String> {
     match ServerConfig::from_url(&v) {
@@ -36,3 +61,32 @@ pub fn validate_server_url(v: String) -> Result<(), String> {
         Err(..) => Err("should be SIP002 (https://shadowsocks.org/en/spec/SIP002-URI-Scheme.html) format".to_owned()),
     }
 }
+
+pub fn validate_name_servers(v: String) -> Result<(), String> {
+    match NameServerAddr::parse_list(&v) {
+        Ok(..) => Ok(()),
+        Err(..) => Err("should be a comma separated list of ip, ip:port, tcp://ip:port, tls://ip:port#name or https://domain/path".to_owned()),
+    }
+}
+
+pub fn validate_ip_nets(v: String) -> Result<(), String> {
+    if v.split(',').all(|net| parse_ip_net(net.trim()).is_some()) {
+        Ok(())
+    } else {
+        Err("should be a comma separated list of ip or ip/prefix".to_owned())
+    }
+}
+
+pub fn validate_probe_dns_addr(v: String) -> Result<(), String> {
+    match parse_probe_dns_addr(&v) {
+        Some(..) => Ok(()),
+        None => Err("should be either ip, ip:port or domain:port".to_owned()),
+    }
+}
+
+pub fn validate_domain_name(v: String) -> Result<(), String> {
+    match Name::from_ascii(&v) {
+        Ok(..) => Ok(()),
+        Err(..) => Err("should be a domain name".to_owned()),
+    }
+}
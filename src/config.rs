@@ -42,6 +42,7 @@
 //! These defined server will be used with a load balancing algorithm.
 
 use std::{
+    collections::HashMap,
     convert::From,
     default::Default,
     error,
@@ -53,16 +54,20 @@ use std::{
     path::{Path, PathBuf},
     str::FromStr,
     string::ToString,
+    sync::Arc,
     time::Duration,
 };
 
 use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
 use bytes::Bytes;
 use cfg_if::cfg_if;
+use ipnet::IpNet;
 use log::error;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use serde::{Deserialize, Serialize};
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
+use trust_dns_proto::rr::Name;
 #[cfg(feature = "trust-dns")]
 use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig};
 use url::{self, Url};
@@ -71,6 +76,7 @@ use crate::{
     acl::AccessControl,
     context::Context,
     crypto::cipher::CipherType,
+    hosts::{parse_entry, Hosts},
     plugin::PluginConfig,
     relay::{dns_resolver::resolve_bind_addr, socks5::Address},
 };
@@ -90,6 +96,8 @@ struct SSConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     manager_port: Option<u16>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    control_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     password: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     method: Option<String>,
@@ -113,6 +121,65 @@ struct SSConfig {
     nofile: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     ipv6_first: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    acl: Option<SSAclConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    acl_policies: Option<HashMap<String, SSAclConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hosts: Option<HashMap<String, SSHostsConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hosts_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    balancer: Option<SSBalancerConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    online_config: Option<SSOnlineConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mux: Option<SSMuxConfig>,
+    #[cfg(feature = "local-dns-relay")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_dns: Option<String>,
+    #[cfg(feature = "local-dns-relay")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_dns: Option<String>,
+    #[cfg(feature = "local-dns-relay")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_dns_mode: Option<String>,
+    #[cfg(feature = "local-dns-relay")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_bogus_ips: Option<Vec<String>>,
+    #[cfg(feature = "local-dns-relay")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_dns_min_rtt: Option<u64>,
+    #[cfg(feature = "local-dns-relay")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_check_ttl: Option<bool>,
+    #[cfg(feature = "local-dns-relay")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_dns_ecs: Option<String>,
+    #[cfg(feature = "local-dns-relay")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_dns_ecs: Option<String>,
+    #[cfg(feature = "local-dns-relay")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_relay: Option<String>,
+    #[cfg(feature = "local-dns-relay")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_cache_size: Option<usize>,
+    #[cfg(feature = "local-dns-relay")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_cache_prefetch: Option<bool>,
+    #[cfg(feature = "local-dns-relay")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_relay_tls: Option<String>,
+    #[cfg(feature = "local-dns-relay")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_relay_https: Option<String>,
+    #[cfg(feature = "local-dns-relay")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_relay_cert: Option<String>,
+    #[cfg(feature = "local-dns-relay")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_relay_key: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -127,6 +194,108 @@ struct SSServerExtConfig {
     plugin_opts: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    acl: Option<SSAclConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    weight: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remarks: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSBalancerConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strategy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp_probe: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp_probe_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_probe_dns: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_probe_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check_timeout: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSOnlineConfig {
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    update_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSMuxConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_connections: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_streams: Option<usize>,
+}
+
+/// ACL could be a name of `acl_policies`, a path to ACL file, or inline rules
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum SSAclConfig {
+    Path(String),
+    Rules(Vec<String>),
+}
+
+/// Values of a name in `hosts`, an IP address, a list of IP addresses, or a name for alias
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum SSHostsConfig {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+/// Loads ACLs in configuration, the same file is compiled only once and shared by `Arc`
+#[derive(Default)]
+struct AclLoader {
+    files: HashMap<String, Arc<AccessControl>>,
+    policies: HashMap<String, Arc<AccessControl>>,
+}
+
+impl AclLoader {
+    fn load(&mut self, acl: &SSAclConfig) -> Result<Arc<AccessControl>, Error> {
+        match *acl {
+            SSAclConfig::Path(ref name) if self.policies.contains_key(name) => Ok(self.policies[name].clone()),
+            SSAclConfig::Path(ref path) => {
+                if let Some(acl) = self.files.get(path) {
+                    return Ok(acl.clone());
+                }
+
+                match AccessControl::load_from_file(path) {
+                    Ok(acl) => {
+                        let acl = Arc::new(acl);
+                        self.files.insert(path.clone(), acl.clone());
+                        Ok(acl)
+                    }
+                    Err(err) => {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "invalid `acl`",
+                            Some(format!("loading ACL \"{}\", {}", path, err)),
+                        );
+                        Err(err)
+                    }
+                }
+            }
+            SSAclConfig::Rules(ref rules) => match AccessControl::load_from_str(&rules.join("\n")) {
+                Ok(acl) => Ok(Arc::new(acl)),
+                Err(err) => {
+                    let err = Error::new(ErrorKind::Invalid, "invalid `acl`", Some(err.to_string()));
+                    Err(err)
+                }
+            },
+        }
+    }
 }
 
 /// Server address
@@ -212,6 +381,15 @@ impl<I: Into<String>> From<(I, u16)> for ServerAddr {
     }
 }
 
+/// Maximum number of TCP relays carried by a multiplexed connection, servers reset the others
+pub const MAX_MUX_STREAMS: usize = 256;
+
+/// Maximum `weight` of a server in load balancer
+pub const MAX_SERVER_WEIGHT: u32 = 100;
+
+/// Characters escaped in tag of SIP002 URL
+const URL_TAG_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
 /// Configuration for a server
 #[derive(Clone, Debug)]
 pub struct ServerConfig {
@@ -229,6 +407,16 @@ pub struct ServerConfig {
     plugin: Option<PluginConfig>,
     /// Plugin address
     plugin_addr: Option<ServerAddr>,
+    /// ACL for this server, overrides `Config::acl`
+    acl: Option<Arc<AccessControl>>,
+    /// Where `acl` is loaded from, for serializing
+    acl_config: Option<SSAclConfig>,
+    /// Weight in load balancer, relative to other servers
+    weight: u32,
+    /// Name for humans, the tag of SIP002 URL
+    remarks: Option<String>,
+    /// Unique ID, e.g. UUID of servers in SIP008 online configuration
+    id: Option<String>,
 }
 
 impl ServerConfig {
@@ -249,6 +437,11 @@ impl ServerConfig {
             enc_key,
             plugin,
             plugin_addr: None,
+            acl: None,
+            acl_config: None,
+            weight: 1,
+            remarks: None,
+            id: None,
         }
     }
 
@@ -319,6 +512,67 @@ impl ServerConfig {
         self.plugin_addr.as_ref()
     }
 
+    /// Set ACL for this server
+    pub fn set_acl(&mut self, acl: Arc<AccessControl>) {
+        self.acl = Some(acl);
+        self.acl_config = None;
+    }
+
+    /// Set ACL for this server to policy `name` of `Config::acl_policies`
+    pub fn set_acl_policy(&mut self, name: String, acl: Arc<AccessControl>) {
+        self.acl = Some(acl);
+        self.acl_config = Some(SSAclConfig::Path(name));
+    }
+
+    /// Get ACL of this server
+    pub fn acl(&self) -> Option<&AccessControl> {
+        self.acl.as_deref()
+    }
+
+    /// Get name of ACL policy of this server, or path of its ACL file if it is loaded from configuration
+    pub fn acl_policy(&self) -> Option<&str> {
+        match self.acl_config {
+            Some(SSAclConfig::Path(ref name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Set weight in load balancer, must be in [1, `MAX_SERVER_WEIGHT`]
+    pub fn set_weight(&mut self, weight: u32) {
+        self.weight = weight;
+    }
+
+    /// Get weight in load balancer
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    /// Set remarks, a name for humans
+    pub fn set_remarks(&mut self, remarks: String) {
+        self.remarks = Some(remarks);
+    }
+
+    /// Get remarks
+    pub fn remarks(&self) -> Option<&str> {
+        self.remarks.as_deref()
+    }
+
+    /// Set unique ID
+    pub fn set_id(&mut self, id: String) {
+        self.id = Some(id);
+    }
+
+    /// Get unique ID
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Load from a JSON object, in the same format as items of `servers` in configuration
+    pub fn load_from_str(s: &str) -> Result<ServerConfig, Error> {
+        let svr = json5::from_str::<SSServerExtConfig>(s)?;
+        Config::load_server_from_ssconfig(svr, None, &mut AclLoader::default())
+    }
+
     /// Get server's external address
     pub fn external_addr(&self) -> &ServerAddr {
         self.plugin_addr.as_ref().unwrap_or(&self.addr)
@@ -351,6 +605,11 @@ impl ServerConfig {
             url += &serde_urlencoded::to_string(&plugin_param).unwrap();
         }
 
+        if let Some(ref remarks) = self.remarks {
+            url += "#";
+            url += &utf8_percent_encode(remarks, URL_TAG_ENCODE_SET).to_string();
+        }
+
         url
     }
 
@@ -426,12 +685,32 @@ impl ServerConfig {
             }
         }
 
-        let svrconfig = ServerConfig::new(addr, pwd.to_owned(), method.parse().unwrap(), None, plugin);
+        let mut svrconfig = ServerConfig::new(addr, pwd.to_owned(), method.parse().unwrap(), None, plugin);
+
+        if let Some(tag) = parsed.fragment() {
+            match percent_decode_str(tag).decode_utf8() {
+                Ok(remarks) => svrconfig.set_remarks(remarks.into_owned()),
+                Err(err) => {
+                    error!("Failed to decode tag \"{}\", err: {}", tag, err);
+                    return Err(UrlParseError::InvalidTag);
+                }
+            }
+        }
 
         Ok(svrconfig)
     }
 }
 
+/// Name of server in logs, remarks with the address, or only the address if it has no remarks
+impl Display for ServerConfig {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.remarks {
+            Some(ref remarks) => write!(f, "{} ({})", remarks, self.addr),
+            None => Display::fmt(&self.addr, f),
+        }
+    }
+}
+
 impl FromStr for ServerConfig {
     type Err = UrlParseError;
 
@@ -524,6 +803,285 @@ impl From<PathBuf> for ManagerAddr {
     }
 }
 
+/// Protocol for querying a DNS name server
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum NameServerProtocol {
+    /// DNS over UDP, `udp://`
+    Udp,
+    /// DNS over TCP, `tcp://`
+    Tcp,
+    /// DNS over TLS (RFC 7858), `tls://`
+    Tls,
+    /// DNS over HTTPS (RFC 8484), `https://`
+    Https,
+}
+
+impl NameServerProtocol {
+    /// Default port of this protocol
+    pub fn default_port(self) -> u16 {
+        match self {
+            NameServerProtocol::Udp | NameServerProtocol::Tcp => 53,
+            NameServerProtocol::Tls => 853,
+            NameServerProtocol::Https => 443,
+        }
+    }
+
+    /// URL scheme of this protocol
+    pub fn scheme(self) -> &'static str {
+        match self {
+            NameServerProtocol::Udp => "udp",
+            NameServerProtocol::Tcp => "tcp",
+            NameServerProtocol::Tls => "tls",
+            NameServerProtocol::Https => "https",
+        }
+    }
+}
+
+/// Default path of DNS over HTTPS requests
+pub const DNS_OVER_HTTPS_PATH: &str = "/dns-query";
+
+/// Address of a DNS name server
+///
+/// Formatted like
+///
+/// - `8.8.8.8`, `8.8.8.8:53` or `udp://8.8.8.8:53` for DNS over UDP
+/// - `tcp://8.8.8.8:53` for DNS over TCP
+/// - `tls://1.1.1.1:853#cloudflare-dns.com` for DNS over TLS
+/// - `https://cloudflare-dns.com/dns-query` or `https://1.1.1.1/dns-query#cloudflare-dns.com` for DNS over HTTPS
+///
+/// The fragment is the name for verifying server's certificate, which could be omitted if the server is a domain name.
+#[derive(Debug, Clone)]
+pub struct NameServerAddr {
+    protocol: NameServerProtocol,
+    addr: ServerAddr,
+    tls_name: Option<String>,
+    path: Option<String>,
+}
+
+impl NameServerAddr {
+    /// Get protocol
+    pub fn protocol(&self) -> NameServerProtocol {
+        self.protocol
+    }
+
+    /// Get address
+    pub fn addr(&self) -> &ServerAddr {
+        &self.addr
+    }
+
+    /// Name for verifying server's certificate, only for DNS over TLS and HTTPS
+    pub fn tls_name(&self) -> Option<&str> {
+        self.tls_name.as_ref().map(AsRef::as_ref)
+    }
+
+    /// Path of requests, only for DNS over HTTPS
+    pub fn path(&self) -> &str {
+        self.path.as_ref().map(AsRef::as_ref).unwrap_or(DNS_OVER_HTTPS_PATH)
+    }
+
+    /// Parse a comma separated list of name servers, like `tls://1.1.1.1#cloudflare-dns.com, 8.8.8.8`
+    pub fn parse_list(s: &str) -> Result<Vec<NameServerAddr>, NameServerAddrError> {
+        let mut servers = Vec::new();
+        for ns in s.split(',') {
+            let ns = ns.trim();
+            if ns.is_empty() {
+                continue;
+            }
+            servers.push(ns.parse::<NameServerAddr>()?);
+        }
+
+        if servers.is_empty() {
+            return Err(NameServerAddrError);
+        }
+        Ok(servers)
+    }
+
+    fn parse_addr(s: &str, default_port: u16) -> Result<ServerAddr, NameServerAddrError> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(ServerAddr::SocketAddr(addr));
+        }
+
+        // IP without port, IPv6 may be quoted in brackets
+        if let Ok(ip) = s.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            return Ok(ServerAddr::SocketAddr(SocketAddr::new(ip, default_port)));
+        }
+
+        let (host, port) = match s.find(':') {
+            Some(pos) => match s[pos + 1..].parse::<u16>() {
+                Ok(port) => (&s[..pos], port),
+                Err(..) => return Err(NameServerAddrError),
+            },
+            None => (s, default_port),
+        };
+
+        if host.is_empty() {
+            return Err(NameServerAddrError);
+        }
+        Ok(ServerAddr::DomainName(host.to_owned(), port))
+    }
+}
+
+/// Error for parsing `NameServerAddr`
+#[derive(Debug)]
+pub struct NameServerAddrError;
+
+impl FromStr for NameServerAddr {
+    type Err = NameServerAddrError;
+
+    fn from_str(s: &str) -> Result<NameServerAddr, NameServerAddrError> {
+        let (scheme, rest) = match s.find("://") {
+            Some(pos) => (&s[..pos], &s[pos + 3..]),
+            None => ("udp", s),
+        };
+
+        let protocol = match &scheme.to_ascii_lowercase()[..] {
+            "udp" => NameServerProtocol::Udp,
+            "tcp" => NameServerProtocol::Tcp,
+            #[cfg(feature = "dns-over-tls")]
+            "tls" => NameServerProtocol::Tls,
+            #[cfg(feature = "dns-over-https")]
+            "https" => NameServerProtocol::Https,
+            _ => return Err(NameServerAddrError),
+        };
+
+        let (rest, tls_name) = match rest.find('#') {
+            Some(pos) => (&rest[..pos], Some(rest[pos + 1..].to_owned())),
+            None => (rest, None),
+        };
+
+        let (host, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], Some(rest[pos..].to_owned())),
+            None => (rest, None),
+        };
+
+        let addr = NameServerAddr::parse_addr(host, protocol.default_port())?;
+
+        let tls_name = match protocol {
+            NameServerProtocol::Udp | NameServerProtocol::Tcp => {
+                if tls_name.is_some() {
+                    return Err(NameServerAddrError);
+                }
+                None
+            }
+            NameServerProtocol::Tls | NameServerProtocol::Https => match (tls_name, &addr) {
+                (Some(name), _) => Some(name),
+                (None, ServerAddr::DomainName(ref dname, _)) => Some(dname.clone()),
+                // Certificates couldn't be verified without a name
+                (None, ServerAddr::SocketAddr(..)) => return Err(NameServerAddrError),
+            },
+        };
+
+        let path = match protocol {
+            NameServerProtocol::Https => path,
+            _ if path.is_some() => return Err(NameServerAddrError),
+            _ => None,
+        };
+
+        Ok(NameServerAddr {
+            protocol,
+            addr,
+            tls_name,
+            path,
+        })
+    }
+}
+
+impl From<SocketAddr> for NameServerAddr {
+    fn from(addr: SocketAddr) -> NameServerAddr {
+        NameServerAddr {
+            protocol: NameServerProtocol::Udp,
+            addr: ServerAddr::SocketAddr(addr),
+            tls_name: None,
+            path: None,
+        }
+    }
+}
+
+impl Display for NameServerAddr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}://{}", self.protocol.scheme(), self.addr)?;
+        if let Some(ref path) = self.path {
+            f.write_str(path)?;
+        }
+        match (&self.tls_name, &self.addr) {
+            (Some(ref name), ServerAddr::DomainName(ref dname, _)) if name == dname => Ok(()),
+            (Some(ref name), _) => write!(f, "#{}", name),
+            (None, _) => Ok(()),
+        }
+    }
+}
+
+/// Parse a network like `10.0.0.0/8`, or a single address like `10.0.0.1`
+pub fn parse_ip_net(s: &str) -> Option<IpNet> {
+    match s.parse::<IpNet>() {
+        Ok(net) => Some(net),
+        Err(..) => s.parse::<IpAddr>().ok().map(IpNet::from),
+    }
+}
+
+/// EDNS Client Subnet (RFC 7871) sent by DNS relay to an upstream
+///
+/// Formatted like
+///
+/// - `strip` for asking resolvers not to use any address of the client, or the proxy server
+/// - `client` for the subnet in client's query, or client's address truncated to /24 (IPv4) or /56 (IPv6)
+/// - `203.0.113.0/24` for a fixed subnet
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DnsClientSubnet {
+    /// Sends an option with source prefix 0
+    Strip,
+    /// Client's subnet, nothing will be sent if client's address is not globally routable
+    Client,
+    /// Address with source prefix length
+    Subnet(IpAddr, u8),
+}
+
+/// Error for parsing `DnsClientSubnet`
+#[derive(Debug)]
+pub struct DnsClientSubnetError;
+
+impl FromStr for DnsClientSubnet {
+    type Err = DnsClientSubnetError;
+
+    fn from_str(s: &str) -> Result<DnsClientSubnet, DnsClientSubnetError> {
+        match s {
+            "strip" => return Ok(DnsClientSubnet::Strip),
+            "client" => return Ok(DnsClientSubnet::Client),
+            _ => {}
+        }
+
+        let (addr, prefix) = match s.find('/') {
+            Some(pos) => (&s[..pos], Some(&s[pos + 1..])),
+            None => (s, None),
+        };
+
+        let addr = addr.parse::<IpAddr>().map_err(|_| DnsClientSubnetError)?;
+        let max_prefix = match addr {
+            IpAddr::V4(..) => 32,
+            IpAddr::V6(..) => 128,
+        };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().map_err(|_| DnsClientSubnetError)?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(DnsClientSubnetError);
+        }
+
+        Ok(DnsClientSubnet::Subnet(addr, prefix))
+    }
+}
+
+impl Display for DnsClientSubnet {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            DnsClientSubnet::Strip => f.write_str("strip"),
+            DnsClientSubnet::Client => f.write_str("client"),
+            DnsClientSubnet::Subnet(addr, prefix) => write!(f, "{}/{}", addr, prefix),
+        }
+    }
+}
+
 /// Shadowsocks URL parsing Error
 #[derive(Debug, Clone)]
 pub enum UrlParseError {
@@ -534,6 +1092,7 @@ pub enum UrlParseError {
     InvalidAuthInfo,
     InvalidServerAddr,
     InvalidQueryString,
+    InvalidTag,
 }
 
 impl From<url::ParseError> for UrlParseError {
@@ -552,6 +1111,7 @@ impl fmt::Display for UrlParseError {
             UrlParseError::InvalidAuthInfo => write!(f, "invalid authentication info"),
             UrlParseError::InvalidServerAddr => write!(f, "invalid server address"),
             UrlParseError::InvalidQueryString => write!(f, "invalid query string"),
+            UrlParseError::InvalidTag => write!(f, "invalid tag"),
         }
     }
 }
@@ -566,6 +1126,7 @@ impl error::Error for UrlParseError {
             UrlParseError::InvalidAuthInfo => None,
             UrlParseError::InvalidServerAddr => None,
             UrlParseError::InvalidQueryString => None,
+            UrlParseError::InvalidTag => None,
         }
     }
 }
@@ -870,43 +1431,318 @@ impl FromStr for RedirType {
     }
 }
 
-/// Configuration
-#[derive(Clone, Debug)]
-pub struct Config {
-    /// Remote ShadowSocks server configurations
-    pub server: Vec<ServerConfig>,
-    /// Local server's bind address, or ShadowSocks server's outbound address
-    pub local_addr: Option<ClientConfig>,
-    /// Destination address for tunnel
-    pub forward: Option<Address>,
-    /// DNS configuration, uses system-wide DNS configuration by default
-    ///
-    /// Value could be a `IpAddr`, uses UDP DNS protocol with port `53`. For example: `8.8.8.8`
-    ///
-    /// Also Value could be some pre-defined DNS server names:
-    ///
-    /// - `google`
-    /// - `cloudflare`, `cloudflare_tls`, `cloudflare_https`
-    /// - `quad9`, `quad9_tls`
-    pub dns: Option<String>,
-    /// Server mode, `tcp_only`, `tcp_and_udp`, and `udp_only`
-    pub mode: Mode,
-    /// Set `TCP_NODELAY` socket option
-    pub no_delay: bool,
-    /// Address of `ss-manager`. Send servers' statistic data to the manager server
-    pub manager_addr: Option<ManagerAddr>,
-    /// Manager's default method
-    pub manager_method: Option<CipherType>,
-    /// Config is for Client or Server
-    pub config_type: ConfigType,
-    /// Timeout for UDP Associations, default is 5 minutes
-    pub udp_timeout: Option<Duration>,
+/// Probe of load balancer for checking servers' TCP latency
+#[derive(Clone, Debug, PartialEq)]
+pub enum TcpProbe {
+    /// Sends `GET` request to a HTTP URL through servers, and waits for the response
+    Http(Url),
+    /// Only connects to servers, without sending anything
+    Connect,
+}
+
+impl TcpProbe {
+    /// Target address of HTTP probe, `None` for `Connect`
+    pub fn target_addr(&self) -> Option<Address> {
+        match *self {
+            TcpProbe::Http(ref url) => {
+                let host = url.host_str()?;
+                let port = url.port_or_known_default()?;
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                match host.parse::<IpAddr>() {
+                    Ok(ip) => Some(Address::SocketAddress(SocketAddr::new(ip, port))),
+                    Err(..) => Some(Address::DomainNameAddress(host.to_owned(), port)),
+                }
+            }
+            TcpProbe::Connect => None,
+        }
+    }
+}
+
+/// Parse `TcpProbe` error
+#[derive(Debug)]
+pub struct TcpProbeError;
+
+impl FromStr for TcpProbe {
+    type Err = TcpProbeError;
+
+    fn from_str(s: &str) -> Result<TcpProbe, TcpProbeError> {
+        if s == "connect" {
+            return Ok(TcpProbe::Connect);
+        }
+
+        let url = Url::parse(s).map_err(|_| TcpProbeError)?;
+        if url.scheme() != "http" {
+            return Err(TcpProbeError);
+        }
+
+        let probe = TcpProbe::Http(url);
+        match probe.target_addr() {
+            Some(..) => Ok(probe),
+            None => Err(TcpProbeError),
+        }
+    }
+}
+
+impl Display for TcpProbe {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            TcpProbe::Http(ref url) => Display::fmt(url, f),
+            TcpProbe::Connect => f.write_str("connect"),
+        }
+    }
+}
+
+/// Strategy of load balancer for choosing a server for each connection
+///
+/// Servers are weighted by `weight` of `ServerConfig`, except `Best`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BalancerStrategy {
+    /// The server with the lowest latency score
+    Best,
+    /// Servers in turn
+    RoundRobin,
+    /// Randomly, servers with lower latency scores are more likely to be chosen
+    WeightedRandom,
+    /// The server with the fewest active connections
+    LeastConnections,
+    /// Consistent hashing on destination host, so a site is always visited from the same server
+    ConsistentHash,
+    /// Consistent hashing on client's IP address, so a client always uses the same server
+    Sticky,
+}
+
+impl BalancerStrategy {
+    /// Name of strategy in configuration
+    pub fn name(self) -> &'static str {
+        match self {
+            BalancerStrategy::Best => "best",
+            BalancerStrategy::RoundRobin => "round_robin",
+            BalancerStrategy::WeightedRandom => "weighted_random",
+            BalancerStrategy::LeastConnections => "least_connections",
+            BalancerStrategy::ConsistentHash => "consistent_hash",
+            BalancerStrategy::Sticky => "sticky",
+        }
+    }
+
+    /// Names of all strategies
+    pub fn available_strategies() -> &'static [&'static str] {
+        &[
+            "best",
+            "round_robin",
+            "weighted_random",
+            "least_connections",
+            "consistent_hash",
+            "sticky",
+        ]
+    }
+}
+
+impl Display for BalancerStrategy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Parse `BalancerStrategy` error
+#[derive(Debug)]
+pub struct BalancerStrategyError;
+
+impl FromStr for BalancerStrategy {
+    type Err = BalancerStrategyError;
+
+    fn from_str(s: &str) -> Result<BalancerStrategy, BalancerStrategyError> {
+        match s {
+            "best" => Ok(BalancerStrategy::Best),
+            "round_robin" => Ok(BalancerStrategy::RoundRobin),
+            "weighted_random" => Ok(BalancerStrategy::WeightedRandom),
+            "least_connections" => Ok(BalancerStrategy::LeastConnections),
+            "consistent_hash" => Ok(BalancerStrategy::ConsistentHash),
+            "sticky" => Ok(BalancerStrategy::Sticky),
+            _ => Err(BalancerStrategyError),
+        }
+    }
+}
+
+/// Load balancer, which chooses servers by their latencies checked periodically
+#[derive(Clone, Debug)]
+pub struct BalancerConfig {
+    /// Strategy for choosing a server for each connection
+    pub strategy: BalancerStrategy,
+    /// Probe for checking TCP latency
+    pub tcp_probe: TcpProbe,
+    /// Expected status code of HTTP probe's response, any response is accepted if `None`
+    pub tcp_probe_status: Option<u16>,
+    /// DNS server that receives queries for checking UDP latency through servers
+    pub udp_probe_dns: Address,
+    /// Name queried for checking UDP latency
+    pub udp_probe_name: String,
+    /// Interval between two checks of a server
+    pub check_interval: Duration,
+    /// Checks that took longer than this are stopped, and counted as the maximum latency
+    pub check_timeout: Duration,
+}
+
+impl Default for BalancerConfig {
+    fn default() -> BalancerConfig {
+        BalancerConfig {
+            strategy: BalancerStrategy::Best,
+            tcp_probe: TcpProbe::Http(Url::parse("http://dl.google.com/generate_204").unwrap()),
+            tcp_probe_status: None,
+            udp_probe_dns: Address::SocketAddress(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53)),
+            udp_probe_name: "baidu.com".to_owned(),
+            check_interval: Duration::from_secs(6),
+            // Latency shouldn't greater than 2 secs, that's too long
+            check_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Parse address of DNS server for probing UDP, port is 53 if omitted
+pub fn parse_probe_dns_addr(s: &str) -> Option<Address> {
+    if let Ok(ip) = s.parse::<IpAddr>() {
+        return Some(Address::SocketAddress(SocketAddr::new(ip, 53)));
+    }
+
+    match s.parse::<Address>() {
+        Ok(addr @ Address::SocketAddress(..)) => Some(addr),
+        Ok(addr @ Address::DomainNameAddress(..)) if s.contains(':') => Some(addr),
+        Ok(Address::DomainNameAddress(host, ..)) => Some(Address::DomainNameAddress(host, 53)),
+        Err(..) => None,
+    }
+}
+
+/// Location of SIP008 online configuration
+#[derive(Clone, Debug, PartialEq)]
+pub enum OnlineConfigUrl {
+    /// Fetches with `GET` request from a HTTP URL, or a HTTPS URL with feature `online-config-https`
+    Http(Url),
+    /// Reads from a local file
+    File(PathBuf),
+}
+
+/// Parse `OnlineConfigUrl` error
+#[derive(Debug)]
+pub struct OnlineConfigUrlError;
+
+impl FromStr for OnlineConfigUrl {
+    type Err = OnlineConfigUrlError;
+
+    fn from_str(s: &str) -> Result<OnlineConfigUrl, OnlineConfigUrlError> {
+        if !s.contains("://") {
+            return Ok(OnlineConfigUrl::File(PathBuf::from(s)));
+        }
+
+        let url = Url::parse(s).map_err(|_| OnlineConfigUrlError)?;
+        match url.scheme() {
+            "http" if url.host_str().is_some() => Ok(OnlineConfigUrl::Http(url)),
+            #[cfg(feature = "online-config-https")]
+            "https" if url.host_str().is_some() => Ok(OnlineConfigUrl::Http(url)),
+            "file" => url.to_file_path().map(OnlineConfigUrl::File).map_err(|_| OnlineConfigUrlError),
+            _ => Err(OnlineConfigUrlError),
+        }
+    }
+}
+
+impl Display for OnlineConfigUrl {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            OnlineConfigUrl::Http(ref url) => Display::fmt(url, f),
+            OnlineConfigUrl::File(ref path) => Display::fmt(&path.display(), f),
+        }
+    }
+}
+
+/// SIP008 online configuration, servers in it are added to `Config::server` and updated periodically
+///
+/// https://shadowsocks.org/en/wiki/SIP008-Online-Configuration-Delivery.html
+#[derive(Clone, Debug)]
+pub struct OnlineConfig {
+    /// Where the configuration is fetched from
+    pub url: OnlineConfigUrl,
+    /// Interval between two updates
+    pub update_interval: Duration,
+    /// The last fetched configuration is saved here, and used if fetching failed
+    pub cache_path: Option<PathBuf>,
+}
+
+impl OnlineConfig {
+    /// Creates an online configuration, updated every hour
+    pub fn new(url: OnlineConfigUrl) -> OnlineConfig {
+        OnlineConfig {
+            url,
+            update_interval: Duration::from_secs(3600),
+            cache_path: None,
+        }
+    }
+}
+
+/// Multiplexing TCP relays of local server over a few connections to each server
+#[derive(Clone, Debug)]
+pub struct MuxConfig {
+    /// Maximum number of multiplexed connections to a server
+    pub max_connections: usize,
+    /// New connections are made if all connections are carrying this many relays, until `max_connections`
+    ///
+    /// Must not exceed `MAX_MUX_STREAMS`
+    pub max_streams: usize,
+}
+
+impl Default for MuxConfig {
+    fn default() -> MuxConfig {
+        MuxConfig {
+            max_connections: 4,
+            max_streams: 128,
+        }
+    }
+}
+
+/// Configuration
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Remote ShadowSocks server configurations
+    pub server: Vec<ServerConfig>,
+    /// Local server's bind address, or ShadowSocks server's outbound address
+    pub local_addr: Option<ClientConfig>,
+    /// Destination address for tunnel
+    pub forward: Option<Address>,
+    /// DNS configuration, uses system-wide DNS configuration by default
+    ///
+    /// Value could be a `IpAddr`, uses UDP DNS protocol with port `53`. For example: `8.8.8.8`
+    ///
+    /// Also Value could be some pre-defined DNS server names:
+    ///
+    /// - `google`
+    /// - `cloudflare`, `cloudflare_tls`, `cloudflare_https`
+    /// - `quad9`, `quad9_tls`
+    pub dns: Option<String>,
+    /// Server mode, `tcp_only`, `tcp_and_udp`, and `udp_only`
+    pub mode: Mode,
+    /// Set `TCP_NODELAY` socket option
+    pub no_delay: bool,
+    /// Address of `ss-manager`. Send servers' statistic data to the manager server
+    pub manager_addr: Option<ManagerAddr>,
+    /// Address of control socket of local server, for managing servers at runtime
+    pub control_addr: Option<ManagerAddr>,
+    /// Manager's default method
+    pub manager_method: Option<CipherType>,
+    /// Config is for Client or Server
+    pub config_type: ConfigType,
+    /// Timeout for UDP Associations, default is 5 minutes
+    pub udp_timeout: Option<Duration>,
     /// `RLIMIT_NOFILE` option for *nix systems
     pub nofile: Option<u64>,
     /// Timeout for TCP connections, could be replaced by server*.timeout
     pub timeout: Option<Duration>,
     /// ACL configuration
-    pub acl: Option<AccessControl>,
+    ///
+    /// For servers, this is the default ACL for servers that don't have their own
+    pub acl: Option<Arc<AccessControl>>,
+    /// Named ACLs, servers created by manager could reference them by names
+    pub acl_policies: HashMap<String, Arc<AccessControl>>,
+    /// `acl_policies` as they are configured, for converting back to configuration
+    acl_policy_configs: HashMap<String, SSAclConfig>,
+    /// Static host records, overriding DNS resolution
+    pub hosts: Option<Arc<Hosts>>,
     /// Path to stat callback unix address, only for Android
     /// TCP Transparent Proxy type
     pub tcp_redir: RedirType,
@@ -922,18 +1758,64 @@ pub struct Config {
     /// Internal DNS's bind address
     #[cfg(feature = "local-dns-relay")]
     pub dns_local_addr: Option<ClientConfig>,
-    /// Local DNS's address
+    /// Maximum number of responses cached by DNS relay, `0` disables caching
+    #[cfg(feature = "local-dns-relay")]
+    pub dns_cache_size: Option<usize>,
+    /// Refresh popular responses in DNS relay's cache before they are expired
+    #[cfg(feature = "local-dns-relay")]
+    pub dns_cache_prefetch: bool,
+    /// Transport for sending queries to remote DNS through proxy
     ///
-    /// Sending DNS query directly to this address
-    pub local_dns_addr: Option<SocketAddr>,
-    /// Remote DNS's address
+    /// `udp_only` and `tcp_and_udp` send queries with UDP, and retry with TCP if responses are truncated.
+    /// `tcp_and_udp` also retries with TCP if UDP failed.
+    #[cfg(feature = "local-dns-relay")]
+    pub remote_dns_mode: Mode,
+    /// Responses from local DNS with these addresses are considered poisoned
+    #[cfg(feature = "local-dns-relay")]
+    pub dns_bogus_addrs: Vec<IpNet>,
+    /// Responses from local DNS that arrived faster than this are considered poisoned
+    #[cfg(feature = "local-dns-relay")]
+    pub local_dns_min_rtt: Option<Duration>,
+    /// Responses from local DNS with different TTLs in the same RRset are considered poisoned
+    #[cfg(feature = "local-dns-relay")]
+    pub dns_check_ttl: bool,
+    /// EDNS Client Subnet sent to local DNS, no ECS option if `None`
+    #[cfg(feature = "local-dns-relay")]
+    pub local_dns_ecs: Option<DnsClientSubnet>,
+    /// EDNS Client Subnet sent to remote DNS, no ECS option if `None`
+    #[cfg(feature = "local-dns-relay")]
+    pub remote_dns_ecs: Option<DnsClientSubnet>,
+    /// DNS relay's bind address for serving DNS over TLS (RFC 7858)
+    #[cfg(feature = "local-dns-relay")]
+    pub dns_tls_local_addr: Option<ClientConfig>,
+    /// DNS relay's bind address for serving DNS over HTTPS (RFC 8484)
+    #[cfg(feature = "local-dns-relay")]
+    pub dns_https_local_addr: Option<ClientConfig>,
+    /// PEM certificate chain of DNS relay's DNS over TLS and HTTPS servers
+    #[cfg(feature = "local-dns-relay")]
+    pub dns_tls_cert_path: Option<PathBuf>,
+    /// PEM private key of DNS relay's DNS over TLS and HTTPS servers
+    #[cfg(feature = "local-dns-relay")]
+    pub dns_tls_key_path: Option<PathBuf>,
+    /// Local DNS's addresses
+    ///
+    /// Sending DNS query directly to these addresses, in order until one of them succeeded.
+    /// Name servers of system's resolver (`/etc/resolv.conf` on *nix) are used if it is `None`.
+    pub local_dns_addr: Option<Vec<NameServerAddr>>,
+    /// Remote DNS's addresses
     ///
-    /// Sending DNS query through proxy to this address
-    pub remote_dns_addr: Option<Address>,
+    /// Sending DNS query through proxy to these addresses, in order until one of them succeeded
+    pub remote_dns_addr: Option<Vec<NameServerAddr>>,
     /// Uses IPv6 addresses first
     ///
     /// Set to `true` if you want to query IPv6 addresses before IPv4
     pub ipv6_first: bool,
+    /// Load balancer of servers
+    pub balancer: BalancerConfig,
+    /// SIP008 online configuration, which provides servers besides `server`
+    pub online_config: Option<OnlineConfig>,
+    /// Multiplexes TCP relays to servers, servers not supporting it are connected as usual
+    pub mux: Option<MuxConfig>,
 }
 
 /// Configuration parsing error kind
@@ -1006,12 +1888,16 @@ impl Config {
             mode: Mode::TcpOnly,
             no_delay: false,
             manager_addr: None,
+            control_addr: None,
             manager_method: None,
             config_type,
             udp_timeout: None,
             nofile: None,
             timeout: None,
             acl: None,
+            acl_policies: HashMap::new(),
+            acl_policy_configs: HashMap::new(),
+            hosts: None,
             tcp_redir: RedirType::tcp_default(),
             udp_redir: RedirType::udp_default(),
             #[cfg(feature = "local-flow-stat")]
@@ -1020,15 +1906,100 @@ impl Config {
             local_dns_path: None,
             #[cfg(feature = "local-dns-relay")]
             dns_local_addr: None,
+            #[cfg(feature = "local-dns-relay")]
+            dns_cache_size: None,
+            #[cfg(feature = "local-dns-relay")]
+            dns_cache_prefetch: false,
+            #[cfg(feature = "local-dns-relay")]
+            remote_dns_mode: Mode::TcpOnly,
+            #[cfg(feature = "local-dns-relay")]
+            dns_bogus_addrs: Vec::new(),
+            #[cfg(feature = "local-dns-relay")]
+            local_dns_min_rtt: None,
+            #[cfg(feature = "local-dns-relay")]
+            dns_check_ttl: false,
+            #[cfg(feature = "local-dns-relay")]
+            local_dns_ecs: None,
+            #[cfg(feature = "local-dns-relay")]
+            remote_dns_ecs: None,
+            #[cfg(feature = "local-dns-relay")]
+            dns_tls_local_addr: None,
+            #[cfg(feature = "local-dns-relay")]
+            dns_https_local_addr: None,
+            #[cfg(feature = "local-dns-relay")]
+            dns_tls_cert_path: None,
+            #[cfg(feature = "local-dns-relay")]
+            dns_tls_key_path: None,
             local_dns_addr: None,
             remote_dns_addr: None,
             ipv6_first: false,
+            balancer: BalancerConfig::default(),
+            online_config: None,
+            mux: None,
         }
     }
 
     fn load_from_ssconfig(config: SSConfig, config_type: ConfigType) -> Result<Config, Error> {
         let mut nconfig = Config::new(config_type);
 
+        // DNS relay
+        #[cfg(feature = "local-dns-relay")]
+        Config::load_dns_relay_from_ssconfig(&config, &mut nconfig)?;
+
+        // ACL policies, must be loaded before servers for being referenced by names
+        let mut acl_loader = AclLoader::default();
+        if let Some(policies) = config.acl_policies {
+            for (name, acl_config) in policies {
+                let acl = acl_loader.load(&acl_config)?;
+                nconfig.acl_policies.insert(name.clone(), acl);
+                nconfig.acl_policy_configs.insert(name, acl_config);
+            }
+            acl_loader.policies = nconfig.acl_policies.clone();
+        }
+
+        if let Some(ref acl) = config.acl {
+            nconfig.acl = Some(acl_loader.load(acl)?);
+        }
+
+        // Records in `hosts` take precedence over `hosts_file`
+        if config.hosts.is_some() || config.hosts_file.is_some() {
+            let mut hosts = Hosts::new();
+
+            if let Some(ref path) = config.hosts_file {
+                if let Err(err) = hosts.extend_from_file(path) {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid `hosts_file`",
+                        Some(format!("loading hosts \"{}\", {}", path, err)),
+                    );
+                    return Err(err);
+                }
+            }
+
+            if let Some(records) = config.hosts {
+                for (name, values) in records {
+                    let values = match values {
+                        SSHostsConfig::Single(v) => vec![v],
+                        SSHostsConfig::Multiple(vs) => vs,
+                    };
+
+                    match parse_entry(&values) {
+                        Ok(entry) => hosts.insert(&name, entry),
+                        Err(err) => {
+                            let err = Error::new(
+                                ErrorKind::Invalid,
+                                "invalid `hosts`",
+                                Some(format!("\"{}\", {}", name, err)),
+                            );
+                            return Err(err);
+                        }
+                    }
+                }
+            }
+
+            nconfig.hosts = Some(Arc::new(hosts));
+        }
+
         // Standard config
         // Client
         if let Some(la) = config.local_address {
@@ -1110,37 +2081,7 @@ impl Config {
         // Ext servers
         if let Some(servers) = config.servers {
             for svr in servers {
-                let addr = match svr.address.parse::<Ipv4Addr>() {
-                    Ok(v4) => ServerAddr::SocketAddr(SocketAddr::V4(SocketAddrV4::new(v4, svr.port))),
-                    Err(..) => match svr.address.parse::<Ipv6Addr>() {
-                        Ok(v6) => ServerAddr::SocketAddr(SocketAddr::V6(SocketAddrV6::new(v6, svr.port, 0, 0))),
-                        Err(..) => ServerAddr::DomainName(svr.address, svr.port),
-                    },
-                };
-
-                let method = match svr.method.parse::<CipherType>() {
-                    Ok(m) => m,
-                    Err(..) => {
-                        let err = Error::new(
-                            ErrorKind::Invalid,
-                            "unsupported method",
-                            Some(format!("`{}` is not a supported method", svr.method)),
-                        );
-                        return Err(err);
-                    }
-                };
-
-                let plugin = match svr.plugin {
-                    None => None,
-                    Some(p) => Some(PluginConfig {
-                        plugin: p,
-                        plugin_opt: svr.plugin_opts,
-                    }),
-                };
-
-                let timeout = svr.timeout.or(config.timeout).map(Duration::from_secs);
-                let nsvr = ServerConfig::new(addr, svr.password, method, timeout, plugin);
-
+                let nsvr = Config::load_server_from_ssconfig(svr, config.timeout, &mut acl_loader)?;
                 nconfig.server.push(nsvr);
             }
         }
@@ -1169,6 +2110,21 @@ impl Config {
             nconfig.manager_addr = Some(manager);
         }
 
+        // Control Address
+        if let Some(ca) = config.control_address {
+            match ca.parse::<ManagerAddr>() {
+                Ok(addr) => nconfig.control_addr = Some(addr),
+                Err(..) => {
+                    let e = Error::new(
+                        ErrorKind::Malformed,
+                        "malformed `control_address`, must be ip:port, domain:port or /path/to/unix.sock",
+                        None,
+                    );
+                    return Err(e);
+                }
+            }
+        }
+
         // DNS
         nconfig.dns = config.dns;
 
@@ -1207,9 +2163,280 @@ impl Config {
             nconfig.ipv6_first = f;
         }
 
+        // Health checking of load balancer
+        if let Some(ref balancer) = config.balancer {
+            Config::load_balancer_from_ssconfig(balancer, &mut nconfig.balancer)?;
+        }
+
+        // SIP008 online configuration
+        if let Some(online) = config.online_config {
+            let url = match online.url.parse::<OnlineConfigUrl>() {
+                Ok(url) => url,
+                Err(..) => {
+                    let e = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid `online_config.url`, must be a HTTP URL or a file path",
+                        Some(format!("invalid value \"{}\"", online.url)),
+                    );
+                    return Err(e);
+                }
+            };
+
+            let mut oconfig = OnlineConfig::new(url);
+            match online.update_interval {
+                Some(0) => {
+                    let e = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid `online_config.update_interval`, must be greater than 0",
+                        None,
+                    );
+                    return Err(e);
+                }
+                Some(interval) => oconfig.update_interval = Duration::from_secs(interval),
+                None => {}
+            }
+            oconfig.cache_path = online.cache_path.map(PathBuf::from);
+
+            nconfig.online_config = Some(oconfig);
+        }
+
+        // Multiplexing TCP relays
+        if let Some(mux) = config.mux {
+            let mut mconfig = MuxConfig::default();
+            match mux.max_connections {
+                Some(0) => {
+                    let e = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid `mux.max_connections`, must be greater than 0",
+                        None,
+                    );
+                    return Err(e);
+                }
+                Some(n) => mconfig.max_connections = n,
+                None => {}
+            }
+            match mux.max_streams {
+                Some(n) if n == 0 || n > MAX_MUX_STREAMS => {
+                    let e = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid `mux.max_streams`, must be in [1, 256]",
+                        Some(format!("got {}", n)),
+                    );
+                    return Err(e);
+                }
+                Some(n) => mconfig.max_streams = n,
+                None => {}
+            }
+
+            nconfig.mux = Some(mconfig);
+        }
+
         Ok(nconfig)
     }
 
+    fn load_server_from_ssconfig(
+        svr: SSServerExtConfig,
+        timeout: Option<u64>,
+        acl_loader: &mut AclLoader,
+    ) -> Result<ServerConfig, Error> {
+        let addr = match svr.address.parse::<Ipv4Addr>() {
+            Ok(v4) => ServerAddr::SocketAddr(SocketAddr::V4(SocketAddrV4::new(v4, svr.port))),
+            Err(..) => match svr.address.parse::<Ipv6Addr>() {
+                Ok(v6) => ServerAddr::SocketAddr(SocketAddr::V6(SocketAddrV6::new(v6, svr.port, 0, 0))),
+                Err(..) => ServerAddr::DomainName(svr.address, svr.port),
+            },
+        };
+
+        let method = match svr.method.parse::<CipherType>() {
+            Ok(m) => m,
+            Err(..) => {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "unsupported method",
+                    Some(format!("`{}` is not a supported method", svr.method)),
+                );
+                return Err(err);
+            }
+        };
+
+        let plugin = match svr.plugin {
+            None => None,
+            Some(p) => Some(PluginConfig {
+                plugin: p,
+                plugin_opt: svr.plugin_opts,
+            }),
+        };
+
+        let timeout = svr.timeout.or(timeout).map(Duration::from_secs);
+        let mut nsvr = ServerConfig::new(addr, svr.password, method, timeout, plugin);
+
+        if let Some(ref acl) = svr.acl {
+            nsvr.set_acl(acl_loader.load(acl)?);
+            nsvr.acl_config = Some(acl.clone());
+        }
+
+        match svr.weight {
+            Some(weight) if weight == 0 || weight > MAX_SERVER_WEIGHT => {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `weight`, must be in [1, 100]",
+                    Some(format!("got {}", weight)),
+                );
+                return Err(e);
+            }
+            Some(weight) => nsvr.set_weight(weight),
+            None => {}
+        }
+
+        if let Some(remarks) = svr.remarks {
+            nsvr.set_remarks(remarks);
+        }
+
+        if let Some(id) = svr.id {
+            nsvr.set_id(id);
+        }
+
+        Ok(nsvr)
+    }
+
+    fn load_balancer_from_ssconfig(config: &SSBalancerConfig, balancer: &mut BalancerConfig) -> Result<(), Error> {
+        fn invalid(key: &'static str, value: &str) -> Error {
+            Error::new(ErrorKind::Invalid, key, Some(format!("invalid value \"{}\"", value)))
+        }
+
+        if let Some(ref strategy) = config.strategy {
+            balancer.strategy = strategy
+                .parse::<BalancerStrategy>()
+                .map_err(|_| invalid("invalid `balancer.strategy`", strategy))?;
+        }
+
+        if let Some(ref probe) = config.tcp_probe {
+            balancer.tcp_probe = probe
+                .parse::<TcpProbe>()
+                .map_err(|_| invalid("invalid `balancer.tcp_probe`, must be a HTTP URL or `connect`", probe))?;
+        }
+
+        if config.tcp_probe_status.is_some() {
+            balancer.tcp_probe_status = config.tcp_probe_status;
+        }
+
+        if let Some(ref addr) = config.udp_probe_dns {
+            balancer.udp_probe_dns =
+                parse_probe_dns_addr(addr).ok_or_else(|| invalid("invalid `balancer.udp_probe_dns`", addr))?;
+        }
+
+        if let Some(ref name) = config.udp_probe_name {
+            if Name::from_ascii(name).is_err() {
+                return Err(invalid("invalid `balancer.udp_probe_name`", name));
+            }
+            balancer.udp_probe_name = name.clone();
+        }
+
+        if let Some(interval) = config.check_interval {
+            if interval == 0 {
+                return Err(invalid("invalid `balancer.check_interval`", "0"));
+            }
+            balancer.check_interval = Duration::from_secs(interval);
+        }
+
+        if let Some(timeout) = config.check_timeout {
+            if timeout == 0 {
+                return Err(invalid("invalid `balancer.check_timeout`", "0"));
+            }
+            balancer.check_timeout = Duration::from_secs(timeout);
+        }
+
+        Ok(())
+    }
+
+    /// DNS relay's configuration, for both `DnsLocal` and the relay integrated in other local servers
+    #[cfg(feature = "local-dns-relay")]
+    fn load_dns_relay_from_ssconfig(config: &SSConfig, nconfig: &mut Config) -> Result<(), Error> {
+        fn invalid(key: &'static str, value: &str) -> Error {
+            Error::new(ErrorKind::Invalid, key, Some(format!("invalid value \"{}\"", value)))
+        }
+
+        if let Some(ref ns) = config.local_dns {
+            let servers = NameServerAddr::parse_list(ns).map_err(|_| invalid("invalid `local_dns`", ns))?;
+            nconfig.local_dns_addr = Some(servers);
+        }
+
+        if let Some(ref ns) = config.remote_dns {
+            let servers = NameServerAddr::parse_list(ns).map_err(|_| invalid("invalid `remote_dns`", ns))?;
+            nconfig.remote_dns_addr = Some(servers);
+        }
+
+        if let Some(ref m) = config.remote_dns_mode {
+            match m.parse::<Mode>() {
+                Ok(m) => nconfig.remote_dns_mode = m,
+                Err(..) => {
+                    let e = Error::new(
+                        ErrorKind::Malformed,
+                        "malformed `remote_dns_mode`, must be one of `tcp_only`, `udp_only` and `tcp_and_udp`",
+                        None,
+                    );
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Some(ref addrs) = config.dns_bogus_ips {
+            for addr in addrs {
+                let net = parse_ip_net(addr).ok_or_else(|| invalid("invalid `dns_bogus_ips`", addr))?;
+                nconfig.dns_bogus_addrs.push(net);
+            }
+        }
+
+        nconfig.local_dns_min_rtt = config.local_dns_min_rtt.map(Duration::from_millis);
+
+        if let Some(b) = config.dns_check_ttl {
+            nconfig.dns_check_ttl = b;
+        }
+
+        if let Some(ref ecs) = config.local_dns_ecs {
+            let ecs = ecs.parse::<DnsClientSubnet>().map_err(|_| invalid("invalid `local_dns_ecs`", ecs))?;
+            nconfig.local_dns_ecs = Some(ecs);
+        }
+
+        if let Some(ref ecs) = config.remote_dns_ecs {
+            let ecs = ecs.parse::<DnsClientSubnet>().map_err(|_| invalid("invalid `remote_dns_ecs`", ecs))?;
+            nconfig.remote_dns_ecs = Some(ecs);
+        }
+
+        if let Some(ref addr) = config.dns_relay {
+            let addr = addr.parse::<ServerAddr>().map_err(|_| invalid("invalid `dns_relay`", addr))?;
+            nconfig.dns_local_addr = Some(addr);
+        }
+
+        if config.dns_cache_size.is_some() {
+            nconfig.dns_cache_size = config.dns_cache_size;
+        }
+
+        if let Some(b) = config.dns_cache_prefetch {
+            nconfig.dns_cache_prefetch = b;
+        }
+
+        if let Some(ref addr) = config.dns_relay_tls {
+            let addr = addr.parse::<ServerAddr>().map_err(|_| invalid("invalid `dns_relay_tls`", addr))?;
+            nconfig.dns_tls_local_addr = Some(addr);
+        }
+
+        if let Some(ref addr) = config.dns_relay_https {
+            let addr = addr.parse::<ServerAddr>().map_err(|_| invalid("invalid `dns_relay_https`", addr))?;
+            nconfig.dns_https_local_addr = Some(addr);
+        }
+
+        if let Some(ref p) = config.dns_relay_cert {
+            nconfig.dns_tls_cert_path = Some(PathBuf::from(p));
+        }
+
+        if let Some(ref p) = config.dns_relay_key {
+            nconfig.dns_tls_key_path = Some(PathBuf::from(p));
+        }
+
+        Ok(())
+    }
+
     /// Load Config from a `str`
     pub fn load_from_str(s: &str, config_type: ConfigType) -> Result<Config, Error> {
         let c = json5::from_str::<SSConfig>(s)?;
@@ -1227,42 +2454,37 @@ impl Config {
     #[doc(hidden)]
     #[cfg(feature = "trust-dns")]
     /// Get `trust-dns`'s `ResolverConfig` by DNS configuration string
-    pub fn get_dns_config(&self) -> Option<ResolverConfig> {
-        self.dns.as_ref().and_then(|ds| {
-            match &ds[..] {
-                "google" => Some(ResolverConfig::google()),
-
-                "cloudflare" => Some(ResolverConfig::cloudflare()),
-                #[cfg(feature = "dns-over-tls")]
-                "cloudflare_tls" => Some(ResolverConfig::cloudflare_tls()),
-                #[cfg(feature = "dns-over-https")]
-                "cloudflare_https" => Some(ResolverConfig::cloudflare_https()),
-
-                "quad9" => Some(ResolverConfig::quad9()),
-                #[cfg(feature = "dns-over-tls")]
-                "quad9_tls" => Some(ResolverConfig::quad9_tls()),
-
-                _ => {
-                    // Set ips directly
-                    //
-                    // TODO: Support customizable DNS over TLS or HTTPS
-                    match ds.parse::<IpAddr>() {
-                        Ok(ip) => Some(ResolverConfig::from_parts(
-                            None,
-                            vec![],
-                            NameServerConfigGroup::from_ips_clear(&[ip], 53),
-                        )),
-                        Err(..) => {
-                            error!(
-                                "Failed to parse DNS \"{}\" in config to IpAddr, fallback to system config",
-                                ds
-                            );
-                            None
-                        }
-                    }
+    ///
+    /// Besides of the predefined names, it could be a comma separated list of `NameServerAddr`.
+    /// Domain names of name servers are resolved by system's resolver.
+    pub async fn get_dns_config(&self) -> Option<ResolverConfig> {
+        let ds = self.dns.as_ref()?;
+        match &ds[..] {
+            "google" => Some(ResolverConfig::google()),
+
+            "cloudflare" => Some(ResolverConfig::cloudflare()),
+            #[cfg(feature = "dns-over-tls")]
+            "cloudflare_tls" => Some(ResolverConfig::cloudflare_tls()),
+            #[cfg(feature = "dns-over-https")]
+            "cloudflare_https" => Some(ResolverConfig::cloudflare_https()),
+
+            "quad9" => Some(ResolverConfig::quad9()),
+            #[cfg(feature = "dns-over-tls")]
+            "quad9_tls" => Some(ResolverConfig::quad9_tls()),
+
+            _ => match NameServerAddr::parse_list(ds) {
+                Ok(servers) => get_name_server_group(&servers)
+                    .await
+                    .map(|group| ResolverConfig::from_parts(None, vec![], group)),
+                Err(..) => {
+                    error!(
+                        "Failed to parse DNS \"{}\" in config to name servers, fallback to system config",
+                        ds
+                    );
+                    None
                 }
-            }
-        })
+            },
+        }
     }
 
     /// Check if there are any plugin are enabled with servers
@@ -1320,6 +2542,61 @@ impl Config {
     }
 }
 
+#[cfg(feature = "trust-dns")]
+async fn get_name_server_group(servers: &[NameServerAddr]) -> Option<NameServerConfigGroup> {
+    use tokio::net::lookup_host;
+    use trust_dns_resolver::config::{NameServerConfig, Protocol};
+
+    let mut group = NameServerConfigGroup::with_capacity(servers.len());
+    for ns in servers {
+        let protocol = match ns.protocol() {
+            NameServerProtocol::Udp => Protocol::Udp,
+            NameServerProtocol::Tcp => Protocol::Tcp,
+            #[cfg(feature = "dns-over-tls")]
+            NameServerProtocol::Tls => Protocol::Tls,
+            #[cfg(feature = "dns-over-https")]
+            NameServerProtocol::Https => {
+                if ns.path() != DNS_OVER_HTTPS_PATH {
+                    error!(
+                        "DNS \"{}\" is not supported, DNS over HTTPS's path must be \"{}\"",
+                        ns, DNS_OVER_HTTPS_PATH
+                    );
+                    return None;
+                }
+                Protocol::Https
+            }
+            #[allow(unreachable_patterns)]
+            _ => {
+                error!("DNS \"{}\" is not supported, consider enable it by features", ns);
+                return None;
+            }
+        };
+
+        let addrs = match *ns.addr() {
+            ServerAddr::SocketAddr(addr) => vec![addr],
+            ServerAddr::DomainName(ref dname, port) => match lookup_host((dname.as_str(), port)).await {
+                Ok(addrs) => addrs.collect(),
+                Err(err) => {
+                    error!("Failed to resolve DNS \"{}\", error: {}", ns, err);
+                    return None;
+                }
+            },
+        };
+
+        for socket_addr in addrs {
+            group.push(NameServerConfig {
+                socket_addr,
+                protocol,
+                tls_dns_name: ns.tls_name().map(ToOwned::to_owned),
+                #[cfg(any(feature = "dns-over-tls", feature = "dns-over-https"))]
+                tls_config: None,
+            });
+        }
+    }
+
+    Some(group)
+}
+
 impl fmt::Display for Config {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // Convert to json
@@ -1340,10 +2617,13 @@ impl fmt::Display for Config {
         }
 
         // Servers
-        // For 1 servers, uses standard configure format
+        // For 1 servers, uses standard configure format, which doesn't have `remarks`, `id` and `acl` of servers
         match self.server.len() {
             0 => {}
-            1 => {
+            1 if self.server[0].remarks().is_none()
+                && self.server[0].id().is_none()
+                && self.server[0].acl_config.is_none() =>
+            {
                 let svr = &self.server[0];
 
                 jconf.server = Some(match *svr.addr() {
@@ -1378,6 +2658,10 @@ impl fmt::Display for Config {
                         plugin: svr.plugin().map(|p| p.plugin.to_string()),
                         plugin_opts: svr.plugin().and_then(|p| p.plugin_opt.clone()),
                         timeout: svr.timeout().map(|t| t.as_secs()),
+                        acl: svr.acl_config.clone(),
+                        weight: if svr.weight() != 1 { Some(svr.weight()) } else { None },
+                        remarks: svr.remarks().map(ToOwned::to_owned),
+                        id: svr.id().map(ToOwned::to_owned),
                     });
                 }
 
@@ -1402,6 +2686,30 @@ impl fmt::Display for Config {
             };
         }
 
+        jconf.control_address = self.control_addr.as_ref().map(|ca| ca.to_string());
+
+        // Servers may reference policies by names
+        let acl_policies = self
+            .acl_policy_configs
+            .iter()
+            .filter(|(name, _)| self.acl_policies.contains_key(*name))
+            .map(|(name, acl)| (name.clone(), acl.clone()))
+            .collect::<HashMap<_, _>>();
+        if !acl_policies.is_empty() {
+            jconf.acl_policies = Some(acl_policies);
+        }
+
+        jconf.online_config = self.online_config.as_ref().map(|oc| SSOnlineConfig {
+            url: oc.url.to_string(),
+            update_interval: Some(oc.update_interval.as_secs()),
+            cache_path: oc.cache_path.as_ref().map(|p| p.display().to_string()),
+        });
+
+        jconf.mux = self.mux.as_ref().map(|mc| SSMuxConfig {
+            max_connections: Some(mc.max_connections),
+            max_streams: Some(mc.max_streams),
+        });
+
         jconf.mode = Some(self.mode.to_string());
 
         if self.no_delay {
@@ -1423,3 +2731,103 @@ impl fmt::Display for Config {
         write!(f, "{}", json5::to_string(&jconf).unwrap())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn url_with_tag() {
+        let mut svr_cfg = ServerConfig::new(
+            ServerAddr::from(("example.com", 8388)),
+            "password".to_owned(),
+            CipherType::Aes256Gcm,
+            None,
+            None,
+        );
+        svr_cfg.set_remarks("HK 1 #香港".to_owned());
+
+        let url = svr_cfg.to_url();
+        assert!(url.ends_with("#HK%201%20%23%E9%A6%99%E6%B8%AF"));
+
+        let decoded = ServerConfig::from_url(&url).unwrap();
+        assert_eq!(decoded.remarks(), Some("HK 1 #香港"));
+        assert_eq!(decoded.addr().to_string(), "example.com:8388");
+        assert_eq!(decoded.to_string(), "HK 1 #香港 (example.com:8388)");
+
+        let decoded = ServerConfig::from_url("ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@example.com:8388").unwrap();
+        assert_eq!(decoded.remarks(), None);
+        assert_eq!(decoded.to_string(), "example.com:8388");
+    }
+
+    #[test]
+    fn weight_bounds() {
+        let config = |weight: u32| {
+            format!(
+                r#"{{"servers": [{{"address": "127.0.0.1", "port": 8388, "password": "password", "method": "aes-256-gcm", "weight": {}}}]}}"#,
+                weight
+            )
+        };
+
+        for weight in &[0, MAX_SERVER_WEIGHT + 1, 100_000_000] {
+            assert!(Config::load_from_str(&config(*weight), ConfigType::Socks5Local).is_err());
+        }
+
+        let loaded = Config::load_from_str(&config(MAX_SERVER_WEIGHT), ConfigType::Socks5Local).unwrap();
+        assert_eq!(loaded.server[0].weight(), MAX_SERVER_WEIGHT);
+    }
+
+    #[test]
+    fn server_acl_to_string() {
+        let doc = r#"{
+            "servers": [
+                {"address": "127.0.0.1", "port": 8388, "password": "password", "method": "aes-256-gcm", "acl": ["[bypass_all]"]},
+                {"address": "127.0.0.1", "port": 8389, "password": "password", "method": "aes-256-gcm", "acl": "trial"},
+                {"address": "127.0.0.1", "port": 8390, "password": "password", "method": "aes-256-gcm"}
+            ],
+            "acl_policies": {
+                "trial": ["[bypass_all]", "[proxy_list]", "example.com"]
+            }
+        }"#;
+
+        let config = Config::load_from_str(doc, ConfigType::Server).unwrap();
+        let reloaded = Config::load_from_str(&config.to_string(), ConfigType::Server).unwrap();
+        assert_eq!(reloaded.to_string(), config.to_string());
+
+        assert!(reloaded.server[0].acl().is_some());
+        assert_eq!(reloaded.server[0].acl_policy(), None);
+        assert!(reloaded.server[1].acl().is_some());
+        assert_eq!(reloaded.server[1].acl_policy(), Some("trial"));
+        assert!(reloaded.server[2].acl().is_none());
+        assert!(reloaded.acl_policies.contains_key("trial"));
+    }
+
+    #[test]
+    fn balancer_check_bounds() {
+        let config = |key: &str, value: u64| {
+            format!(
+                r#"{{"server": "127.0.0.1", "server_port": 8388, "password": "password", "method": "aes-256-gcm", "balancer": {{"{}": {}}}}}"#,
+                key, value
+            )
+        };
+
+        for key in &["check_interval", "check_timeout"] {
+            assert!(Config::load_from_str(&config(key, 0), ConfigType::Socks5Local).is_err());
+            assert!(Config::load_from_str(&config(key, 1), ConfigType::Socks5Local).is_ok());
+        }
+    }
+
+    #[test]
+    fn parse_online_config_url() {
+        let url = "http://example.com/servers.json".parse::<OnlineConfigUrl>().unwrap();
+        assert_eq!(url.to_string(), "http://example.com/servers.json");
+
+        #[cfg(feature = "online-config-https")]
+        assert!("https://example.com/servers.json".parse::<OnlineConfigUrl>().is_ok());
+
+        let url = "/etc/servers.json".parse::<OnlineConfigUrl>().unwrap();
+        assert_eq!(url, OnlineConfigUrl::File(PathBuf::from("/etc/servers.json")));
+
+        assert!("ftp://example.com/servers.json".parse::<OnlineConfigUrl>().is_err());
+    }
+}
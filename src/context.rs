@@ -22,16 +22,18 @@ use trust_dns_resolver::TokioAsyncResolver;
 
 #[cfg(feature = "trust-dns")]
 use crate::relay::dns_resolver::create_resolver;
-#[cfg(not(feature = "local-dns-relay"))]
 use crate::relay::dns_resolver::resolve;
 #[cfg(feature = "local-dns-relay")]
 use crate::relay::dnsrelay::upstream::LocalUpstream;
 #[cfg(feature = "local-flow-stat")]
 use crate::relay::flow::ServerFlowStatistic;
+use crate::relay::loadbalancing::server::{BalancerService, SharedBalancerService};
+use crate::relay::tcprelay::mux::MuxPool;
 use crate::{
     acl::AccessControl,
     config::{Config, ConfigType, ServerConfig},
     crypto::CipherType,
+    hosts::HostsMatch,
     relay::socks5::Address,
 };
 
@@ -131,7 +133,7 @@ impl ServerState {
     /// Create a global shared server state
     pub async fn new_shared(config: &Config) -> SharedServerState {
         let state = ServerState {
-            dns_resolver: match create_resolver(config.get_dns_config(), config.timeout, config.ipv6_first).await {
+            dns_resolver: match create_resolver(config.get_dns_config().await, config.timeout, config.ipv6_first).await {
                 Ok(resolver) => Some(resolver),
                 Err(..) => None,
             },
@@ -172,6 +174,12 @@ pub struct Context {
     // https://github.com/shadowsocks/shadowsocks-org/issues/44
     nonce_ppbloom: Mutex<PingPongBloom>,
 
+    // Load balancer shared by all local servers, created when it is used for the first time
+    balancer: Mutex<Option<SharedBalancerService>>,
+
+    // Multiplexed connections to servers, shared by all local servers
+    mux_pool: MuxPool,
+
     // For Android's flow stat report
     #[cfg(feature = "local-flow-stat")]
     local_flow_statistic: ServerFlowStatistic,
@@ -228,6 +236,8 @@ impl Context {
             server_state,
             server_running: AtomicBool::new(true),
             nonce_ppbloom,
+            balancer: Mutex::new(None),
+            mux_pool: MuxPool::new(),
             #[cfg(feature = "local-flow-stat")]
             local_flow_statistic: ServerFlowStatistic::new(),
             #[cfg(feature = "local-dns-relay")]
@@ -276,13 +286,45 @@ impl Context {
     }
 
     /// Perform a DNS resolution
+    ///
+    /// Records in `hosts` take precedence, aliases to names that are not in `hosts` are resolved by DNS
     pub async fn dns_resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let hosts_match = self.config.hosts.as_ref().and_then(|hosts| hosts.lookup(host));
+        let host = match hosts_match {
+            Some(HostsMatch { addrs: Some(ref addrs), .. }) => {
+                let mut addrs = addrs.iter().map(|ip| SocketAddr::new(*ip, port)).collect::<Vec<_>>();
+                // Keeps the same order as DNS resolver
+                addrs.sort_by_key(|addr| addr.is_ipv6() != self.config.ipv6_first);
+                return Ok(addrs);
+            }
+            Some(ref m) => m.canonical_name(host),
+            None => host,
+        };
+
+        // Local DNS of DNS relay is also used for resolving, if it is specified explicitly
         #[cfg(feature = "local-dns-relay")]
-        return self.local_dns().lookup_ip(host, port).await;
-        #[cfg(not(feature = "local-dns-relay"))]
+        {
+            if self.config.local_dns_addr.is_some() || self.config.local_dns_path.is_some() {
+                return self.local_dns().lookup_ip(host, port).await;
+            }
+        }
+
         resolve(self, host, port).await
     }
 
+    /// Load balancer of servers in configuration, shared by all local servers
+    pub(crate) fn balancer(&self) -> SharedBalancerService {
+        let mut balancer = self.balancer.lock();
+        balancer
+            .get_or_insert_with(|| BalancerService::new_shared(&self.config))
+            .clone()
+    }
+
+    /// Multiplexed connections to servers, only used if `mux` is configured
+    pub(crate) fn mux_pool(&self) -> &MuxPool {
+        &self.mux_pool
+    }
+
     /// Check if the server is still in running state
     pub fn server_running(&self) -> bool {
         self.server_running.load(Ordering::Acquire)
@@ -307,27 +349,35 @@ impl Context {
         ppbloom.check_and_set(nonce)
     }
 
+    /// ACL for server `svr_cfg`, its own ACL takes precedence over the global one
+    pub fn server_acl<'a>(&'a self, svr_cfg: &'a ServerConfig) -> Option<&'a AccessControl> {
+        match svr_cfg.acl() {
+            Some(acl) => Some(acl),
+            None => self.acl().as_deref(),
+        }
+    }
+
     /// Check client ACL (for server)
-    pub fn check_client_blocked(&self, addr: &SocketAddr) -> bool {
-        match self.acl() {
+    pub fn check_client_blocked(&self, svr_cfg: &ServerConfig, addr: &SocketAddr) -> bool {
+        match self.server_acl(svr_cfg) {
             None => false,
-            Some(ref a) => a.check_client_blocked(addr),
+            Some(a) => a.check_client_blocked(addr),
         }
     }
 
     /// Check outbound address ACL (for server)
-    pub fn check_outbound_blocked(&self, addr: &Address) -> bool {
-        match self.acl() {
+    pub fn check_outbound_blocked(&self, svr_cfg: &ServerConfig, addr: &Address) -> bool {
+        match self.server_acl(svr_cfg) {
             None => false,
-            Some(ref a) => a.check_outbound_blocked(addr),
+            Some(a) => a.check_outbound_blocked(addr),
         }
     }
 
     /// Check resolved outbound address ACL (for server)
-    pub fn check_resolved_outbound_blocked(&self, addr: &SocketAddr) -> bool {
-        match self.acl() {
+    pub fn check_resolved_outbound_blocked(&self, svr_cfg: &ServerConfig, addr: &SocketAddr) -> bool {
+        match self.server_acl(svr_cfg) {
             None => false,
-            Some(ref a) => a.check_resolved_outbound_blocked(addr),
+            Some(a) => a.check_resolved_outbound_blocked(addr),
         }
     }
 
@@ -358,7 +408,7 @@ impl Context {
         }
     }
 
-    pub fn acl(&self) -> &Option<AccessControl> {
+    pub fn acl(&self) -> &Option<Arc<AccessControl>> {
         &self.config.acl
     }
 
@@ -65,7 +65,7 @@ impl From<Error> for io::Error {
             #[cfg(feature = "openssl")]
             Error::OpenSSLError(err) => From::from(err),
             Error::IoError(err) => err,
-            Error::AeadDecryptFailed => io::Error::new(io::ErrorKind::Other, "AEAD decrypt error"),
+            Error::AeadDecryptFailed => io::Error::new(io::ErrorKind::InvalidData, "AEAD decrypt error"),
             Error::SodiumError => io::Error::new(io::ErrorKind::Other, "sodium error"),
         }
     }
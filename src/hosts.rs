@@ -0,0 +1,247 @@
+//! Static host records, overriding DNS resolution
+//!
+//! Records are loaded from `hosts` in configuration, or from files in `/etc/hosts` format.
+//! Besides of IP addresses, a name could also be an alias of another name, like a CNAME record.
+//! Names starting with `*.` match all their sub-domains.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Error, ErrorKind},
+    net::IpAddr,
+    path::Path,
+};
+
+use log::warn;
+
+/// Maximum length of an alias chain, for breaking loops
+const MAX_ALIAS_DEPTH: usize = 8;
+
+/// A record of a name
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum HostsEntry {
+    /// Resolves to these addresses
+    Addrs(Vec<IpAddr>),
+    /// Alias of another name
+    Alias(String),
+}
+
+/// Result of looking up a name in `Hosts`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HostsMatch {
+    /// Aliases that were followed, in order
+    pub aliases: Vec<String>,
+    /// Addresses of the last alias (or the name itself), `None` if it is not in `Hosts` and should be resolved by DNS
+    pub addrs: Option<Vec<IpAddr>>,
+}
+
+impl HostsMatch {
+    /// The name that `addrs` belongs to
+    pub fn canonical_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.aliases.last().map(AsRef::as_ref).unwrap_or(name)
+    }
+}
+
+/// Static host records
+#[derive(Debug, Clone, Default)]
+pub struct Hosts {
+    names: HashMap<String, HostsEntry>,
+    wildcards: HashMap<String, HostsEntry>,
+}
+
+impl Hosts {
+    /// Create an empty `Hosts`
+    pub fn new() -> Hosts {
+        Hosts::default()
+    }
+
+    /// Load records from a file in `/etc/hosts` format
+    ///
+    /// Each line is an IP address followed by names, addresses of the same name in different lines are merged.
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> io::Result<Hosts> {
+        let mut hosts = Hosts::new();
+        hosts.extend_from_file(path)?;
+        Ok(hosts)
+    }
+
+    /// Add records from a file in `/etc/hosts` format
+    pub fn extend_from_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let reader = BufReader::new(File::open(path)?);
+        for line in reader.lines() {
+            let line = line?;
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => &line[..],
+            };
+
+            let mut fields = line.split_whitespace();
+            let ip = match fields.next() {
+                Some(ip) => ip,
+                None => continue,
+            };
+            let ip = match ip.parse::<IpAddr>() {
+                Ok(ip) => ip,
+                Err(..) => {
+                    warn!("ignored invalid hosts line \"{}\"", line.trim());
+                    continue;
+                }
+            };
+
+            for name in fields {
+                self.add_addr(name, ip);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add an address to `name`, which replaces an alias
+    pub fn add_addr(&mut self, name: &str, addr: IpAddr) {
+        let (map, key) = self.map_mut(name);
+        match map.get_mut(&key) {
+            Some(HostsEntry::Addrs(ref mut addrs)) => {
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+            _ => {
+                map.insert(key, HostsEntry::Addrs(vec![addr]));
+            }
+        }
+    }
+
+    /// Set the record of `name`, `*.` prefixed names match all sub-domains
+    pub fn insert(&mut self, name: &str, entry: HostsEntry) {
+        let (map, key) = self.map_mut(name);
+        map.insert(key, entry);
+    }
+
+    /// Add all records in `other`, which take precedence over the existing ones
+    pub fn extend(&mut self, other: Hosts) {
+        self.names.extend(other.names);
+        self.wildcards.extend(other.wildcards);
+    }
+
+    fn map_mut(&mut self, name: &str) -> (&mut HashMap<String, HostsEntry>, String) {
+        let name = normalize_name(name);
+        match name.strip_prefix("*.").map(ToOwned::to_owned) {
+            Some(domain) => (&mut self.wildcards, domain),
+            None => (&mut self.names, name),
+        }
+    }
+
+    /// Check if there is no records
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.wildcards.is_empty()
+    }
+
+    /// Find the record of `name`, without following aliases
+    pub fn get(&self, name: &str) -> Option<&HostsEntry> {
+        let name = normalize_name(name);
+        if let Some(entry) = self.names.get(&name) {
+            return Some(entry);
+        }
+
+        // Longest wildcard wins, `*.b.c` is preferred over `*.c` for `a.b.c`
+        let mut domain = &name[..];
+        while let Some(pos) = domain.find('.') {
+            domain = &domain[pos + 1..];
+            if let Some(entry) = self.wildcards.get(domain) {
+                return Some(entry);
+            }
+        }
+        None
+    }
+
+    /// Look up `name`, following aliases
+    pub fn lookup(&self, name: &str) -> Option<HostsMatch> {
+        let mut entry = self.get(name)?;
+        let mut aliases = Vec::new();
+
+        loop {
+            match *entry {
+                HostsEntry::Addrs(ref addrs) => {
+                    return Some(HostsMatch {
+                        aliases,
+                        addrs: Some(addrs.clone()),
+                    });
+                }
+                HostsEntry::Alias(ref target) => {
+                    if aliases.len() >= MAX_ALIAS_DEPTH {
+                        warn!("hosts alias chain of \"{}\" is too long", name);
+                        return None;
+                    }
+
+                    aliases.push(target.clone());
+                    entry = match self.get(target) {
+                        Some(e) => e,
+                        None => return Some(HostsMatch { aliases, addrs: None }),
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Parse values of a name in configuration, a list of IP addresses or a name for alias
+pub fn parse_entry(values: &[String]) -> io::Result<HostsEntry> {
+    let mut addrs = Vec::with_capacity(values.len());
+    for value in values {
+        match value.parse::<IpAddr>() {
+            Ok(ip) => addrs.push(ip),
+            Err(..) if values.len() == 1 && !value.is_empty() => return Ok(HostsEntry::Alias(normalize_name(value))),
+            Err(..) => return Err(Error::new(ErrorKind::InvalidData, format!("invalid hosts value \"{}\"", value))),
+        }
+    }
+
+    if addrs.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "empty hosts value"));
+    }
+    Ok(HostsEntry::Addrs(addrs))
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lookup_hosts() {
+        let mut hosts = Hosts::new();
+        hosts.add_addr("Internal.Example.com", "10.0.0.1".parse().unwrap());
+        hosts.add_addr("internal.example.com.", "fd00::1".parse().unwrap());
+        hosts.insert("*.tracker.example", HostsEntry::Addrs(vec!["0.0.0.0".parse().unwrap()]));
+        hosts.insert("*.cdn.tracker.example", HostsEntry::Alias("internal.example.com".to_owned()));
+        hosts.insert("api.example.com", parse_entry(&["internal.example.com".to_owned()]).unwrap());
+        hosts.insert("www.example.com", HostsEntry::Alias("example.net".to_owned()));
+        hosts.insert("loop1.example.com", HostsEntry::Alias("loop2.example.com".to_owned()));
+        hosts.insert("loop2.example.com", HostsEntry::Alias("loop1.example.com".to_owned()));
+
+        let m = hosts.lookup("internal.example.com").unwrap();
+        assert!(m.aliases.is_empty());
+        assert_eq!(m.addrs.unwrap().len(), 2);
+
+        let m = hosts.lookup("a.b.tracker.example").unwrap();
+        assert_eq!(m.addrs, Some(vec!["0.0.0.0".parse().unwrap()]));
+        assert!(hosts.lookup("tracker.example").is_none());
+
+        let m = hosts.lookup("x.cdn.tracker.example").unwrap();
+        assert_eq!(m.aliases, vec!["internal.example.com".to_owned()]);
+        assert_eq!(m.canonical_name("x.cdn.tracker.example"), "internal.example.com");
+
+        let m = hosts.lookup("API.example.com").unwrap();
+        assert_eq!(m.addrs.unwrap().len(), 2);
+
+        let m = hosts.lookup("www.example.com").unwrap();
+        assert_eq!(m.aliases, vec!["example.net".to_owned()]);
+        assert_eq!(m.addrs, None);
+
+        assert!(hosts.lookup("loop1.example.com").is_none());
+        assert!(hosts.lookup("example.com").is_none());
+
+        assert!(parse_entry(&["10.0.0.1".to_owned(), "example.com".to_owned()]).is_err());
+        assert!(parse_entry(&[]).is_err());
+    }
+}
@@ -86,6 +86,7 @@ pub use self::{
     relay::{
         local::run as run_local,
         manager::run as run_manager,
+        probe::run as run_probe,
         server::run as run_server,
         tcprelay::client::Socks5Client,
     },
@@ -95,6 +96,7 @@ pub mod acl;
 pub mod config;
 pub mod context;
 pub mod crypto;
+pub mod hosts;
 pub mod plugin;
 pub mod relay;
 
@@ -0,0 +1,216 @@
+//! Control socket of local server
+//!
+//! Service for managing remote servers of a running local server, in the same protocol style as manager.
+//! Each request is a datagram of `command` or `command: JSON`
+//!
+//! - `add: {"address": "1.2.3.4", "port": 8388, "password": "...", "method": "aes-256-gcm"}`, same as items of `servers`
+//! - `remove: {"server": "1.2.3.4:8388"}`
+//! - `enable: {"server": "1.2.3.4:8388"}`
+//! - `disable: {"server": "1.2.3.4:8388"}`
+//! - `list`, status and scores of all servers
+
+use std::{
+    io::{self, Error, ErrorKind},
+    str,
+};
+
+use byte_string::ByteStr;
+use log::{error, info, trace};
+
+use crate::{
+    config::{ServerAddr, ServerConfig},
+    context::SharedContext,
+    relay::{
+        loadbalancing::server::{ServerStatisticStatus, ServerStatus},
+        manager::ManagerDatagram,
+        udprelay::MAXIMUM_UDP_PAYLOAD_SIZE,
+    },
+};
+
+mod protocol {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Debug)]
+    pub struct ServerRequest {
+        pub server: String,
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct ServerScore {
+        pub score: u64,
+        pub rtt: u64,
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct ServerStatus {
+        pub server: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub remarks: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub id: Option<String>,
+        pub enabled: bool,
+        pub ejected: bool,
+        pub connections: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub tcp: Option<ServerScore>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub udp: Option<ServerScore>,
+    }
+}
+
+struct ControlService {
+    socket: ManagerDatagram,
+    context: SharedContext,
+}
+
+impl ControlService {
+    async fn serve(&mut self) -> io::Result<()> {
+        let mut buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+
+        loop {
+            let (recv_len, src_addr) = self.socket.recv_from(&mut buf).await?;
+            let resp_pkt = self.handle_packet(&buf[..recv_len]);
+
+            if src_addr.is_unnamed() {
+                trace!(
+                    "received a packet ({} bytes) from an unnamed unix-socket client, \
+                     unsound because we are unable to send response back to it",
+                    recv_len
+                );
+                continue;
+            }
+
+            if let Err(err) = self.socket.send_to(&resp_pkt, &src_addr).await {
+                error!("response send_to failed, destination: {:?}, error: {}", src_addr, err);
+            }
+        }
+    }
+
+    fn handle_packet(&self, pkt: &[u8]) -> Vec<u8> {
+        trace!("CONTROL REQUEST: {:?}", ByteStr::new(pkt));
+
+        let pkt = match str::from_utf8(pkt) {
+            Ok(p) => p,
+            Err(..) => {
+                error!("received non-UTF8 encoded packet: {:?}", ByteStr::new(pkt));
+                return b"invalid encoding".to_vec();
+            }
+        };
+
+        let (action, param) = match pkt.find(':') {
+            None => (pkt.trim(), ""),
+            Some(idx) => {
+                let (action, param) = pkt.split_at(idx);
+                (action.trim(), param[1..].trim())
+            }
+        };
+
+        match self.dispatch_command(action, param) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("failed to handle control action \"{}\", error: {}", action, err);
+                Vec::from(err.to_string())
+            }
+        }
+    }
+
+    fn dispatch_command(&self, action: &str, param: &str) -> io::Result<Vec<u8>> {
+        let balancer = self.context.balancer();
+
+        match action {
+            "add" => {
+                let svr_cfg = match ServerConfig::load_from_str(param) {
+                    Ok(s) => s,
+                    Err(err) => {
+                        let err = Error::new(ErrorKind::InvalidData, err.to_string());
+                        return Err(err);
+                    }
+                };
+
+                // Plugins are launched only when starting
+                if svr_cfg.plugin().is_some() {
+                    let err = Error::new(ErrorKind::Other, "plugin is not supported for servers added at runtime");
+                    return Err(err);
+                }
+
+                balancer.add_server(&self.context, svr_cfg.clone())?;
+
+                info!("added server {} via control socket", svr_cfg);
+                Ok(b"ok\n".to_vec())
+            }
+            "remove" => {
+                let addr = parse_server_request(param)?;
+                balancer.remove_server(&addr)?;
+                Ok(b"ok\n".to_vec())
+            }
+            "enable" | "disable" => {
+                let addr = parse_server_request(param)?;
+                balancer.set_server_enabled(&addr, action == "enable")?;
+                Ok(b"ok\n".to_vec())
+            }
+            "list" => {
+                let status = server_status(balancer.server_status());
+                let mut buf = serde_json::to_vec(&status).expect("convert server status into JSON");
+                buf.push(b'\n');
+                Ok(buf)
+            }
+            _ => {
+                let err = Error::new(ErrorKind::InvalidData, format!("unrecognized command \"{}\"", action));
+                Err(err)
+            }
+        }
+    }
+}
+
+fn parse_server_request(param: &str) -> io::Result<ServerAddr> {
+    let req: protocol::ServerRequest = match serde_json::from_str(param) {
+        Ok(p) => p,
+        Err(err) => {
+            let err = Error::new(ErrorKind::InvalidData, err);
+            return Err(err);
+        }
+    };
+
+    match req.server.parse::<ServerAddr>() {
+        Ok(addr) => Ok(addr),
+        Err(..) => {
+            let err = Error::new(ErrorKind::InvalidData, format!("invalid server address \"{}\"", req.server));
+            Err(err)
+        }
+    }
+}
+
+/// Status of servers in the protocol's format, ejected if it is ejected for either TCP or UDP
+fn server_status(status: Vec<ServerStatus>) -> Vec<protocol::ServerStatus> {
+    fn score_of(status: &Option<ServerStatisticStatus>) -> Option<protocol::ServerScore> {
+        status.as_ref().map(|s| protocol::ServerScore {
+            score: s.score,
+            rtt: s.rtt,
+        })
+    }
+
+    status
+        .into_iter()
+        .map(|status| protocol::ServerStatus {
+            server: status.addr.to_string(),
+            ejected: status.tcp.iter().chain(&status.udp).any(|s| s.ejected),
+            tcp: score_of(&status.tcp),
+            udp: score_of(&status.udp),
+            remarks: status.remarks,
+            id: status.id,
+            enabled: status.enabled,
+            connections: status.connections,
+        })
+        .collect()
+}
+
+/// Run control socket for managing remote servers of local server at runtime
+pub async fn run(context: SharedContext) -> io::Result<()> {
+    let bind_addr = context.config().control_addr.as_ref().expect("control address");
+    let socket = ManagerDatagram::bind(bind_addr, &context).await?;
+
+    info!("shadowsocks control socket listening on {}", bind_addr);
+
+    let mut service = ControlService { socket, context };
+    service.serve().await
+}
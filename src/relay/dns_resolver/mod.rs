@@ -47,13 +47,13 @@ macro_rules! lookup_then {
 /// Helper macro for resolving host and then process each addresses
 #[macro_export]
 macro_rules! lookup_outbound_then {
-    ($context:expr, $addr:expr, $port:expr, |$resolved_addr:ident| $body:block) => {{
+    ($context:expr, $svr_cfg:expr, $addr:expr, $port:expr, |$resolved_addr:ident| $body:block) => {{
         use std::io::{Error, ErrorKind};
 
         let mut result = None;
 
         for $resolved_addr in $context.dns_resolve($addr, $port).await? {
-            if $context.check_resolved_outbound_blocked(&$resolved_addr) {
+            if $context.check_resolved_outbound_blocked($svr_cfg, &$resolved_addr) {
                 let err = Error::new(
                     ErrorKind::Other,
                     format!(
@@ -0,0 +1,215 @@
+//! Cache for DNS relay's responses
+
+use std::time::Instant;
+
+use lru_time_cache::LruCache;
+use spin::Mutex;
+use trust_dns_proto::{
+    op::{response_code::ResponseCode, Message, Query},
+    rr::{RData, Record},
+};
+
+use super::ecs::ClientSubnet;
+
+/// Default number of cached responses
+pub const DEFAULT_CACHE_SIZE: usize = 1024;
+
+/// TTL will be capped to 1 day, as most of the resolvers do
+const MAX_TTL: u32 = 24 * 60 * 60;
+
+/// Entries will be prefetched if the remaining TTL is less than 1/N of the original TTL
+const PREFETCH_RATIO: u32 = 10;
+
+/// Entries that were hit more than this times are considered as popular, which are worth prefetching
+const PREFETCH_MIN_HITS: u32 = 2;
+
+// lru_time_cache requires `Ord` keys, so name, type and class are stored in plain forms
+type CacheKey = (String, u16, u16, Option<ClientSubnet>);
+
+struct CacheEntry {
+    message: Message,
+    forward: bool,
+    inserted: Instant,
+    ttl: u32,
+    hits: u32,
+    prefetching: bool,
+}
+
+/// A cached response
+pub struct CachedResponse {
+    /// Response with TTLs that are decreased by the time it has been cached
+    pub message: Message,
+    /// Whether the response was resolved by remote upstream
+    pub forward: bool,
+    /// This entry should be refreshed in background
+    pub prefetch: bool,
+}
+
+/// Bounded LRU cache for responses, keyed by (name, type, class) of queries, and client's subnet if it is sent to upstreams
+///
+/// Positive responses are cached by the minimum TTL of answers, negative responses (`NXDOMAIN` or `NODATA`)
+/// are cached by the `MINIMUM` field of the SOA record in authority section (RFC 2308).
+pub struct DnsCache {
+    cache: Mutex<LruCache<CacheKey, CacheEntry>>,
+    prefetch: bool,
+}
+
+impl DnsCache {
+    /// Create a cache with `capacity` entries
+    pub fn new(capacity: usize, prefetch: bool) -> DnsCache {
+        DnsCache {
+            cache: Mutex::new(LruCache::with_capacity(capacity)),
+            prefetch,
+        }
+    }
+
+    fn cache_key(query: &Query, client_subnet: Option<ClientSubnet>) -> CacheKey {
+        (
+            query.name().to_lowercase().to_ascii(),
+            u16::from(query.query_type()),
+            u16::from(query.query_class()),
+            client_subnet,
+        )
+    }
+
+    /// Get a cached response for `query`
+    pub fn get(&self, query: &Query, client_subnet: Option<ClientSubnet>) -> Option<CachedResponse> {
+        let key = DnsCache::cache_key(query, client_subnet);
+
+        let mut cache = self.cache.lock();
+
+        let (elapsed, expired) = match cache.get_mut(&key) {
+            None => return None,
+            Some(entry) => {
+                let elapsed = entry.inserted.elapsed().as_secs().min(u64::from(u32::max_value())) as u32;
+                (elapsed, elapsed >= entry.ttl)
+            }
+        };
+
+        if expired {
+            cache.remove(&key);
+            return None;
+        }
+
+        let entry = cache.get_mut(&key).expect("cached entry");
+        entry.hits += 1;
+
+        let remaining = entry.ttl - elapsed;
+        let prefetch = self.prefetch
+            && !entry.prefetching
+            && entry.hits >= PREFETCH_MIN_HITS
+            && remaining * PREFETCH_RATIO <= entry.ttl;
+        if prefetch {
+            entry.prefetching = true;
+        }
+
+        let mut message = entry.message.clone();
+        decrease_ttl(&mut message, elapsed);
+
+        Some(CachedResponse {
+            message,
+            forward: entry.forward,
+            prefetch,
+        })
+    }
+
+    /// Cache response of `query`, responses that are not cacheable will be ignored
+    pub fn insert(&self, query: &Query, client_subnet: Option<ClientSubnet>, message: &Message, forward: bool) {
+        let ttl = match cache_ttl(message) {
+            Some(ttl) if ttl > 0 => ttl.min(MAX_TTL),
+            _ => return,
+        };
+
+        let entry = CacheEntry {
+            message: message.clone(),
+            forward,
+            inserted: Instant::now(),
+            ttl,
+            hits: 0,
+            prefetching: false,
+        };
+
+        self.cache.lock().insert(DnsCache::cache_key(query, client_subnet), entry);
+    }
+
+    /// Prefetching failed, allows it to be prefetched again
+    pub fn prefetch_failed(&self, query: &Query, client_subnet: Option<ClientSubnet>) {
+        if let Some(entry) = self.cache.lock().get_mut(&DnsCache::cache_key(query, client_subnet)) {
+            entry.prefetching = false;
+        }
+    }
+}
+
+/// TTL for caching `message`, `None` if it shouldn't be cached
+fn cache_ttl(message: &Message) -> Option<u32> {
+    if message.truncated() {
+        return None;
+    }
+
+    match message.response_code() {
+        ResponseCode::NoError if !message.answers().is_empty() => message.answers().iter().map(Record::ttl).min(),
+        ResponseCode::NoError | ResponseCode::NXDomain => message.name_servers().iter().find_map(|rec| match rec.rdata() {
+            RData::SOA(ref soa) => Some(rec.ttl().min(soa.minimum())),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+fn decrease_ttl(message: &mut Message, elapsed: u32) {
+    fn decrease(records: Vec<Record>, elapsed: u32) -> Vec<Record> {
+        records
+            .into_iter()
+            .map(|mut rec| {
+                let ttl = rec.ttl().saturating_sub(elapsed);
+                rec.set_ttl(ttl);
+                rec
+            })
+            .collect()
+    }
+
+    let answers = decrease(message.take_answers(), elapsed);
+    message.insert_answers(answers);
+    let name_servers = decrease(message.take_name_servers(), elapsed);
+    message.insert_name_servers(name_servers);
+    let additionals = decrease(message.take_additionals(), elapsed);
+    message.insert_additionals(additionals);
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::Ipv4Addr, str::FromStr};
+
+    use trust_dns_proto::rr::{rdata::SOA, Name, RecordType};
+
+    use super::*;
+
+    #[test]
+    fn cache_positive_and_negative() {
+        let name = Name::from_str("example.com.").unwrap();
+        let query = Query::query(name.clone(), RecordType::A);
+
+        let mut positive = Message::new();
+        positive.add_answer(Record::from_rdata(name.clone(), 300, RData::A(Ipv4Addr::new(1, 1, 1, 1))));
+        positive.add_answer(Record::from_rdata(name.clone(), 60, RData::A(Ipv4Addr::new(1, 0, 0, 1))));
+        assert_eq!(cache_ttl(&positive), Some(60));
+
+        let mut negative = Message::new();
+        negative.set_response_code(ResponseCode::NXDomain);
+        let soa = SOA::new(name.clone(), name.clone(), 1, 3600, 600, 86400, 30);
+        negative.add_name_server(Record::from_rdata(name.clone(), 900, RData::SOA(soa)));
+        assert_eq!(cache_ttl(&negative), Some(30));
+
+        let mut failure = Message::new();
+        failure.set_response_code(ResponseCode::ServFail);
+        assert_eq!(cache_ttl(&failure), None);
+
+        let cache = DnsCache::new(16, false);
+        cache.insert(&query, None, &positive, true);
+        let cached = cache.get(&Query::query(Name::from_str("EXAMPLE.com.").unwrap(), RecordType::A), None).unwrap();
+        assert!(cached.forward);
+        assert_eq!(cached.message.answers().len(), 2);
+        assert!(cache.get(&Query::query(name, RecordType::AAAA), None).is_none());
+        assert!(cache.get(&query, Some(ClientSubnet::zero())).is_none());
+    }
+}
@@ -0,0 +1,200 @@
+//! EDNS Client Subnet (RFC 7871) for queries sent by DNS relay
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use trust_dns_proto::{
+    op::{Edns, Message},
+    rr::rdata::opt::{EdnsCode, EdnsOption},
+};
+
+use crate::config::DnsClientSubnet;
+
+/// Longest prefix of IPv4 addresses that will be sent, as recommended by RFC 7871
+const MAX_IPV4_PREFIX: u8 = 24;
+
+/// Longest prefix of IPv6 addresses that will be sent, as recommended by RFC 7871
+const MAX_IPV6_PREFIX: u8 = 56;
+
+/// A subnet carried in ECS option, bits beyond the prefix are always zero
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub struct ClientSubnet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl ClientSubnet {
+    /// Create a subnet with `addr` truncated to `prefix` bits
+    pub fn new(addr: IpAddr, prefix: u8) -> ClientSubnet {
+        let addr = match addr {
+            IpAddr::V4(addr) => {
+                let prefix = prefix.min(32);
+                let mask = if prefix == 0 { 0 } else { !0u32 << (32 - prefix) };
+                IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+            }
+            IpAddr::V6(addr) => {
+                let prefix = prefix.min(128);
+                let mask = if prefix == 0 { 0 } else { !0u128 << (128 - prefix) };
+                IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+            }
+        };
+        ClientSubnet { addr, prefix }
+    }
+
+    /// Subnet with source prefix 0, which asks resolvers not to use any address for the query
+    pub fn zero() -> ClientSubnet {
+        ClientSubnet::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+    }
+
+    /// Subnet of a client's address, `None` if it is not globally routable
+    pub fn from_client_addr(addr: &SocketAddr) -> Option<ClientSubnet> {
+        let ip = match addr.ip() {
+            IpAddr::V6(ip) => match ip.to_ipv4() {
+                // IPv4-mapped addresses from dual stack sockets
+                Some(v4) if ip.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => IpAddr::V4(v4),
+                _ => IpAddr::V6(ip),
+            },
+            ip => ip,
+        };
+
+        let global = match ip {
+            IpAddr::V4(ip) => {
+                !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast())
+            }
+            IpAddr::V6(ip) => {
+                let first = ip.segments()[0];
+                // fc00::/7 unique local and fe80::/10 link local
+                !(ip.is_loopback() || ip.is_unspecified() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+            }
+        };
+
+        if global {
+            Some(ClientSubnet::new(ip, default_prefix(&ip)))
+        } else {
+            None
+        }
+    }
+
+    /// Subnet in ECS option of `message`, prefixes longer than /24 (IPv4) or /56 (IPv6) will be shortened
+    pub fn from_message(message: &Message) -> Option<ClientSubnet> {
+        let data = match message.edns()?.option(EdnsCode::Subnet)? {
+            EdnsOption::Unknown(_, ref data) => data,
+            #[allow(unreachable_patterns)]
+            _ => return None,
+        };
+
+        if data.len() < 4 {
+            return None;
+        }
+
+        let family = u16::from(data[0]) << 8 | u16::from(data[1]);
+        let prefix = data[2];
+        let addr_bytes = &data[4..];
+        if addr_bytes.len() != address_len(prefix) {
+            return None;
+        }
+
+        let addr = match family {
+            1 if prefix <= 32 => {
+                let mut octets = [0u8; 4];
+                octets[..addr_bytes.len()].copy_from_slice(addr_bytes);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            2 if prefix <= 128 => {
+                let mut octets = [0u8; 16];
+                octets[..addr_bytes.len()].copy_from_slice(addr_bytes);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+
+        Some(ClientSubnet::new(addr, prefix.min(default_prefix(&addr))))
+    }
+
+    /// Encode as an ECS option of a query
+    pub fn to_option(self) -> EdnsOption {
+        let (family, octets) = match self.addr {
+            IpAddr::V4(addr) => (1u16, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (2u16, addr.octets().to_vec()),
+        };
+
+        let mut data = Vec::with_capacity(4 + octets.len());
+        data.push((family >> 8) as u8);
+        data.push(family as u8);
+        data.push(self.prefix);
+        // SCOPE PREFIX-LENGTH must be 0 in queries
+        data.push(0);
+        data.extend_from_slice(&octets[..address_len(self.prefix)]);
+
+        EdnsOption::Unknown(u16::from(EdnsCode::Subnet), data)
+    }
+
+    /// Set the ECS option of `edns`
+    pub fn apply(self, edns: &mut Edns) {
+        edns.set_option(self.to_option());
+    }
+}
+
+/// Number of bytes for the address in option, bits beyond the prefix are not sent
+fn address_len(prefix: u8) -> usize {
+    (usize::from(prefix) + 7) >> 3
+}
+
+fn default_prefix(addr: &IpAddr) -> u8 {
+    match *addr {
+        IpAddr::V4(..) => MAX_IPV4_PREFIX,
+        IpAddr::V6(..) => MAX_IPV6_PREFIX,
+    }
+}
+
+/// Subnet that should be sent to an upstream configured with `policy`, for a client with `client_subnet`
+pub fn upstream_subnet(policy: Option<DnsClientSubnet>, client_subnet: Option<ClientSubnet>) -> Option<ClientSubnet> {
+    match policy? {
+        DnsClientSubnet::Strip => Some(ClientSubnet::zero()),
+        DnsClientSubnet::Client => client_subnet,
+        DnsClientSubnet::Subnet(addr, prefix) => Some(ClientSubnet::new(addr, prefix)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn client_subnet_option() {
+        let subnet = ClientSubnet::new("203.0.113.77".parse().unwrap(), 24);
+        assert_eq!(
+            subnet.to_option(),
+            EdnsOption::Unknown(8, vec![0, 1, 24, 0, 203, 0, 113])
+        );
+
+        let subnet = ClientSubnet::new("2001:db8:1234:5678::1".parse().unwrap(), 56);
+        assert_eq!(
+            subnet.to_option(),
+            EdnsOption::Unknown(8, vec![0, 2, 56, 0, 0x20, 0x01, 0x0d, 0xb8, 0x12, 0x34, 0x56])
+        );
+
+        assert_eq!(ClientSubnet::zero().to_option(), EdnsOption::Unknown(8, vec![0, 1, 0, 0]));
+
+        let mut message = Message::new();
+        let mut edns = Edns::new();
+        ClientSubnet::new("198.51.100.1".parse().unwrap(), 32).apply(&mut edns);
+        message.set_edns(edns);
+        let message = Message::from_vec(&message.to_vec().unwrap()).unwrap();
+        assert_eq!(
+            ClientSubnet::from_message(&message),
+            Some(ClientSubnet::new("198.51.100.0".parse().unwrap(), 24))
+        );
+
+        assert_eq!(ClientSubnet::from_client_addr(&"127.0.0.1:53".parse().unwrap()), None);
+        assert_eq!(ClientSubnet::from_client_addr(&"[fd00::1]:53".parse().unwrap()), None);
+        assert_eq!(
+            ClientSubnet::from_client_addr(&"[::ffff:198.51.100.1]:53".parse().unwrap()),
+            Some(ClientSubnet::new("198.51.100.0".parse().unwrap(), 24))
+        );
+
+        let client = ClientSubnet::from_client_addr(&"198.51.100.1:53".parse().unwrap());
+        assert_eq!(upstream_subnet(None, client), None);
+        assert_eq!(upstream_subnet(Some(DnsClientSubnet::Client), client), client);
+        assert_eq!(upstream_subnet(Some(DnsClientSubnet::Strip), client), Some(ClientSubnet::zero()));
+    }
+}
@@ -2,28 +2,46 @@ use std::{
     io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use tokio::sync::mpsc;
+use byteorder::{BigEndian, ByteOrder};
+use futures::{future, FutureExt};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    sync::mpsc,
+};
 
 use log::{debug, error, info, warn};
 use trust_dns_proto::{
-    op::{header::MessageType, response_code::ResponseCode, Message, Query},
-    rr::{DNSClass, Name, RData, RecordType},
+    op::{header::MessageType, response_code::ResponseCode, Edns, Message, Query},
+    rr::{DNSClass, Name, RData, Record, RecordType},
 };
 
 use crate::{
     acl::AccessControl,
-    config::ConfigType,
-    context::SharedContext,
+    config::{ConfigType, DnsClientSubnet},
+    context::{Context, SharedContext},
     relay::{
         loadbalancing::server::{PlainPingBalancer, ServerType},
+        socks5::Address,
         sys::create_udp_socket,
         utils::try_timeout,
     },
 };
 
+use self::{
+    cache::{DnsCache, DEFAULT_CACHE_SIZE},
+    ecs::ClientSubnet,
+    poison::PoisonDetector,
+};
+
+mod cache;
+mod ecs;
+mod poison;
+#[cfg(any(feature = "dns-over-tls", feature = "dns-over-https"))]
+mod tls;
 pub mod upstream;
 
 fn should_forward_by_ptr_name(acl: &AccessControl, name: &Name) -> bool {
@@ -72,7 +90,7 @@ fn check_name_in_proxy_list(acl: &AccessControl, name: &Name) -> Option<bool> {
 }
 
 /// given the query, determine whether remote/local query should be used, or inconclusive
-fn should_forward_by_query(acl: &Option<AccessControl>, query: &Query) -> Option<bool> {
+fn should_forward_by_query(acl: &Option<Arc<AccessControl>>, query: &Query) -> Option<bool> {
     if let Some(acl) = acl {
         if query.query_class() != DNSClass::IN {
             // unconditionally use default for all non-IN queries
@@ -99,12 +117,22 @@ fn should_forward_by_query(acl: &Option<AccessControl>, query: &Query) -> Option
 }
 
 /// given the local response, determine whether remote response should be used instead
+///
+/// `rtt` is the time that local response took, responses that are suspected poisoned are never used.
 fn should_forward_by_response(
-    acl: &Option<AccessControl>,
+    acl: &Option<Arc<AccessControl>>,
+    poison: &PoisonDetector,
     local_response: &io::Result<Message>,
+    rtt: Duration,
     query: &Query,
 ) -> bool {
     if let Some(acl) = acl {
+        if let Ok(ref local_response) = local_response {
+            if poison.is_poisoned(query, local_response, rtt) {
+                return true;
+            }
+        }
+
         macro_rules! examine_record {
             ($rec:ident, $is_answer:expr) => {
                 if let RData::CNAME(ref name) = $rec.rdata() {
@@ -142,10 +170,13 @@ fn should_forward_by_response(
 }
 
 async fn acl_lookup<Remote>(
-    acl: &Option<AccessControl>,
+    acl: &Option<Arc<AccessControl>>,
     local: &upstream::LocalUpstream,
     remote: Arc<Remote>,
-    query: &Query
+    poison: &PoisonDetector,
+    query: &Query,
+    local_ecs: Option<ClientSubnet>,
+    remote_ecs: Option<ClientSubnet>
 ) -> (io::Result<Message>, bool)
     where
         Remote: upstream::Upstream,
@@ -156,8 +187,8 @@ async fn acl_lookup<Remote>(
         query.query_type(), query.name(), local, remote
     );
 
-    let remote_response_fut = try_timeout(remote.lookup(query), Some(Duration::new(3, 0)));
-    let local_response_fut = try_timeout(local.lookup(query), Some(Duration::new(3, 0)));
+    let remote_response_fut = try_timeout(remote.lookup(query, remote_ecs), Some(Duration::new(3, 0)));
+    let local_response_fut = try_timeout(local.lookup(query, local_ecs), Some(Duration::new(3, 0)));
 
     match should_forward_by_query(acl, query) {
         Some(true) => {
@@ -174,8 +205,9 @@ async fn acl_lookup<Remote>(
     }
 
     let decider = async {
+        let start = Instant::now();
         let local_response = local_response_fut.await;
-        if should_forward_by_response(acl, &local_response, query) {
+        if should_forward_by_response(acl, poison, &local_response, start.elapsed(), query) {
             None
         } else {
             Some(local_response)
@@ -210,7 +242,24 @@ async fn acl_lookup<Remote>(
     }
 }
 
+/// Maximum UDP payload size that DNS relay supports, advertised in EDNS0 OPT records
+///
+/// Responses larger than this (or than the size advertised by clients) will be truncated,
+/// clients should retry with TCP.
+pub const EDNS_MAX_PAYLOAD: u16 = 4096;
+
+/// TTL of answers from `hosts`
+const HOSTS_TTL: u32 = 60;
+
+/// Maximum size of a UDP datagram
+const MAXIMUM_UDP_PACKET_SIZE: usize = 65536;
+
+/// Idle timeout of TCP connections from clients
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Start a DNS relay local server
+///
+/// It listens on both UDP and TCP with the same address.
 pub async fn run(context: SharedContext) -> io::Result<()> {
     let local_addr = match context.config().config_type {
         ConfigType::DnsLocal => {
@@ -230,9 +279,138 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
     let socket = create_udp_socket(&bind_addr).await?;
 
+    // Listens on the same port with UDP, in case of binding to port 0
     let actual_local_addr = socket.local_addr()?;
-    info!("shadowsocks DNS relay listening on {}", actual_local_addr);
+    let listener = TcpListener::bind(&actual_local_addr).await?;
+
+    info!("shadowsocks DNS relay listening on {} (TCP and UDP)", actual_local_addr);
+
+    let config = context.config();
+    let servers = match config.remote_dns_addr {
+        Some(ref servers) => servers.clone(),
+        None => {
+            let err = io::Error::new(io::ErrorKind::Other, "missing remote DNS servers for DNS relay");
+            return Err(err);
+        }
+    };
+
+    // Queries are cached and shared by clients, servers are picked only by name servers
+    let balancer = PlainPingBalancer::new(context.clone(), ServerType::Tcp).await;
+    let tcp_upstream = upstream::ProxyTcpUpstream {
+        context: context.clone(),
+        svr_cfg: move |ns: &Address| balancer.pick_server(None, ns).server_config().clone(),
+        servers,
+    };
+
+    if config.remote_dns_mode.enable_udp() {
+        let balancer = PlainPingBalancer::new(context.clone(), ServerType::Udp).await;
+        let udp_upstream = upstream::ProxyUdpUpstream {
+            context: context.clone(),
+            svr_cfg: move |ns: &Address| balancer.pick_server(None, ns).server_config().clone(),
+            tcp_upstream,
+            fallback_on_error: config.remote_dns_mode.enable_tcp(),
+        };
+        serve(context, socket, listener, udp_upstream).await
+    } else {
+        serve(context, socket, listener, tcp_upstream).await
+    }
+}
+
+/// Serves clients with `remote_upstream` for queries that should be resolved remotely
+async fn serve<Remote>(
+    context: SharedContext,
+    socket: UdpSocket,
+    listener: TcpListener,
+    remote_upstream: Remote
+) -> io::Result<()>
+    where
+        Remote: upstream::Upstream + Send + Sync + 'static,
+{
+    let config = context.config();
+
+    let cache = match config.dns_cache_size.unwrap_or(DEFAULT_CACHE_SIZE) {
+        0 => None,
+        size => Some(DnsCache::new(size, config.dns_cache_prefetch)),
+    };
+
+    let relay = Arc::new(DnsRelay {
+        context: context.clone(),
+        remote_upstream: Arc::new(remote_upstream),
+        cache,
+        poison: PoisonDetector::new(config),
+    });
+
+    #[allow(unused_mut)]
+    let mut vfut = vec![run_udp(relay.clone(), socket).boxed(), run_tcp(relay.clone(), listener).boxed()];
+
+    if let Some(ref tls_addr) = config.dns_tls_local_addr {
+        #[cfg(feature = "dns-over-tls")]
+        {
+            let acceptor = tls::create_acceptor(config, &[])?;
+            let listener = TcpListener::bind(&tls_addr.bind_addr(&context).await?).await?;
+            info!("shadowsocks DNS relay listening on {} (TLS)", listener.local_addr()?);
+            vfut.push(tls::run_tls(relay.clone(), listener, acceptor).boxed());
+        }
+
+        #[cfg(not(feature = "dns-over-tls"))]
+        {
+            let err = io::Error::new(
+                io::ErrorKind::Other,
+                format!("DNS relay on {} with TLS is not supported, consider enable it by feature \"dns-over-tls\"", tls_addr),
+            );
+            return Err(err);
+        }
+    }
+
+    if let Some(ref https_addr) = config.dns_https_local_addr {
+        #[cfg(feature = "dns-over-https")]
+        {
+            let acceptor = tls::create_acceptor(config, &[b"h2".to_vec(), b"http/1.1".to_vec()])?;
+            let listener = TcpListener::bind(&https_addr.bind_addr(&context).await?).await?;
+            info!("shadowsocks DNS relay listening on {} (HTTPS)", listener.local_addr()?);
+            vfut.push(tls::run_https(relay.clone(), listener, acceptor).boxed());
+        }
+
+        #[cfg(not(feature = "dns-over-https"))]
+        {
+            let err = io::Error::new(
+                io::ErrorKind::Other,
+                format!("DNS relay on {} with HTTPS is not supported, consider enable it by feature \"dns-over-https\"", https_addr),
+            );
+            return Err(err);
+        }
+    }
+
+    let (res, ..) = future::select_all(vfut).await;
+    res
+}
+
+/// Shared states of a DNS relay server
+struct DnsRelay<Remote> {
+    context: SharedContext,
+    remote_upstream: Arc<Remote>,
+    cache: Option<DnsCache>,
+    poison: PoisonDetector,
+}
+
+impl<Remote> DnsRelay<Remote> {
+    /// Subnet of the client, if any of the upstreams is configured to send it
+    ///
+    /// Client's own ECS option is preferred, it is also a key of cached responses.
+    fn client_subnet(&self, request: &Message, src: &SocketAddr) -> Option<ClientSubnet> {
+        let config = self.context.config();
+        if ![config.local_dns_ecs, config.remote_dns_ecs].contains(&Some(DnsClientSubnet::Client)) {
+            return None;
+        }
+
+        ClientSubnet::from_message(request).or_else(|| ClientSubnet::from_client_addr(src))
+    }
+}
 
+async fn run_udp<Remote>(relay: Arc<DnsRelay<Remote>>, socket: UdpSocket) -> io::Result<()>
+    where
+        Remote: upstream::Upstream + Send + Sync + 'static,
+{
     let (mut rx, mut tx) = socket.split();
     let (qtx, mut qrx) = mpsc::channel::<(SocketAddr, Vec<u8>)>(1024);
 
@@ -244,18 +422,9 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
         }
     });
 
-    let config = context.config();
-    // FIXME: We use TCP to send remote queries by default, which should be configuable.
-    let balancer = PlainPingBalancer::new(context.clone(), ServerType::Tcp).await;
-    let remote_upstream = Arc::new(upstream::ProxyTcpUpstream {
-        context: context.clone(),
-        svr_cfg: move || balancer.pick_server().server_config().clone(),
-        ns: config.remote_dns_addr.clone().expect("remote query DNS address"),
-    });
-
+    let mut req_buffer = vec![0u8; MAXIMUM_UDP_PACKET_SIZE];
     loop {
-        let mut req_buffer: [u8; 512] = [0; 512];
-        let (_, src) = match rx.recv_from(&mut req_buffer).await {
+        let (n, src) = match rx.recv_from(&mut req_buffer).await {
             Ok(x) => x,
             Err(e) => {
                 error!("DNS relay read from UDP socket error: {}", e);
@@ -263,7 +432,7 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
             }
         };
 
-        let request = match Message::from_vec(&req_buffer) {
+        let request = match Message::from_vec(&req_buffer[..n]) {
             Ok(x) => x,
             Err(e) => {
                 error!("failed to parse UDP query message, error: {:?}", e);
@@ -273,45 +442,18 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
         debug!("received src: {}, query: {:?}", src, request);
 
-        let context = context.clone();
-        let remote_upstream = remote_upstream.clone();
+        let relay = relay.clone();
         let mut qtx = qtx.clone();
 
         tokio::spawn(async move {
-            let mut message = Message::new();
-            message.set_id(request.id());
-            message.set_recursion_desired(true);
-            message.set_recursion_available(true);
-            message.set_message_type(MessageType::Response);
-
-            if !request.recursion_desired() {
-                message.set_recursion_desired(false);
-                message.set_response_code(ResponseCode::NotImp);
-            } else if request.query_count() > 0 {
-                let question = &request.queries()[0];
-                let (r, forward) = acl_lookup(context.acl(), context.local_dns(), remote_upstream, question).await;
-
-                if let Ok(result) = r {
-                    for rec in result.answers() {
-                        debug!("dns answer: {:?}", rec);
-
-                        match rec.rdata() {
-                            RData::A(ref ip) => context.add_to_reverse_lookup_cache(&IpAddr::V4(*ip), forward),
-                            RData::AAAA(ref ip) => context.add_to_reverse_lookup_cache(&IpAddr::V6(*ip), forward),
-                            _ => (),
-                        }
-                    }
-
-                    message = result;
-                    message.set_id(request.id());
-                } else {
-                    message.set_response_code(ResponseCode::ServFail);
-                }
-            }
+            let message = handle_request(&relay, &request, src).await;
 
             debug!("DNS src: {}, final response: {:?}", src, message);
 
-            match message.to_vec() {
+            // Clients without EDNS0 could only receive 512 bytes
+            let max_payload = request.max_payload().min(EDNS_MAX_PAYLOAD) as usize;
+
+            match encode_udp_response(message, max_payload) {
                 Err(err) => {
                     error!("failed to serialize message, error: {}", err);
                 }
@@ -324,3 +466,289 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
         });
     }
 }
+
+/// Serialize the response, truncates it and sets the TC bit if it is larger than `max_payload`
+fn encode_udp_response(message: Message, max_payload: usize) -> io::Result<Vec<u8>> {
+    let res_buffer = message.to_vec()?;
+    if res_buffer.len() <= max_payload {
+        return Ok(res_buffer);
+    }
+
+    debug!(
+        "DNS response {} bytes is larger than {} bytes, truncated",
+        res_buffer.len(), max_payload
+    );
+
+    let mut truncated = message.truncate();
+    truncated.add_queries(message.queries().iter().cloned());
+    Ok(truncated.to_vec()?)
+}
+
+async fn run_tcp<Remote>(relay: Arc<DnsRelay<Remote>>, mut listener: TcpListener) -> io::Result<()>
+    where
+        Remote: upstream::Upstream + Send + Sync + 'static,
+{
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                error!("DNS relay accept TCP connection error: {}", e);
+                continue;
+            }
+        };
+
+        let relay = relay.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = handle_tcp_client(relay, stream, src).await {
+                debug!("DNS relay TCP client {} closed with error: {}", src, err);
+            }
+        });
+    }
+}
+
+/// Serves queries on a TCP connection, each message is prefixed with a 2 bytes length field
+async fn handle_tcp_client<Remote, S>(relay: Arc<DnsRelay<Remote>>, mut stream: S, src: SocketAddr) -> io::Result<()>
+    where
+        Remote: upstream::Upstream + Send + Sync + 'static,
+        S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let mut len_buffer = [0u8; 2];
+        match try_timeout(stream.read_exact(&mut len_buffer), Some(TCP_IDLE_TIMEOUT)).await {
+            Ok(..) => (),
+            // Client closed or idle for too long
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof || err.kind() == io::ErrorKind::TimedOut => {
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        }
+
+        let size = BigEndian::read_u16(&len_buffer) as usize;
+        let mut req_buffer = vec![0u8; size];
+        try_timeout(stream.read_exact(&mut req_buffer), Some(TCP_IDLE_TIMEOUT)).await?;
+
+        let request = match Message::from_vec(&req_buffer) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("failed to parse TCP query message, error: {:?}", e);
+                return Ok(());
+            }
+        };
+
+        debug!("received TCP src: {}, query: {:?}", src, request);
+
+        let message = handle_request(&relay, &request, src).await;
+
+        debug!("DNS TCP src: {}, final response: {:?}", src, message);
+
+        let res_buffer = message.to_vec()?;
+        if res_buffer.len() > u16::max_value() as usize {
+            error!("DNS response is too large to be sent via TCP, {} bytes", res_buffer.len());
+            return Ok(());
+        }
+
+        let size = res_buffer.len();
+        let mut send_buffer = vec![0; size + 2];
+
+        BigEndian::write_u16(&mut send_buffer[0..2], size as u16);
+        send_buffer[2..size + 2].copy_from_slice(&res_buffer);
+        stream.write_all(&send_buffer).await?;
+    }
+}
+
+/// Resolves the request by cache or ACL rules and builds the response
+async fn handle_request<Remote>(relay: &Arc<DnsRelay<Remote>>, request: &Message, src: SocketAddr) -> Message
+    where
+        Remote: upstream::Upstream + Send + Sync + 'static,
+{
+    let mut message = Message::new();
+    message.set_id(request.id());
+    message.set_recursion_desired(true);
+    message.set_recursion_available(true);
+    message.set_message_type(MessageType::Response);
+
+    if !request.recursion_desired() {
+        message.set_recursion_desired(false);
+        message.set_response_code(ResponseCode::NotImp);
+    } else if request.query_count() > 0 {
+        let question = &request.queries()[0];
+        let client_subnet = relay.client_subnet(request, &src);
+
+        let r = if has_hosts_record(&relay.context, question) {
+            hosts_lookup(relay, question, client_subnet).await
+        } else {
+            match relay.cache.as_ref().and_then(|cache| cache.get(question, client_subnet)) {
+                Some(cached) => {
+                    debug!("DNS cache hit {} {}", question.name(), question.query_type());
+
+                    if cached.prefetch {
+                        // Refresh it in background, before it is expired
+                        let relay = relay.clone();
+                        let question = question.clone();
+                        tokio::spawn(async move {
+                            debug!("DNS prefetching {} {}", question.name(), question.query_type());
+                            if lookup(&relay, &question, client_subnet).await.is_err() {
+                                if let Some(ref cache) = relay.cache {
+                                    cache.prefetch_failed(&question, client_subnet);
+                                }
+                            }
+                        });
+                    }
+
+                    update_reverse_lookup_cache(&relay.context, &cached.message, cached.forward);
+                    Ok(cached.message)
+                }
+                None => lookup(relay, question, client_subnet).await,
+            }
+        };
+
+        if let Ok(result) = r {
+            message = copy_response(&result);
+            message.set_id(request.id());
+        } else {
+            message.set_response_code(ResponseCode::ServFail);
+        }
+    }
+
+    // EDNS0 OPT records are hop-by-hop, only responds to clients that support EDNS0
+    if request.edns().is_some() {
+        let mut edns = Edns::new();
+        edns.set_max_payload(EDNS_MAX_PAYLOAD);
+        message.set_edns(edns);
+    }
+
+    message
+}
+
+fn has_hosts_record(context: &Context, question: &Query) -> bool {
+    match context.config().hosts {
+        Some(ref hosts) => hosts.get(&question.name().to_ascii()).is_some(),
+        None => false,
+    }
+}
+
+/// Answers the query by records in `hosts`, aliases are answered as CNAME records
+async fn hosts_lookup<Remote>(
+    relay: &DnsRelay<Remote>,
+    question: &Query,
+    client_subnet: Option<ClientSubnet>
+) -> io::Result<Message>
+    where
+        Remote: upstream::Upstream,
+{
+    let hosts = relay.context.config().hosts.as_ref().expect("hosts");
+    let hosts_match = match hosts.lookup(&question.name().to_ascii()) {
+        Some(m) => m,
+        None => {
+            let err = io::Error::new(io::ErrorKind::Other, format!("invalid hosts record of {}", question.name()));
+            return Err(err);
+        }
+    };
+
+    debug!("DNS hosts matched {} {}, {:?}", question.name(), question.query_type(), hosts_match);
+
+    let mut response = Message::new();
+    response.set_message_type(MessageType::Response);
+    response.set_recursion_desired(true);
+    response.set_recursion_available(true);
+    response.add_query(question.clone());
+
+    let mut owner = question.name().clone();
+    for alias in &hosts_match.aliases {
+        let mut target = Name::from_ascii(alias)?;
+        target.set_fqdn(true);
+        response.add_answer(Record::from_rdata(owner, HOSTS_TTL, RData::CNAME(target.clone())));
+        owner = target;
+    }
+
+    match hosts_match.addrs {
+        Some(addrs) => {
+            for ip in addrs {
+                let rdata = match (ip, question.query_type()) {
+                    (IpAddr::V4(ip), RecordType::A) | (IpAddr::V4(ip), RecordType::ANY) => RData::A(ip),
+                    (IpAddr::V6(ip), RecordType::AAAA) | (IpAddr::V6(ip), RecordType::ANY) => RData::AAAA(ip),
+                    _ => continue,
+                };
+                response.add_answer(Record::from_rdata(owner.clone(), HOSTS_TTL, rdata));
+            }
+        }
+        None if question.query_type() != RecordType::CNAME => {
+            // Canonical name is not in hosts, resolves it by DNS
+            let mut query = question.clone();
+            query.set_name(owner);
+
+            let result = lookup(relay, &query, client_subnet).await?;
+            response.set_response_code(result.response_code());
+            response.add_answers(result.answers().iter().cloned());
+            response.add_name_servers(result.name_servers().iter().cloned());
+        }
+        None => {}
+    }
+
+    Ok(response)
+}
+
+/// Resolves the query by ACL rules, and saves the response into cache
+///
+/// `client_subnet` is the subnet of client, which is only set if it will be sent to upstreams.
+async fn lookup<Remote>(relay: &DnsRelay<Remote>, question: &Query, client_subnet: Option<ClientSubnet>) -> io::Result<Message>
+    where
+        Remote: upstream::Upstream,
+{
+    let context = &relay.context;
+    let config = context.config();
+    let (r, forward) = acl_lookup(
+        context.acl(),
+        context.local_dns(),
+        relay.remote_upstream.clone(),
+        &relay.poison,
+        question,
+        ecs::upstream_subnet(config.local_dns_ecs, client_subnet),
+        ecs::upstream_subnet(config.remote_dns_ecs, client_subnet),
+    )
+    .await;
+    let result = r?;
+
+    update_reverse_lookup_cache(context, &result, forward);
+
+    if let Some(ref cache) = relay.cache {
+        cache.insert(question, client_subnet, &result, forward);
+    }
+
+    Ok(result)
+}
+
+/// Remembers whether addresses in answers were resolved by remote upstream
+fn update_reverse_lookup_cache(context: &Context, result: &Message, forward: bool) {
+    for rec in result.answers() {
+        debug!("dns answer: {:?}", rec);
+
+        match rec.rdata() {
+            RData::A(ref ip) => context.add_to_reverse_lookup_cache(&IpAddr::V4(*ip), forward),
+            RData::AAAA(ref ip) => context.add_to_reverse_lookup_cache(&IpAddr::V6(*ip), forward),
+            _ => (),
+        }
+    }
+}
+
+/// Copies a response from upstream without the EDNS0 OPT record
+fn copy_response(result: &Message) -> Message {
+    let mut message = Message::new();
+    message.set_message_type(result.message_type());
+    message.set_op_code(result.op_code());
+    message.set_authoritative(result.authoritative());
+    message.set_truncated(result.truncated());
+    message.set_recursion_desired(result.recursion_desired());
+    message.set_recursion_available(result.recursion_available());
+    message.set_authentic_data(result.authentic_data());
+    message.set_checking_disabled(result.checking_disabled());
+    message.set_response_code(result.response_code());
+    message.add_queries(result.queries().iter().cloned());
+    message.add_answers(result.answers().iter().cloned());
+    message.add_name_servers(result.name_servers().iter().cloned());
+    for rec in result.additionals() {
+        message.add_additional(rec.clone());
+    }
+    message
+}
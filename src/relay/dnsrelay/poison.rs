@@ -0,0 +1,138 @@
+//! Detection of poisoned (injected) responses from local DNS
+//!
+//! Injected responses are forged by middleboxes on path, which usually
+//!
+//! - arrive earlier than any genuine response could, because middleboxes are closer than the name servers
+//! - carry addresses from a small set of bogus addresses
+//! - are assembled carelessly, records of the same RRset have different TTLs
+
+use std::{collections::HashMap, net::IpAddr, time::Duration};
+
+use ipnet::IpNet;
+use log::warn;
+use trust_dns_proto::{
+    op::{Message, Query},
+    rr::{RData, RecordType},
+};
+
+use crate::config::Config;
+
+/// Heuristics for detecting poisoned responses, all of them are disabled by default
+#[derive(Debug, Clone, Default)]
+pub struct PoisonDetector {
+    min_rtt: Option<Duration>,
+    bogus_addrs: Vec<IpNet>,
+    check_ttl: bool,
+}
+
+impl PoisonDetector {
+    /// Create with heuristics enabled in configuration
+    pub fn new(config: &Config) -> PoisonDetector {
+        PoisonDetector {
+            min_rtt: config.local_dns_min_rtt,
+            bogus_addrs: config.dns_bogus_addrs.clone(),
+            check_ttl: config.dns_check_ttl,
+        }
+    }
+
+    /// Check if `response` of `query` from local DNS, which arrived after `rtt`, is likely to be poisoned
+    pub fn is_poisoned(&self, query: &Query, response: &Message, rtt: Duration) -> bool {
+        if response.answers().is_empty() {
+            return false;
+        }
+
+        if let Some(min_rtt) = self.min_rtt {
+            if rtt < min_rtt {
+                warn!(
+                    "local DNS response of {} arrived in {:?}, faster than {:?}, considered poisoned",
+                    query, rtt, min_rtt
+                );
+                return true;
+            }
+        }
+
+        if !self.bogus_addrs.is_empty() {
+            for rec in response.answers() {
+                let ip = match rec.rdata() {
+                    RData::A(ref ip) => IpAddr::V4(*ip),
+                    RData::AAAA(ref ip) => IpAddr::V6(*ip),
+                    _ => continue,
+                };
+
+                if self.bogus_addrs.iter().any(|net| net.contains(&ip)) {
+                    warn!("local DNS response of {} has bogus address {}, considered poisoned", query, ip);
+                    return true;
+                }
+            }
+        }
+
+        if self.check_ttl && has_inconsistent_ttl(response) {
+            warn!("local DNS response of {} has inconsistent TTLs in RRset, considered poisoned", query);
+            return true;
+        }
+
+        false
+    }
+}
+
+/// TTLs of records in the same RRset must be the same (RFC 2181 section 5.2)
+fn has_inconsistent_ttl(response: &Message) -> bool {
+    let mut rrsets = HashMap::<(String, RecordType), u32>::new();
+    for rec in response.answers() {
+        let key = (rec.name().to_lowercase().to_ascii(), rec.record_type());
+        match rrsets.get(&key) {
+            Some(ttl) if *ttl != rec.ttl() => return true,
+            Some(..) => {}
+            None => {
+                rrsets.insert(key, rec.ttl());
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::Ipv4Addr, str::FromStr};
+
+    use trust_dns_proto::rr::{Name, Record};
+
+    use super::*;
+
+    #[test]
+    fn detect_poisoned_response() {
+        let name = Name::from_str("blocked.example.").unwrap();
+        let query = Query::query(name.clone(), RecordType::A);
+
+        let mut response = Message::new();
+        response.add_answer(Record::from_rdata(name.clone(), 300, RData::A(Ipv4Addr::new(203, 0, 113, 1))));
+        response.add_answer(Record::from_rdata(name.clone(), 300, RData::A(Ipv4Addr::new(203, 0, 113, 2))));
+
+        let rtt = Duration::from_millis(2);
+
+        let detector = PoisonDetector::default();
+        assert!(!detector.is_poisoned(&query, &response, rtt));
+
+        let detector = PoisonDetector {
+            min_rtt: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        assert!(detector.is_poisoned(&query, &response, rtt));
+        assert!(!detector.is_poisoned(&query, &response, Duration::from_millis(30)));
+        assert!(!detector.is_poisoned(&query, &Message::new(), rtt));
+
+        let detector = PoisonDetector {
+            bogus_addrs: vec!["203.0.113.2/32".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(detector.is_poisoned(&query, &response, rtt));
+
+        let detector = PoisonDetector {
+            check_ttl: true,
+            ..Default::default()
+        };
+        assert!(!detector.is_poisoned(&query, &response, rtt));
+        response.add_answer(Record::from_rdata(name, 60, RData::A(Ipv4Addr::new(203, 0, 113, 3))));
+        assert!(detector.is_poisoned(&query, &response, rtt));
+    }
+}
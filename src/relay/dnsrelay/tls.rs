@@ -0,0 +1,254 @@
+//! DNS over TLS (RFC 7858) and DNS over HTTPS (RFC 8484) servers of DNS relay
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Error, ErrorKind},
+    path::Path,
+    sync::Arc,
+};
+
+use log::{debug, error};
+use tokio::net::TcpListener;
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+        NoClientAuth,
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+use super::{upstream, DnsRelay};
+use crate::config::Config;
+
+/// Create a TLS acceptor with certificate and private key in configuration
+pub fn create_acceptor(config: &Config, alpn_protocols: &[Vec<u8>]) -> io::Result<TlsAcceptor> {
+    let cert_path = match config.dns_tls_cert_path {
+        Some(ref p) => p,
+        None => return Err(Error::new(ErrorKind::Other, "missing certificate for DNS relay")),
+    };
+    let key_path = match config.dns_tls_key_path {
+        Some(ref p) => p,
+        None => return Err(Error::new(ErrorKind::Other, "missing private key for DNS relay")),
+    };
+
+    let cert_chain = match certs(&mut BufReader::new(File::open(cert_path)?)) {
+        Ok(c) if !c.is_empty() => c,
+        _ => {
+            let err = Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid PEM certificate \"{}\"", cert_path.display()),
+            );
+            return Err(err);
+        }
+    };
+    let key = load_private_key(key_path)?;
+
+    let mut tls_config = ServerConfig::new(NoClientAuth::new());
+    if let Err(err) = tls_config.set_single_cert(cert_chain, key) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("tls config: {}", err)));
+    }
+    tls_config.set_protocols(alpn_protocols);
+
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+}
+
+/// Load the first PKCS#8 or RSA private key in PEM file
+fn load_private_key(path: &Path) -> io::Result<tokio_rustls::rustls::PrivateKey> {
+    if let Ok(mut keys) = pkcs8_private_keys(&mut BufReader::new(File::open(path)?)) {
+        if !keys.is_empty() {
+            return Ok(keys.swap_remove(0));
+        }
+    }
+
+    if let Ok(mut keys) = rsa_private_keys(&mut BufReader::new(File::open(path)?)) {
+        if !keys.is_empty() {
+            return Ok(keys.swap_remove(0));
+        }
+    }
+
+    let err = Error::new(
+        ErrorKind::InvalidData,
+        format!("invalid PEM private key \"{}\"", path.display()),
+    );
+    Err(err)
+}
+
+/// Serves DNS over TLS, which is the same as DNS over TCP except for TLS
+#[cfg(feature = "dns-over-tls")]
+pub async fn run_tls<Remote>(relay: Arc<DnsRelay<Remote>>, mut listener: TcpListener, acceptor: TlsAcceptor) -> io::Result<()>
+    where
+        Remote: upstream::Upstream + Send + Sync + 'static,
+{
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                error!("DNS relay accept TLS connection error: {}", e);
+                continue;
+            }
+        };
+
+        let relay = relay.clone();
+        let acceptor = acceptor.clone();
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(s) => s,
+                Err(err) => {
+                    debug!("DNS relay TLS handshake with {} failed, error: {}", src, err);
+                    return;
+                }
+            };
+
+            if let Err(err) = super::handle_tcp_client(relay, stream, src).await {
+                debug!("DNS relay TLS client {} closed with error: {}", src, err);
+            }
+        });
+    }
+}
+
+/// Serves DNS over HTTPS with both GET and POST methods
+#[cfg(feature = "dns-over-https")]
+pub async fn run_https<Remote>(relay: Arc<DnsRelay<Remote>>, mut listener: TcpListener, acceptor: TlsAcceptor) -> io::Result<()>
+    where
+        Remote: upstream::Upstream + Send + Sync + 'static,
+{
+    use std::convert::Infallible;
+
+    use hyper::{server::conn::Http, service::service_fn};
+
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                error!("DNS relay accept HTTPS connection error: {}", e);
+                continue;
+            }
+        };
+
+        let relay = relay.clone();
+        let acceptor = acceptor.clone();
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(s) => s,
+                Err(err) => {
+                    debug!("DNS relay HTTPS handshake with {} failed, error: {}", src, err);
+                    return;
+                }
+            };
+
+            let service = service_fn(move |req| {
+                let relay = relay.clone();
+                async move { Ok::<_, Infallible>(https::handle_request(&relay, req, src).await) }
+            });
+
+            if let Err(err) = Http::new().serve_connection(stream, service).await {
+                debug!("DNS relay HTTPS client {} closed with error: {}", src, err);
+            }
+        });
+    }
+}
+
+#[cfg(feature = "dns-over-https")]
+mod https {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use base64::{decode_config, URL_SAFE_NO_PAD};
+    use bytes::BytesMut;
+    use hyper::{body::HttpBody, header, Body, Method, Request, Response, StatusCode};
+    use log::debug;
+    use trust_dns_proto::{op::Message, rr::Record};
+
+    use super::super::{upstream, DnsRelay};
+    use crate::config::DNS_OVER_HTTPS_PATH;
+
+    const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+
+    /// Maximum size of a DNS message
+    const MAXIMUM_MESSAGE_SIZE: usize = 65535;
+
+    fn make_error(status: StatusCode) -> Response<Body> {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = status;
+        resp
+    }
+
+    async fn read_request(req: Request<Body>) -> Result<Vec<u8>, StatusCode> {
+        match *req.method() {
+            Method::GET => {
+                let query = req.uri().query().unwrap_or("");
+                let dns = url::form_urlencoded::parse(query.as_bytes())
+                    .find(|(key, _)| key == "dns")
+                    .map(|(_, value)| value);
+
+                match dns {
+                    Some(dns) => decode_config(dns.as_bytes(), URL_SAFE_NO_PAD).map_err(|_| StatusCode::BAD_REQUEST),
+                    None => Err(StatusCode::BAD_REQUEST),
+                }
+            }
+            Method::POST => {
+                match req.headers().get(header::CONTENT_TYPE) {
+                    Some(ct) if ct == DNS_MESSAGE_CONTENT_TYPE => {}
+                    _ => return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+                }
+
+                let mut body = req.into_body();
+                let mut buf = BytesMut::new();
+                while let Some(chunk) = body.data().await {
+                    let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+                    if buf.len() + chunk.len() > MAXIMUM_MESSAGE_SIZE {
+                        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+                    }
+                    buf.extend_from_slice(&chunk);
+                }
+                Ok(buf.to_vec())
+            }
+            _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+        }
+    }
+
+    pub async fn handle_request<Remote>(relay: &Arc<DnsRelay<Remote>>, req: Request<Body>, src: SocketAddr) -> Response<Body>
+        where
+            Remote: upstream::Upstream + Send + Sync + 'static,
+    {
+        if req.uri().path() != DNS_OVER_HTTPS_PATH {
+            return make_error(StatusCode::NOT_FOUND);
+        }
+
+        let req_buffer = match read_request(req).await {
+            Ok(b) => b,
+            Err(status) => return make_error(status),
+        };
+
+        let request = match Message::from_vec(&req_buffer) {
+            Ok(x) => x,
+            Err(e) => {
+                debug!("failed to parse HTTPS query message, error: {:?}", e);
+                return make_error(StatusCode::BAD_REQUEST);
+            }
+        };
+
+        debug!("received HTTPS query: {:?}", request);
+
+        let message = super::super::handle_request(relay, &request, src).await;
+
+        debug!("DNS HTTPS final response: {:?}", message);
+
+        let res_buffer = match message.to_vec() {
+            Ok(b) => b,
+            Err(..) => return make_error(StatusCode::INTERNAL_SERVER_ERROR),
+        };
+
+        let mut builder = Response::builder().header(header::CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE);
+        // Allows HTTP caches to store the response no longer than the records' TTL
+        if let Some(ttl) = message.answers().iter().map(Record::ttl).min() {
+            builder = builder.header(header::CACHE_CONTROL, format!("max-age={}", ttl));
+        }
+
+        builder
+            .body(Body::from(res_buffer))
+            .unwrap_or_else(|_| make_error(StatusCode::INTERNAL_SERVER_ERROR))
+    }
+}
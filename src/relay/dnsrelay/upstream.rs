@@ -1,19 +1,22 @@
 use std::{
     fmt,
     fmt::{Debug, Formatter},
-    io,
+    io::{self, Error, ErrorKind},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
 };
 
 use async_trait::async_trait;
 use byteorder::{BigEndian, ByteOrder};
 use rand::Rng;
+use log::{debug, error};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::UdpSocket
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{lookup_host, TcpStream, UdpSocket},
+    time,
 };
 use trust_dns_proto::{
-    op::{Message, Query},
+    op::{Edns, Message, Query},
     rr::{DNSClass, RecordType, Name, RData},
 };
 
@@ -22,41 +25,54 @@ use std::path::PathBuf;
 #[cfg(unix)]
 use tokio::net::UnixStream;
 
+use super::{ecs::ClientSubnet, EDNS_MAX_PAYLOAD};
 use crate::{
-    config::{Config, ServerConfig},
+    config::{Config, NameServerAddr, NameServerProtocol, ServerAddr, ServerConfig},
     context::SharedContext,
     relay::{
         socks5::Address,
         tcprelay::ProxyStream,
+        udprelay::client::ServerClient,
     },
 };
 
+/// Timeout of a response through proxy's UDP relay, then it is retried with the next name server or TCP
+///
+/// The whole lookup is limited to 3 seconds, a lost datagram shouldn't take all of it.
+const PROXY_UDP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Timeout of a response from name server with UDP, then it is retried with the next name server
+const UDP_TIMEOUT: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 pub enum LocalUpstream {
-    Udp(UdpUpstream),
-    // Tcp(TcpUpstream),
+    NameServers(NameServerUpstream),
     #[cfg(unix)]
     UnixSocket(UnixSocketUpstream),
 }
 
 impl LocalUpstream {
+    /// Android's local DNS socket, or name servers in configuration, or system's name servers by default
     pub fn new(config: &Config) -> LocalUpstream {
-        #[cfg(target_os = "android")]
-        return LocalUpstream::UnixSocket(UnixSocketUpstream {
-            path: config.local_dns_path.clone().expect("local query DNS path"),
-        });
-        #[cfg(not(target_os = "android"))]
-        LocalUpstream::Udp(UdpUpstream {
-            server: config.local_dns_addr.clone().expect("local query DNS address"),
-        })
+        #[cfg(unix)]
+        {
+            if let Some(ref path) = config.local_dns_path {
+                return LocalUpstream::UnixSocket(UnixSocketUpstream { path: path.clone() });
+            }
+        }
+
+        let servers = match config.local_dns_addr {
+            Some(ref servers) => servers.clone(),
+            None => system_name_servers(),
+        };
+        LocalUpstream::NameServers(NameServerUpstream { servers })
     }
 
-    pub async fn lookup(&self, query: &Query) -> io::Result<Message> {
+    pub async fn lookup(&self, query: &Query, ecs: Option<ClientSubnet>) -> io::Result<Message> {
         match self {
-            LocalUpstream::Udp(upstream) => upstream.lookup(query).await,
-            // LocalUpstream::Tcp(upstream) => upstream.lookup(query),
+            LocalUpstream::NameServers(upstream) => upstream.lookup(query, ecs).await,
             #[cfg(unix)]
-            LocalUpstream::UnixSocket(upstream) => upstream.lookup(query).await,
+            LocalUpstream::UnixSocket(upstream) => upstream.lookup(query, ecs).await,
         }
     }
 
@@ -69,7 +85,7 @@ impl LocalUpstream {
         let mut queryv6 = queryv4.clone();
         queryv4.set_query_type(RecordType::A);
         queryv6.set_query_type(RecordType::AAAA);
-        let (responsev4, responsev6) = tokio::try_join!(self.lookup(&queryv4), self.lookup(&queryv6))?;
+        let (responsev4, responsev6) = tokio::try_join!(self.lookup(&queryv4, None), self.lookup(&queryv6, None))?;
         macro_rules! parse {
             ($response:expr) => {
                 $response.answers().iter().filter_map(|rec| match rec.rdata() {
@@ -83,24 +99,60 @@ impl LocalUpstream {
     }
 }
 
+/// Name servers of system's resolver, like `nameserver`s in `/etc/resolv.conf`
+#[cfg(any(unix, windows))]
+fn system_name_servers() -> Vec<NameServerAddr> {
+    use trust_dns_resolver::system_conf::read_system_conf;
+
+    match read_system_conf() {
+        // Both UDP and TCP are listed for each name server, UDP will be retried with TCP if it is truncated
+        Ok((config, ..)) => config
+            .name_servers()
+            .iter()
+            .filter(|ns| ns.protocol.is_datagram())
+            .map(|ns| NameServerAddr::from(ns.socket_addr))
+            .collect(),
+        Err(err) => {
+            error!("failed to read system's DNS configuration, error: {}", err);
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn system_name_servers() -> Vec<NameServerAddr> {
+    error!("system's DNS configuration is not supported, local DNS servers must be specified");
+    Vec::new()
+}
+
 #[async_trait]
 pub trait Upstream: Debug {
-    async fn lookup(&self, query: &Query) -> io::Result<Message>;
+    /// Resolves `query`, with EDNS Client Subnet option if `ecs` is not `None`
+    async fn lookup(&self, query: &Query, ecs: Option<ClientSubnet>) -> io::Result<Message>;
 }
 
-fn generate_query_message(query: &Query) -> Message {
+fn generate_query_message(query: &Query, ecs: Option<ClientSubnet>) -> Message {
     let mut message = Message::new();
     message.set_id(rand::thread_rng().gen());
     message.set_recursion_desired(true);
     message.add_query(query.clone());
+
+    // Allows upstreams to send large responses in UDP
+    let mut edns = Edns::new();
+    edns.set_max_payload(EDNS_MAX_PAYLOAD);
+    if let Some(ecs) = ecs {
+        ecs.apply(&mut edns);
+    }
+    message.set_edns(edns);
+
     message
 }
 
-async fn stream_lookup<T>(query: &Query, stream: &mut T) -> io::Result<Message>
+async fn stream_lookup<T>(query: &Query, ecs: Option<ClientSubnet>, stream: &mut T) -> io::Result<Message>
     where
         T: AsyncReadExt + AsyncWriteExt + Unpin,
 {
-    let req_buffer = generate_query_message(query).to_vec()?;
+    let req_buffer = generate_query_message(query, ecs).to_vec()?;
     let size = req_buffer.len();
     let mut send_buffer = vec![0; size + 2];
 
@@ -118,56 +170,368 @@ async fn stream_lookup<T>(query: &Query, stream: &mut T) -> io::Result<Message>
     Ok(Message::from_vec(&res_buffer)?)
 }
 
+/// Sends `query` to `ns` through a connected stream, with TCP, TLS or HTTPS by `ns`'s protocol
+async fn connected_lookup<S>(ns: &NameServerAddr, query: &Query, ecs: Option<ClientSubnet>, mut stream: S) -> io::Result<Message>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match ns.protocol() {
+        // Streams couldn't carry UDP, fallback to TCP
+        NameServerProtocol::Udp | NameServerProtocol::Tcp => stream_lookup(query, ecs, &mut stream).await,
+        #[cfg(feature = "dns-over-tls")]
+        NameServerProtocol::Tls => {
+            let mut stream = tls_connect(ns, stream, TLS_CONFIG.clone()).await?;
+            stream_lookup(query, ecs, &mut stream).await
+        }
+        #[cfg(feature = "dns-over-https")]
+        NameServerProtocol::Https => {
+            let stream = tls_connect(ns, stream, HTTPS_TLS_CONFIG.clone()).await?;
+            https_lookup(ns, query, ecs, stream).await
+        }
+        #[allow(unreachable_patterns)]
+        _ => {
+            let err = Error::new(
+                ErrorKind::Other,
+                format!("DNS {} is not supported, consider enable it by features", ns),
+            );
+            Err(err)
+        }
+    }
+}
+
+#[cfg(any(feature = "dns-over-tls", feature = "dns-over-https"))]
+fn create_tls_config(alpn_protocols: Vec<Vec<u8>>) -> std::sync::Arc<tokio_rustls::rustls::ClientConfig> {
+    let mut config = tokio_rustls::rustls::ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    config.alpn_protocols = alpn_protocols;
+    std::sync::Arc::new(config)
+}
+
+#[cfg(feature = "dns-over-tls")]
+lazy_static::lazy_static! {
+    static ref TLS_CONFIG: std::sync::Arc<tokio_rustls::rustls::ClientConfig> = create_tls_config(Vec::new());
+}
+
+#[cfg(feature = "dns-over-https")]
+lazy_static::lazy_static! {
+    static ref HTTPS_TLS_CONFIG: std::sync::Arc<tokio_rustls::rustls::ClientConfig> =
+        create_tls_config(vec![b"http/1.1".to_vec()]);
+}
+
+#[cfg(any(feature = "dns-over-tls", feature = "dns-over-https"))]
+async fn tls_connect<S>(
+    ns: &NameServerAddr,
+    stream: S,
+    config: std::sync::Arc<tokio_rustls::rustls::ClientConfig>
+) -> io::Result<tokio_rustls::client::TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+{
+    use tokio_rustls::{webpki::DNSNameRef, TlsConnector};
+
+    let tls_name = ns.tls_name().expect("tls name");
+    let name = match DNSNameRef::try_from_ascii_str(tls_name) {
+        Ok(n) => n,
+        Err(..) => {
+            let err = Error::new(ErrorKind::InvalidInput, format!("invalid dnsname \"{}\"", tls_name));
+            return Err(err);
+        }
+    };
+
+    TlsConnector::from(config).connect(name, stream).await
+}
+
+/// DNS over HTTPS (RFC 8484) with POST method
+#[cfg(feature = "dns-over-https")]
+async fn https_lookup<S>(ns: &NameServerAddr, query: &Query, ecs: Option<ClientSubnet>, stream: S) -> io::Result<Message>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    use hyper::{body, client::conn, header, Body, Request, StatusCode};
+
+    let (mut sender, connection) = conn::handshake(stream)
+        .await
+        .map_err(|err| Error::new(ErrorKind::Other, err))?;
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            debug!("DNS over HTTPS connection error: {}", err);
+        }
+    });
+
+    // ID should be 0 for being cache friendly
+    let mut message = generate_query_message(query, ecs);
+    message.set_id(0);
+
+    let req = Request::post(ns.path())
+        .header(header::HOST, ns.tls_name().expect("tls name"))
+        .header(header::CONTENT_TYPE, "application/dns-message")
+        .header(header::ACCEPT, "application/dns-message")
+        .body(Body::from(message.to_vec()?))
+        .map_err(|err| Error::new(ErrorKind::Other, err))?;
+
+    let resp = sender
+        .send_request(req)
+        .await
+        .map_err(|err| Error::new(ErrorKind::Other, err))?;
+    if resp.status() != StatusCode::OK {
+        let err = Error::new(
+            ErrorKind::Other,
+            format!("DNS over HTTPS {} responded {}", ns, resp.status()),
+        );
+        return Err(err);
+    }
+
+    let buf = body::to_bytes(resp.into_body())
+        .await
+        .map_err(|err| Error::new(ErrorKind::Other, err))?;
+    Ok(Message::from_vec(&buf)?)
+}
+
+/// Queries name servers directly, in order until one of them succeeded
+#[derive(Debug)]
+pub struct NameServerUpstream {
+    pub servers: Vec<NameServerAddr>,
+}
+
+impl NameServerUpstream {
+    async fn lookup_ns(ns: &NameServerAddr, query: &Query, ecs: Option<ClientSubnet>) -> io::Result<Message> {
+        let server = match *ns.addr() {
+            ServerAddr::SocketAddr(addr) => addr,
+            ServerAddr::DomainName(ref dname, port) => match lookup_host((dname.as_str(), port)).await?.next() {
+                Some(addr) => addr,
+                None => {
+                    let err = Error::new(ErrorKind::Other, format!("DNS {} resolved to empty address", ns));
+                    return Err(err);
+                }
+            },
+        };
+
+        match ns.protocol() {
+            NameServerProtocol::Udp => UdpUpstream { server }.lookup(query, ecs).await,
+            _ => {
+                let stream = TcpStream::connect(server).await?;
+                connected_lookup(ns, query, ecs, stream).await
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Upstream for NameServerUpstream {
+    async fn lookup(&self, query: &Query, ecs: Option<ClientSubnet>) -> io::Result<Message> {
+        let mut last_err = None;
+        for ns in &self.servers {
+            match NameServerUpstream::lookup_ns(ns, query, ecs).await {
+                Ok(response) => return Ok(response),
+                Err(err) => {
+                    debug!("DNS lookup {} with {} failed, error: {}", query, ns, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| Error::new(ErrorKind::Other, "no DNS name servers")))
+    }
+}
+
 #[derive(Debug)]
 pub struct UdpUpstream {
     pub server: SocketAddr,
 }
 
+impl UdpUpstream {
+    /// Receives the response of query `id`, datagrams from other addresses or with other ids are ignored
+    async fn recv_response(&self, socket: &mut UdpSocket, id: u16) -> io::Result<Message> {
+        let mut buf = vec![0; EDNS_MAX_PAYLOAD as usize];
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await?;
+            if from != self.server {
+                debug!("DNS response from {} is ignored, expecting {}", from, self.server);
+                continue;
+            }
+
+            let response = Message::from_vec(&buf[..n])?;
+            if response.id() != id {
+                debug!("DNS response id {} from {} mismatched with query id {}", response.id(), from, id);
+                continue;
+            }
+            return Ok(response);
+        }
+    }
+}
+
 #[async_trait]
 impl Upstream for UdpUpstream {
-    async fn lookup(&self, query: &Query) -> io::Result<Message> {
+    async fn lookup(&self, query: &Query, ecs: Option<ClientSubnet>) -> io::Result<Message> {
         let mut socket = UdpSocket::bind(SocketAddr::new(match self.server {
             SocketAddr::V4(..) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
             SocketAddr::V6(..) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
         }, 0)).await?;
-        socket.send_to(&generate_query_message(query).to_vec()?, self.server).await?;
-        let mut response = vec![0; 512];
-        socket.recv_from(&mut response).await?;
-        Ok(Message::from_vec(&response)?)
+        let request = generate_query_message(query, ecs);
+        socket.send_to(&request.to_vec()?, self.server).await?;
+        let response = match time::timeout(UDP_TIMEOUT, self.recv_response(&mut socket, request.id())).await {
+            Ok(r) => r?,
+            Err(..) => return Err(ErrorKind::TimedOut.into()),
+        };
+
+        if response.truncated() {
+            // Response is too large for UDP, retry with TCP
+            debug!("DNS response from {} is truncated, retry with TCP", self.server);
+            return TcpUpstream { server: self.server }.lookup(query, ecs).await;
+        }
+
+        Ok(response)
     }
 }
 
-// #[derive(Debug)]
-// pub struct TcpUpstream {
-//     pub server: SocketAddr,
-// }
-//
-// #[async_trait]
-// impl Upstream for TcpUpstream {
-//     async fn lookup(&self, query: &Query) -> Result<Message> {
-//         unimplemented!()
-//     }
-// }
+#[derive(Debug)]
+pub struct TcpUpstream {
+    pub server: SocketAddr,
+}
 
+#[async_trait]
+impl Upstream for TcpUpstream {
+    async fn lookup(&self, query: &Query, ecs: Option<ClientSubnet>) -> io::Result<Message> {
+        let mut stream = TcpStream::connect(self.server).await?;
+        stream_lookup(query, ecs, &mut stream).await
+    }
+}
+
+/// Queries name servers through proxy, in order until one of them succeeded
+///
+/// Name servers with UDP are queried with TCP instead.
 pub struct ProxyTcpUpstream<F> {
     pub context: SharedContext,
     pub svr_cfg: F,
-    pub ns: Address,
+    pub servers: Vec<NameServerAddr>,
 }
 
 impl<F> Debug for ProxyTcpUpstream<F> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("ProxyTcpUpstream")
-            .field("ns", &self.ns)
+            .field("servers", &self.servers)
+            .finish()
+    }
+}
+
+impl<F> ProxyTcpUpstream<F> where F: Fn(&Address) -> ServerConfig + Send + Sync {
+    pub async fn lookup_ns(&self, ns: &NameServerAddr, query: &Query, ecs: Option<ClientSubnet>) -> io::Result<Message> {
+        let addr = match *ns.addr() {
+            ServerAddr::SocketAddr(addr) => Address::SocketAddress(addr),
+            ServerAddr::DomainName(ref dname, port) => Address::DomainNameAddress(dname.clone(), port),
+        };
+        let stream = ProxyStream::connect_proxied(self.context.clone(), &(self.svr_cfg)(&addr), &addr).await?;
+        connected_lookup(ns, query, ecs, stream).await
+    }
+}
+
+#[async_trait]
+impl<F> Upstream for ProxyTcpUpstream<F> where F: Fn(&Address) -> ServerConfig + Send + Sync {
+    async fn lookup(&self, query: &Query, ecs: Option<ClientSubnet>) -> io::Result<Message> {
+        let mut last_err = None;
+        for ns in &self.servers {
+            match self.lookup_ns(ns, query, ecs).await {
+                Ok(response) => return Ok(response),
+                Err(err) => {
+                    debug!("DNS lookup {} with {} through proxy failed, error: {}", query, ns, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| Error::new(ErrorKind::Other, "no DNS name servers")))
+    }
+}
+
+/// Queries name servers through proxy's UDP relay
+///
+/// Responses that are truncated will be retried with `tcp_upstream`, and so are name servers that are not UDP.
+pub struct ProxyUdpUpstream<F, G> {
+    pub context: SharedContext,
+    pub svr_cfg: F,
+    pub tcp_upstream: ProxyTcpUpstream<G>,
+    /// Also retry with `tcp_upstream` if UDP failed
+    pub fallback_on_error: bool,
+}
+
+impl<F, G> Debug for ProxyUdpUpstream<F, G> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyUdpUpstream")
+            .field("servers", &self.tcp_upstream.servers)
+            .field("fallback_on_error", &self.fallback_on_error)
             .finish()
     }
 }
 
+impl<F, G> ProxyUdpUpstream<F, G>
+    where
+        F: Fn(&Address) -> ServerConfig + Send + Sync,
+        G: Fn(&Address) -> ServerConfig + Send + Sync,
+{
+    async fn udp_lookup_ns(&self, ns: &NameServerAddr, query: &Query, ecs: Option<ClientSubnet>) -> io::Result<Message> {
+        let addr = match *ns.addr() {
+            ServerAddr::SocketAddr(addr) => Address::SocketAddress(addr),
+            ServerAddr::DomainName(ref dname, port) => Address::DomainNameAddress(dname.clone(), port),
+        };
+
+        let request = generate_query_message(query, ecs);
+
+        let mut client = ServerClient::new(&(self.svr_cfg)(&addr)).await?;
+        client.send_to(&self.context, &addr, &request.to_vec()?).await?;
+        let (_, payload) = match time::timeout(PROXY_UDP_TIMEOUT, client.recv_from(&self.context)).await {
+            Ok(r) => r?,
+            Err(..) => return Err(ErrorKind::TimedOut.into()),
+        };
+
+        let response = Message::from_vec(&payload)?;
+        if response.id() != request.id() {
+            let err = Error::new(
+                ErrorKind::InvalidData,
+                format!("DNS response id {} mismatched with query id {}", response.id(), request.id()),
+            );
+            return Err(err);
+        }
+        Ok(response)
+    }
+
+    async fn lookup_ns(&self, ns: &NameServerAddr, query: &Query, ecs: Option<ClientSubnet>) -> io::Result<Message> {
+        if ns.protocol() != NameServerProtocol::Udp {
+            return self.tcp_upstream.lookup_ns(ns, query, ecs).await;
+        }
+
+        match self.udp_lookup_ns(ns, query, ecs).await {
+            Ok(response) if response.truncated() => {
+                // Response is too large for UDP, retry with TCP
+                debug!("DNS response from {} through proxy is truncated, retry with TCP", ns);
+                self.tcp_upstream.lookup_ns(ns, query, ecs).await
+            }
+            Err(err) if self.fallback_on_error => {
+                debug!("DNS lookup {} with {} through proxy's UDP failed, retry with TCP, error: {}", query, ns, err);
+                self.tcp_upstream.lookup_ns(ns, query, ecs).await
+            }
+            r => r,
+        }
+    }
+}
+
 #[async_trait]
-impl<F> Upstream for ProxyTcpUpstream<F> where F: Fn() -> ServerConfig + Send + Sync {
-    async fn lookup(&self, query: &Query) -> io::Result<Message> {
-        let mut stream = ProxyStream::connect_proxied(self.context.clone(), &(self.svr_cfg)(), &self.ns).await?;
-        stream_lookup(query, &mut stream).await
+impl<F, G> Upstream for ProxyUdpUpstream<F, G>
+    where
+        F: Fn(&Address) -> ServerConfig + Send + Sync,
+        G: Fn(&Address) -> ServerConfig + Send + Sync,
+{
+    async fn lookup(&self, query: &Query, ecs: Option<ClientSubnet>) -> io::Result<Message> {
+        let mut last_err = None;
+        for ns in &self.tcp_upstream.servers {
+            match self.lookup_ns(ns, query, ecs).await {
+                Ok(response) => return Ok(response),
+                Err(err) => {
+                    debug!("DNS lookup {} with {} through proxy failed, error: {}", query, ns, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| Error::new(ErrorKind::Other, "no DNS name servers")))
     }
 }
 
@@ -180,8 +544,8 @@ pub struct UnixSocketUpstream {
 #[cfg(unix)]
 #[async_trait]
 impl Upstream for UnixSocketUpstream {
-    async fn lookup(&self, query: &Query) -> io::Result<Message> {
+    async fn lookup(&self, query: &Query, ecs: Option<ClientSubnet>) -> io::Result<Message> {
         let mut stream = UnixStream::connect(&self.path).await?;
-        stream_lookup(query, &mut stream).await
+        stream_lookup(query, ecs, &mut stream).await
     }
 }
@@ -1,17 +1,19 @@
 use std::{
-    collections::VecDeque,
+    collections::{hash_map::DefaultHasher, VecDeque},
     fmt,
-    io,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    hash::{Hash, Hasher},
+    io::{self, Error, ErrorKind},
+    mem,
+    net::SocketAddr,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
     time::{Duration, Instant},
 };
 
 use crate::{
-    config::{Config, ServerConfig},
+    config::{BalancerStrategy, Config, ServerAddr, ServerConfig, TcpProbe, MAX_SERVER_WEIGHT},
     context::{Context, SharedContext},
     relay::{
         socks5::Address,
@@ -20,26 +22,45 @@ use crate::{
     },
 };
 
-use log::{debug, info, trace};
+use futures::Future;
+use log::{debug, info, trace, warn};
+use rand::Rng;
 use tokio::{
     self,
     io::{AsyncReadExt, AsyncWriteExt},
-    sync::{Barrier, Mutex},
+    sync::Barrier,
     time,
 };
+use trust_dns_proto::{
+    op::{Message, Query},
+    rr::{Name, RecordType},
+};
 
 const MAX_LATENCY_QUEUE_SIZE: usize = 99;
-const DEFAULT_CHECK_INTERVAL_SEC: u64 = 6;
-const DEFAULT_CHECK_TIMEOUT_SEC: u64 = 2; // Latency shouldn't greater than 2 secs, that's too long
-const MAX_SERVER_RTT: u64 = DEFAULT_CHECK_TIMEOUT_SEC * 1000;
+/// Scores are in [0, MAX_SCORE], the lower the better
+const MAX_SCORE: u64 = 1000;
+/// Points on the hash ring of consistent hashing, for each weight of servers
+const VIRTUAL_NODES_PER_WEIGHT: u32 = 40;
+/// Servers are ejected from load balancing after this many consecutive failures
+const OUTLIER_CONSECUTIVE_FAILURES: u32 = 5;
+/// Ejection time of the first ejection, doubled for each of the following ones
+const OUTLIER_BASE_EJECTION_TIME: Duration = Duration::from_secs(10);
+/// Ejection time won't exceed this, and ejections are forgotten after servers stay healthy for this long
+const OUTLIER_MAX_EJECTION_TIME: Duration = Duration::from_secs(300);
 
 /// Identifier of a valid server
 pub trait ServerData: Send + Sync {
-    fn create_server(context: &SharedContext, server_idx: usize, data: &SharedServerStatisticData) -> Self;
+    fn create_server(context: &SharedContext, svr_cfg: &ServerConfig, data: &SharedServerStatisticData) -> Self;
 }
 
 #[derive(Debug)]
 struct ServerStatisticData {
+    /// Type of statistic, for logging
+    server_type: ServerType,
+    /// Name of server, for logging
+    server_name: String,
+    /// Maximum latency (in millisec), which is the timeout of checking
+    max_rtt: u64,
     /// Median of latency time (in millisec)
     ///
     /// Use median instead of average time,
@@ -53,10 +74,20 @@ struct ServerStatisticData {
     latency_stdev: f64,
     /// Score's average
     latency_mean: f64,
+    /// Failures in a row, from both probes and real traffic
+    consecutive_failures: u32,
+    /// Ejections in a row, for backing off exponentially
+    ejections: u32,
+    /// Skipped in load balancing until then
+    ejected_until: Option<Instant>,
+    /// Last time an outcome of real traffic was reported
+    last_reported: Option<Instant>,
+    /// Targets of ambiguous failures of real traffic in a row, which count once for each target
+    failed_targets: Vec<String>,
 }
 
-fn max_latency_stdev() -> f64 {
-    let mrtt = MAX_SERVER_RTT as f64;
+fn max_latency_stdev(max_rtt: u64) -> f64 {
+    let mrtt = max_rtt as f64;
     let avg = (0.0 + mrtt) / 2.0;
     let diff1 = (0.0 - avg) * (0.0 - avg);
     let diff2 = (mrtt - avg) * (mrtt - avg);
@@ -65,22 +96,30 @@ fn max_latency_stdev() -> f64 {
 }
 
 impl ServerStatisticData {
-    fn new() -> ServerStatisticData {
+    fn new(server_type: ServerType, server_name: String, max_rtt: u64) -> ServerStatisticData {
         ServerStatisticData {
-            rtt: MAX_SERVER_RTT,
+            server_type,
+            server_name,
+            max_rtt,
+            rtt: max_rtt,
             fail_rate: 1.0,
             latency_queue: VecDeque::new(),
             latency_stdev: 0.0,
             latency_mean: 0.0,
+            consecutive_failures: 0,
+            ejections: 0,
+            ejected_until: None,
+            last_reported: None,
+            failed_targets: Vec::new(),
         }
     }
 
     fn score(&self) -> u64 {
         // Normalize rtt
-        let nrtt = self.rtt as f64 / MAX_SERVER_RTT as f64;
+        let nrtt = self.rtt as f64 / self.max_rtt as f64;
 
         // Normalize stdev
-        let nstdev = self.latency_stdev / max_latency_stdev();
+        let nstdev = self.latency_stdev / max_latency_stdev(self.max_rtt);
 
         const SCORE_RTT_WEIGHT: f64 = 1.0;
         const SCORE_FAIL_WEIGHT: f64 = 3.0;
@@ -95,7 +134,7 @@ impl ServerStatisticData {
             / (SCORE_RTT_WEIGHT + SCORE_FAIL_WEIGHT + SCORE_STDEV_WEIGHT);
 
         // Times 1000 converts to u64, for 0.001 precision
-        (score * 1000.0) as u64
+        (score * MAX_SCORE as f64) as u64
     }
 
     fn push_score(&mut self, score: Score) -> u64 {
@@ -160,68 +199,350 @@ impl ServerStatisticData {
         self.score()
     }
 
+    /// Report a successful request with its latency, which ends failures in a row
+    fn report_success(&mut self, latency: u64) -> u64 {
+        self.consecutive_failures = 0;
+        self.failed_targets.clear();
+        self.push_score(Score::Latency(latency))
+    }
+
     pub fn report_failure(&mut self) -> u64 {
+        self.consecutive_failures += 1;
+        if self.consecutive_failures >= OUTLIER_CONSECUTIVE_FAILURES {
+            self.eject();
+        }
+
         self.push_score(Score::Errored)
     }
+
+    /// Report a failure that may be caused by `target` instead of server, it is counted only if failures in a row
+    /// haven't included `target` yet
+    fn report_target_failure(&mut self, target: &str) -> bool {
+        if self.failed_targets.iter().any(|t| t == target) {
+            return false;
+        }
+        self.failed_targets.push(target.to_owned());
+        self.report_failure();
+        true
+    }
+
+    /// Eject for exponentially increasing time, `OUTLIER_BASE_EJECTION_TIME * 2^ejections`
+    fn eject(&mut self) {
+        let now = Instant::now();
+
+        if let Some(until) = self.ejected_until {
+            if now < until {
+                // Still ejected, failures are from connections made before ejecting
+                return;
+            }
+            if now - until >= OUTLIER_MAX_EJECTION_TIME {
+                // Have been healthy for long enough
+                self.ejections = 0;
+            }
+        }
+
+        let ejection_time = OUTLIER_BASE_EJECTION_TIME
+            .checked_mul(1 << self.ejections.min(16))
+            .map_or(OUTLIER_MAX_EJECTION_TIME, |t| t.min(OUTLIER_MAX_EJECTION_TIME));
+
+        warn!(
+            "{} server {} failed {} times in a row, ejected from load balancing for {:?}",
+            self.server_type, self.server_name, self.consecutive_failures, ejection_time
+        );
+
+        self.ejections += 1;
+        self.ejected_until = Some(now + ejection_time);
+        self.consecutive_failures = 0;
+        self.failed_targets.clear();
+    }
+
+    fn is_ejected(&self) -> bool {
+        match self.ejected_until {
+            Some(until) => Instant::now() < until,
+            None => false,
+        }
+    }
+
+    fn reported_within(&self, duration: Duration) -> bool {
+        match self.last_reported {
+            Some(t) => t.elapsed() < duration,
+            None => false,
+        }
+    }
 }
 
 /// Shared handle for mutating server's statistic data
 #[derive(Clone)]
-pub struct SharedServerStatisticData(Arc<Mutex<ServerStatisticData>>);
+pub struct SharedServerStatisticData(Arc<spin::Mutex<ServerStatisticData>>);
 
 impl SharedServerStatisticData {
-    fn new() -> SharedServerStatisticData {
-        SharedServerStatisticData(Arc::new(Mutex::new(ServerStatisticData::new())))
+    fn new(server_type: ServerType, server_name: String, max_rtt: u64) -> SharedServerStatisticData {
+        let data = ServerStatisticData::new(server_type, server_name, max_rtt);
+        SharedServerStatisticData(Arc::new(spin::Mutex::new(data)))
     }
 
-    pub async fn report_failure(&self) -> u64 {
-        let mut data = self.0.lock().await;
-        data.report_failure()
+    pub fn report_failure(&self) -> u64 {
+        self.0.lock().report_failure()
     }
 
-    async fn push_score(&self, score: Score) -> u64 {
-        let mut data = self.0.lock().await;
-        data.push_score(score)
+    fn report_success(&self, latency: u64) -> u64 {
+        self.0.lock().report_success(latency)
     }
 
-    pub async fn score(&self) -> u64 {
-        let data = self.0.lock().await;
-        data.score()
+    pub fn score(&self) -> u64 {
+        self.0.lock().score()
     }
 
-    async fn debug_string(&self) -> String {
-        format!("{:?}", self.0.lock().await)
+    /// Check if server is ejected from load balancing because of failing in a row
+    pub fn is_ejected(&self) -> bool {
+        self.0.lock().is_ejected()
+    }
+
+    /// Report that a connection to server is made in `latency`
+    ///
+    /// It is only a latency sample, failures in a row don't end until server responds. Probes are not skipped
+    /// because of it either, as connecting tells nothing about relaying.
+    pub fn report_connected(&self, latency: Duration) -> u64 {
+        self.0.lock().push_score(Score::Latency(latency.as_millis() as u64))
+    }
+
+    /// Create a reporter for outcomes of a connection relayed by server to `target`
+    ///
+    /// `target` is `None` for connections carrying relays to many targets, whose failures are all server's faults.
+    pub fn traffic_reporter(&self, target: Option<&Address>) -> TrafficReporter {
+        TrafficReporter {
+            data: self.clone(),
+            target: target.map(ToString::to_string),
+            request_sent: None,
+            responded: false,
+            failed: false,
+        }
+    }
+
+    fn reported_within(&self, duration: Duration) -> bool {
+        self.0.lock().reported_within(duration)
+    }
+
+    fn rtt(&self) -> u64 {
+        self.0.lock().rtt
+    }
+
+    fn debug_string(&self) -> String {
+        format!("{:?}", *self.0.lock())
     }
 }
 
-/// Server Statistic scores
+/// Reports outcomes of a connection relayed by server, as passive health checks
+///
+/// - Latency of the first byte responded, which is comparable with probes
+/// - Failures that are server's faults, AEAD decryption failures and resets before responding anything
+/// - Failures that may be caused by the target, closing before responding, which are counted once for each target,
+///   because servers close relays this way if targets are unreachable or blocked by ACL
+pub struct TrafficReporter {
+    data: SharedServerStatisticData,
+    target: Option<String>,
+    request_sent: Option<Instant>,
+    responded: bool,
+    failed: bool,
+}
+
+impl TrafficReporter {
+    /// Data have been sent to server
+    pub fn sent(&mut self) {
+        if self.request_sent.is_none() && !self.responded {
+            self.request_sent = Some(Instant::now());
+        }
+    }
+
+    /// `n` bytes have been received from server, 0 for EOF
+    pub fn received(&mut self, n: usize) {
+        if self.responded || self.failed {
+            return;
+        }
+
+        let request_sent = match self.request_sent {
+            Some(t) => t,
+            None => {
+                // Server speaks first, nothing to measure
+                self.responded = n > 0;
+                return;
+            }
+        };
+
+        if n == 0 {
+            // Server closed without responding anything
+            self.report_target_failure("closed before responding");
+        } else {
+            self.responded = true;
+            let latency = request_sent.elapsed().as_millis() as u64;
+            let mut data = self.data.0.lock();
+            data.last_reported = Some(Instant::now());
+            data.report_success(latency);
+        }
+    }
+
+    /// Reading from or writing to server failed with `err`
+    pub fn failed(&mut self, err: &io::Error) {
+        match err.kind() {
+            // AEAD decryption failures
+            ErrorKind::InvalidData => self.report_failure("decryption failed"),
+            // Targets may reset after responding
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe if !self.responded => {
+                self.report_failure("connection reset")
+            }
+            ErrorKind::UnexpectedEof if !self.responded => self.report_target_failure("closed before responding"),
+            // Timeouts of idle connections and refused mux streams are not failures of servers
+            _ => {}
+        }
+    }
+
+    fn report_target_failure(&mut self, reason: &str) {
+        let target = match self.target {
+            Some(ref t) => t.clone(),
+            None => return self.report_failure(reason),
+        };
+
+        if self.failed {
+            return;
+        }
+        self.failed = true;
+
+        let mut data = self.data.0.lock();
+        data.last_reported = Some(Instant::now());
+        if data.report_target_failure(&target) {
+            debug!(
+                "relaying {} via {} server {} failed, {}",
+                target, data.server_type, data.server_name, reason
+            );
+        } else {
+            trace!(
+                "relaying {} via {} server {} failed again, {}, not counted",
+                target,
+                data.server_type,
+                data.server_name,
+                reason
+            );
+        }
+    }
+
+    fn report_failure(&mut self, reason: &str) {
+        if self.failed {
+            return;
+        }
+        self.failed = true;
+
+        let mut data = self.data.0.lock();
+        debug!(
+            "relaying via {} server {} failed, {}",
+            data.server_type, data.server_name, reason
+        );
+        data.last_reported = Some(Instant::now());
+        data.report_failure();
+    }
+}
+
+/// Remote server shared by all local services, statistic data of TCP and UDP are kept separately
+pub struct RemoteServer {
+    svr_cfg: ServerConfig,
+    tcp: SharedServerStatisticData,
+    udp: SharedServerStatisticData,
+    connections: Arc<AtomicUsize>,
+    enabled: AtomicBool,
+    removed: AtomicBool,
+}
+
+type SharedRemoteServer = Arc<RemoteServer>;
+
+impl RemoteServer {
+    fn new(config: &Config, svr_cfg: ServerConfig) -> RemoteServer {
+        let max_rtt = config.balancer.check_timeout.as_millis() as u64;
+        let server_name = svr_cfg.to_string();
+
+        RemoteServer {
+            tcp: SharedServerStatisticData::new(ServerType::Tcp, server_name.clone(), max_rtt),
+            udp: SharedServerStatisticData::new(ServerType::Udp, server_name, max_rtt),
+            svr_cfg,
+            connections: Arc::new(AtomicUsize::new(0)),
+            enabled: AtomicBool::new(true),
+            removed: AtomicBool::new(false),
+        }
+    }
+
+    fn new_shared(config: &Config, svr_cfg: ServerConfig) -> SharedRemoteServer {
+        Arc::new(RemoteServer::new(config, svr_cfg))
+    }
+
+    fn server_config(&self) -> &ServerConfig {
+        &self.svr_cfg
+    }
+
+    /// Statistic data of `server_type`
+    fn data(&self, server_type: ServerType) -> &SharedServerStatisticData {
+        match server_type {
+            ServerType::Tcp => &self.tcp,
+            ServerType::Udp => &self.udp,
+        }
+    }
+
+    fn score(&self, server_type: ServerType) -> u64 {
+        self.data(server_type).score()
+    }
+
+    /// Check if server is ejected from load balancing of `server_type` because of failing in a row
+    fn is_ejected(&self, server_type: ServerType) -> bool {
+        self.data(server_type).is_ejected()
+    }
+
+    /// Check if server is enabled, disabled servers are never picked
+    fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed)
+    }
+
+    fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::Relaxed)
+    }
+
+    fn set_removed(&self) {
+        self.removed.store(true, Ordering::Relaxed)
+    }
+
+    /// Number of active connections via this server, of both TCP and UDP
+    fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    fn active_connection(&self) -> ActiveConnection {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self.connections.clone())
+    }
+}
+
+/// Server of a `PingBalancer`, with data customized by the balancer
 pub struct ServerStatistic<S: ServerData> {
     server: S,
     context: SharedContext,
-    server_idx: usize,
-    data: SharedServerStatisticData,
+    server_type: ServerType,
+    remote: SharedRemoteServer,
 }
 
 pub type SharedServerStatistic<S> = Arc<ServerStatistic<S>>;
 
 impl<S: ServerData> ServerStatistic<S> {
-    fn new(context: SharedContext, server_idx: usize) -> ServerStatistic<S> {
-        let data = SharedServerStatisticData::new();
-
+    fn new(context: SharedContext, server_type: ServerType, remote: SharedRemoteServer) -> ServerStatistic<S> {
         ServerStatistic {
-            server: S::create_server(&context, server_idx, &data),
+            server: S::create_server(&context, remote.server_config(), remote.data(server_type)),
             context,
-            server_idx,
-            data,
+            server_type,
+            remote,
         }
     }
 
-    fn new_shared(context: SharedContext, server_idx: usize) -> SharedServerStatistic<S> {
-        Arc::new(ServerStatistic::new(context, server_idx))
-    }
-
     pub fn server_config(&self) -> &ServerConfig {
-        self.context.server_config(self.server_idx)
+        self.remote.server_config()
     }
 
     #[allow(dead_code)]
@@ -241,20 +562,23 @@ impl<S: ServerData> ServerStatistic<S> {
         self.context.config()
     }
 
-    async fn push_score(&self, score: Score) -> u64 {
-        self.data.push_score(score).await
+    /// Statistic data of the balancer's server type, for reporting outcomes of connections
+    pub fn data(&self) -> &SharedServerStatisticData {
+        self.remote.data(self.server_type)
     }
 
-    pub async fn score(&self) -> u64 {
-        self.data.score().await
+    /// Count an active connection via this server, until the returned guard is dropped
+    pub fn active_connection(&self) -> ActiveConnection {
+        self.remote.active_connection()
     }
+}
 
-    pub async fn report_failure(&self) -> u64 {
-        self.data.report_failure().await
-    }
+/// Guard of an active connection, see `ServerStatistic::active_connection`
+pub struct ActiveConnection(Arc<AtomicUsize>);
 
-    async fn data_debug_string(&self) -> String {
-        self.data.debug_string().await
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
     }
 }
 
@@ -279,221 +603,622 @@ impl fmt::Display for ServerType {
     }
 }
 
-struct BestServer<S: ServerData> {
-    servers: Vec<SharedServerStatistic<S>>,
-    best_idx: AtomicUsize,
+/// States of choosing servers for one type
+struct Ranking {
+    best_idx: usize,
+    /// Indexes of servers ordered by scores, the best one first
+    order: Vec<usize>,
+    /// Latest scores of servers
+    scores: Vec<u64>,
+    /// Current weights of smooth weighted round robin
+    round_robin: Vec<i64>,
 }
 
-type SharedBestServer<S> = Arc<BestServer<S>>;
+impl Ranking {
+    fn new() -> Ranking {
+        Ranking {
+            best_idx: 0,
+            order: Vec::new(),
+            scores: Vec::new(),
+            round_robin: Vec::new(),
+        }
+    }
+}
 
-impl<S: ServerData> BestServer<S> {
-    fn new(servers: Vec<SharedServerStatistic<S>>) -> BestServer<S> {
-        BestServer {
+/// Servers of the load balancer, all indexes are of `servers`
+///
+/// TCP and UDP are ranked separately, a server may be fast for TCP but lossy for UDP
+struct ServerList {
+    servers: Vec<SharedRemoteServer>,
+    tcp: Ranking,
+    udp: Ranking,
+    /// Points of servers on the hash ring, `(hash, server index)` ordered by hash
+    hash_ring: Vec<(u64, usize)>,
+}
+
+impl ServerList {
+    fn new(servers: Vec<SharedRemoteServer>) -> ServerList {
+        let mut list = ServerList {
             servers,
-            best_idx: AtomicUsize::new(0),
+            tcp: Ranking::new(),
+            udp: Ranking::new(),
+            hash_ring: Vec::new(),
+        };
+        list.rebuild();
+        list
+    }
+
+    fn ranking(&self, server_type: ServerType) -> &Ranking {
+        match server_type {
+            ServerType::Tcp => &self.tcp,
+            ServerType::Udp => &self.udp,
         }
     }
 
-    fn new_shared(servers: Vec<SharedServerStatistic<S>>) -> SharedBestServer<S> {
-        Arc::new(BestServer::new(servers))
+    /// Rebuild states indexed by servers, after servers are changed
+    fn rebuild(&mut self) {
+        self.tcp.round_robin = vec![0; self.servers.len()];
+        self.udp.round_robin = vec![0; self.servers.len()];
+        self.hash_ring = build_hash_ring(&self.servers);
+        self.recalculate_best_server(ServerType::Tcp);
+        self.recalculate_best_server(ServerType::Udp);
+    }
+
+    fn position(&self, addr: &ServerAddr) -> Option<usize> {
+        let addr = addr.to_string();
+        self.servers
+            .iter()
+            .position(|svr| svr.server_config().addr().to_string() == addr)
+    }
+
+    fn pick_servers(
+        &mut self,
+        server_type: ServerType,
+        strategy: BalancerStrategy,
+        client_addr: Option<&SocketAddr>,
+        target: Option<&Address>,
+    ) -> Vec<SharedRemoteServer> {
+        let chosen_idx = self.choose_server(server_type, strategy, client_addr, target);
+
+        let mut servers = Vec::with_capacity(self.servers.len());
+        servers.push(self.servers[chosen_idx].clone());
+
+        // The others are ordered by scores, ejected ones are the last and disabled ones are never used
+        let (available, ejected): (Vec<usize>, Vec<usize>) = self
+            .ranking(server_type)
+            .order
+            .iter()
+            .filter(|idx| **idx != chosen_idx && self.servers[**idx].is_enabled())
+            .partition(|idx| !self.servers[**idx].is_ejected(server_type));
+        for idx in available.into_iter().chain(ejected) {
+            servers.push(self.servers[idx].clone());
+        }
+
+        servers
     }
 
-    fn pick_server(&self) -> SharedServerStatistic<S> {
-        let idx = self.best_idx.load(Ordering::Relaxed);
-        self.servers[idx].clone()
+    fn choose_server(
+        &mut self,
+        server_type: ServerType,
+        strategy: BalancerStrategy,
+        client_addr: Option<&SocketAddr>,
+        target: Option<&Address>,
+    ) -> usize {
+        let enabled = self.servers.iter().map(|svr| svr.is_enabled()).collect::<Vec<_>>();
+        let mut available = self
+            .servers
+            .iter()
+            .zip(&enabled)
+            .map(|(svr, enabled)| *enabled && !svr.is_ejected(server_type))
+            .collect::<Vec<_>>();
+        if !available.contains(&true) {
+            // Ejections are ignored if all servers are ejected, better than refusing all connections
+            available = enabled;
+        }
+
+        match strategy {
+            BalancerStrategy::Best => self.choose_best(server_type, &available),
+            BalancerStrategy::RoundRobin => self.choose_round_robin(server_type, &available),
+            BalancerStrategy::WeightedRandom => self.choose_weighted_random(server_type, &available),
+            BalancerStrategy::LeastConnections => self.choose_least_connections(server_type, &available),
+            BalancerStrategy::ConsistentHash => match target {
+                Some(target) => self.choose_by_hash(&target_host(target), &available),
+                None => self.choose_best(server_type, &available),
+            },
+            BalancerStrategy::Sticky => match client_addr {
+                Some(addr) => self.choose_by_hash(&addr.ip(), &available),
+                None => self.choose_best(server_type, &available),
+            },
+        }
     }
 
-    async fn recalculate_best_server(&self) -> Option<(usize, usize)> {
-        let current_best_idx = self.best_idx.load(Ordering::Relaxed);
+    fn choose_best(&self, server_type: ServerType, available: &[bool]) -> usize {
+        let ranking = self.ranking(server_type);
+        if available[ranking.best_idx] {
+            return ranking.best_idx;
+        }
 
-        let mut best_idx = 0;
-        let mut best_score = u64::max_value();
+        *ranking
+            .order
+            .iter()
+            .find(|idx| available[**idx])
+            .expect("at least one available server")
+    }
 
+    /// Smooth weighted round robin, which is also used by nginx
+    fn choose_round_robin(&mut self, server_type: ServerType, available: &[bool]) -> usize {
+        let current = match server_type {
+            ServerType::Tcp => &mut self.tcp.round_robin,
+            ServerType::Udp => &mut self.udp.round_robin,
+        };
+
+        let mut total = 0;
+        let mut chosen_idx = None;
         for (idx, svr) in self.servers.iter().enumerate() {
-            let score = svr.score().await;
-            if score < best_score {
-                best_idx = idx;
-                best_score = score;
+            if !available[idx] {
+                continue;
+            }
+
+            let weight = i64::from(svr.server_config().weight());
+            current[idx] += weight;
+            total += weight;
+
+            match chosen_idx {
+                Some(chosen_idx) if current[idx] <= current[chosen_idx] => {}
+                _ => chosen_idx = Some(idx),
             }
         }
 
-        if best_idx != current_best_idx {
-            self.best_idx.store(best_idx, Ordering::Relaxed);
+        let chosen_idx = chosen_idx.expect("at least one available server");
+        current[chosen_idx] -= total;
 
-            Some((current_best_idx, best_idx))
-        } else {
-            None
+        chosen_idx
+    }
+
+    fn choose_weighted_random(&self, server_type: ServerType, available: &[bool]) -> usize {
+        let ranking = self.ranking(server_type);
+
+        // Scores are in [0, MAX_SCORE], the lower the better
+        let weight_of = |idx: usize| {
+            if !available[idx] {
+                return 0;
+            }
+            let score = ranking.scores[idx].min(MAX_SCORE);
+            u64::from(self.servers[idx].server_config().weight()) * (MAX_SCORE + 1 - score)
+        };
+
+        let total = (0..self.servers.len()).map(weight_of).sum::<u64>();
+        let mut point = rand::thread_rng().gen_range(0, total);
+        for idx in 0..self.servers.len() {
+            let weight = weight_of(idx);
+            if point < weight {
+                return idx;
+            }
+            point -= weight;
+        }
+
+        unreachable!("random point {} out of total weight {}", point, total)
+    }
+
+    fn choose_least_connections(&self, server_type: ServerType, available: &[bool]) -> usize {
+        let mut candidates = self.ranking(server_type).order.iter().filter(|idx| available[**idx]);
+
+        // Compares connections / weight, the server with better score wins if they are the same
+        let mut chosen_idx = *candidates.next().expect("at least one available server");
+        for idx in candidates {
+            let svr = &self.servers[*idx];
+            let chosen = &self.servers[chosen_idx];
+
+            let lhs = svr.connections() as u64 * u64::from(chosen.server_config().weight());
+            let rhs = chosen.connections() as u64 * u64::from(svr.server_config().weight());
+            if lhs < rhs {
+                chosen_idx = *idx;
+            }
         }
+
+        chosen_idx
     }
 
-    fn best_server_idx(&self) -> usize {
-        self.best_idx.load(Ordering::Relaxed)
+    fn choose_by_hash<K: Hash + ?Sized>(&self, key: &K, available: &[bool]) -> usize {
+        let hash = hash_of(key);
+        let pos = match self.hash_ring.binary_search_by_key(&hash, |(h, _)| *h) {
+            Ok(pos) => pos,
+            // The first point after hash, clockwise on the ring
+            Err(pos) => pos % self.hash_ring.len(),
+        };
+
+        // Keys of ejected servers are moved to the next available ones, the others stay
+        let (head, tail) = self.hash_ring.split_at(pos);
+        tail.iter()
+            .chain(head)
+            .map(|(_, idx)| *idx)
+            .find(|idx| available[*idx])
+            .expect("at least one available server")
+    }
+
+    /// Rank servers by scores of `server_type`, returns names of the old and the new best server if it is switched
+    fn recalculate_best_server(&mut self, server_type: ServerType) -> Option<(String, String)> {
+        let old_best = self
+            .servers
+            .get(self.ranking(server_type).best_idx)
+            .map(|svr| svr.server_config().to_string());
+
+        let scores = self.servers.iter().map(|svr| svr.score(server_type)).collect::<Vec<_>>();
+        let unavailable = self
+            .servers
+            .iter()
+            .map(|svr| (!svr.is_enabled(), svr.is_ejected(server_type)))
+            .collect::<Vec<_>>();
+
+        // Stable, servers with the same score keep the order in configuration
+        let mut order = (0..self.servers.len()).collect::<Vec<_>>();
+        order.sort_by_key(|idx| (unavailable[*idx], scores[*idx]));
+
+        let ranking = match server_type {
+            ServerType::Tcp => &mut self.tcp,
+            ServerType::Udp => &mut self.udp,
+        };
+        ranking.best_idx = order.first().cloned().unwrap_or(0);
+        ranking.order = order;
+        ranking.scores = scores;
+        let best_idx = ranking.best_idx;
+
+        let new_best = self.servers.get(best_idx).map(|svr| svr.server_config().to_string());
+        match (old_best, new_best) {
+            (Some(old_best), Some(new_best)) if old_best != new_best => Some((old_best, new_best)),
+            _ => None,
+        }
     }
 }
 
-/// Load balancer based on pinging latencies of all servers
-#[derive(Clone)]
-pub struct PingBalancer<S: ServerData> {
-    best: SharedBestServer<S>,
+type SharedServerList = Arc<spin::Mutex<ServerList>>;
+
+/// Probing states of one type of servers
+struct Probing {
+    /// Servers are only probed for types that are used by local services
+    subscribed: AtomicBool,
+    /// Probing is only required if there are more than 1 servers
+    checking: AtomicBool,
 }
 
-impl<S: ServerData + 'static> PingBalancer<S> {
-    /// Create a PingBalancer
-    pub async fn new(context: SharedContext, server_type: ServerType) -> PingBalancer<S> {
-        let server_count = context.config().server.len();
-        let mut servers = Vec::with_capacity(server_count);
+impl Probing {
+    fn new() -> Probing {
+        Probing {
+            subscribed: AtomicBool::new(false),
+            checking: AtomicBool::new(false),
+        }
+    }
+}
 
-        // Check only required if servers count > 1, otherwise, always use the first one
-        let check_required = server_count > 1;
-        // Barrier count = current + probing tasks
-        let check_barrier = Arc::new(Barrier::new(1 + server_count));
+/// Load balancer shared by all local services of a process
+///
+/// Each server is probed once per interval for each type, no matter how many local services are using it.
+/// Local services pick servers from it with `PingBalancer`.
+pub struct BalancerService {
+    strategy: BalancerStrategy,
+    list: SharedServerList,
+    tcp: Probing,
+    udp: Probing,
+}
 
-        for idx in 0..server_count {
-            let stat = ServerStatistic::<S>::new_shared(context.clone(), idx);
+pub type SharedBalancerService = Arc<BalancerService>;
+
+impl BalancerService {
+    /// Create with servers in configuration, they are not probed until subscribed
+    pub fn new(config: &Config) -> BalancerService {
+        let servers = config
+            .server
+            .iter()
+            .map(|svr_cfg| RemoteServer::new_shared(config, svr_cfg.clone()))
+            .collect();
+
+        BalancerService {
+            strategy: config.balancer.strategy,
+            list: Arc::new(spin::Mutex::new(ServerList::new(servers))),
+            tcp: Probing::new(),
+            udp: Probing::new(),
+        }
+    }
 
-            if check_required {
-                let stat = stat.clone();
-                let context = context.clone();
-                let check_barrier = check_barrier.clone();
+    pub fn new_shared(config: &Config) -> SharedBalancerService {
+        Arc::new(BalancerService::new(config))
+    }
 
-                // Start a background task for probing
-                tokio::spawn(async move {
-                    // Check once for initializing data
-                    PingBalancer::<S>::check_update_score(&stat, server_type).await;
+    fn probing(&self, server_type: ServerType) -> &Probing {
+        match server_type {
+            ServerType::Tcp => &self.tcp,
+            ServerType::Udp => &self.udp,
+        }
+    }
 
-                    trace!(
-                        "started latency probing task for server {}, initial score {}",
-                        stat.server_config().addr(),
-                        stat.score().await,
-                    );
+    fn is_subscribed(&self, server_type: ServerType) -> bool {
+        self.probing(server_type).subscribed.load(Ordering::Acquire)
+    }
 
-                    check_barrier.wait().await;
+    fn server_count(&self) -> usize {
+        self.list.lock().servers.len()
+    }
 
-                    while context.server_running() {
-                        PingBalancer::<S>::check_update_score(&stat, server_type).await;
-                        time::delay_for(Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC)).await;
-                    }
+    fn pick_server(
+        &self,
+        server_type: ServerType,
+        client_addr: Option<&SocketAddr>,
+        target: Option<&Address>,
+    ) -> SharedRemoteServer {
+        let mut list = self.list.lock();
+        let idx = list.choose_server(server_type, self.strategy, client_addr, target);
+        list.servers[idx].clone()
+    }
 
-                    debug!(
-                        "probing task for remote {} server {} exited",
-                        server_type,
-                        stat.server_config().addr()
-                    );
-                });
-            }
+    fn pick_servers(
+        &self,
+        server_type: ServerType,
+        client_addr: Option<&SocketAddr>,
+        target: Option<&Address>,
+    ) -> Vec<SharedRemoteServer> {
+        self.list
+            .lock()
+            .pick_servers(server_type, self.strategy, client_addr, target)
+    }
+
+    /// Use servers for `server_type`, which starts probing them if there are more than 1 servers
+    ///
+    /// It returns after all servers are checked once, if probing is started by this call
+    async fn subscribe(&self, context: &SharedContext, server_type: ServerType) {
+        self.probing(server_type).subscribed.store(true, Ordering::Release);
 
-            servers.push(stat);
+        // Check only required if servers count > 1, otherwise, always use the first one
+        if self.server_count() > 1 {
+            self.start_checking(context, server_type).await;
         }
+    }
 
-        let best = BestServer::new_shared(servers);
+    /// Start probing all servers and choosing the best one of `server_type` periodically
+    ///
+    /// It returns after all servers are checked once
+    fn start_checking(
+        &self,
+        context: &SharedContext,
+        server_type: ServerType,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        let started = self.probing(server_type).checking.swap(true, Ordering::AcqRel);
+        let context = context.clone();
+        let list = self.list.clone();
+
+        async move {
+            if !started {
+                BalancerService::checking_task(context, server_type, list).await;
+            }
+        }
+    }
 
-        if check_required {
-            // Wait all tasks start (run at least one round)
-            check_barrier.wait().await;
-            trace!("all latency probing tasks are started, creating best server choosing task");
+    async fn checking_task(context: SharedContext, server_type: ServerType, list: SharedServerList) {
+        let servers = list.lock().servers.clone();
 
-            // Reinitialize a Barrier for waiting choosing task
-            let check_barrier = Arc::new(Barrier::new(2));
-            let best = best.clone();
+        // Barrier count = current + probing tasks
+        let check_barrier = Arc::new(Barrier::new(1 + servers.len()));
+        for remote in servers {
+            BalancerService::spawn_probing(context.clone(), server_type, remote, Some(check_barrier.clone()));
+        }
 
-            {
-                let context = context.clone();
-                let check_barrier = check_barrier.clone();
+        // Wait all tasks start (run at least one round)
+        check_barrier.wait().await;
+        trace!(
+            "all {} latency probing tasks are started, creating best server choosing task",
+            server_type
+        );
 
-                tokio::spawn(async move {
-                    // Check once for initializing data
-                    best.recalculate_best_server().await;
+        // Reinitialize a Barrier for waiting choosing task
+        let check_barrier = Arc::new(Barrier::new(2));
 
-                    trace!(
-                        "started best server choosing task, chosen server index {}",
-                        best.best_server_idx()
-                    );
+        {
+            let check_barrier = check_barrier.clone();
 
-                    check_barrier.wait().await;
+            tokio::spawn(async move {
+                // Check once for initializing data
+                list.lock().recalculate_best_server(server_type);
 
-                    while context.server_running() {
-                        if let Some((old_idx, new_idx)) = best.recalculate_best_server().await {
-                            info!(
-                                "switched {} server from {} to {}",
-                                server_type,
-                                context.server_config(old_idx).addr(),
-                                context.server_config(new_idx).addr()
-                            );
-                        }
+                trace!(
+                    "started best {} server choosing task, chosen server index {}",
+                    server_type,
+                    list.lock().ranking(server_type).best_idx
+                );
 
-                        time::delay_for(Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC)).await;
+                check_barrier.wait().await;
+
+                while context.server_running() {
+                    let switched = list.lock().recalculate_best_server(server_type);
+                    if let Some((old_name, new_name)) = switched {
+                        info!("switched {} server from {} to {}", server_type, old_name, new_name);
                     }
-                });
-            }
 
-            // Wait for choosing task to check at least once
-            check_barrier.wait().await;
+                    time::delay_for(context.config().balancer.check_interval).await;
+                }
+            });
         }
 
-        PingBalancer { best }
+        // Wait for choosing task to check at least once
+        check_barrier.wait().await;
+    }
+
+    /// Start a background task for probing `remote` for `server_type`, until it is removed
+    fn spawn_probing(
+        context: SharedContext,
+        server_type: ServerType,
+        remote: SharedRemoteServer,
+        check_barrier: Option<Arc<Barrier>>,
+    ) {
+        tokio::spawn(async move {
+            // Check once for initializing data
+            BalancerService::check_update_score(&context, &remote, server_type).await;
+
+            trace!(
+                "started {} latency probing task for server {}, initial score {}",
+                server_type,
+                remote.server_config(),
+                remote.score(server_type),
+            );
+
+            if let Some(check_barrier) = check_barrier {
+                check_barrier.wait().await;
+            }
+
+            let check_interval = context.config().balancer.check_interval;
+            while context.server_running() && !remote.is_removed() {
+                // Outcomes of real traffic are fresher than probing
+                if remote.data(server_type).reported_within(check_interval) {
+                    trace!(
+                        "skipped probing {} server {}, reported by real traffic recently",
+                        server_type,
+                        remote.server_config()
+                    );
+                } else {
+                    BalancerService::check_update_score(&context, &remote, server_type).await;
+                }
+                time::delay_for(check_interval).await;
+            }
+
+            debug!(
+                "probing task for remote {} server {} exited",
+                server_type,
+                remote.server_config()
+            );
+        });
     }
 
-    async fn check_update_score(stat: &ServerStatistic<S>, server_type: ServerType) {
-        let score = match PingBalancer::<S>::check_delay(stat, server_type).await {
-            Ok(d) => stat.push_score(Score::Latency(d)).await,
-            Err(..) => stat.push_score(Score::Errored).await, // Penalty
+    /// Probe servers added at runtime, for all types that are being checked
+    fn probe_server(&self, context: &SharedContext, remote: &SharedRemoteServer) {
+        for &server_type in &[ServerType::Tcp, ServerType::Udp] {
+            if self.probing(server_type).checking.load(Ordering::Acquire) {
+                BalancerService::spawn_probing(context.clone(), server_type, remote.clone(), None);
+            } else if self.is_subscribed(server_type) && self.server_count() > 1 {
+                // Probe all servers, including the new one
+                tokio::spawn(self.start_checking(context, server_type));
+            }
+        }
+    }
+
+    async fn check_update_score(context: &SharedContext, remote: &RemoteServer, server_type: ServerType) {
+        let data = remote.data(server_type);
+        let score = match BalancerService::check_delay(context, remote.server_config(), server_type).await {
+            Ok(d) => data.report_success(d),
+            Err(..) => data.report_failure(), // Penalty
         };
 
         debug!(
             "updated remote {} server {} (score: {})",
             server_type,
-            stat.server_config().addr(),
+            remote.server_config(),
             score
         );
 
         trace!(
             "{} server {} {}",
             server_type,
-            stat.server_config().addr(),
-            stat.data_debug_string().await
+            remote.server_config(),
+            data.debug_string()
         );
     }
 
-    async fn check_request_tcp(stat: &ServerStatistic<S>) -> io::Result<()> {
-        static GET_BODY: &[u8] =
-            b"GET /generate_204 HTTP/1.1\r\nHost: dl.google.com\r\nConnection: close\r\nAccept: */*\r\n\r\n";
+    async fn check_request_tcp(context: &SharedContext, svr_cfg: &ServerConfig) -> io::Result<()> {
+        let balancer = &context.config().balancer;
+
+        let url = match balancer.tcp_probe {
+            TcpProbe::Http(ref url) => url,
+            TcpProbe::Connect => {
+                // Target address won't be sent without writing anything
+                let addr = Address::DomainNameAddress("localhost".to_owned(), 80);
+                TcpServerClient::connect(context.clone(), &addr, svr_cfg).await?;
+                return Ok(());
+            }
+        };
+
+        // Validated while loading configuration
+        let addr = balancer.tcp_probe.target_addr().expect("tcp probe target address");
+
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_owned(),
+        };
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_owned(),
+        };
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nAccept: */*\r\n\r\n",
+            path, host
+        );
 
-        let addr = Address::DomainNameAddress("dl.google.com".to_owned(), 80);
+        let mut stream = TcpServerClient::connect(context.clone(), &addr, svr_cfg).await?;
+        stream.write_all(request.as_bytes()).await?;
 
-        let mut stream = TcpServerClient::connect(stat.clone_context(), &addr, stat.server_config()).await?;
-        stream.write_all(GET_BODY).await?;
+        let expected_status = match balancer.tcp_probe_status {
+            Some(status) => status,
+            None => {
+                let mut buf = [0u8; 1];
+                stream.read_exact(&mut buf).await?;
+                return Ok(());
+            }
+        };
 
-        let mut buf = [0u8; 1];
+        // Status line, "HTTP/1.1 204 No Content"
+        let mut buf = [0u8; 12];
         stream.read_exact(&mut buf).await?;
 
-        Ok(())
+        let status = if buf.starts_with(b"HTTP/") && buf[8] == b' ' {
+            std::str::from_utf8(&buf[9..12]).ok().and_then(|s| s.parse::<u16>().ok())
+        } else {
+            None
+        };
+
+        match status {
+            Some(status) if status == expected_status => Ok(()),
+            Some(status) => {
+                let err = Error::new(
+                    ErrorKind::Other,
+                    format!("probe {} responded status {}, expecting {}", url, status, expected_status),
+                );
+                Err(err)
+            }
+            None => {
+                let err = Error::new(ErrorKind::Other, format!("probe {} responded invalid HTTP status", url));
+                Err(err)
+            }
+        }
     }
 
-    async fn check_request_udp(stat: &ServerStatistic<S>) -> io::Result<()> {
-        static DNS_QUERY: &[u8] = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x05\x62\x61\x69\x64\x75\x03\x63\x6f\x6d\x00\x00\x01\x00\x01";
+    async fn check_request_udp(context: &SharedContext, svr_cfg: &ServerConfig) -> io::Result<()> {
+        let balancer = &context.config().balancer;
 
-        let addr = Address::SocketAddress(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53));
+        // Validated while loading configuration
+        let name = Name::from_ascii(&balancer.udp_probe_name).expect("udp probe name");
 
-        let mut client = UdpServerClient::new(stat.server_config()).await?;
-        client.send_to(stat.context(), &addr, DNS_QUERY).await?;
-        let _ = client.recv_from(stat.context()).await?;
+        let mut query = Message::new();
+        query.set_id(0x1234);
+        query.set_recursion_desired(true);
+        query.add_query(Query::query(name, RecordType::A));
+        let query = query.to_vec()?;
+
+        let mut client = UdpServerClient::new(svr_cfg).await?;
+        client.send_to(context, &balancer.udp_probe_dns, &query).await?;
+        let _ = client.recv_from(context).await?;
 
         Ok(())
     }
 
-    async fn check_request(stat: &ServerStatistic<S>, server_type: ServerType) -> io::Result<()> {
+    async fn check_request(context: &SharedContext, svr_cfg: &ServerConfig, server_type: ServerType) -> io::Result<()> {
         match server_type {
-            ServerType::Tcp => PingBalancer::<S>::check_request_tcp(stat).await,
-            ServerType::Udp => PingBalancer::<S>::check_request_udp(stat).await,
+            ServerType::Tcp => BalancerService::check_request_tcp(context, svr_cfg).await,
+            ServerType::Udp => BalancerService::check_request_udp(context, svr_cfg).await,
         }
     }
 
-    async fn check_delay(stat: &ServerStatistic<S>, server_type: ServerType) -> io::Result<u64> {
+    async fn check_delay(context: &SharedContext, svr_cfg: &ServerConfig, server_type: ServerType) -> io::Result<u64> {
         let start = Instant::now();
 
-        // Send HTTP GET and read the first byte
-        let timeout = Duration::from_secs(DEFAULT_CHECK_TIMEOUT_SEC);
-        let res = time::timeout(timeout, PingBalancer::<S>::check_request(stat, server_type)).await;
+        // Run the probe through server
+        let timeout = context.config().balancer.check_timeout;
+        let res = time::timeout(timeout, BalancerService::check_request(context, svr_cfg, server_type)).await;
 
         let elapsed = Instant::now() - start;
         let elapsed = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()); // Converted to ms
@@ -503,18 +1228,13 @@ impl<S: ServerData + 'static> PingBalancer<S> {
                 trace!(
                     "checked remote {} server {} latency with {} ms",
                     server_type,
-                    stat.server_config().addr(),
+                    svr_cfg,
                     elapsed
                 );
                 Ok(elapsed)
             }
             Ok(Err(err)) => {
-                debug!(
-                    "failed to check {} server {}, error: {}",
-                    server_type,
-                    stat.server_config().addr(),
-                    err
-                );
+                debug!("failed to check {} server {}, error: {}", server_type, svr_cfg, err);
 
                 // NOTE: connection / handshake error, server is down
                 Err(err)
@@ -524,7 +1244,7 @@ impl<S: ServerData + 'static> PingBalancer<S> {
                 trace!(
                     "checked remote {} server {} latency timeout, elapsed {} ms",
                     server_type,
-                    stat.server_config().addr(),
+                    svr_cfg,
                     elapsed
                 );
 
@@ -533,14 +1253,359 @@ impl<S: ServerData + 'static> PingBalancer<S> {
             }
         }
     }
+
+    /// Add a new server
+    pub fn add_server(&self, context: &SharedContext, svr_cfg: ServerConfig) -> io::Result<()> {
+        let remote = {
+            let mut list = self.list.lock();
+            if list.position(svr_cfg.addr()).is_some() {
+                let err = Error::new(ErrorKind::Other, format!("server {} already exists", svr_cfg.addr()));
+                return Err(err);
+            }
+
+            let remote = RemoteServer::new_shared(context.config(), svr_cfg);
+            list.servers.push(remote.clone());
+            list.rebuild();
+            remote
+        };
+
+        info!("added remote server {}", remote.server_config());
+
+        self.probe_server(context, &remote);
+        Ok(())
+    }
+
+    /// Remove server with `addr`, connections via it are kept until they finish
+    pub fn remove_server(&self, addr: &ServerAddr) -> io::Result<()> {
+        let mut list = self.list.lock();
+        let idx = match list.position(addr) {
+            Some(idx) => idx,
+            None => {
+                let err = Error::new(ErrorKind::Other, format!("server {} doesn't exist", addr));
+                return Err(err);
+            }
+        };
+
+        let has_other_enabled = list
+            .servers
+            .iter()
+            .enumerate()
+            .any(|(i, svr)| i != idx && svr.is_enabled());
+        if !has_other_enabled {
+            let err = Error::new(ErrorKind::Other, "at least one server should be enabled");
+            return Err(err);
+        }
+
+        let remote = list.servers.remove(idx);
+        remote.set_removed();
+        list.rebuild();
+
+        info!("removed remote server {}", remote.server_config());
+        Ok(())
+    }
+
+    /// Replace server with `addr` by `svr_cfg` in place, which is enabled if the replaced one is
+    pub fn replace_server(&self, context: &SharedContext, addr: &ServerAddr, svr_cfg: ServerConfig) -> io::Result<()> {
+        let (replaced, remote) = {
+            let mut list = self.list.lock();
+            let idx = match list.position(addr) {
+                Some(idx) => idx,
+                None => {
+                    let err = Error::new(ErrorKind::Other, format!("server {} doesn't exist", addr));
+                    return Err(err);
+                }
+            };
+
+            match list.position(svr_cfg.addr()) {
+                Some(pos) if pos != idx => {
+                    let err = Error::new(ErrorKind::Other, format!("server {} already exists", svr_cfg.addr()));
+                    return Err(err);
+                }
+                _ => {}
+            }
+
+            let remote = RemoteServer::new_shared(context.config(), svr_cfg);
+            remote.set_enabled(list.servers[idx].is_enabled());
+
+            let replaced = mem::replace(&mut list.servers[idx], remote.clone());
+            replaced.set_removed();
+            list.rebuild();
+            (replaced, remote)
+        };
+
+        info!(
+            "replaced remote server {} with {}",
+            replaced.server_config(),
+            remote.server_config()
+        );
+
+        self.probe_server(context, &remote);
+        Ok(())
+    }
+
+    /// Enable or disable server with `addr`
+    pub fn set_server_enabled(&self, addr: &ServerAddr, enabled: bool) -> io::Result<()> {
+        let mut list = self.list.lock();
+        let idx = match list.position(addr) {
+            Some(idx) => idx,
+            None => {
+                let err = Error::new(ErrorKind::Other, format!("server {} doesn't exist", addr));
+                return Err(err);
+            }
+        };
+
+        if !enabled {
+            let has_other_enabled = list
+                .servers
+                .iter()
+                .enumerate()
+                .any(|(i, svr)| i != idx && svr.is_enabled());
+            if !has_other_enabled {
+                let err = Error::new(ErrorKind::Other, "at least one server should be enabled");
+                return Err(err);
+            }
+        }
+
+        list.servers[idx].set_enabled(enabled);
+        list.recalculate_best_server(ServerType::Tcp);
+        list.recalculate_best_server(ServerType::Udp);
+
+        info!(
+            "{} remote server {}",
+            if enabled { "enabled" } else { "disabled" },
+            list.servers[idx].server_config()
+        );
+        Ok(())
+    }
+
+    /// Status of all servers, in order of being added
+    ///
+    /// Statistics are only available for types used by local services
+    pub fn server_status(&self) -> Vec<ServerStatus> {
+        let statistic_of = |svr: &RemoteServer, server_type: ServerType| {
+            if !self.is_subscribed(server_type) {
+                return None;
+            }
+
+            Some(ServerStatisticStatus {
+                ejected: svr.is_ejected(server_type),
+                score: svr.score(server_type),
+                rtt: svr.data(server_type).rtt(),
+            })
+        };
+
+        let list = self.list.lock();
+        list.servers
+            .iter()
+            .map(|svr| ServerStatus {
+                addr: svr.server_config().addr().clone(),
+                remarks: svr.server_config().remarks().map(ToOwned::to_owned),
+                id: svr.server_config().id().map(ToOwned::to_owned),
+                enabled: svr.is_enabled(),
+                connections: svr.connections(),
+                tcp: statistic_of(svr, ServerType::Tcp),
+                udp: statistic_of(svr, ServerType::Udp),
+            })
+            .collect()
+    }
+}
+
+/// Statistic of a server for one type
+#[derive(Debug, Clone)]
+pub struct ServerStatisticStatus {
+    /// Ejected temporarily because of failing in a row
+    pub ejected: bool,
+    /// Score in [0, 1000], the lower the better
+    pub score: u64,
+    /// Median of latency in millisec
+    pub rtt: u64,
+}
+
+/// Status of a server in load balancer
+#[derive(Debug, Clone)]
+pub struct ServerStatus {
+    /// Server's address
+    pub addr: ServerAddr,
+    /// Server's remarks
+    pub remarks: Option<String>,
+    /// Server's unique ID
+    pub id: Option<String>,
+    /// Disabled servers are not used until enabled again
+    pub enabled: bool,
+    /// Number of active connections
+    pub connections: usize,
+    /// Statistic of TCP, if it is used
+    pub tcp: Option<ServerStatisticStatus>,
+    /// Statistic of UDP, if it is used
+    pub udp: Option<ServerStatisticStatus>,
+}
+
+/// Statistic of probing a server in a row, see `probe_server`
+#[derive(Debug, Clone)]
+pub struct ProbeStatistic {
+    /// Median of latency in millisec
+    pub rtt: u64,
+    /// Standard deviation of latency in millisec
+    pub stdev: f64,
+    /// Failed probes / all probes
+    pub fail_rate: f64,
+    /// Score in [0, 1000], the lower the better
+    pub score: u64,
+}
+
+impl ProbeStatistic {
+    /// Check if any of the probes succeeded
+    pub fn is_working(&self) -> bool {
+        self.fail_rate < 1.0
+    }
+}
+
+/// Probe `svr_cfg` for `rounds` times with the probes of load balancing, scored in the same way as checking
+pub(crate) async fn probe_server(
+    context: &SharedContext,
+    svr_cfg: &ServerConfig,
+    server_type: ServerType,
+    rounds: usize,
+) -> ProbeStatistic {
+    let max_rtt = context.config().balancer.check_timeout.as_millis() as u64;
+    let mut data = ServerStatisticData::new(server_type, svr_cfg.to_string(), max_rtt);
+
+    // All rounds are kept, even if there are more than MAX_LATENCY_QUEUE_SIZE
+    let timeout = context.config().balancer.check_timeout;
+    for _ in 0..rounds {
+        let start = Instant::now();
+        let res = time::timeout(timeout, BalancerService::check_request(context, svr_cfg, server_type)).await;
+
+        // Unlike checking, timeouts are failures, servers never responding are broken
+        let score = match res {
+            Ok(Ok(..)) => Score::Latency(start.elapsed().as_millis() as u64),
+            Ok(Err(err)) => {
+                debug!("failed to probe {} server {}, error: {}", server_type, svr_cfg, err);
+                Score::Errored
+            }
+            Err(..) => {
+                debug!("probing {} server {} timed out", server_type, svr_cfg);
+                Score::Errored
+            }
+        };
+        data.latency_queue.push_back(score);
+    }
+
+    let score = data.recalculate_score();
+    ProbeStatistic {
+        rtt: data.rtt,
+        stdev: data.latency_stdev,
+        fail_rate: data.fail_rate,
+        score,
+    }
+}
+
+fn hash_of<K: Hash + ?Sized>(key: &K) -> u64 {
+    // SipHash with fixed keys, the same key always hashes to the same value
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Points of servers on the hash ring, servers with larger weights have more points
+fn build_hash_ring(servers: &[SharedRemoteServer]) -> Vec<(u64, usize)> {
+    let mut ring = Vec::new();
+    for (idx, svr) in servers.iter().enumerate() {
+        let svr_cfg = svr.server_config();
+        let addr = svr_cfg.addr().to_string();
+        // Weight is checked when loading, capped again for servers configured in code
+        let weight = svr_cfg.weight().min(MAX_SERVER_WEIGHT);
+        for node in 0..weight * VIRTUAL_NODES_PER_WEIGHT {
+            ring.push((hash_of(&(addr.as_str(), node)), idx));
+        }
+    }
+    ring.sort();
+    ring
+}
+
+/// Host of target address, ports are ignored for keeping a site on the same server
+fn target_host(target: &Address) -> String {
+    match *target {
+        Address::SocketAddress(ref addr) => addr.ip().to_string(),
+        Address::DomainNameAddress(ref host, ..) => host.to_ascii_lowercase(),
+    }
+}
+
+/// Load balancer of a local service, picking servers of the `BalancerService` shared in context
+pub struct PingBalancer<S: ServerData> {
+    context: SharedContext,
+    server_type: ServerType,
+    service: SharedBalancerService,
+    /// Servers with data of this balancer, created when they are picked for the first time
+    servers: Arc<spin::Mutex<Vec<SharedServerStatistic<S>>>>,
+}
+
+// Derived `Clone` requires `S: Clone`
+impl<S: ServerData> Clone for PingBalancer<S> {
+    fn clone(&self) -> PingBalancer<S> {
+        PingBalancer {
+            context: self.context.clone(),
+            server_type: self.server_type,
+            service: self.service.clone(),
+            servers: self.servers.clone(),
+        }
+    }
 }
 
 impl<S: ServerData> PingBalancer<S> {
-    /// Pick the best server with current known statistic data
+    /// Create a PingBalancer picking servers by scores of `server_type`
     ///
+    /// Servers are shared by all local services in `context`, and they could be changed at runtime
+    pub async fn new(context: SharedContext, server_type: ServerType) -> PingBalancer<S> {
+        let service = context.balancer();
+        service.subscribe(&context, server_type).await;
+
+        PingBalancer {
+            context,
+            server_type,
+            service,
+            servers: Arc::new(spin::Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Pick a server for `target` with the strategy in configuration
+    ///
+    /// `client_addr` is `None` if requests are not made for a specific client, like cached DNS queries.
     /// Return a `Arc` shared server statistic reference
-    pub fn pick_server(&self) -> SharedServerStatistic<S> {
-        self.best.pick_server()
+    pub fn pick_server(&self, client_addr: Option<&SocketAddr>, target: &Address) -> SharedServerStatistic<S> {
+        let remote = self.service.pick_server(self.server_type, client_addr, Some(target));
+        self.statistics_of(vec![remote]).remove(0)
+    }
+
+    /// Pick a server for a connection from `client_addr` to `target` with the strategy in configuration
+    ///
+    /// The others are candidates for failing over if it is unreachable, ordered by current known statistic data
+    pub fn pick_servers(&self, client_addr: &SocketAddr, target: &Address) -> Vec<SharedServerStatistic<S>> {
+        let remotes = self
+            .service
+            .pick_servers(self.server_type, Some(client_addr), Some(target));
+        self.statistics_of(remotes)
+    }
+
+    fn statistics_of(&self, remotes: Vec<SharedRemoteServer>) -> Vec<SharedServerStatistic<S>> {
+        let mut servers = self.servers.lock();
+
+        // Servers removed from the service won't be picked again
+        servers.retain(|svr| !svr.remote.is_removed());
+
+        let mut stats = Vec::with_capacity(remotes.len());
+        for remote in remotes {
+            let stat = match servers.iter().find(|svr| Arc::ptr_eq(&svr.remote, &remote)) {
+                Some(svr) => svr.clone(),
+                None => {
+                    let svr = Arc::new(ServerStatistic::new(self.context.clone(), self.server_type, remote));
+                    servers.push(svr.clone());
+                    svr
+                }
+            };
+            stats.push(stat);
+        }
+        stats
     }
 }
 
@@ -548,7 +1613,7 @@ impl<S: ServerData> PingBalancer<S> {
 pub struct EmptyServerData;
 
 impl ServerData for EmptyServerData {
-    fn create_server(_: &SharedContext, _: usize, _: &SharedServerStatisticData) -> EmptyServerData {
+    fn create_server(_: &SharedContext, _: &ServerConfig, _: &SharedServerStatisticData) -> EmptyServerData {
         EmptyServerData
     }
 }
@@ -558,3 +1623,247 @@ pub type PlainPingBalancer = PingBalancer<EmptyServerData>;
 
 /// Shared PlainServerStatistic
 pub type SharedPlainServerStatistic = SharedServerStatistic<EmptyServerData>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{config::ConfigType, crypto::CipherType};
+
+    #[test]
+    fn outlier_ejection_backoff() {
+        let mut data = ServerStatisticData::new(ServerType::Tcp, "127.0.0.1:8388".to_owned(), 2000);
+
+        for _ in 1..OUTLIER_CONSECUTIVE_FAILURES {
+            data.report_failure();
+        }
+        data.report_success(100);
+        for _ in 1..OUTLIER_CONSECUTIVE_FAILURES {
+            data.report_failure();
+        }
+        assert!(!data.is_ejected());
+
+        data.report_failure();
+        assert!(data.is_ejected());
+        let ejection_time = data.ejected_until.unwrap() - Instant::now();
+        assert!(ejection_time <= OUTLIER_BASE_EJECTION_TIME);
+        assert!(ejection_time > OUTLIER_BASE_EJECTION_TIME / 2);
+
+        // Ejection expired, failing again doubles the ejection time
+        data.ejected_until = Some(Instant::now());
+        for _ in 0..OUTLIER_CONSECUTIVE_FAILURES {
+            data.report_failure();
+        }
+        let ejection_time = data.ejected_until.unwrap() - Instant::now();
+        assert!(ejection_time > OUTLIER_BASE_EJECTION_TIME);
+        assert!(ejection_time <= OUTLIER_BASE_EJECTION_TIME * 2);
+    }
+
+    #[test]
+    fn probes_skipped_by_relayed_traffic() {
+        let data = SharedServerStatisticData::new(ServerType::Tcp, "127.0.0.1:8388".to_owned(), 2000);
+        let interval = Duration::from_secs(10);
+
+        // Connecting alone doesn't tell if server relays
+        data.report_connected(Duration::from_millis(100));
+        assert!(!data.reported_within(interval));
+
+        let mut reporter = data.traffic_reporter(None);
+        reporter.sent();
+        reporter.received(10);
+        assert!(data.reported_within(interval));
+    }
+
+    #[test]
+    fn closed_relays_counted_once_for_each_target() {
+        let data = SharedServerStatisticData::new(ServerType::Tcp, "127.0.0.1:8388".to_owned(), 2000);
+        let close = |host: &str| {
+            let mut reporter = data.traffic_reporter(Some(&Address::DomainNameAddress(host.to_owned(), 80)));
+            reporter.sent();
+            reporter.received(0);
+        };
+
+        // Server closes relays to an unreachable target
+        for _ in 0..OUTLIER_CONSECUTIVE_FAILURES * 2 {
+            close("unreachable.example.com");
+        }
+        assert!(!data.is_ejected());
+
+        for i in 1..OUTLIER_CONSECUTIVE_FAILURES {
+            close(&format!("www{}.example.com", i));
+        }
+        assert!(data.is_ejected());
+
+        // Resets before responding are server's faults
+        let data = SharedServerStatisticData::new(ServerType::Tcp, "127.0.0.1:8389".to_owned(), 2000);
+        let target = Address::DomainNameAddress("www.example.com".to_owned(), 80);
+        for _ in 0..OUTLIER_CONSECUTIVE_FAILURES {
+            let mut reporter = data.traffic_reporter(Some(&target));
+            reporter.sent();
+            reporter.failed(&ErrorKind::ConnectionReset.into());
+        }
+        assert!(data.is_ejected());
+    }
+
+    #[test]
+    fn rank_tcp_and_udp_separately() {
+        let config = Config::new(ConfigType::Socks5Local);
+        let servers = ["127.0.0.1:8388", "127.0.0.1:8389"]
+            .iter()
+            .map(|addr| {
+                let svr_cfg = ServerConfig::basic(addr.parse().unwrap(), "password".to_owned(), CipherType::Aes256Gcm);
+                RemoteServer::new_shared(&config, svr_cfg)
+            })
+            .collect::<Vec<_>>();
+
+        // The first server is good at TCP, the second one is good at UDP
+        servers[0].data(ServerType::Tcp).report_success(100);
+        servers[1].data(ServerType::Tcp).report_failure();
+        servers[0].data(ServerType::Udp).report_failure();
+        servers[1].data(ServerType::Udp).report_success(100);
+
+        let mut list = ServerList::new(servers);
+        assert_eq!(list.choose_server(ServerType::Tcp, BalancerStrategy::Best, None, None), 0);
+        assert_eq!(list.choose_server(ServerType::Udp, BalancerStrategy::Best, None, None), 1);
+    }
+
+    fn server_list(weights: &[u32]) -> ServerList {
+        let config = Config::new(ConfigType::Socks5Local);
+        let servers = weights
+            .iter()
+            .enumerate()
+            .map(|(idx, weight)| {
+                let addr = format!("127.0.0.1:{}", 8388 + idx).parse().unwrap();
+                let mut svr_cfg = ServerConfig::basic(addr, "password".to_owned(), CipherType::Aes256Gcm);
+                svr_cfg.set_weight(*weight);
+                RemoteServer::new_shared(&config, svr_cfg)
+            })
+            .collect();
+        ServerList::new(servers)
+    }
+
+    fn target(host: &str, port: u16) -> Address {
+        Address::DomainNameAddress(host.to_owned(), port)
+    }
+
+    #[test]
+    fn round_robin_smooth_weighted() {
+        let mut list = server_list(&[2, 1]);
+
+        let picked = (0..6)
+            .map(|_| list.choose_server(ServerType::Tcp, BalancerStrategy::RoundRobin, None, None))
+            .collect::<Vec<_>>();
+        assert_eq!(picked, [0, 1, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn weighted_random_with_worst_scores() {
+        let mut list = server_list(&[1, 3, 1]);
+
+        // Servers with the worst scores still have a chance, and disabled ones have none
+        list.tcp.scores = vec![MAX_SCORE * 2, MAX_SCORE, MAX_SCORE];
+        let available = [true, true, false];
+        for _ in 0..100 {
+            let idx = list.choose_weighted_random(ServerType::Tcp, &available);
+            assert!(available[idx]);
+        }
+    }
+
+    #[test]
+    fn consistent_hash_keeps_host() {
+        let mut list = server_list(&[1, 1, 1, 1]);
+
+        for host in &["www.example.com", "www.google.com", "github.com"] {
+            let picked = list.choose_server(
+                ServerType::Tcp,
+                BalancerStrategy::ConsistentHash,
+                None,
+                Some(&target(host, 443)),
+            );
+            for port in &[80, 443, 8080] {
+                let again = list.choose_server(
+                    ServerType::Tcp,
+                    BalancerStrategy::ConsistentHash,
+                    None,
+                    Some(&target(host, *port)),
+                );
+                assert_eq!(again, picked);
+            }
+        }
+    }
+
+    #[test]
+    fn consistent_hash_ejection_moves_to_successor() {
+        let mut list = server_list(&[1, 1, 1, 1]);
+
+        let hosts = (0..200).map(|i| format!("host{}.example.com", i)).collect::<Vec<_>>();
+        let pick = |list: &mut ServerList, host: &str| {
+            list.choose_server(
+                ServerType::Tcp,
+                BalancerStrategy::ConsistentHash,
+                None,
+                Some(&target(host, 443)),
+            )
+        };
+        let before = hosts.iter().map(|host| pick(&mut list, host)).collect::<Vec<_>>();
+
+        let ejected = 1;
+        for _ in 0..OUTLIER_CONSECUTIVE_FAILURES {
+            list.servers[ejected].data(ServerType::Tcp).report_failure();
+        }
+        assert!(list.servers[ejected].is_ejected(ServerType::Tcp));
+
+        for (host, before) in hosts.iter().zip(before) {
+            let after = pick(&mut list, host);
+            if before != ejected {
+                assert_eq!(after, before, "{} moved from a healthy server", host);
+                continue;
+            }
+
+            // The next point on the ring which is not the ejected server
+            let hash = hash_of(host.as_str());
+            let successor = list
+                .hash_ring
+                .iter()
+                .filter(|(h, _)| *h >= hash)
+                .chain(list.hash_ring.iter())
+                .map(|(_, idx)| *idx)
+                .find(|idx| *idx != ejected)
+                .unwrap();
+            assert_eq!(after, successor, "{} didn't move to its successor", host);
+        }
+    }
+
+    #[test]
+    fn sticky_by_client_ip() {
+        let mut list = server_list(&[1, 1, 1, 1]);
+
+        let mut used = Vec::new();
+        for i in 1..=50 {
+            let picked = list.choose_server(
+                ServerType::Tcp,
+                BalancerStrategy::Sticky,
+                Some(&format!("192.168.1.{}:10000", i).parse().unwrap()),
+                None,
+            );
+
+            // Other connections from the same client, to other targets
+            for port in &[10001, 20000, 30000] {
+                let client = format!("192.168.1.{}:{}", i, port).parse().unwrap();
+                let again = list.choose_server(
+                    ServerType::Tcp,
+                    BalancerStrategy::Sticky,
+                    Some(&client),
+                    Some(&target("example.com", 443)),
+                );
+                assert_eq!(again, picked);
+            }
+            used.push(picked);
+        }
+
+        // Clients are spread over servers
+        used.sort();
+        used.dedup();
+        assert!(used.len() > 1);
+    }
+}
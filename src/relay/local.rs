@@ -11,7 +11,12 @@ use crate::{
     config::{Config, ConfigType},
     context::{Context, ServerState},
     plugin::{PluginMode, Plugins},
-    relay::{tcprelay::local::run as run_tcp, udprelay::local::run as run_udp, utils::set_nofile},
+    relay::{
+        subscription::Subscription,
+        tcprelay::local::run as run_tcp,
+        udprelay::local::run as run_udp,
+        utils::set_nofile,
+    },
 };
 
 /// Relay server running under local environment.
@@ -38,6 +43,12 @@ pub async fn run(mut config: Config) -> io::Result<()> {
         }
     }
 
+    // Servers of online config are added before launching plugins
+    let subscription = match config.online_config {
+        Some(..) => Some(Subscription::load(&mut config).await?),
+        None => None,
+    };
+
     let config_type = config.config_type;
     let mode = config.mode;
 
@@ -65,7 +76,10 @@ pub async fn run(mut config: Config) -> io::Result<()> {
         _ => false,
     };
 
-    let context = if enable_tcp {
+    // DNS relay sends queries through proxy with TCP
+    let enable_dns = config_type == ConfigType::DnsLocal;
+
+    let context = if enable_tcp || enable_dns {
         // Run TCP local server if
         //
         //  1. Enabled TCP relay
@@ -78,8 +92,10 @@ pub async fn run(mut config: Config) -> io::Result<()> {
 
         let context = Context::new_shared(config, state);
 
-        let tcp_fut = run_tcp(context.clone());
-        vf.push(tcp_fut.boxed());
+        if enable_tcp {
+            let tcp_fut = run_tcp(context.clone());
+            vf.push(tcp_fut.boxed());
+        }
 
         context
     } else {
@@ -102,7 +118,7 @@ pub async fn run(mut config: Config) -> io::Result<()> {
 
     #[cfg(feature = "local-dns-relay")]
     {
-        if context.config().dns_local_addr.is_some() {
+        if enable_dns || context.config().dns_local_addr.is_some() {
             use crate::relay::dnsrelay::run as run_dns;
 
             // DNS relay local server
@@ -111,6 +127,22 @@ pub async fn run(mut config: Config) -> io::Result<()> {
         }
     }
 
+    if context.config().control_addr.is_some() {
+        use crate::relay::control::run as run_control;
+
+        // Control socket for managing servers at runtime
+        let control_fut = run_control(context.clone());
+        vf.push(control_fut.boxed());
+    }
+
+    if let Some(subscription) = subscription {
+        use crate::relay::subscription::run as run_subscription;
+
+        // Updates servers of online config periodically
+        let subscription_fut = run_subscription(context.clone(), subscription);
+        vf.push(subscription_fut.boxed());
+    }
+
     #[cfg(feature = "local-flow-stat")]
     {
         if context.config().stat_path.is_some() {
@@ -50,6 +50,8 @@ mod protocol {
         pub plugin_opt: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         pub mode: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub acl: Option<String>,
     }
 
     #[derive(Deserialize, Debug)]
@@ -401,7 +403,7 @@ impl ManagerService {
         };
 
         let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), p.server_port);
-        let svr_cfg = ServerConfig::new(
+        let mut svr_cfg = ServerConfig::new(
             ServerAddr::from(bind_addr),
             p.password,
             method,
@@ -415,6 +417,17 @@ impl ManagerService {
             },
         );
 
+        // ACL policy defined in `acl_policies`
+        if let Some(policy) = p.acl {
+            match self.context.config().acl_policies.get(&policy) {
+                Some(acl) => svr_cfg.set_acl_policy(policy, acl.clone()),
+                None => {
+                    let err = Error::new(ErrorKind::Other, format!("unrecognized ACL policy \"{}\"", policy));
+                    return Err(err);
+                }
+            }
+        }
+
         let mut config = Config::new(ConfigType::Server);
         config.server.push(svr_cfg);
 
@@ -444,8 +457,7 @@ impl ManagerService {
         // Mode
         config.mode = self.context.config().mode;
 
-        // ACL
-        // FIXME: AccessControl structure may be quite expensive to copy
+        // ACL, for servers without policies
         config.acl = self.context.config().acl.clone();
 
         // Close it first
@@ -485,6 +497,7 @@ impl ManagerService {
                 plugin: None,
                 plugin_opt: None,
                 mode: None,
+                acl: svr_cfg.acl_policy().map(ToOwned::to_owned),
             };
 
             if is_first {
@@ -578,6 +591,7 @@ pub async fn run(config: Config) -> io::Result<()> {
             clean_config.mode = config.mode;
             clean_config.no_delay = config.no_delay;
             clean_config.udp_timeout = config.udp_timeout;
+            clean_config.acl = config.acl.clone();
 
             clean_config.server.push(svr_cfg.clone());
 
@@ -5,12 +5,15 @@ pub(crate) mod dns_resolver;
 pub mod dnsrelay;
 pub(crate) mod flow;
 pub(crate) mod loadbalancing;
+pub(crate) mod control;
 pub mod local;
 pub mod manager;
+pub mod probe;
 #[cfg(feature = "local-redir")]
 pub(crate) mod redir;
 pub mod server;
 pub mod socks5;
+pub(crate) mod subscription;
 pub(crate) mod sys;
 pub mod tcprelay;
 pub mod udprelay;
@@ -0,0 +1,75 @@
+//! One-shot probing of servers
+//!
+//! Servers are probed with the same probes and scored in the same way as load balancing of local server,
+//! for finding out which of them are broken.
+
+use std::io;
+
+use futures::future::{self, join_all};
+use log::trace;
+
+use crate::{
+    config::{Config, ServerConfig},
+    context::{Context, ServerState},
+    plugin::{PluginMode, Plugins},
+    relay::{
+        loadbalancing::server::{probe_server, ServerType},
+        subscription::Subscription,
+    },
+};
+
+pub use crate::relay::loadbalancing::server::ProbeStatistic;
+
+/// Probing results of a server
+#[derive(Debug, Clone)]
+pub struct ServerProbe {
+    /// The probed server
+    pub server: ServerConfig,
+    /// Results of TCP probes
+    pub tcp: ProbeStatistic,
+    /// Results of UDP probes
+    pub udp: ProbeStatistic,
+}
+
+/// Probe all servers in `config` for `rounds` rounds, results are in the same order of servers
+///
+/// Servers of online config are also probed, and plugins are launched until probing finished.
+pub async fn run(mut config: Config, rounds: usize) -> io::Result<Vec<ServerProbe>> {
+    trace!("probing servers for {} rounds with {:?}", rounds, config);
+
+    if config.online_config.is_some() {
+        Subscription::load(&mut config).await?;
+    }
+
+    // Killed when dropped
+    let _plugins = if config.has_server_plugins() {
+        Some(Plugins::launch_plugins(&mut config, PluginMode::Client).await?)
+    } else {
+        None
+    };
+
+    let state = ServerState::new_shared(&config).await;
+    let context = Context::new_shared(config, state);
+
+    let probes = context.config().server.iter().map(|svr_cfg| {
+        let context = &context;
+        async move {
+            // Both of TCP and UDP are probed, whatever the mode is
+            let (tcp, udp) = future::join(
+                probe_server(context, svr_cfg, ServerType::Tcp, rounds),
+                probe_server(context, svr_cfg, ServerType::Udp, rounds),
+            )
+            .await;
+
+            ServerProbe {
+                server: svr_cfg.clone(),
+                tcp,
+                udp,
+            }
+        }
+    });
+    let probes = join_all(probes).await;
+
+    context.set_server_stopped();
+    Ok(probes)
+}
@@ -0,0 +1,486 @@
+//! SIP008 online configuration
+//!
+//! Servers are fetched from `online_config.url` when starting, and merged into running load balancers periodically.
+//! Each server is identified by its `id`, or `server:server_port` if it doesn't have one. Servers whose `id` changed are
+//! matched by their addresses.
+//!
+//! https://shadowsocks.org/en/wiki/SIP008-Online-Configuration-Delivery.html
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{self, Error, ErrorKind},
+    mem,
+    net::{IpAddr, SocketAddr},
+    str,
+    time::Duration,
+};
+
+use log::{error, info, warn};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+use url::Url;
+
+use crate::{
+    config::{Config, OnlineConfig, OnlineConfigUrl, ServerAddr, ServerConfig},
+    context::SharedContext,
+    crypto::cipher::CipherType,
+    plugin::PluginConfig,
+};
+
+/// Fetching online configuration takes no longer than this
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Online configurations larger than this are rejected
+const MAXIMUM_CONFIG_SIZE: u64 = 4 * 1024 * 1024;
+
+mod protocol {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    pub struct OnlineConfig {
+        pub version: u32,
+        pub servers: Vec<Server>,
+    }
+
+    #[derive(Deserialize, Debug, Clone, PartialEq)]
+    pub struct Server {
+        pub id: Option<String>,
+        pub remarks: Option<String>,
+        pub server: String,
+        pub server_port: u16,
+        pub password: String,
+        pub method: String,
+        pub plugin: Option<String>,
+        pub plugin_opts: Option<String>,
+    }
+}
+
+/// A server from online configuration
+struct OnlineServer {
+    id: String,
+    entry: protocol::Server,
+    svr_cfg: ServerConfig,
+}
+
+impl OnlineServer {
+    fn from_entry(entry: protocol::Server, timeout: Option<Duration>) -> io::Result<OnlineServer> {
+        let id = match entry.id {
+            Some(ref id) => id.clone(),
+            None => format!("{}:{}", entry.server, entry.server_port),
+        };
+
+        let method = match entry.method.parse::<CipherType>() {
+            Ok(m) => m,
+            Err(..) => {
+                let err = Error::new(
+                    ErrorKind::InvalidData,
+                    format!("server {} has unsupported method {}", id, entry.method),
+                );
+                return Err(err);
+            }
+        };
+
+        let addr = match entry.server.parse::<IpAddr>() {
+            Ok(ip) => ServerAddr::from(SocketAddr::new(ip, entry.server_port)),
+            Err(..) => ServerAddr::from((entry.server.clone(), entry.server_port)),
+        };
+
+        let plugin = match entry.plugin {
+            Some(ref plugin) if !plugin.is_empty() => Some(PluginConfig {
+                plugin: plugin.clone(),
+                plugin_opt: entry.plugin_opts.clone(),
+            }),
+            _ => None,
+        };
+
+        let mut svr_cfg = ServerConfig::new(addr, entry.password.clone(), method, timeout, plugin);
+        if let Some(ref remarks) = entry.remarks {
+            svr_cfg.set_remarks(remarks.clone());
+        }
+        svr_cfg.set_id(id.clone());
+
+        Ok(OnlineServer { id, entry, svr_cfg })
+    }
+}
+
+impl Display for OnlineServer {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        Display::fmt(&self.svr_cfg, f)
+    }
+}
+
+/// Servers of online configuration that are running
+pub struct Subscription {
+    servers: Vec<OnlineServer>,
+}
+
+impl Subscription {
+    /// Fetches online configuration and adds its servers to `config`, before starting local server
+    ///
+    /// The cached copy is used if fetching failed. It fails only if there is no server at all.
+    pub async fn load(config: &mut Config) -> io::Result<Subscription> {
+        let online = config.online_config.clone().expect("online config");
+
+        let servers = match fetch_servers(&online, config.timeout).await {
+            Ok(servers) => servers,
+            Err(err) => {
+                error!("failed to fetch online config from {}, error: {}", online.url, err);
+
+                match online.cache_path {
+                    Some(ref path) => match fs::read(path).await.and_then(|doc| parse_servers(&doc, config.timeout)) {
+                        Ok(servers) => {
+                            warn!("loaded cached online config from {}", path.display());
+                            servers
+                        }
+                        Err(err) => {
+                            error!("failed to load cached online config from {}, error: {}", path.display(), err);
+                            Vec::new()
+                        }
+                    },
+                    None => Vec::new(),
+                }
+            }
+        };
+
+        let mut subscription = Subscription { servers: Vec::new() };
+        for server in servers {
+            let addr = server.svr_cfg.addr().to_string();
+            if config.server.iter().any(|svr| svr.addr().to_string() == addr) {
+                warn!("ignored online server {}, which already exists", server);
+                continue;
+            }
+
+            info!("loaded online server {}", server);
+            config.server.push(server.svr_cfg.clone());
+            subscription.servers.push(server);
+        }
+
+        if config.server.is_empty() {
+            let err = Error::new(ErrorKind::Other, "no server available from online config");
+            return Err(err);
+        }
+
+        Ok(subscription)
+    }
+
+    /// Merges `servers` into running load balancers, servers that failed to be changed are kept as they were
+    fn update(&mut self, context: &SharedContext, servers: Vec<OnlineServer>) {
+        let balancer = context.balancer();
+        let mut current = mem::take(&mut self.servers);
+
+        // Pairs servers with current ones of the same `id`, then of the same address, in case the provider changed ids
+        let mut paired = Vec::with_capacity(servers.len());
+        let mut unpaired = Vec::new();
+        for server in servers {
+            match current.iter().position(|s| s.id == server.id) {
+                Some(pos) => paired.push((Some(current.remove(pos)), server)),
+                None => unpaired.push(server),
+            }
+        }
+        for server in unpaired {
+            let addr = server.svr_cfg.addr().to_string();
+            let old = current
+                .iter()
+                .position(|s| s.svr_cfg.addr().to_string() == addr)
+                .map(|pos| current.remove(pos));
+            paired.push((old, server));
+        }
+
+        for (old, server) in paired {
+            let old = match old {
+                Some(old) => old,
+                None => {
+                    // Plugins are launched only when starting
+                    if server.svr_cfg.plugin().is_some() {
+                        warn!("ignored new online server {}, plugin requires restarting", server);
+                        continue;
+                    }
+
+                    match balancer.add_server(context, server.svr_cfg.clone()) {
+                        Ok(..) => {
+                            info!("added online server {}", server);
+                            self.servers.push(server);
+                        }
+                        Err(err) => error!("failed to add online server {}, error: {}", server, err),
+                    }
+                    continue;
+                }
+            };
+
+            if old.entry == server.entry {
+                self.servers.push(server);
+                continue;
+            }
+
+            if server.svr_cfg.plugin().is_some() || old.svr_cfg.plugin().is_some() {
+                warn!("kept online server {} unchanged, plugin requires restarting", old);
+                self.servers.push(old);
+                continue;
+            }
+
+            match balancer.replace_server(context, old.svr_cfg.addr(), server.svr_cfg.clone()) {
+                Ok(..) => {
+                    info!("updated online server {} to {}", old, server);
+                    self.servers.push(server);
+                }
+                Err(err) => {
+                    error!("failed to update online server {}, error: {}", old, err);
+                    self.servers.push(old);
+                }
+            }
+        }
+
+        // Servers that are not in online configuration anymore, removed after the new ones are added,
+        // so the last enabled one could be replaced
+        for old in current {
+            match balancer.remove_server(old.svr_cfg.addr()) {
+                Ok(..) => info!("removed online server {}", old),
+                Err(err) => {
+                    error!("failed to remove online server {}, error: {}", old, err);
+                    self.servers.push(old);
+                }
+            }
+        }
+    }
+}
+
+/// Fetches and parses online configuration, which is saved to `cache_path` if succeeded
+async fn fetch_servers(online: &OnlineConfig, timeout: Option<Duration>) -> io::Result<Vec<OnlineServer>> {
+    let doc = match online.url {
+        OnlineConfigUrl::Http(ref url) => match time::timeout(FETCH_TIMEOUT, fetch_http(url)).await {
+            Ok(r) => r?,
+            Err(..) => {
+                let err = Error::new(ErrorKind::TimedOut, "fetch timeout");
+                return Err(err);
+            }
+        },
+        OnlineConfigUrl::File(ref path) => fs::read(path).await?,
+    };
+
+    let servers = parse_servers(&doc, timeout)?;
+
+    if let Some(ref path) = online.cache_path {
+        if let Err(err) = fs::write(path, &doc).await {
+            warn!("failed to save online config to {}, error: {}", path.display(), err);
+        }
+    }
+
+    Ok(servers)
+}
+
+async fn fetch_http(url: &Url) -> io::Result<Vec<u8>> {
+    // Validated while loading configuration
+    let host = url.host_str().expect("online config host");
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let stream = TcpStream::connect((host.trim_start_matches('[').trim_end_matches(']'), port)).await?;
+
+    match url.scheme() {
+        #[cfg(feature = "online-config-https")]
+        "https" => request_http(url, tls_connect(host, stream).await?).await,
+        _ => request_http(url, stream).await,
+    }
+}
+
+#[cfg(feature = "online-config-https")]
+async fn tls_connect(host: &str, stream: TcpStream) -> io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    use std::sync::Arc;
+
+    use lazy_static::lazy_static;
+    use tokio_rustls::{
+        rustls::ClientConfig,
+        webpki::DNSNameRef,
+        TlsConnector,
+    };
+
+    lazy_static! {
+        static ref TLS_CONFIG: Arc<ClientConfig> = {
+            let mut config = ClientConfig::new();
+            config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+            Arc::new(config)
+        };
+    }
+
+    let name = match DNSNameRef::try_from_ascii_str(host) {
+        Ok(n) => n,
+        Err(..) => {
+            let err = Error::new(ErrorKind::InvalidInput, format!("invalid dnsname \"{}\"", host));
+            return Err(err);
+        }
+    };
+
+    TlsConnector::from(TLS_CONFIG.clone()).connect(name, stream).await
+}
+
+async fn request_http<S>(url: &Url, mut stream: S) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let host = url.host_str().expect("online config host");
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_owned(),
+    };
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_owned(),
+    };
+
+    // HTTP/1.0 for responses not being chunked, and the connection is closed after responding
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\nUser-Agent: shadowsocks-rust/{}\r\n\r\n",
+        path,
+        host,
+        crate::VERSION
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut buf = Vec::new();
+    match (&mut stream).take(MAXIMUM_CONFIG_SIZE + 1).read_to_end(&mut buf).await {
+        Ok(..) => {}
+        // Some HTTPS servers close connections without TLS close_notify
+        Err(ref err) if err.kind() == ErrorKind::UnexpectedEof && !buf.is_empty() => {}
+        Err(err) => return Err(err),
+    }
+    if buf.len() as u64 > MAXIMUM_CONFIG_SIZE {
+        let err = Error::new(ErrorKind::InvalidData, "online config is too large");
+        return Err(err);
+    }
+
+    let header_len = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos + 4,
+        None => {
+            let err = Error::new(ErrorKind::InvalidData, "invalid HTTP response");
+            return Err(err);
+        }
+    };
+
+    // Status line, "HTTP/1.1 200 OK"
+    let status = str::from_utf8(&buf[..header_len])
+        .ok()
+        .and_then(|header| header.split_whitespace().nth(1))
+        .and_then(|status| status.parse::<u16>().ok());
+
+    match status {
+        Some(200) => Ok(buf.split_off(header_len)),
+        Some(status) => {
+            let err = Error::new(ErrorKind::Other, format!("responded status {}", status));
+            Err(err)
+        }
+        None => {
+            let err = Error::new(ErrorKind::InvalidData, "invalid HTTP status");
+            Err(err)
+        }
+    }
+}
+
+/// Parses SIP008 document, invalid servers are skipped
+fn parse_servers(doc: &[u8], timeout: Option<Duration>) -> io::Result<Vec<OnlineServer>> {
+    let config: protocol::OnlineConfig = match serde_json::from_slice(doc) {
+        Ok(c) => c,
+        Err(err) => {
+            let err = Error::new(ErrorKind::InvalidData, err);
+            return Err(err);
+        }
+    };
+
+    if config.version != 1 {
+        let err = Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported online config version {}", config.version),
+        );
+        return Err(err);
+    }
+
+    let mut servers = Vec::<OnlineServer>::with_capacity(config.servers.len());
+    for entry in config.servers {
+        match OnlineServer::from_entry(entry, timeout) {
+            Ok(server) => {
+                if servers.iter().any(|s| s.id == server.id) {
+                    warn!("ignored online server {}, id is duplicated", server);
+                    continue;
+                }
+                servers.push(server);
+            }
+            Err(err) => warn!("ignored online server, {}", err),
+        }
+    }
+
+    Ok(servers)
+}
+
+/// Updates servers from online configuration periodically
+pub async fn run(context: SharedContext, mut subscription: Subscription) -> io::Result<()> {
+    let online = context.config().online_config.clone().expect("online config");
+
+    loop {
+        time::delay_for(online.update_interval).await;
+
+        match fetch_servers(&online, context.config().timeout).await {
+            Ok(servers) => subscription.update(&context, servers),
+            Err(err) => error!(
+                "failed to update online config from {}, keeping current servers, error: {}",
+                online.url, err
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_online_config() {
+        let doc = br#"{
+            "version": 1,
+            "servers": [
+                {
+                    "id": "27b8a625-4f4b-4428-9f0f-8a2317db7c79",
+                    "remarks": "Name of the server",
+                    "server": "example.com",
+                    "server_port": 8388,
+                    "password": "example",
+                    "method": "chacha20-ietf-poly1305",
+                    "plugin": "xxx",
+                    "plugin_opts": "xxxxx"
+                },
+                {
+                    "server": "127.0.0.1",
+                    "server_port": 8389,
+                    "password": "example",
+                    "method": "aes-256-gcm"
+                },
+                {
+                    "server": "127.0.0.1",
+                    "server_port": 8390,
+                    "password": "example",
+                    "method": "unknown"
+                }
+            ],
+            "bytes_used": 274877906944,
+            "bytes_remaining": 1099511627776
+        }"#;
+
+        let servers = parse_servers(doc, None).unwrap();
+        assert_eq!(servers.len(), 2);
+
+        assert_eq!(servers[0].id, "27b8a625-4f4b-4428-9f0f-8a2317db7c79");
+        assert_eq!(servers[0].svr_cfg.addr().to_string(), "example.com:8388");
+        assert_eq!(servers[0].svr_cfg.plugin().unwrap().plugin, "xxx");
+        assert_eq!(servers[0].to_string(), "Name of the server (example.com:8388)");
+        assert_eq!(servers[0].svr_cfg.id(), Some("27b8a625-4f4b-4428-9f0f-8a2317db7c79"));
+
+        assert_eq!(servers[1].id, "127.0.0.1:8389");
+        assert_eq!(servers[1].svr_cfg.method().to_string(), "aes-256-gcm");
+        assert!(servers[1].svr_cfg.plugin().is_none());
+
+        assert!(parse_servers(br#"{"version": 2, "servers": []}"#, None).is_err());
+    }
+}
@@ -7,7 +7,6 @@ use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     pin::Pin,
     str::FromStr,
-    sync::Arc,
     task::{self, Poll},
 };
 
@@ -41,13 +40,7 @@ use tokio::io::{AsyncRead, AsyncWrite};
 use crate::{
     context::SharedContext,
     relay::{
-        loadbalancing::server::{
-            PingBalancer,
-            ServerData,
-            ServerType,
-            SharedServerStatistic,
-            SharedServerStatisticData,
-        },
+        loadbalancing::server::{PlainPingBalancer, ServerType},
         socks5::Address,
     },
 };
@@ -95,6 +88,8 @@ impl ProxyHttpStream {
 
     #[cfg(feature = "local-http-rustls")]
     async fn connect_https(stream: ProxyStream, domain: &str) -> io::Result<ProxyHttpStream> {
+        use std::sync::Arc;
+
         use lazy_static::lazy_static;
         use log::warn;
         use tokio_rustls::{
@@ -197,16 +192,16 @@ impl AsyncWrite for ProxyHttpStream {
     }
 }
 
+/// Connects via servers picked for `client_addr`, failing over to the next one if a server is unreachable
 #[derive(Clone)]
 struct ShadowSocksConnector {
-    context: SharedContext,
-    svr_idx: usize,
-    stat: SharedServerStatisticData,
+    balancer: PlainPingBalancer,
+    client_addr: SocketAddr,
 }
 
 impl ShadowSocksConnector {
-    fn new(context: SharedContext, svr_idx: usize, stat: SharedServerStatisticData) -> ShadowSocksConnector {
-        ShadowSocksConnector { context, svr_idx, stat }
+    fn new(balancer: PlainPingBalancer, client_addr: SocketAddr) -> ShadowSocksConnector {
+        ShadowSocksConnector { balancer, client_addr }
     }
 }
 
@@ -220,13 +215,11 @@ impl tower::Service<Uri> for ShadowSocksConnector {
     }
 
     fn call(&mut self, dst: Uri) -> Self::Future {
-        let context = self.context.clone();
-        let svr_idx = self.svr_idx;
-        let stat = self.stat.clone();
+        let balancer = self.balancer.clone();
+        let client_addr = self.client_addr;
 
         ShadowSocksConnecting {
             fut: async move {
-                let svr_cfg = context.server_config(svr_idx);
                 let is_https = dst.scheme_str() == Some("https");
 
                 match host_addr(&dst) {
@@ -239,20 +232,19 @@ impl tower::Service<Uri> for ShadowSocksConnector {
                         Err(err)
                     }
                     Some(addr) => {
-                        match ProxyStream::connect_proxied(context.clone(), svr_cfg, &addr).await {
-                            Ok(s) => {
-                                if is_https {
-                                    let host = dst.host().unwrap().trim_start_matches('[').trim_start_matches(']');
-                                    ProxyHttpStream::connect_https(s, host).await
-                                } else {
-                                    Ok(ProxyHttpStream::connect_http(s))
-                                }
-                            }
-                            Err(err) => {
-                                // Report failure to global statistic
-                                stat.report_failure().await;
-                                Err(err)
-                            }
+                        let servers = balancer.pick_servers(&client_addr, &addr);
+                        let s = match ProxyStream::connect_proxied_failover(servers[0].clone_context(), &servers, &addr)
+                            .await
+                        {
+                            Ok(s) => s,
+                            Err(err) => return Err(err.into_inner()),
+                        };
+
+                        if is_https {
+                            let host = dst.host().unwrap().trim_start_matches('[').trim_start_matches(']');
+                            ProxyHttpStream::connect_https(s, host).await
+                        } else {
+                            Ok(ProxyHttpStream::connect_http(s))
                         }
                     }
                 }
@@ -641,14 +633,13 @@ fn get_addr_from_header(req: &mut Request<Body>) -> Result<Address, ()> {
 
 async fn server_dispatch(
     mut req: Request<Body>,
-    svr_score: SharedServerStatistic<ServerScore>,
+    balancer: PlainPingBalancer,
     client_addr: SocketAddr,
+    proxy_client: ShadowSocksHttpClient,
     bypass_client: DirectHttpClient,
 ) -> io::Result<Response<Body>> {
     trace!("request {} {:?}", client_addr, req);
 
-    let context = svr_score.context();
-
     // Parse URI
     //
     // Proxy request URI must contains a host
@@ -670,7 +661,9 @@ async fn server_dispatch(
         Some(h) => h,
     };
 
-    let svr_cfg = svr_score.server_config();
+    let servers = balancer.pick_servers(&client_addr, &host);
+    let svr_score = &servers[0];
+    let context = svr_score.context();
 
     if Method::CONNECT == req.method() {
         // Establish a TCP tunnel
@@ -681,15 +674,9 @@ async fn server_dispatch(
         // Connect to Shadowsocks' remote
         //
         // FIXME: What STATUS should I return for connection error?
-        let stream = match ProxyStream::connect(svr_score.clone_context(), svr_cfg, &host).await {
+        let stream = match ProxyStream::connect(svr_score.clone_context(), &servers, &host).await {
             Ok(s) => s,
-            Err(err) => {
-                if err.is_proxied() {
-                    // Report failure to global statistic
-                    svr_score.report_failure().await;
-                }
-                return Err(err.into_inner());
-            }
+            Err(err) => return Err(err.into_inner()),
         };
 
         debug!("CONNECT relay connected {} <-> {}", client_addr, host);
@@ -755,12 +742,8 @@ async fn server_dispatch(
         } else {
             trace!("proxied {} -> {} {:?}", client_addr, host, req);
 
-            // Keep connections for clients in ServerScore::client
-            //
-            // client instance is kept for Keep-Alive connections
-            let client = &svr_score.server().proxy_client;
-
-            match client.request(req).await {
+            // Keep-Alive connections are kept in client instance of each client connection
+            match proxy_client.request(req).await {
                 Ok(res) => res,
                 Err(err) => {
                     error!(
@@ -800,44 +783,31 @@ async fn server_dispatch(
     }
 }
 
-struct ServerScore {
-    proxy_client: ShadowSocksHttpClient,
-}
-
-impl ServerScore {
-    fn new(context: SharedContext, server_idx: usize, data: SharedServerStatisticData) -> ServerScore {
-        ServerScore {
-            // Create HTTP clients for each remote servers
-            // It may reuse keep-alive connections
-            proxy_client: Client::builder().build::<_, Body>(ShadowSocksConnector::new(context, server_idx, data)),
-        }
-    }
-}
-
-impl ServerData for ServerScore {
-    fn create_server(context: &SharedContext, server_idx: usize, data: &SharedServerStatisticData) -> ServerScore {
-        ServerScore::new(context.clone(), server_idx, data.clone())
-    }
-}
-
 /// Starts a TCP local server with HTTP proxy protocol
 pub async fn run(context: SharedContext) -> io::Result<()> {
     let local_addr = context.config().local_addr.as_ref().expect("local config");
     let bind_addr = local_addr.bind_addr(&context).await?;
 
     let bypass_client = Client::builder().build::<_, Body>(DirectConnector::new(context.clone()));
-    let servers: PingBalancer<ServerScore> = PingBalancer::new(context, ServerType::Tcp).await;
-    let servers = Arc::new(servers);
+    let balancer = PlainPingBalancer::new(context, ServerType::Tcp).await;
 
     let make_service = make_service_fn(|socket: &AddrStream| {
         let client_addr = socket.remote_addr();
-        let servers = servers.clone();
+        let balancer = balancer.clone();
         let bypass_client = bypass_client.clone();
 
+        // Servers are picked for each client, so are proxied connections kept
+        let proxy_client = Client::builder().build::<_, Body>(ShadowSocksConnector::new(balancer.clone(), client_addr));
+
         async move {
             Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                let svr_score = servers.pick_server();
-                server_dispatch(req, svr_score, client_addr, bypass_client.clone())
+                server_dispatch(
+                    req,
+                    balancer.clone(),
+                    client_addr,
+                    proxy_client.clone(),
+                    bypass_client.clone(),
+                )
             }))
         }
     });
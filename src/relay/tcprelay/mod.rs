@@ -21,6 +21,7 @@ mod crypto_io;
 mod http_local;
 pub mod local;
 mod monitor;
+pub(crate) mod mux;
 mod proxy_stream;
 #[cfg(feature = "local-redir")]
 mod redir;
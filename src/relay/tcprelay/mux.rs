@@ -0,0 +1,1041 @@
+//! Multiplexing TCP relays over a few long-lived connections between local and server
+//!
+//! A multiplexed connection starts as an ordinary shadowsocks connection, whose target address is the reserved
+//! `mux.shadowsocks.arpa:0`, followed by a version byte. Servers supporting multiplexing reply the same version byte,
+//! and then both sides exchange frames:
+//!
+//! ```plain
+//! +------+-----------+--------+---------+
+//! | TYPE | STREAM ID | LENGTH | PAYLOAD |
+//! +------+-----------+--------+---------+
+//! |  1   |     4     |   2    | LENGTH  |
+//! +------+-----------+--------+---------+
+//! ```
+//!
+//! - `SYN` opens a stream, its payload is the target `Address`
+//! - `DATA` carries data of a stream
+//! - `FIN` finishes the sending direction of a stream
+//! - `RST` aborts a stream, the peer won't read from it anymore. Its payload is empty, or `RST_REFUSED` if the stream
+//!   is refused before being opened
+//! - `WINDOW_UPDATE` allows the peer to send more data, its payload is the increment in 4 bytes
+//!
+//! At most `INITIAL_WINDOW` bytes of each stream are in flight, so slow streams won't block the others.
+//! A window update exceeding `INITIAL_WINDOW` is a protocol error, which closes the connection.
+//! Servers refuse streams beyond `MAX_MUX_STREAMS` of a connection, which fail with `ConnectionRefused` and are not
+//! counted as failures of the server.
+//!
+//! Streams of a closed connection fail with `NotConnected`, which is not counted as a failure of the server
+//! by each stream. Failure of the connection is reported once instead.
+//!
+//! Servers without multiplexing fail to connect to the reserved address and close the connection,
+//! then local server connects to them as usual.
+
+use std::{
+    cmp,
+    collections::HashMap,
+    future::Future,
+    io::{self, Error, ErrorKind},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{self, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::future::{self, Either};
+use log::{debug, trace, warn};
+use spin::Mutex;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    sync::{mpsc, oneshot},
+    time,
+};
+
+use crate::{
+    config::{MuxConfig, ServerConfig, MAX_MUX_STREAMS},
+    context::SharedContext,
+    relay::{
+        loadbalancing::server::{SharedServerStatisticData, TrafficReporter},
+        socks5::Address,
+    },
+};
+
+use super::{proxy_stream::connect_proxy_server, CryptoStream};
+
+/// Version of the multiplexing protocol
+pub const MUX_VERSION: u8 = 1;
+
+/// Reserved domain name of the target address for requesting multiplexing
+const MUX_MARKER_DOMAIN: &str = "mux.shadowsocks.arpa";
+
+const FRAME_SYN: u8 = 0x00;
+const FRAME_DATA: u8 = 0x01;
+const FRAME_FIN: u8 = 0x02;
+const FRAME_RST: u8 = 0x03;
+const FRAME_WINDOW_UPDATE: u8 = 0x04;
+
+/// Payload of `RST` refusing a stream beyond `MAX_MUX_STREAMS`
+const RST_REFUSED: u8 = 0x01;
+
+const FRAME_HEADER_LEN: usize = 1 + 4 + 2;
+const MAX_FRAME_PAYLOAD: usize = 16 * 1024;
+
+/// Bytes of a stream that could be sent before the peer acknowledged
+const INITIAL_WINDOW: usize = 256 * 1024;
+
+/// Frames are written together until the buffer reaches this size
+const WRITE_BATCH_SIZE: usize = 64 * 1024;
+
+/// Bytes of data frames queued for writing, streams wait until they are written
+const MAX_QUEUED_BYTES: usize = 1024 * 1024;
+
+/// Connections without streams are closed after this duration
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Servers that don't support multiplexing are tried again after this duration
+const MUX_RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Timeout for the server to reply the version byte
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Target address for requesting multiplexing
+pub fn marker_address() -> Address {
+    Address::DomainNameAddress(MUX_MARKER_DOMAIN.to_owned(), 0)
+}
+
+/// Check if the target address is a request for multiplexing
+pub fn is_marker(addr: &Address) -> bool {
+    match *addr {
+        Address::DomainNameAddress(ref dname, 0) => dname == MUX_MARKER_DOMAIN,
+        _ => false,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    ty: u8,
+    id: u32,
+    payload: Bytes,
+}
+
+impl Frame {
+    fn new(ty: u8, id: u32, payload: Bytes) -> Frame {
+        Frame { ty, id, payload }
+    }
+
+    fn empty(ty: u8, id: u32) -> Frame {
+        Frame::new(ty, id, Bytes::new())
+    }
+
+    fn syn(id: u32, addr: &Address) -> Frame {
+        let mut buf = BytesMut::with_capacity(addr.serialized_len());
+        addr.write_to_buf(&mut buf);
+        Frame::new(FRAME_SYN, id, buf.freeze())
+    }
+
+    fn rst_refused(id: u32) -> Frame {
+        Frame::new(FRAME_RST, id, Bytes::from_static(&[RST_REFUSED]))
+    }
+
+    fn window_update(id: u32, increment: u32) -> Frame {
+        let mut buf = BytesMut::with_capacity(4);
+        buf.put_u32(increment);
+        Frame::new(FRAME_WINDOW_UPDATE, id, buf.freeze())
+    }
+
+    fn serialized_len(&self) -> usize {
+        FRAME_HEADER_LEN + self.payload.len()
+    }
+
+    fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(self.ty);
+        buf.put_u32(self.id);
+        buf.put_u16(self.payload.len() as u16);
+        buf.put_slice(&self.payload);
+    }
+
+    async fn read_from<R>(r: &mut R) -> io::Result<Frame>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        r.read_exact(&mut header).await?;
+
+        let mut header = &header[..];
+        let ty = header.get_u8();
+        let id = header.get_u32();
+        let len = header.get_u16() as usize;
+
+        if len > MAX_FRAME_PAYLOAD {
+            let err = Error::new(ErrorKind::Other, format!("mux frame payload too long, {} bytes", len));
+            return Err(err);
+        }
+
+        let mut payload = BytesMut::with_capacity(len);
+        payload.resize(len, 0);
+        r.read_exact(&mut payload).await?;
+
+        Ok(Frame::new(ty, id, payload.freeze()))
+    }
+}
+
+struct StreamState {
+    // Received but not read yet
+    recv_buf: BytesMut,
+    // Read but not acknowledged to the peer yet
+    consumed: usize,
+    // Bytes allowed to be sent
+    send_window: usize,
+    fin_received: bool,
+    fin_sent: bool,
+    // Stream is aborted by RST or by closing the whole connection
+    aborted: Option<ErrorKind>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl StreamState {
+    fn new() -> StreamState {
+        StreamState {
+            recv_buf: BytesMut::new(),
+            consumed: 0,
+            send_window: INITIAL_WINDOW,
+            fin_received: false,
+            fin_sent: false,
+            aborted: None,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    fn abort(&mut self, kind: ErrorKind) {
+        if self.aborted.is_none() {
+            self.aborted = Some(kind);
+        }
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+type SharedStreamState = Arc<Mutex<StreamState>>;
+
+/// State of a multiplexed connection shared by its streams and tasks
+struct Session {
+    streams: Mutex<HashMap<u32, SharedStreamState>>,
+    // Frames are sent by the writer task, taken when the connection is closed
+    sender: Mutex<Option<mpsc::UnboundedSender<Frame>>>,
+    // Bytes of frames queued but not written yet
+    queued: AtomicUsize,
+    // Streams waiting for the queue to be written
+    queue_wakers: Mutex<Vec<Waker>>,
+    next_id: AtomicU32,
+    closed: AtomicBool,
+    local_addr: Option<SocketAddr>,
+    // Last time a stream was opened or closed
+    last_active: Mutex<Instant>,
+}
+
+type SharedSession = Arc<Session>;
+
+impl Session {
+    fn new(sender: mpsc::UnboundedSender<Frame>, first_id: u32, local_addr: Option<SocketAddr>) -> Session {
+        Session {
+            streams: Mutex::new(HashMap::new()),
+            sender: Mutex::new(Some(sender)),
+            queued: AtomicUsize::new(0),
+            queue_wakers: Mutex::new(Vec::new()),
+            next_id: AtomicU32::new(first_id),
+            closed: AtomicBool::new(false),
+            local_addr,
+            last_active: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    fn stream_count(&self) -> usize {
+        self.streams.lock().len()
+    }
+
+    fn send(&self, frame: Frame) -> io::Result<()> {
+        self.queued.fetch_add(frame.serialized_len(), Ordering::AcqRel);
+        self.send_reserved(frame)
+    }
+
+    /// Sends a frame whose size is already added by `poll_reserve`
+    fn send_reserved(&self, frame: Frame) -> io::Result<()> {
+        let sender = self.sender.lock();
+        match *sender {
+            Some(ref tx) if tx.send(frame).is_ok() => Ok(()),
+            _ => Err(ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn try_reserve(&self, len: usize) -> bool {
+        self.queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                if queued + len <= MAX_QUEUED_BYTES {
+                    Some(queued + len)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    /// Reserves `len` bytes in the queue for a data frame, or waits until the queue is written
+    fn poll_reserve(&self, cx: &mut task::Context<'_>, len: usize) -> Poll<()> {
+        if self.try_reserve(len) {
+            return Poll::Ready(());
+        }
+
+        self.queue_wakers.lock().push(cx.waker().clone());
+
+        // Queue may be written before the waker is registered
+        if self.try_reserve(len) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Called by the writer task after `len` bytes of queued frames are written
+    fn written(&self, len: usize) {
+        self.queued.fetch_sub(len, Ordering::AcqRel);
+
+        let wakers = std::mem::take(&mut *self.queue_wakers.lock());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    fn get_stream(&self, id: u32) -> Option<SharedStreamState> {
+        self.streams.lock().get(&id).cloned()
+    }
+
+    fn register(self: &Arc<Self>, id: u32) -> MuxStream {
+        let state = Arc::new(Mutex::new(StreamState::new()));
+        self.streams.lock().insert(id, state.clone());
+        *self.last_active.lock() = Instant::now();
+
+        MuxStream {
+            id,
+            session: self.clone(),
+            state,
+        }
+    }
+
+    /// Opens a new stream to `addr`, only for client side
+    fn open(self: &Arc<Self>, addr: &Address) -> io::Result<MuxStream> {
+        let id = self.next_id.fetch_add(2, Ordering::AcqRel);
+        let stream = self.register(id);
+        self.send(Frame::syn(id, addr))?;
+        Ok(stream)
+    }
+
+    /// Closes the connection, all streams are aborted with `kind`
+    fn close(&self, kind: ErrorKind) {
+        self.closed.store(true, Ordering::Release);
+        let _ = self.sender.lock().take();
+
+        let wakers = std::mem::take(&mut *self.queue_wakers.lock());
+        for waker in wakers {
+            waker.wake();
+        }
+
+        let streams = std::mem::take(&mut *self.streams.lock());
+        for (_, state) in streams {
+            state.lock().abort(kind);
+        }
+    }
+}
+
+/// A logical TCP relay carried by a multiplexed connection
+pub struct MuxStream {
+    id: u32,
+    session: SharedSession,
+    state: SharedStreamState,
+}
+
+impl MuxStream {
+    /// Returns the local socket address of the multiplexed connection
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.session
+            .local_addr
+            .ok_or_else(|| Error::new(ErrorKind::Other, "local address of mux connection is unknown"))
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut state = self.state.lock();
+
+        if !state.recv_buf.is_empty() {
+            let n = cmp::min(buf.len(), state.recv_buf.len());
+            buf[..n].copy_from_slice(&state.recv_buf[..n]);
+            state.recv_buf.advance(n);
+
+            state.consumed += n;
+            if state.consumed >= INITIAL_WINDOW / 2 && !state.fin_received {
+                let increment = state.consumed as u32;
+                state.consumed = 0;
+                // Error is ignored, the connection is closed and the next read will fail
+                let _ = self.session.send(Frame::window_update(self.id, increment));
+            }
+
+            return Poll::Ready(Ok(n));
+        }
+
+        if state.fin_received {
+            return Poll::Ready(Ok(0));
+        }
+
+        if let Some(kind) = state.aborted {
+            return Poll::Ready(Err(kind.into()));
+        }
+
+        state.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = {
+            let mut state = self.state.lock();
+
+            if let Some(kind) = state.aborted {
+                return Poll::Ready(Err(kind.into()));
+            }
+
+            if state.fin_sent {
+                return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+            }
+
+            if state.send_window == 0 {
+                state.write_waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            let n = cmp::min(cmp::min(buf.len(), state.send_window), MAX_FRAME_PAYLOAD);
+            if self.session.is_closed() {
+                return Poll::Ready(Err(ErrorKind::NotConnected.into()));
+            }
+            if self.session.poll_reserve(cx, FRAME_HEADER_LEN + n).is_pending() {
+                return Poll::Pending;
+            }
+
+            state.send_window -= n;
+            n
+        };
+
+        self.session
+            .send_reserved(Frame::new(FRAME_DATA, self.id, Bytes::copy_from_slice(&buf[..n])))?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock();
+        if !state.fin_sent {
+            state.fin_sent = true;
+            if state.aborted.is_none() {
+                self.session.send(Frame::empty(FRAME_FIN, self.id))?;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.session.streams.lock().remove(&self.id);
+        *self.session.last_active.lock() = Instant::now();
+
+        let state = self.state.lock();
+        if state.aborted.is_some() {
+            return;
+        }
+
+        // Errors are ignored, the connection is already closed
+        if !state.fin_sent {
+            let _ = self.session.send(Frame::empty(FRAME_FIN, self.id));
+        }
+        if !state.fin_received {
+            // Tells the peer to stop sending
+            let _ = self.session.send(Frame::empty(FRAME_RST, self.id));
+        }
+    }
+}
+
+/// Dispatches frames from peer to streams, until the connection is closed
+async fn read_frames<R>(
+    session: &SharedSession,
+    mut r: R,
+    incoming: Option<mpsc::UnboundedSender<(MuxStream, Address)>>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        // Data frames are limited by `MAX_QUEUED_BYTES`, the others pile up only if the peer stops reading
+        if session.queued.load(Ordering::Acquire) > 2 * MAX_QUEUED_BYTES {
+            return Err(Error::new(ErrorKind::Other, "mux peer stopped reading frames"));
+        }
+
+        let frame = match Frame::read_from(&mut r).await {
+            Ok(f) => f,
+            Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        trace!(
+            "mux received frame type {} stream {} with {} bytes",
+            frame.ty,
+            frame.id,
+            frame.payload.len()
+        );
+
+        if frame.ty == FRAME_SYN {
+            let incoming = match incoming {
+                Some(ref i) => i,
+                None => return Err(Error::new(ErrorKind::Other, "mux server opened a stream")),
+            };
+
+            if session.get_stream(frame.id).is_some() {
+                let err = Error::new(ErrorKind::Other, format!("mux stream {} is already opened", frame.id));
+                return Err(err);
+            }
+
+            if session.stream_count() >= MAX_MUX_STREAMS {
+                debug!("mux stream {} is refused, too many streams", frame.id);
+                session.send(Frame::rst_refused(frame.id))?;
+                continue;
+            }
+
+            let addr = Address::read_from(&mut &frame.payload[..]).await?;
+            let stream = session.register(frame.id);
+            if incoming.send((stream, addr)).is_err() {
+                return Ok(());
+            }
+            continue;
+        }
+
+        // Frames of closed streams are ignored
+        let state = match session.get_stream(frame.id) {
+            Some(s) => s,
+            None => continue,
+        };
+        let mut state = state.lock();
+
+        match frame.ty {
+            FRAME_DATA => {
+                if state.recv_buf.len() + state.consumed + frame.payload.len() > INITIAL_WINDOW {
+                    let err = Error::new(ErrorKind::Other, format!("mux stream {} exceeded window", frame.id));
+                    return Err(err);
+                }
+
+                state.recv_buf.extend_from_slice(&frame.payload);
+                if let Some(waker) = state.read_waker.take() {
+                    waker.wake();
+                }
+            }
+            FRAME_FIN => {
+                state.fin_received = true;
+                if let Some(waker) = state.read_waker.take() {
+                    waker.wake();
+                }
+            }
+            FRAME_RST => {
+                if frame.payload[..] == [RST_REFUSED] {
+                    state.abort(ErrorKind::ConnectionRefused);
+                } else {
+                    state.abort(ErrorKind::ConnectionReset);
+                }
+            }
+            FRAME_WINDOW_UPDATE => {
+                if frame.payload.len() != 4 {
+                    return Err(Error::new(ErrorKind::Other, "invalid mux window update frame"));
+                }
+
+                let increment = (&frame.payload[..]).get_u32() as usize;
+                if state.send_window + increment > INITIAL_WINDOW {
+                    let err = Error::new(ErrorKind::Other, format!("mux stream {} window overflowed", frame.id));
+                    return Err(err);
+                }
+
+                state.send_window += increment;
+                if let Some(waker) = state.write_waker.take() {
+                    waker.wake();
+                }
+            }
+            ty => {
+                return Err(Error::new(ErrorKind::Other, format!("unknown mux frame type {}", ty)));
+            }
+        }
+    }
+}
+
+/// Writes frames queued by streams, until the connection is closed
+async fn write_frames<W>(session: &SharedSession, mut w: W, mut rx: mpsc::UnboundedReceiver<Frame>) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(WRITE_BATCH_SIZE);
+
+    while let Some(frame) = rx.recv().await {
+        frame.write_to_buf(&mut buf);
+
+        // Sends queued frames together
+        while buf.len() < WRITE_BATCH_SIZE {
+            match rx.try_recv() {
+                Ok(frame) => frame.write_to_buf(&mut buf),
+                Err(..) => break,
+            }
+        }
+
+        w.write_all(&buf).await?;
+        w.flush().await?;
+        session.written(buf.len());
+        buf.clear();
+    }
+
+    w.shutdown().await
+}
+
+/// Runs a multiplexed connection until it is closed
+async fn run_session<S>(
+    session: SharedSession,
+    r: ReadHalf<S>,
+    w: WriteHalf<S>,
+    rx: mpsc::UnboundedReceiver<Frame>,
+    incoming: Option<mpsc::UnboundedSender<(MuxStream, Address)>>,
+    mut reporter: Option<TrafficReporter>,
+) where
+    S: AsyncRead + AsyncWrite,
+{
+    let reader = read_frames(&session, r, incoming);
+    let writer = write_frames(&session, w, rx);
+    tokio::pin!(reader);
+    tokio::pin!(writer);
+
+    let result = match future::select(reader, writer).await {
+        Either::Left((r, _)) => r,
+        Either::Right((r, _)) => r,
+    };
+
+    let kind = match result {
+        Ok(..) => {
+            trace!("mux connection closed");
+            ErrorKind::NotConnected
+        }
+        Err(err) => {
+            debug!("mux connection closed with error {}", err);
+
+            // Reported once for the connection, instead of once for each of its streams
+            if let Some(ref mut reporter) = reporter {
+                reporter.failed(&err);
+            }
+
+            match err.kind() {
+                ErrorKind::TimedOut => ErrorKind::TimedOut,
+                _ => ErrorKind::NotConnected,
+            }
+        }
+    };
+
+    session.close(kind);
+}
+
+/// Serves a multiplexed connection from client, after the version byte is replied
+///
+/// Streams opened by client are sent to the returned receiver, which ends when the returned future finishes.
+pub fn serve<S>(stream: S) -> (impl Future<Output = ()>, mpsc::UnboundedReceiver<(MuxStream, Address)>)
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (r, w) = tokio::io::split(stream);
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+
+    let session = Arc::new(Session::new(tx, 0, None));
+    (run_session(session, r, w, rx, Some(incoming_tx), None), incoming_rx)
+}
+
+/// Connects a multiplexed connection to server
+///
+/// The outer error is failing to connect the server, and the inner error is failing to start multiplexing on it.
+/// Failure of the established connection is reported to `data`.
+async fn connect_session(
+    context: &SharedContext,
+    svr_cfg: &ServerConfig,
+    data: &SharedServerStatisticData,
+) -> io::Result<io::Result<SharedSession>> {
+    let mut stream = connect_proxy_server(context, svr_cfg).await?;
+    // Frames are batched by the writer task
+    stream.set_nodelay(true)?;
+    let local_addr = stream.get_ref().local_addr().ok();
+
+    let mut stream = CryptoStream::new(context.clone(), stream, svr_cfg);
+
+    let handshake = async {
+        let addr = marker_address();
+        let mut buf = BytesMut::with_capacity(addr.serialized_len() + 1);
+        addr.write_to_buf(&mut buf);
+        buf.put_u8(MUX_VERSION);
+        stream.write_all(&buf).await?;
+
+        let mut version = [0u8; 1];
+        stream.read_exact(&mut version).await?;
+        if version[0] != MUX_VERSION {
+            let err = Error::new(ErrorKind::Other, format!("unsupported mux version {}", version[0]));
+            return Err(err);
+        }
+        Ok(())
+    };
+
+    match time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => return Ok(Err(err)),
+        Err(..) => return Ok(Err(ErrorKind::TimedOut.into())),
+    }
+
+    let session = start_client(stream, local_addr, Some(data.traffic_reporter(None)));
+    tokio::spawn(check_idle(context.clone(), session.clone()));
+
+    Ok(Ok(session))
+}
+
+/// Runs client side of a multiplexed connection in background, after the version byte is replied
+fn start_client<S>(stream: S, local_addr: Option<SocketAddr>, reporter: Option<TrafficReporter>) -> SharedSession
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (r, w) = tokio::io::split(stream);
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    // Client side stream IDs are odd numbers
+    let session = Arc::new(Session::new(tx, 1, local_addr));
+    tokio::spawn(run_session(session.clone(), r, w, rx, None, reporter));
+    session
+}
+
+/// Closes the connection if it has no streams for `SESSION_IDLE_TIMEOUT`
+async fn check_idle(context: SharedContext, session: SharedSession) {
+    while context.server_running() && !session.is_closed() {
+        time::delay_for(SESSION_IDLE_TIMEOUT / 4).await;
+
+        if session.stream_count() == 0 && session.last_active.lock().elapsed() >= SESSION_IDLE_TIMEOUT {
+            trace!("mux connection is idle, closing");
+            break;
+        }
+    }
+
+    session.close(ErrorKind::NotConnected);
+}
+
+#[derive(Default)]
+struct ServerSessions {
+    sessions: Vec<SharedSession>,
+    // Connections being established
+    connecting: usize,
+    // Streams waiting for connections being established
+    waiters: Vec<oneshot::Sender<io::Result<Option<SharedSession>>>>,
+    // Server doesn't support multiplexing, until then
+    unsupported_until: Option<Instant>,
+}
+
+enum Pick {
+    Session(SharedSession),
+    // Waiting for a connection being established, the flag tells if it is established for this stream
+    Wait(oneshot::Receiver<io::Result<Option<SharedSession>>>, bool),
+    Plain,
+}
+
+/// Multiplexed connections of local server, to each server
+#[derive(Default)]
+pub struct MuxPool {
+    servers: Mutex<HashMap<String, ServerSessions>>,
+}
+
+impl MuxPool {
+    /// Create an empty pool
+    pub fn new() -> MuxPool {
+        MuxPool::default()
+    }
+
+    fn pick(
+        &self,
+        context: &SharedContext,
+        config: &MuxConfig,
+        svr_cfg: &ServerConfig,
+        data: &SharedServerStatisticData,
+    ) -> Pick {
+        let mut servers = self.servers.lock();
+        let entry = servers.entry(svr_cfg.addr().to_string()).or_default();
+
+        if let Some(until) = entry.unsupported_until {
+            if Instant::now() < until {
+                return Pick::Plain;
+            }
+            entry.unsupported_until = None;
+        }
+
+        entry.sessions.retain(|s| !s.is_closed());
+
+        let least_loaded = entry
+            .sessions
+            .iter()
+            .map(|s| (s.stream_count(), s))
+            .filter(|(count, _)| *count < config.max_streams)
+            .min_by_key(|(count, _)| *count);
+        if let Some((_, session)) = least_loaded {
+            return Pick::Session(session.clone());
+        }
+
+        let connect = entry.sessions.len() + entry.connecting < config.max_connections;
+        if !connect && entry.connecting == 0 {
+            return Pick::Plain;
+        }
+
+        if connect {
+            // Connects in another task, so it won't be cancelled with this stream
+            entry.connecting += 1;
+            tokio::spawn(establish(context.clone(), svr_cfg.clone(), data.clone()));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        entry.waiters.push(tx);
+        Pick::Wait(rx, connect)
+    }
+
+    fn established(&self, svr_cfg: &ServerConfig, result: io::Result<io::Result<SharedSession>>) {
+        let mut servers = self.servers.lock();
+        let entry = servers.entry(svr_cfg.addr().to_string()).or_default();
+        entry.connecting -= 1;
+
+        let result = match result {
+            Ok(Ok(session)) => {
+                debug!("mux connection established with {}", svr_cfg.addr());
+                entry.sessions.push(session.clone());
+                Ok(Some(session))
+            }
+            Ok(Err(err)) => {
+                warn!(
+                    "server {} doesn't support mux, {}, connecting without mux",
+                    svr_cfg.addr(),
+                    err
+                );
+                entry.unsupported_until = Some(Instant::now() + MUX_RETRY_INTERVAL);
+                Ok(None)
+            }
+            Err(err) => {
+                debug!("failed to establish mux connection with {}, {}", svr_cfg.addr(), err);
+                Err(err)
+            }
+        };
+
+        // Waiters connect without mux if it isn't supported, and fail if server is unreachable
+        for waiter in entry.waiters.drain(..) {
+            let result = match result {
+                Ok(ref session) => Ok(session.clone()),
+                Err(ref err) => Err(Error::new(err.kind(), err.to_string())),
+            };
+            let _ = waiter.send(result);
+        }
+    }
+
+    /// Open a stream to `addr` via `svr_cfg`
+    ///
+    /// Returns `None` if the stream should be made as an ordinary connection, because the server doesn't support
+    /// multiplexing or there are too many streams. The flag tells if a new connection was established for the stream.
+    /// Fails if the server couldn't be connected.
+    pub async fn open(
+        &self,
+        context: &SharedContext,
+        config: &MuxConfig,
+        svr_cfg: &ServerConfig,
+        data: &SharedServerStatisticData,
+        addr: &Address,
+    ) -> io::Result<Option<(MuxStream, bool)>> {
+        // Connection may be closed after picked
+        let stream = match self.pick(context, config, svr_cfg, data) {
+            Pick::Session(session) => session.open(addr).ok().map(|s| (s, false)),
+            Pick::Wait(rx, connected) => match rx.await {
+                // Connection established for other waiters may be full already
+                Ok(Ok(Some(session))) if session.stream_count() < config.max_streams => {
+                    session.open(addr).ok().map(|s| (s, connected))
+                }
+                Ok(Err(err)) => return Err(err),
+                _ => None,
+            },
+            Pick::Plain => None,
+        };
+        Ok(stream)
+    }
+}
+
+async fn establish(context: SharedContext, svr_cfg: ServerConfig, data: SharedServerStatisticData) {
+    let result = connect_session(&context, &svr_cfg, &data).await;
+    context.mux_pool().established(&svr_cfg, result);
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn frame_round_trip() {
+        let frames = vec![
+            Frame::syn(1, &Address::DomainNameAddress("example.com".to_owned(), 443)),
+            Frame::new(FRAME_DATA, 3, Bytes::from_static(b"hello")),
+            Frame::empty(FRAME_FIN, 5),
+            Frame::window_update(7, 1024),
+        ];
+
+        let mut buf = BytesMut::new();
+        for frame in &frames {
+            frame.write_to_buf(&mut buf);
+        }
+
+        let mut r = &buf[..];
+        for frame in frames {
+            assert_eq!(Frame::read_from(&mut r).await.unwrap(), frame);
+        }
+        assert!(r.is_empty());
+    }
+
+    #[tokio::test]
+    async fn relay_streams() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Echoes every stream, and checks their target addresses
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (session, mut incoming) = serve(socket);
+            tokio::spawn(session);
+
+            while let Some((stream, target)) = incoming.recv().await {
+                assert_eq!(target, Address::DomainNameAddress("example.com".to_owned(), 80));
+                tokio::spawn(async move {
+                    let (mut r, mut w) = tokio::io::split(stream);
+                    tokio::io::copy(&mut r, &mut w).await.unwrap();
+                    w.shutdown().await.unwrap();
+                });
+            }
+        });
+
+        let session = start_client(TcpStream::connect(addr).await.unwrap(), None, None);
+        let target = Address::DomainNameAddress("example.com".to_owned(), 80);
+
+        // Larger than window, so both directions wait for window updates
+        let relays = (0..4u8).map(|i| {
+            let mut stream = session.open(&target).unwrap();
+            async move {
+                let data = vec![i; INITIAL_WINDOW * 3];
+                let (mut r, mut w) = tokio::io::split(&mut stream);
+
+                let send = async {
+                    w.write_all(&data).await.unwrap();
+                    w.shutdown().await.unwrap();
+                };
+                let mut received = Vec::new();
+                let recv = r.read_to_end(&mut received);
+                let (_, n) = future::join(send, recv).await;
+
+                assert_eq!(n.unwrap(), data.len());
+                assert!(received == data);
+            }
+        });
+        future::join_all(relays).await;
+
+        assert_eq!(session.stream_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn refuse_streams_beyond_limit() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Holds every accepted stream open
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (session, mut incoming) = serve(socket);
+            tokio::spawn(session);
+
+            let mut streams = Vec::new();
+            while let Some((stream, _)) = incoming.recv().await {
+                streams.push(stream);
+            }
+        });
+
+        let session = start_client(TcpStream::connect(addr).await.unwrap(), None, None);
+        let target = Address::DomainNameAddress("example.com".to_owned(), 80);
+
+        let mut streams = (0..=MAX_MUX_STREAMS)
+            .map(|_| session.open(&target).unwrap())
+            .collect::<Vec<_>>();
+
+        let mut buf = [0u8; 1];
+        let err = streams.last_mut().unwrap().read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+
+        // The others are kept
+        streams.pop();
+        assert_eq!(session.stream_count(), MAX_MUX_STREAMS);
+        assert!(!session.is_closed());
+    }
+
+    #[tokio::test]
+    async fn close_on_window_overflow() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Allows the stream to send more than `INITIAL_WINDOW` bytes
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let _ = Frame::read_from(&mut socket).await.unwrap();
+
+            let mut buf = BytesMut::new();
+            Frame::window_update(1, 1).write_to_buf(&mut buf);
+            socket.write_all(&buf).await.unwrap();
+
+            let mut buf = Vec::new();
+            let _ = socket.read_to_end(&mut buf).await;
+        });
+
+        let session = start_client(TcpStream::connect(addr).await.unwrap(), None, None);
+        let mut stream = session
+            .open(&Address::DomainNameAddress("example.com".to_owned(), 80))
+            .unwrap();
+
+        let mut buf = [0u8; 1];
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+        assert!(session.is_closed());
+    }
+}
@@ -2,26 +2,40 @@
 
 use std::{
     fmt::{self, Display, Formatter},
-    io::{self, Error},
+    io::{self, Error, ErrorKind},
     net::SocketAddr,
     pin::Pin,
     task::{self, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use bytes::{Buf, BytesMut};
 use futures::ready;
-use log::{debug, error, trace};
+use log::{debug, error, trace, warn};
 use pin_project::{pin_project, project};
 use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
 
 use crate::{
     config::{ConfigType, ServerAddr, ServerConfig},
     context::{Context, SharedContext},
-    relay::{socks5::Address, sys::tcp_stream_connect, utils::try_timeout},
+    relay::{
+        loadbalancing::server::{
+            ActiveConnection,
+            ServerData,
+            SharedServerStatistic,
+            SharedServerStatisticData,
+            TrafficReporter,
+        },
+        socks5::Address,
+        sys::tcp_stream_connect,
+        utils::try_timeout,
+    },
 };
 
-use super::{connection::Connection, CryptoStream, STcpStream};
+use super::{connection::Connection, mux::MuxStream, utils::connect_happy_eyeballs, CryptoStream, STcpStream};
+
+/// Maximum number of servers tried for one connection, before giving up
+const MAX_FAILOVER_SERVERS: usize = 3;
 
 enum ProxiedConnectState {
     Connected(Address),
@@ -167,13 +181,14 @@ impl AsyncWrite for ProxiedConnection {
 enum ProxyConnection {
     Direct(#[pin] STcpStream),
     Proxied(#[pin] ProxiedConnection),
+    Multiplexed(#[pin] MuxStream),
 }
 
 impl ProxyConnection {
     /// Check if the underlying connection is proxied
     fn is_proxied(&self) -> bool {
         match *self {
-            ProxyConnection::Proxied { .. } => true,
+            ProxyConnection::Proxied { .. } | ProxyConnection::Multiplexed { .. } => true,
             _ => false,
         }
     }
@@ -182,6 +197,7 @@ impl ProxyConnection {
         match *self {
             ProxyConnection::Direct(ref stream) => stream.get_ref().local_addr(),
             ProxyConnection::Proxied(ref stream) => stream.local_addr(),
+            ProxyConnection::Multiplexed(ref stream) => stream.local_addr(),
         }
     }
 }
@@ -194,6 +210,8 @@ macro_rules! forward_call {
             __ProxyConnectionProjection::Direct(stream) => stream.$method($($param),*),
             // ProxyConnection::Proxied(stream) => stream.$method($($param),*),
             __ProxyConnectionProjection::Proxied(stream) => stream.$method($($param),*),
+            // ProxyConnection::Multiplexed(stream) => stream.$method($($param),*),
+            __ProxyConnectionProjection::Multiplexed(stream) => stream.$method($($param),*),
         }
     };
 }
@@ -263,19 +281,25 @@ pub struct ProxyStream {
     #[pin]
     connection: ProxyConnection,
     context: SharedContext,
+    // Counted in the load balancer while this connection is alive
+    active: Option<ActiveConnection>,
+    // Outcomes of proxied connections are reported to the load balancer
+    reporter: Option<TrafficReporter>,
 }
 
 impl ProxyStream {
     /// Connect to remote by ACL rules
-    pub async fn connect(
+    ///
+    /// Proxied connections are made via the first reachable server of `servers`, see `connect_proxied_failover`
+    pub async fn connect<S: ServerData>(
         context: SharedContext,
-        svr_cfg: &ServerConfig,
+        servers: &[SharedServerStatistic<S>],
         addr: &Address,
     ) -> Result<ProxyStream, ProxyStreamError> {
         if context.check_target_bypassed(addr).await {
             ProxyStream::connect_direct_wrapped(context, addr).await
         } else {
-            ProxyStream::connect_proxied_wrapped(context, svr_cfg, addr).await
+            ProxyStream::connect_proxied_failover(context, servers, addr).await
         }
     }
 
@@ -291,9 +315,12 @@ impl ProxyStream {
         let stream = match *addr {
             Address::SocketAddress(ref saddr) => try_timeout(tcp_stream_connect(&saddr, &context), timeout).await?,
             Address::DomainNameAddress(ref domain, port) => {
-                lookup_then!(context, domain, port, |saddr| {
-                    try_timeout(tcp_stream_connect(&saddr, &context), timeout).await
-                })?
+                let addrs = context.dns_resolve(domain, port).await?;
+                let context = &context;
+                connect_happy_eyeballs(addrs, context.config().ipv6_first, |saddr| async move {
+                    try_timeout(tcp_stream_connect(&saddr, context), timeout).await
+                })
+                .await?
                 .1
             }
         };
@@ -301,6 +328,8 @@ impl ProxyStream {
         Ok(ProxyStream {
             context,
             connection: ProxyConnection::Direct(Connection::new(stream, timeout)),
+            active: None,
+            reporter: None,
         })
     }
 
@@ -332,18 +361,106 @@ impl ProxyStream {
         Ok(ProxyStream {
             context,
             connection: ProxyConnection::Proxied(ProxiedConnection::connected(proxy_stream, addr.clone())),
+            active: None,
+            reporter: None,
         })
     }
 
-    async fn connect_proxied_wrapped(
+    /// Connect to remote via a multiplexed connection to proxy server, if `mux` is configured
+    ///
+    /// Falls back to `connect_proxied` if the server doesn't support multiplexing.
+    /// The flag tells if a new connection was made to proxy server.
+    async fn connect_multiplexed(
+        context: SharedContext,
+        svr_cfg: &ServerConfig,
+        data: &SharedServerStatisticData,
+        addr: &Address,
+    ) -> io::Result<(ProxyStream, bool)> {
+        let opened = match context.config().mux {
+            Some(ref mux) => context.mux_pool().open(&context, mux, svr_cfg, data, addr).await?,
+            None => None,
+        };
+
+        match opened {
+            Some((stream, connected)) => {
+                debug!("connect to {} via {} (multiplexed)", addr, svr_cfg.addr());
+
+                let s = ProxyStream {
+                    context,
+                    connection: ProxyConnection::Multiplexed(stream),
+                    active: None,
+                    reporter: None,
+                };
+                Ok((s, connected))
+            }
+            None => ProxyStream::connect_proxied(context, svr_cfg, addr).await.map(|s| (s, true)),
+        }
+    }
+
+    /// Connect to remote via proxy server, and report its outcomes to server's statistic `data`
+    ///
+    /// Latency of connecting, latency of the first byte responded and failures are all reported.
+    /// Retries of connecting share one `timeout`, so a blackholed server fails in one timeout.
+    pub async fn connect_proxied_reported(
         context: SharedContext,
         svr_cfg: &ServerConfig,
+        data: &SharedServerStatisticData,
+        addr: &Address,
+    ) -> io::Result<ProxyStream> {
+        let start = Instant::now();
+        let timeout = svr_cfg.timeout().or(context.config().timeout);
+
+        match try_timeout(ProxyStream::connect_multiplexed(context, svr_cfg, data, addr), timeout).await {
+            Ok((mut s, connected)) => {
+                // Opening a stream on an existing multiplexed connection takes no time
+                if connected {
+                    data.report_connected(start.elapsed());
+                }
+                s.reporter = Some(data.traffic_reporter(Some(addr)));
+                Ok(s)
+            }
+            Err(err) => {
+                // Report to global statistic
+                data.report_failure();
+                Err(err)
+            }
+        }
+    }
+
+    /// Connect to remote via the first reachable server of `servers`
+    ///
+    /// Servers are tried in order, at most `MAX_FAILOVER_SERVERS` of them. Unreachable servers are reported to
+    /// the load balancer, so it could switch to another one before the next round of checking.
+    pub async fn connect_proxied_failover<S: ServerData>(
+        context: SharedContext,
+        servers: &[SharedServerStatistic<S>],
         addr: &Address,
     ) -> Result<ProxyStream, ProxyStreamError> {
-        match ProxyStream::connect_proxied(context, svr_cfg, addr).await {
-            Ok(s) => Ok(s),
-            Err(err) => Err(ProxyStreamError::new(err, false)),
+        let mut last_err = None;
+
+        for server in servers.iter().take(MAX_FAILOVER_SERVERS) {
+            let svr_cfg = server.server_config();
+
+            match ProxyStream::connect_proxied_reported(context.clone(), svr_cfg, server.data(), addr).await {
+                Ok(mut s) => {
+                    s.active = Some(server.active_connection());
+                    return Ok(s);
+                }
+                Err(err) => {
+                    warn!(
+                        "failed to connect {} via {}, {}, failing over to the next server",
+                        addr,
+                        svr_cfg,
+                        err
+                    );
+
+                    last_err = Some(err);
+                }
+            }
         }
+
+        let err = last_err.unwrap_or_else(|| Error::new(ErrorKind::Other, "no available server"));
+        Err(ProxyStreamError::new(err, false))
     }
 
     /// Split into reader and writer
@@ -370,7 +487,16 @@ impl ProxyStream {
 
 impl AsyncRead for ProxyStream {
     fn poll_read(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
-        let p = self.as_mut().project().connection.poll_read(cx, buf);
+        let this = self.as_mut().project();
+        let p = this.connection.poll_read(cx, buf);
+
+        if let Some(ref mut reporter) = *this.reporter {
+            match p {
+                Poll::Ready(Ok(n)) => reporter.received(n),
+                Poll::Ready(Err(ref err)) => reporter.failed(err),
+                Poll::Pending => {}
+            }
+        }
 
         // Flow statistic for Android client
         #[cfg(feature = "local-flow-stat")]
@@ -388,7 +514,16 @@ impl AsyncRead for ProxyStream {
 
 impl AsyncWrite for ProxyStream {
     fn poll_write(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
-        let p = self.as_mut().project().connection.poll_write(cx, buf);
+        let this = self.as_mut().project();
+        let p = this.connection.poll_write(cx, buf);
+
+        if let Some(ref mut reporter) = *this.reporter {
+            match p {
+                Poll::Ready(Ok(..)) => reporter.sent(),
+                Poll::Ready(Err(ref err)) => reporter.failed(err),
+                Poll::Pending => {}
+            }
+        }
 
         // Flow statistic for Android client
         #[cfg(feature = "local-flow-stat")]
@@ -456,7 +591,7 @@ async fn connect_proxy_server_internal(
 }
 
 /// Connect to proxy server with `ServerConfig`
-async fn connect_proxy_server(context: &Context, svr_cfg: &ServerConfig) -> io::Result<STcpStream> {
+pub(super) async fn connect_proxy_server(context: &Context, svr_cfg: &ServerConfig) -> io::Result<STcpStream> {
     let timeout = svr_cfg.timeout().or(context.config().timeout);
 
     let svr_addr = match context.config().config_type {
@@ -471,10 +606,8 @@ async fn connect_proxy_server(context: &Context, svr_cfg: &ServerConfig) -> io::
 
     // Retry if connect failed
     //
-    // FIXME: This won't work if server is actually down.
-    //        Probably we should retry with another server.
-    //
-    // Also works if plugin is starting
+    // Dead servers are handled by failing over to another server in `ProxyStream::connect_proxied_failover`,
+    // which limits all retries to one timeout. Retrying here works if plugin is starting
     const RETRY_TIMES: i32 = 3;
 
     let orig_svr_addr = svr_cfg.addr();
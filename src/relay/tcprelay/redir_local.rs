@@ -24,14 +24,12 @@ use super::ProxyStream;
 ///
 /// This method must be called after handshaking with client (for example, socks5 handshaking)
 async fn establish_client_tcp_redir<'a>(
-    server: &SharedPlainServerStatistic,
+    servers: &[SharedPlainServerStatistic],
     mut s: TcpStream,
     client_addr: SocketAddr,
     addr: &Address,
 ) -> io::Result<()> {
-    let svr_cfg = server.server_config();
-
-    let svr_s = ProxyStream::connect(server.clone_context(), svr_cfg, addr).await?;
+    let svr_s = ProxyStream::connect(servers[0].clone_context(), servers, addr).await?;
     let (mut svr_r, mut svr_w) = svr_s.split();
 
     let (mut r, mut w) = s.split();
@@ -67,7 +65,12 @@ async fn establish_client_tcp_redir<'a>(
     Ok(())
 }
 
-async fn handle_redir_client(server: &SharedPlainServerStatistic, s: TcpStream, daddr: SocketAddr) -> io::Result<()> {
+async fn handle_redir_client(
+    servers: &[SharedPlainServerStatistic],
+    s: TcpStream,
+    daddr: SocketAddr,
+) -> io::Result<()> {
+    let server = &servers[0];
     let svr_cfg = server.server_config();
 
     if let Err(err) = s.set_keepalive(svr_cfg.timeout()) {
@@ -84,7 +87,7 @@ async fn handle_redir_client(server: &SharedPlainServerStatistic, s: TcpStream,
 
     // Get forward address from socket
     let target_addr = Address::from(daddr);
-    establish_client_tcp_redir(server, s, client_addr, &target_addr).await
+    establish_client_tcp_redir(servers, s, client_addr, &target_addr).await
 }
 
 pub async fn run(context: SharedContext) -> io::Result<()> {
@@ -99,15 +102,14 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
     let actual_local_addr = listener.local_addr().expect("determine port bound to");
 
-    let servers = PlainPingBalancer::new(context.clone(), ServerType::Tcp).await;
+    let balancer = PlainPingBalancer::new(context.clone(), ServerType::Tcp).await;
     info!("shadowsocks TCP redirect listening on {}", actual_local_addr);
 
     loop {
         let (socket, peer_addr) = listener.accept().await?;
-        let server = servers.pick_server();
+        let balancer = balancer.clone();
 
         trace!("got connection {}", peer_addr);
-        trace!("picked proxy server: {:?}", server.server_config());
 
         tokio::spawn(async move {
             let dst_addr = match socket.destination_addr(redir_ty) {
@@ -121,7 +123,10 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 }
             };
 
-            if let Err(err) = handle_redir_client(&server, socket, dst_addr).await {
+            let servers = balancer.pick_servers(&peer_addr, &Address::from(dst_addr));
+            trace!("picked proxy server: {:?}", servers[0].server_config());
+
+            if let Err(err) = handle_redir_client(&servers, socket, dst_addr).await {
                 error!("TCP redirect client, error: {:?}", err);
             }
         });
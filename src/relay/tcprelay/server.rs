@@ -1,6 +1,6 @@
 //! Relay for TCP server that running on the server side
 
-use std::{io, io::ErrorKind, net::SocketAddr};
+use std::{io, io::ErrorKind, net::SocketAddr, sync::Arc, time::Duration};
 
 use futures::{
     future::{self, Either},
@@ -9,6 +9,7 @@ use futures::{
 use log::{debug, error, info, trace, warn};
 use tokio::{
     self,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream},
 };
 
@@ -22,9 +23,14 @@ use crate::{
     },
 };
 
-use super::{monitor::TcpMonStream, utils::connect_tcp_stream, CryptoStream, STcpStream};
+use super::{
+    monitor::TcpMonStream,
+    mux::{self, MUX_VERSION},
+    utils::{connect_happy_eyeballs, connect_tcp_stream},
+    CryptoStream,
+    STcpStream,
+};
 
-#[allow(clippy::cognitive_complexity)]
 async fn handle_client(
     context: SharedContext,
     flow_stat: SharedServerFlowStatistic,
@@ -62,10 +68,74 @@ async fn handle_client(
         }
     };
 
+    if mux::is_marker(&remote_addr) {
+        return serve_mux(context, svr_cfg, stream, peer_addr, timeout).await;
+    }
+
+    relay_to_remote(&context, svr_cfg, stream, peer_addr, remote_addr, timeout).await
+}
+
+/// Serves a multiplexed connection, whose streams are relayed to their targets
+async fn serve_mux<S>(
+    context: SharedContext,
+    svr_cfg: &ServerConfig,
+    mut stream: S,
+    peer_addr: SocketAddr,
+    timeout: Option<Duration>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let mut version = [0u8; 1];
+    stream.read_exact(&mut version).await?;
+    if version[0] != MUX_VERSION {
+        error!("unsupported mux version {} from client {}", version[0], peer_addr);
+        return Ok(());
+    }
+    stream.write_all(&version).await?;
+
+    debug!("MUX {} established", peer_addr);
+
+    let (session, mut incoming) = mux::serve(stream);
+
+    // Streams outlive the borrowed svr_cfg
+    let svr_cfg = Arc::new(svr_cfg.clone());
+    let accepting = async move {
+        while let Some((stream, remote_addr)) = incoming.recv().await {
+            let context = context.clone();
+            let svr_cfg = svr_cfg.clone();
+
+            tokio::spawn(async move {
+                // Error is ignored because it is already logged
+                let _ = relay_to_remote(&context, &svr_cfg, stream, peer_addr, remote_addr, timeout).await;
+            });
+        }
+    };
+
+    future::join(session, accepting).await;
+
+    debug!("MUX {} closed", peer_addr);
+
+    Ok(())
+}
+
+/// Relays `stream` from client to `remote_addr`
+#[allow(clippy::cognitive_complexity)]
+async fn relay_to_remote<S>(
+    context: &SharedContext,
+    svr_cfg: &ServerConfig,
+    stream: S,
+    peer_addr: SocketAddr,
+    remote_addr: Address,
+    timeout: Option<Duration>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
     debug!("RELAY {} <-> {} establishing", peer_addr, remote_addr);
 
     // Check if remote_addr matches any ACL rules
-    if context.check_outbound_blocked(&remote_addr) {
+    if context.check_outbound_blocked(svr_cfg, &remote_addr) {
         warn!("outbound {} is blocked by ACL rules", remote_addr);
         return Ok(());
     }
@@ -73,7 +143,7 @@ async fn handle_client(
     let bind_addr = match context.config().local_addr {
         None => None,
         Some(ref addr) => {
-            let ba = addr.bind_addr(&context).await?;
+            let ba = addr.bind_addr(context).await?;
             Some(ba)
         }
     };
@@ -94,19 +164,27 @@ async fn handle_client(
             }
         }
         Address::DomainNameAddress(ref dname, port) => {
-            let result = lookup_outbound_then!(&context, dname.as_str(), port, |addr| {
-                match try_timeout(connect_tcp_stream(&addr, &bind_addr), timeout).await {
-                    Ok(s) => Ok(s),
-                    Err(err) => {
-                        debug!(
-                            "failed to connect remote {}:{} (resolved: {}), {}, try others",
-                            dname, port, addr, err
-                        );
-                        Err(err)
-                    }
+            let mut addrs = context.dns_resolve(dname, port).await?;
+            addrs.retain(|addr| {
+                if context.check_resolved_outbound_blocked(svr_cfg, addr) {
+                    warn!("outbound {}:{} resolved to {} is blocked by ACL rules", dname, port, addr);
+                    false
+                } else {
+                    true
                 }
             });
 
+            if addrs.is_empty() {
+                warn!("outbound {}:{} is blocked by ACL rules", dname, port);
+                return Ok(());
+            }
+
+            let bind_addr = &bind_addr;
+            let result = connect_happy_eyeballs(addrs, context.config().ipv6_first, |addr| async move {
+                try_timeout(connect_tcp_stream(&addr, bind_addr), timeout).await
+            })
+            .await;
+
             match result {
                 Ok((addr, s)) => {
                     trace!("connected remote {}:{} (resolved: {})", dname, port, addr);
@@ -122,7 +200,7 @@ async fn handle_client(
 
     debug!("RELAY {} <-> {} established", peer_addr, remote_addr);
 
-    let (mut cr, mut cw) = stream.split();
+    let (mut cr, mut cw) = tokio::io::split(stream);
     let (mut sr, mut sw) = remote_stream.split();
 
     use tokio::io::copy;
@@ -188,7 +266,7 @@ pub async fn run(context: SharedContext, flow_stat: SharedMultiServerFlowStatist
                 match listener.accept().await {
                     Ok((socket, peer_addr)) => {
                         // Check ACL rules
-                        if context.check_client_blocked(&peer_addr) {
+                        if context.check_client_blocked(svr_cfg, &peer_addr) {
                             warn!("client {} is blocked by ACL rules", peer_addr);
                             continue;
                         }
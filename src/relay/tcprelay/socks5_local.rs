@@ -13,7 +13,7 @@ use tokio::{
 };
 
 use crate::{
-    context::SharedContext,
+    context::{Context, SharedContext},
     relay::{
         loadbalancing::server::{PlainPingBalancer, ServerType, SharedPlainServerStatistic},
         socks5::{self, Address, HandshakeRequest, HandshakeResponse, TcpRequestHeader, TcpResponseHeader},
@@ -29,15 +29,14 @@ struct UdpConfig {
 }
 
 async fn handle_socks5_connect<'a>(
-    server: &SharedPlainServerStatistic,
+    servers: &[SharedPlainServerStatistic],
     stream: &mut TcpStream,
     client_addr: SocketAddr,
     addr: &Address,
 ) -> io::Result<()> {
-    let context = server.context();
-    let svr_cfg = server.server_config();
+    let context = servers[0].context();
 
-    let svr_s = match ProxyStream::connect(server.clone_context(), svr_cfg, addr).await {
+    let svr_s = match ProxyStream::connect(servers[0].clone_context(), servers, addr).await {
         Ok(svr_s) => {
             // Tell the client that we are ready
             let header = TcpResponseHeader::new(socks5::Reply::Succeeded, Address::SocketAddress(svr_s.local_addr()?));
@@ -50,11 +49,6 @@ async fn handle_socks5_connect<'a>(
         Err(perr) => {
             use crate::relay::socks5::Reply;
 
-            if perr.is_proxied() {
-                // Report to global statistic
-                server.report_failure().await;
-            }
-
             let err = perr.into_inner();
             let reply = match err.kind() {
                 ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
@@ -114,13 +108,12 @@ async fn handle_socks5_connect<'a>(
 
 #[allow(clippy::cognitive_complexity)]
 async fn handle_socks5_client(
-    server: &SharedPlainServerStatistic,
+    context: &Context,
+    balancer: &PlainPingBalancer,
     mut s: TcpStream,
     udp_conf: UdpConfig,
 ) -> io::Result<()> {
-    let svr_cfg = server.server_config();
-
-    if let Err(err) = s.set_keepalive(svr_cfg.timeout()) {
+    if let Err(err) = s.set_keepalive(context.config().timeout) {
         error!("failed to set keep alive: {:?}", err);
     }
 
@@ -169,11 +162,14 @@ async fn handle_socks5_client(
     let addr = header.address;
     match header.command {
         socks5::Command::TcpConnect => {
-            let enable_tcp = server.config().mode.enable_tcp();
+            let enable_tcp = context.config().mode.enable_tcp();
             if enable_tcp {
                 debug!("CONNECT {}", addr);
 
-                match handle_socks5_connect(server, &mut s, client_addr, &addr).await {
+                let servers = balancer.pick_servers(&client_addr, &addr);
+                trace!("picked proxy server: {:?}", servers[0].server_config());
+
+                match handle_socks5_connect(&servers, &mut s, client_addr, &addr).await {
                     Ok(..) => Ok(()),
                     Err(err) => Err(io::Error::new(
                         err.kind(),
@@ -232,20 +228,20 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
         client_addr: actual_local_addr,
     };
 
-    let servers = PlainPingBalancer::new(context, ServerType::Tcp).await;
+    let balancer = PlainPingBalancer::new(context.clone(), ServerType::Tcp).await;
 
     info!("shadowsocks TCP listening on {}", actual_local_addr);
 
     loop {
         let (socket, peer_addr) = listener.accept().await?;
-        let server = servers.pick_server();
 
         trace!("got connection {}", peer_addr);
-        trace!("picked proxy server: {:?}", server.server_config());
 
+        let context = context.clone();
+        let balancer = balancer.clone();
         let udp_conf = udp_conf.clone();
         tokio::spawn(async move {
-            if let Err(err) = handle_socks5_client(&server, socket, udp_conf).await {
+            if let Err(err) = handle_socks5_client(&context, &balancer, socket, udp_conf).await {
                 error!("TCP socks5 client exited with error: {}", err);
             }
         });
@@ -23,15 +23,13 @@ use super::ProxyStream;
 ///
 /// This method must be called after handshaking with client (for example, socks5 handshaking)
 async fn establish_client_tcp_tunnel<'a>(
-    server: &SharedPlainServerStatistic,
+    servers: &[SharedPlainServerStatistic],
     mut s: TcpStream,
     client_addr: SocketAddr,
     addr: &Address,
 ) -> io::Result<()> {
-    let svr_cfg = server.server_config();
-
     // NOTE: TUNNEL doesn't need to check ACL, just forward everything to proxy server
-    let svr_s = ProxyStream::connect_proxied(server.clone_context(), svr_cfg, addr).await?;
+    let svr_s = ProxyStream::connect_proxied_failover(servers[0].clone_context(), servers, addr).await?;
     let (mut svr_r, mut svr_w) = svr_s.split();
 
     let (mut r, mut w) = s.split();
@@ -67,7 +65,8 @@ async fn establish_client_tcp_tunnel<'a>(
     Ok(())
 }
 
-async fn handle_tunnel_client(server: &SharedPlainServerStatistic, s: TcpStream) -> io::Result<()> {
+async fn handle_tunnel_client(servers: &[SharedPlainServerStatistic], s: TcpStream) -> io::Result<()> {
+    let server = &servers[0];
     let svr_cfg = server.server_config();
 
     if let Err(err) = s.set_keepalive(svr_cfg.timeout()) {
@@ -85,7 +84,7 @@ async fn handle_tunnel_client(server: &SharedPlainServerStatistic, s: TcpStream)
     // forward must not be None, it is already checked in local.rs
     let target_addr = server.config().forward.as_ref().unwrap();
 
-    establish_client_tcp_tunnel(server, s, client_addr, target_addr).await
+    establish_client_tcp_tunnel(servers, s, client_addr, target_addr).await
 }
 
 pub async fn run(context: SharedContext) -> io::Result<()> {
@@ -103,7 +102,7 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
     let actual_local_addr = listener.local_addr().expect("determine port bound to");
 
-    let servers = PlainPingBalancer::new(context.clone(), ServerType::Tcp).await;
+    let balancer = PlainPingBalancer::new(context.clone(), ServerType::Tcp).await;
 
     let forward_addr = context.config().forward.as_ref().expect("`forward` address in config");
     info!(
@@ -113,13 +112,13 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
     loop {
         let (socket, peer_addr) = listener.accept().await?;
-        let server = servers.pick_server();
+        let servers = balancer.pick_servers(&peer_addr, forward_addr);
 
         trace!("got connection {}", peer_addr);
-        trace!("picked proxy server: {:?}", server.server_config());
+        trace!("picked proxy server: {:?}", servers[0].server_config());
 
         tokio::spawn(async move {
-            if let Err(err) = handle_tunnel_client(&server, socket).await {
+            if let Err(err) = handle_tunnel_client(&servers, socket).await {
                 error!("TCP tunnel client exited with error: {:?}", err);
             }
         });
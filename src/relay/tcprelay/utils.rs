@@ -1,10 +1,19 @@
 //! Utility functions
 
-use std::{io, net::SocketAddr};
+use std::{
+    future::Future,
+    io::{self, Error, ErrorKind},
+    net::SocketAddr,
+    time::Duration,
+};
 
-use log::trace;
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{debug, trace};
 use socket2::{Domain, SockAddr, Socket, Type};
-use tokio::net::TcpStream;
+use tokio::{net::TcpStream, time};
+
+/// Delay before starting the next connection attempt, recommended by RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
 
 /// Connecting to a specific target with TCP protocol
 ///
@@ -47,3 +56,120 @@ pub async fn connect_tcp_stream(addr: &SocketAddr, outbound_addr: &Option<Socket
         }
     }
 }
+
+/// Connecting to one of `addrs` with Happy Eyeballs (RFC 8305)
+///
+/// Addresses are tried alternately between IPv6 and IPv4, starting with the preferred family. The next attempt starts
+/// if the previous one hasn't finished in `CONNECTION_ATTEMPT_DELAY`, or right after it failed. The first connected
+/// one wins and the others are dropped.
+pub async fn connect_happy_eyeballs<T, F, Fut>(
+    addrs: Vec<SocketAddr>,
+    ipv6_first: bool,
+    connect: F,
+) -> io::Result<(SocketAddr, T)>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let connect = &connect;
+
+    let mut addrs = interleave_addrs(addrs, ipv6_first).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+
+    loop {
+        if let Some(addr) = addrs.next() {
+            attempts.push(async move { (addr, connect(addr).await) });
+        }
+
+        let result = if addrs.len() > 0 {
+            match time::timeout(CONNECTION_ATTEMPT_DELAY, attempts.next()).await {
+                Ok(r) => r,
+                // Still connecting, start the next attempt concurrently
+                Err(..) => continue,
+            }
+        } else {
+            attempts.next().await
+        };
+
+        match result {
+            Some((addr, Ok(s))) => return Ok((addr, s)),
+            Some((addr, Err(err))) => {
+                debug!("failed to connect {}, {}", addr, err);
+                last_err = Some(err);
+            }
+            None => break,
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| Error::new(ErrorKind::Other, "resolved empty address")))
+}
+
+/// Sort addresses alternately by family, starting with the preferred one
+fn interleave_addrs(addrs: Vec<SocketAddr>, ipv6_first: bool) -> Vec<SocketAddr> {
+    let n = addrs.len();
+    let (preferred, others): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6() == ipv6_first);
+
+    let mut preferred = preferred.into_iter();
+    let mut others = others.into_iter();
+
+    let mut result = Vec::with_capacity(n);
+    loop {
+        match (preferred.next(), others.next()) {
+            (None, None) => break,
+            (a, b) => {
+                result.extend(a);
+                result.extend(b);
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn interleave_families() {
+        let addrs: Vec<SocketAddr> = vec![
+            "[2001:db8::1]:80".parse().unwrap(),
+            "[2001:db8::2]:80".parse().unwrap(),
+            "[2001:db8::3]:80".parse().unwrap(),
+            "192.0.2.1:80".parse().unwrap(),
+        ];
+
+        let sorted = interleave_addrs(addrs.clone(), false);
+        assert_eq!(sorted, vec![addrs[3], addrs[0], addrs[1], addrs[2]]);
+
+        let sorted = interleave_addrs(addrs.clone(), true);
+        assert_eq!(sorted, vec![addrs[0], addrs[3], addrs[1], addrs[2]]);
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_skips_stalled_address() {
+        let broken: SocketAddr = "[2001:db8::1]:80".parse().unwrap();
+        let working: SocketAddr = "192.0.2.1:80".parse().unwrap();
+
+        let result = time::timeout(
+            Duration::from_secs(2),
+            connect_happy_eyeballs(vec![broken, working], true, |addr| async move {
+                if addr == broken {
+                    // Never answers, like a blackholed AAAA record
+                    time::delay_for(Duration::from_secs(3600)).await;
+                }
+                Ok(addr.port())
+            }),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(result.unwrap(), (working, 80));
+
+        let result = connect_happy_eyeballs(vec![broken, working], true, |addr| async move {
+            Err::<(), _>(Error::new(ErrorKind::Other, addr.to_string()))
+        })
+        .await;
+        assert_eq!(result.unwrap_err().to_string(), working.to_string());
+    }
+}
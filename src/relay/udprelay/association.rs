@@ -295,7 +295,7 @@ impl ProxyAssociation {
                 "UDP association {} -> {} via proxy {} payload truncated, expected {} bytes, but sent {} bytes",
                 src_addr,
                 target,
-                svr_cfg.addr(),
+                svr_cfg,
                 encrypt_buf.len(),
                 send_len
             );
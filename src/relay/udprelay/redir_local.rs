@@ -151,7 +151,7 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 Entry::Occupied(oc) => oc.into_mut(),
                 Entry::Vacant(vc) => {
                     // Pick a server
-                    let server = balancer.pick_server();
+                    let server = balancer.pick_server(Some(&src), &target);
 
                     let sender = match ProxyHandler::new(ty, src, cache_key, assoc_map.clone()) {
                         Ok(s) => s,
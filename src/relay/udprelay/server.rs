@@ -166,7 +166,7 @@ impl UdpAssociation {
 
         debug!("UDP ASSOCIATE {} <-> {} establishing", src, addr);
 
-        if context.check_outbound_blocked(&addr) {
+        if context.check_outbound_blocked(svr_cfg, &addr) {
             warn!("outbound {} is blocked by ACL rules", addr);
             return Ok(());
         }
@@ -187,7 +187,7 @@ impl UdpAssociation {
                 );
                 try_timeout(remote_udp.send_to(body, remote_addr), Some(timeout)).await?
             }
-            Address::DomainNameAddress(ref dname, port) => lookup_outbound_then!(context, dname, port, |remote_addr| {
+            Address::DomainNameAddress(ref dname, port) => lookup_outbound_then!(context, svr_cfg, dname, port, |remote_addr| {
                 match try_timeout(remote_udp.send_to(body, &remote_addr), Some(timeout)).await {
                     Ok(l) => {
                         debug!(
@@ -354,7 +354,7 @@ async fn listen(context: SharedContext, flow_stat: SharedServerFlowStatistic, sv
         }
 
         // Check ACL
-        if context.check_client_blocked(&src) {
+        if context.check_client_blocked(svr_cfg, &src) {
             warn!("client {} is blocked by ACL rules", src);
             continue;
         }
@@ -186,7 +186,7 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 Entry::Occupied(oc) => oc.into_mut(),
                 Entry::Vacant(vc) => {
                     // Pick a server
-                    let server = balancer.pick_server();
+                    let server = balancer.pick_server(Some(&src), &target);
 
                     let sender = ProxyHandler {
                         src_addr: src,
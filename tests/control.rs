@@ -0,0 +1,77 @@
+use tokio::{
+    self,
+    net::UdpSocket,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType},
+    run_local,
+};
+
+async fn control(socket: &mut UdpSocket, request: &str) -> String {
+    socket.send_to(request.as_bytes(), "127.0.0.1:9440").await.unwrap();
+
+    let mut buf = vec![0u8; 65536];
+    let n = time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    String::from_utf8(buf[..n].to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn control_servers() {
+    let _ = env_logger::try_init();
+
+    let local_config = Config::load_from_str(
+        r#"{
+            "local_port": 9400,
+            "local_address": "127.0.0.1",
+            "servers": [
+                {"address": "127.0.0.1", "port": 9410, "password": "password", "method": "aes-256-gcm"}
+            ],
+            "control_address": "127.0.0.1:9440",
+            "balancer": {"tcp_probe": "connect"}
+        }"#,
+        ConfigType::Socks5Local,
+    )
+    .unwrap();
+
+    tokio::spawn(run_local(local_config));
+    time::delay_for(Duration::from_secs(1)).await;
+
+    let mut socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let add = r#"add: {"address": "127.0.0.1", "port": 9420, "password": "password", "method": "aes-256-gcm"}"#;
+    assert_eq!(control(&mut socket, add).await, "ok\n");
+    assert_eq!(
+        control(&mut socket, add).await,
+        "server 127.0.0.1:9420 already exists"
+    );
+
+    let list = control(&mut socket, "list").await;
+    assert!(list.contains(r#""server":"127.0.0.1:9410","enabled":true"#));
+    assert!(list.contains(r#""server":"127.0.0.1:9420","enabled":true"#));
+
+    assert_eq!(
+        control(&mut socket, r#"disable: {"server": "127.0.0.1:9410"}"#).await,
+        "ok\n"
+    );
+    assert_eq!(
+        control(&mut socket, r#"remove: {"server": "127.0.0.1:9420"}"#).await,
+        "at least one server should be enabled"
+    );
+    assert_eq!(
+        control(&mut socket, r#"enable: {"server": "127.0.0.1:9410"}"#).await,
+        "ok\n"
+    );
+    assert_eq!(
+        control(&mut socket, r#"remove: {"server": "127.0.0.1:9420"}"#).await,
+        "ok\n"
+    );
+
+    let list = control(&mut socket, "list").await;
+    assert!(list.contains("127.0.0.1:9410"));
+    assert!(!list.contains("127.0.0.1:9420"));
+}
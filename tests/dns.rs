@@ -0,0 +1,96 @@
+#![cfg(feature = "local-dns-relay")]
+
+use std::net::Ipv4Addr;
+
+use tokio::{
+    self,
+    net::UdpSocket,
+    time::{self, Duration},
+};
+use trust_dns_proto::{
+    op::{Message, MessageType, Query},
+    rr::{Name, RData, Record, RecordType},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType},
+    run_local,
+    run_server,
+};
+
+#[tokio::test]
+async fn dns_relay() {
+    let _ = env_logger::try_init();
+
+    let local_config = Config::load_from_str(
+        r#"{
+            "local_port": 9310,
+            "local_address": "127.0.0.1",
+            "server": "127.0.0.1",
+            "server_port": 9320,
+            "password": "password",
+            "method": "aes-256-gcm",
+            "local_dns": "127.0.0.1:9330",
+            "remote_dns": "127.0.0.1:9330",
+            "remote_dns_mode": "udp_only"
+        }"#,
+        ConfigType::DnsLocal,
+    )
+    .unwrap();
+
+    let server_config = Config::load_from_str(
+        r#"{
+            "server": "127.0.0.1",
+            "server_port": 9320,
+            "password": "password",
+            "method": "aes-256-gcm",
+            "mode": "tcp_and_udp"
+        }"#,
+        ConfigType::Server,
+    )
+    .unwrap();
+
+    tokio::spawn(run_local(local_config));
+    tokio::spawn(run_server(server_config));
+
+    // Start a DNS server answers 1.2.3.4 for all A queries
+    tokio::spawn(async {
+        let mut socket = UdpSocket::bind("127.0.0.1:9330").await.unwrap();
+
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (n, src) = socket.recv_from(&mut buf).await.unwrap();
+            let request = Message::from_vec(&buf[..n]).unwrap();
+
+            let mut response = Message::new();
+            response.set_id(request.id());
+            response.set_message_type(MessageType::Response);
+            response.add_queries(request.queries().iter().cloned());
+            let name = request.queries()[0].name().clone();
+            response.add_answer(Record::from_rdata(name, 300, RData::A(Ipv4Addr::new(1, 2, 3, 4))));
+
+            socket.send_to(&response.to_vec().unwrap(), src).await.unwrap();
+        }
+    });
+
+    time::delay_for(Duration::from_secs(1)).await;
+
+    let mut query = Message::new();
+    query.set_id(1234);
+    query.set_recursion_desired(true);
+    query.add_query(Query::query(Name::from_ascii("www.example.com.").unwrap(), RecordType::A));
+
+    let mut socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.send_to(&query.to_vec().unwrap(), "127.0.0.1:9310").await.unwrap();
+
+    let mut buf = vec![0u8; 65536];
+    let n = time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+
+    let response = Message::from_vec(&buf[..n]).unwrap();
+    assert_eq!(response.id(), 1234);
+    assert_eq!(response.answers().len(), 1);
+    assert_eq!(response.answers()[0].rdata(), &RData::A(Ipv4Addr::new(1, 2, 3, 4)));
+}
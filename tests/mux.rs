@@ -0,0 +1,135 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use futures::future;
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, Mode, MuxConfig, ServerAddr, ServerConfig},
+    crypto::CipherType,
+    relay::{socks5::Address, tcprelay::client::Socks5Client},
+    run_local,
+    run_server,
+};
+
+const RELAY_COUNT: usize = 8;
+
+async fn serve_echo(addr: &'static str) {
+    let mut listener = TcpListener::bind(addr).await.unwrap();
+
+    loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            let (mut r, mut w) = stream.split();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+        });
+    }
+}
+
+// Forwards connections to `svr_addr` and counts them, the first one is closed if `reject_first`,
+// like a server doesn't support mux
+async fn serve_counting_proxy(addr: SocketAddr, svr_addr: SocketAddr, reject_first: bool, accepted: Arc<AtomicUsize>) {
+    let mut listener = TcpListener::bind(addr).await.unwrap();
+
+    loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let count = accepted.fetch_add(1, Ordering::SeqCst) + 1;
+
+        tokio::spawn(async move {
+            if reject_first && count == 1 {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                return;
+            }
+
+            let mut svr_stream = TcpStream::connect(svr_addr).await.unwrap();
+            let (mut cr, mut cw) = stream.split();
+            let (mut sr, mut sw) = svr_stream.split();
+            let _ = future::select(tokio::io::copy(&mut cr, &mut sw), tokio::io::copy(&mut sr, &mut cw)).await;
+        });
+    }
+}
+
+// Runs ssserver on `svr_addr`, behind a counting proxy on `proxy_addr`, and sslocal with mux on `local_addr`
+async fn start_servers(svr_addr: &str, proxy_addr: &str, local_addr: &str, reject_first: bool) -> Arc<AtomicUsize> {
+    let svr_addr = svr_addr.parse::<SocketAddr>().unwrap();
+    let proxy_addr = proxy_addr.parse::<SocketAddr>().unwrap();
+    let local_addr = local_addr.parse::<SocketAddr>().unwrap();
+
+    let mut svr_config = Config::new(ConfigType::Server);
+    svr_config.server = vec![ServerConfig::basic(svr_addr, "password".to_owned(), CipherType::Aes256Gcm)];
+    svr_config.mode = Mode::TcpOnly;
+    tokio::spawn(run_server(svr_config));
+
+    let accepted = Arc::new(AtomicUsize::new(0));
+    tokio::spawn(serve_counting_proxy(proxy_addr, svr_addr, reject_first, accepted.clone()));
+
+    let mut cli_config = Config::new(ConfigType::Socks5Local);
+    cli_config.local_addr = Some(ServerAddr::from(local_addr));
+    cli_config.server = vec![ServerConfig::basic(proxy_addr, "password".to_owned(), CipherType::Aes256Gcm)];
+    cli_config.mode = Mode::TcpOnly;
+    cli_config.mux = Some(MuxConfig {
+        max_connections: 1,
+        max_streams: 128,
+    });
+    tokio::spawn(run_local(cli_config));
+
+    time::delay_for(Duration::from_secs(1)).await;
+
+    accepted
+}
+
+async fn relay_echo(local_addr: &str, echo_addr: &str) {
+    let local_addr = local_addr.parse::<SocketAddr>().unwrap();
+
+    let relays = (0..RELAY_COUNT as u8).map(|i| async move {
+        let target = Address::SocketAddress(echo_addr.parse().unwrap());
+        let c = Socks5Client::connect(target, &local_addr).await.unwrap();
+        let (mut r, mut w) = tokio::io::split(c);
+
+        // Larger than flow control window of a stream
+        let data = vec![i; 1024 * 1024];
+        let mut buf = vec![0u8; data.len()];
+        let (sent, received) = future::join(w.write_all(&data), r.read_exact(&mut buf)).await;
+        sent.unwrap();
+        received.unwrap();
+        assert!(buf == data);
+    });
+    future::join_all(relays).await;
+}
+
+#[tokio::test]
+async fn mux_relay_stream() {
+    let _ = env_logger::try_init();
+
+    tokio::spawn(serve_echo("127.0.0.1:9620"));
+    let accepted = start_servers("127.0.0.1:9600", "127.0.0.1:9601", "127.0.0.1:9610", false).await;
+
+    relay_echo("127.0.0.1:9610", "127.0.0.1:9620").await;
+
+    // Relays share the same connection to server
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn mux_unsupported_server() {
+    let _ = env_logger::try_init();
+
+    tokio::spawn(serve_echo("127.0.0.1:9621"));
+    let accepted = start_servers("127.0.0.1:9602", "127.0.0.1:9603", "127.0.0.1:9611", true).await;
+
+    relay_echo("127.0.0.1:9611", "127.0.0.1:9621").await;
+
+    // Mux handshake is rejected, and each relay connects without mux
+    assert_eq!(accepted.load(Ordering::SeqCst), 1 + RELAY_COUNT);
+}
@@ -0,0 +1,112 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType},
+    run_local,
+};
+
+/// Serves the current online config to every request
+async fn serve_online_config(doc: Arc<Mutex<String>>) {
+    let mut listener = TcpListener::bind("127.0.0.1:9540").await.unwrap();
+
+    loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let mut buf = vec![0u8; 4096];
+        let _ = stream.read(&mut buf).await.unwrap();
+
+        let body = doc.lock().unwrap().clone();
+        let response = format!(
+            "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+    }
+}
+
+async fn list_servers(socket: &mut UdpSocket) -> String {
+    socket.send_to(b"list", "127.0.0.1:9541").await.unwrap();
+
+    let mut buf = vec![0u8; 65536];
+    let n = time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    String::from_utf8(buf[..n].to_vec()).unwrap()
+}
+
+fn online_config(servers: &[(&str, u16)]) -> String {
+    let servers = servers
+        .iter()
+        .map(|(id, port)| {
+            format!(
+                r#"{{"id": "{}", "remarks": "Server {}", "server": "127.0.0.1", "server_port": {}, "password": "password", "method": "aes-256-gcm"}}"#,
+                id, id, port
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(r#"{{"version": 1, "servers": [{}]}}"#, servers)
+}
+
+#[tokio::test]
+async fn online_config_update() {
+    let _ = env_logger::try_init();
+
+    let doc = Arc::new(Mutex::new(online_config(&[("a", 9510)])));
+    tokio::spawn(serve_online_config(doc.clone()));
+    time::delay_for(Duration::from_millis(100)).await;
+
+    let local_config = Config::load_from_str(
+        r#"{
+            "local_port": 9500,
+            "local_address": "127.0.0.1",
+            "control_address": "127.0.0.1:9541",
+            "online_config": {"url": "http://127.0.0.1:9540/config.json", "update_interval": 1},
+            "balancer": {"tcp_probe": "connect"}
+        }"#,
+        ConfigType::Socks5Local,
+    )
+    .unwrap();
+
+    tokio::spawn(run_local(local_config));
+    time::delay_for(Duration::from_secs(1)).await;
+
+    let mut socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let list = list_servers(&mut socket).await;
+    assert!(list.contains("127.0.0.1:9510"));
+
+    // Server "a" is moved, and "b" is new
+    *doc.lock().unwrap() = online_config(&[("a", 9511), ("b", 9520)]);
+    time::delay_for(Duration::from_millis(2500)).await;
+
+    let list = list_servers(&mut socket).await;
+    assert!(!list.contains("127.0.0.1:9510"));
+    assert!(list.contains("127.0.0.1:9511"));
+    assert!(list.contains("127.0.0.1:9520"));
+
+    // Server "a" is removed
+    *doc.lock().unwrap() = online_config(&[("b", 9520)]);
+    time::delay_for(Duration::from_millis(2500)).await;
+
+    let list = list_servers(&mut socket).await;
+    assert!(!list.contains("127.0.0.1:9511"));
+    assert!(list.contains("127.0.0.1:9520"));
+
+    // All servers are replaced
+    *doc.lock().unwrap() = online_config(&[("c", 9530)]);
+    time::delay_for(Duration::from_millis(2500)).await;
+
+    let list = list_servers(&mut socket).await;
+    assert!(!list.contains("127.0.0.1:9520"));
+    assert!(list.contains("127.0.0.1:9530"));
+}